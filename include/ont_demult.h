@@ -0,0 +1,168 @@
+#ifndef ONT_DEMULT_H
+#define ONT_DEMULT_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+/*
+ Kind of the last error (see ont_demult_last_error_kind())
+ */
+typedef enum OntDemultErrorKind {
+  OntDemultErrorNone = 0,
+  OntDemultErrorIo = 1,
+  OntDemultErrorParse = 2,
+  OntDemultErrorConfig = 3,
+  OntDemultErrorInternal = 4,
+} OntDemultErrorKind;
+
+/*
+ Read selection strategy (see the --select option)
+ */
+typedef enum OntDemultSelect {
+  OntDemultSelectStart = 0,
+  OntDemultSelectBoth = 1,
+  OntDemultSelectEither = 2,
+  OntDemultSelectXor = 3,
+  OntDemultSelectEnd = 4,
+  OntDemultSelectPair = 5,
+} OntDemultSelect;
+
+/*
+ Match status of a classified read.  The values are the stable category codes used in all
+ ont_demult outputs
+ */
+typedef enum OntDemultStatus {
+  OntDemultUnmapped = 0,
+  OntDemultLowMapQ = 1,
+  OntDemultNoCutSites = 2,
+  OntDemultUnmatched = 3,
+  OntDemultMatched = 4,
+  OntDemultExcessUnmatched = 5,
+  OntDemultMatchBoth = 6,
+  OntDemultMatchStart = 7,
+  OntDemultMatchEnd = 8,
+  OntDemultMisMatch = 9,
+} OntDemultStatus;
+
+/*
+ Read classifier holding a cut site table and classification parameters
+ */
+typedef struct OntDemultClassifier OntDemultClassifier;
+
+/*
+ Classification of a read
+ */
+typedef struct OntDemultResult {
+  /*
+   Match status
+   */
+  OntDemultStatus status;
+  /*
+   Index of the matched cut site (see ont_demult_site_name()) for Matched and
+   ExcessUnmatched reads, otherwise -1
+   */
+  int64_t site;
+  /*
+   Strand of the match (1 = plus, -1 = minus) for Matched and ExcessUnmatched reads,
+   otherwise 0
+   */
+  int strand;
+  /*
+   Read relative offset of the matched cut site for Matched and ExcessUnmatched reads,
+   otherwise 0
+   */
+  int64_t anchor;
+} OntDemultResult;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/*
+ Create a new classifier.  cut_table has the contents of a cut file (see the README), or can
+ be NULL if there are no cut sites.  The remaining arguments correspond to the command line
+ options of the same names.  Returns NULL on error.
+
+ # Safety
+
+ cut_table must be NULL or point to a NUL terminated string.  The returned classifier must
+ be freed with ont_demult_classifier_free().
+ */
+OntDemultClassifier *ont_demult_classifier_new(const char *cut_table,
+                                               OntDemultSelect select,
+                                               size_t mapq_thresh,
+                                               size_t max_distance,
+                                               size_t max_unmatched,
+                                               size_t margin);
+
+/*
+ Free a classifier created with ont_demult_classifier_new()
+
+ # Safety
+
+ cls must be NULL or a classifier returned by ont_demult_classifier_new() that has not
+ already been freed.
+ */
+void ont_demult_classifier_free(OntDemultClassifier *cls);
+
+/*
+ Number of cut sites in the classifier
+
+ # Safety
+
+ cls must be a valid classifier.
+ */
+size_t ont_demult_n_sites(const OntDemultClassifier *cls);
+
+/*
+ Name of the cut site with index ix (cut sites are indexed in order of name).  Returns NULL if
+ ix is out of range.  The string is owned by the classifier.
+
+ # Safety
+
+ cls must be a valid classifier.
+ */
+const char *ont_demult_site_name(const OntDemultClassifier *cls, size_t ix);
+
+/*
+ Classify a read.  paf has all of the PAF records for a single read (one per line).  If
+ result is not NULL the classification is stored in result.  If buf is not NULL, the line of
+ the results file for the read (without the trailing newline) is written to buf, truncated
+ if necessary to fit in buf_len bytes (including the terminating NUL).  Returns the length
+ of the full results line, or -1 on error.
+
+ # Safety
+
+ cls must be a valid classifier, paf must point to a NUL terminated string, result must be
+ NULL or point to an OntDemultResult, and buf must be NULL or point to at least buf_len bytes.
+ */
+int64_t ont_demult_classify_record(const OntDemultClassifier *cls,
+                                   const char *paf,
+                                   OntDemultResult *result,
+                                   char *buf,
+                                   size_t buf_len);
+
+/*
+ Canonical name of a match status (as used in the results file).  Returns NULL for an
+ unknown status.  The string is static.
+ */
+const char *ont_demult_status_name(int status);
+
+/*
+ Message for the last error in the current thread (or NULL if there has been no error).  The
+ string is valid until the next error in the same thread.
+ */
+const char *ont_demult_last_error(void);
+
+/*
+ Kind of the last error in the current thread: an I/O error, a parse error in the input, a
+ configuration error (invalid arguments or cut site definitions) or an internal error (a
+ caught panic).  Returns OntDemultErrorNone if there has been no error.
+ */
+OntDemultErrorKind ont_demult_last_error_kind(void);
+
+#ifdef __cplusplus
+} // extern "C"
+#endif // __cplusplus
+
+#endif /* ONT_DEMULT_H */
@@ -0,0 +1,144 @@
+// Aggregation of the JSON summaries of multiple runs (aggregate subcommand)
+//
+// The summaries written by each run (<prefix>_summary.json) are combined into a single long
+// format table with one row per run, barcode and metric, for plotting demultiplexing
+// performance across runs.  Run wide metrics are given with the barcode '*'.
+
+use std::{
+    fs::File,
+    io::{self, BufReader, BufWriter, Write},
+    str::FromStr,
+};
+
+use anyhow::Context;
+use serde_json::{json, Value};
+
+#[derive(Debug, Clone, Copy, Default)]
+pub enum AggregateFormat {
+    #[default]
+    Tsv,
+    Json,
+}
+
+impl FromStr for AggregateFormat {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "tsv" => Ok(Self::Tsv),
+            "json" => Ok(Self::Json),
+            _ => Err(anyhow!("Invalid aggregate format {}", s)),
+        }
+    }
+}
+
+struct Row {
+    run: String,
+    barcode: String,
+    metric: String,
+    value: Value,
+}
+
+// Run name from the summary file name, dropping the _summary.json suffix
+fn run_name(file: &str) -> &str {
+    file.strip_suffix("_summary.json")
+        .or_else(|| file.strip_suffix(".json"))
+        .unwrap_or(file)
+}
+
+fn read_summary(file: &str) -> anyhow::Result<Value> {
+    let rdr = BufReader::new(File::open(file)?);
+    let v: Value = serde_json::from_reader(rdr)?;
+    if v.get("program").and_then(|p| p.as_str()) != Some("ont_demult") {
+        return Err(anyhow!("Not an ont_demult summary file"));
+    }
+    Ok(v)
+}
+
+// Fraction n / total (0 if total is 0)
+fn frac(n: u64, total: u64) -> Value {
+    if total > 0 {
+        json!(n as f64 / total as f64)
+    } else {
+        json!(0.0)
+    }
+}
+
+fn summary_rows(run: &str, v: &Value, rows: &mut Vec<Row>) {
+    let mut add = |barcode: &str, metric: String, value: Value| {
+        rows.push(Row {
+            run: run.to_owned(),
+            barcode: barcode.to_owned(),
+            metric,
+            value,
+        })
+    };
+    let get = |k: &str| v.get(k).and_then(|x| x.as_u64()).unwrap_or(0);
+    let total = get("total_reads");
+    let barcodes = v.get("barcodes").and_then(|x| x.as_object());
+    let matched: u64 = barcodes.map(|b| b.values().filter_map(|x| x.as_u64()).sum()).unwrap_or(0);
+    add("*", "reads".to_owned(), json!(total));
+    add("*", "bases".to_owned(), json!(get("total_bases")));
+    for (k, name) in [("counts", "reads"), ("bases", "bases")] {
+        if let Some(m) = v.get(k).and_then(|x| x.as_object()) {
+            for (status, n) in m.iter() {
+                add("*", format!("{}_{}", name, status), n.clone())
+            }
+        }
+    }
+    add("*", "matched_reads".to_owned(), json!(matched));
+    add("*", "matched_fraction".to_owned(), frac(matched, total));
+    add("*", "margin_matches".to_owned(), json!(get("margin_matches")));
+    for (barcode, n) in barcodes.into_iter().flatten() {
+        let n = n.as_u64().unwrap_or(0);
+        add(barcode, "matched_reads".to_owned(), json!(n));
+        add(barcode, "matched_fraction".to_owned(), frac(n, total));
+        add(barcode, "barcode_fraction".to_owned(), frac(n, matched));
+    }
+}
+
+fn write_rows<W: Write>(rows: &[Row], format: AggregateFormat, wrt: &mut W) -> io::Result<()> {
+    match format {
+        AggregateFormat::Tsv => {
+            writeln!(wrt, "run\tbarcode\tmetric\tvalue")?;
+            for r in rows.iter() {
+                writeln!(wrt, "{}\t{}\t{}\t{}", r.run, r.barcode, r.metric, r.value)?;
+            }
+        }
+        AggregateFormat::Json => {
+            let v: Vec<_> = rows
+                .iter()
+                .map(|r| json!({"run": r.run, "barcode": r.barcode, "metric": r.metric, "value": r.value}))
+                .collect();
+            serde_json::to_writer_pretty(&mut *wrt, &v)?;
+            writeln!(wrt)?;
+        }
+    }
+    Ok(())
+}
+
+// Read the summary files and write the aggregated table to output (or stdout if None)
+pub fn aggregate(files: &[&str], format: AggregateFormat, output: Option<&str>) -> anyhow::Result<()> {
+    let mut rows = Vec::new();
+    for file in files {
+        let v = read_summary(file).with_context(|| format!("Error reading summary file {}", file))?;
+        match v.get("status").and_then(|s| s.as_str()) {
+            Some("completed") => (),
+            Some(s) => warn!("Run {} has status {}", file, s),
+            None => warn!("Run {} has no status", file),
+        }
+        summary_rows(run_name(file), &v, &mut rows)
+    }
+    info!("Aggregated {} metrics from {} runs", rows.len(), files.len());
+    match output {
+        Some(file) => {
+            let mut wrt = BufWriter::new(File::create(file).with_context(|| format!("Error creating output file {}", file))?);
+            write_rows(&rows, format, &mut wrt).and_then(|_| wrt.flush())
+        }
+        None => {
+            let stdout = io::stdout();
+            let mut wrt = BufWriter::new(stdout.lock());
+            write_rows(&rows, format, &mut wrt).and_then(|_| wrt.flush())
+        }
+    }
+    .with_context(|| "Error writing aggregated summary")
+}
@@ -0,0 +1,99 @@
+// Alignment of the FASTQ input with minimap2 (--reference without --enzyme or a PAF file)
+//
+// Instead of reading a PAF file, minimap2 is run on the FASTQ input and the reference, and
+// the PAF output is read from a pipe as it is produced, so no intermediate PAF file is needed.
+// The messages written by minimap2 to stderr are logged at the debug level, and the last of
+// these is given in the error if minimap2 fails.  If the input is not read to the end (i.e.,
+// with --preview) minimap2 is killed when the reader is dropped.
+
+use std::{
+    io::{self, BufRead, BufReader, Error, Read},
+    process::{Child, ChildStdout, Command, Stdio},
+    sync::{Arc, Mutex},
+    thread::{self, JoinHandle},
+};
+
+pub struct Minimap2Reader {
+    child: Child,
+    stdout: ChildStdout,
+    last_msg: Arc<Mutex<String>>,   // Last line written to stderr by minimap2
+    logger: Option<JoinHandle<()>>, // Thread logging stderr from minimap2
+    done: bool,                     // Set when minimap2 has exited
+}
+
+impl Minimap2Reader {
+    // Start minimap2 (executable prog) aligning fastq to reference with the additional
+    // arguments in args.  Unless given in args, the number of threads is set to the number
+    // of available cores
+    pub fn new(prog: &str, reference: &str, fastq: &str, args: &str) -> io::Result<Self> {
+        let mut cmd = Command::new(prog);
+        let args: Vec<_> = args.split_whitespace().collect();
+        if !args.iter().any(|a| *a == "-t" || a.starts_with("-t")) {
+            let n = thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+            cmd.arg("-t").arg(n.to_string());
+        }
+        cmd.args(&args).arg(reference).arg(fastq);
+        debug!("Running {:?}", cmd);
+        let mut child = cmd
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| Error::other(format!("Error executing {}: {}", prog, e)))?;
+        let stdout = child.stdout.take().expect("Pipe error");
+        let stderr = child.stderr.take().expect("Pipe error");
+        let last_msg = Arc::new(Mutex::new(String::new()));
+        let lm = last_msg.clone();
+        let logger = thread::spawn(move || {
+            for line in BufReader::new(stderr).lines().map_while(Result::ok) {
+                debug!("minimap2: {}", line);
+                *lm.lock().unwrap() = line
+            }
+        });
+        info!("Aligning reads with minimap2");
+        Ok(Self {
+            child,
+            stdout,
+            last_msg,
+            logger: Some(logger),
+            done: false,
+        })
+    }
+}
+
+impl Read for Minimap2Reader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.stdout.read(buf)?;
+        if n == 0 && !buf.is_empty() && !self.done {
+            // End of output, so check that minimap2 completed successfully
+            let status = self.child.wait()?;
+            self.done = true;
+            if !status.success() {
+                // Wait for the last messages from minimap2 to be read
+                if let Some(h) = self.logger.take() {
+                    let _ = h.join();
+                }
+                return Err(Error::other(format!(
+                    "minimap2 failed ({}): {}",
+                    status,
+                    self.last_msg.lock().unwrap()
+                )));
+            }
+            debug!("minimap2 completed")
+        }
+        Ok(n)
+    }
+}
+
+impl Drop for Minimap2Reader {
+    fn drop(&mut self) {
+        if !self.done {
+            let _ = self.child.kill();
+            let _ = self.child.wait();
+        }
+    }
+}
+
+// Open the PAF output of minimap2 for reading
+pub fn minimap2_reader(prog: &str, reference: &str, fastq: &str, args: &str) -> io::Result<Box<dyn BufRead>> {
+    Ok(Box::new(BufReader::new(Minimap2Reader::new(prog, reference, fastq, args)?)))
+}
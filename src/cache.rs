@@ -0,0 +1,280 @@
+// Cache of read classifications
+//
+// Classifying the reads from a large PAF file is expensive, so the classifications can be
+// stored in a cache directory (--cache-dir) and reused when the same PAF file is processed
+// again with different FASTQ stage options.  Cache entries are keyed by the MD5 checksum of
+// the PAF file together with a digest of the parameters that affect classification
+// (including the cut sites), so changing any of these gives a new entry.  Each entry is a
+// gzip compressed copy of the body of the results file.
+
+use std::{
+    fs::{self, File},
+    io::{self, BufRead, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::classify::AlnPolicy;
+use crate::paf::DEFAULT_FLOAT_PRECISION;
+use crate::compressor::BoxWriter;
+use crate::cut_site::CutSites;
+use crate::params::Param;
+use crate::error;
+use crate::tmp_dir;
+
+const CACHE_MAGIC: &str = "#ont_demult_cache";
+
+// Version of cache entry format.  This must be incremented if the format of the results
+// file changes
+const CACHE_FORMAT: u32 = 1;
+
+// Digest of the parameters affecting classification.  Any new option that changes how
+// reads are classified must be added here
+pub fn param_digest(param: &Param) -> md5::Digest {
+    let mut ctx = md5::Context::new();
+    ctx.consume(format!(
+        "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{:?}\t{:?}\t{:?}\t{}\t{:?}\t{}\t{}\n",
+        env!("CARGO_PKG_VERSION"),
+        CACHE_FORMAT,
+        param.mapq_thresh(),
+        param.max_distance(),
+        param.max_unmatched(),
+        param.margin(),
+        param.end_padding(),
+        param.select(),
+        param.primary_select(),
+        param.start_offset(),
+        param.skip_reads(),
+        param.read_limit(),
+        param.max_paf_records(),
+        param.max_line_length()
+    ));
+    if let Some(f) = param.read_filter() {
+        for (tag, ids) in [("include", f.include()), ("exclude", f.exclude())] {
+            if let Some(ids) = ids {
+                let mut v: Vec<_> = ids.iter().collect();
+                v.sort_unstable();
+                ctx.consume(format!("{}\t{}\n", tag, v.len()));
+                for id in v {
+                    ctx.consume(id);
+                    ctx.consume("\n");
+                }
+            }
+        }
+        if let Some(r) = f.regex() {
+            ctx.consume(format!("regex\t{}\n", r.as_str()));
+        }
+    }
+    // The results lines are cached, so a change of precision needs a new entry
+    if param.float_precision() != DEFAULT_FLOAT_PRECISION {
+        ctx.consume(format!("float_precision\t{}\n", param.float_precision()));
+    }
+    if param.aln_policy() != AlnPolicy::All {
+        ctx.consume(format!("aln_policy\t{}\n", param.aln_policy()));
+    }
+    if let Some(n) = param.refine_ends() {
+        ctx.consume(format!("refine_ends\t{}\n", n));
+    }
+    // Malformed lines are skipped with --lenient rather than stopping the run
+    if param.lenient() {
+        ctx.consume("lenient\n");
+    }
+    if let Some(fl) = param.fastq_lengths() {
+        ctx.consume(format!("qlen_from\tfastq\t{}\t{:x}\n", fl.len(), fl.checksum()));
+    }
+    if let Some(cs) = param.cut_sites() {
+        consume_sites(&mut ctx, cs)
+    }
+    ctx.finalize()
+}
+
+fn consume_sites(ctx: &mut md5::Context, cs: &CutSites) {
+    let mut ctgs: Vec<_> = cs.chash.values().collect();
+    ctgs.sort_unstable_by_key(|c| c.name.clone());
+    for ctg in ctgs {
+        ctx.consume(format!("{}\t{:?}\n", ctg.name, ctg.circular));
+        for s in ctg.cut_sites.iter() {
+            ctx.consume(format!(
+                "{}\t{}\t{}\t{:?}\t{:?}\t{:?}\n",
+                s.name, s.pos, s.barcode, s.max_dist, s.margin, s.pair
+            ));
+        }
+    }
+}
+
+// Digest of the cut sites alone.  The route subcommand (and the main command with
+// --classification) does not take the other classification options, so only the cut sites
+// can be checked against those used to make the classifications
+pub fn sites_digest(param: &Param) -> md5::Digest {
+    let mut ctx = md5::Context::new();
+    if let Some(cs) = param.cut_sites() {
+        consume_sites(&mut ctx, cs)
+    }
+    ctx.finalize()
+}
+
+// MD5 checksum of file contents
+pub fn file_digest<P: AsRef<Path>>(name: P) -> io::Result<md5::Digest> {
+    let mut ctx = md5::Context::new();
+    io::copy(&mut File::open(name)?, &mut ctx)?;
+    Ok(ctx.finalize())
+}
+
+pub struct ClassCache {
+    key: String,
+    path: PathBuf,
+}
+
+impl ClassCache {
+    // Set up cache for the current PAF file and parameters.  Returns None if the PAF
+    // input is from stdin, as this can not be checksummed in advance
+    pub fn new<P: AsRef<Path>>(dir: P, param: &Param) -> io::Result<Option<Self>> {
+        let paf = match param.paf_file() {
+            Some(p) => p,
+            None => {
+                if param.align_reference().is_some() {
+                    warn!("Classification cache can not be used when aligning with minimap2")
+                } else {
+                    warn!("Classification cache can not be used with PAF input from stdin")
+                }
+                return Ok(None);
+            }
+        };
+        fs::create_dir_all(dir.as_ref())?;
+        let key = format!("{:x}{:x}", file_digest(paf)?, param_digest(param));
+        let path = dir.as_ref().join(format!("{}.cls.gz", key));
+        Ok(Some(Self { key, path }))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn is_present(&self) -> bool {
+        self.path.is_file()
+    }
+
+    // Open existing cache entry, checking the header line
+    pub fn reader(&self, param: &Param) -> io::Result<Box<dyn BufRead>> {
+        let mut rdr = param.compressor().reader(Some(&self.path))?;
+        let mut buf = String::new();
+        rdr.read_line(&mut buf)?;
+        let mut it = buf.trim_end().split('\t');
+        if it.next() != Some(CACHE_MAGIC) || it.nth(1) != Some(self.key.as_str()) {
+            return Err(error::Error::parse("Invalid header").in_file(&self.path).at_line(1).into());
+        }
+        Ok(rdr)
+    }
+
+    // Create new cache entry.  The entry is written to a temporary file which is only
+    // renamed to the final name when the entry is complete
+    pub fn writer(&self, param: &Param) -> io::Result<CacheWriter> {
+        let mut tmp = self.path.with_extension("");
+        tmp.set_extension(format!("tmp{}", std::process::id()));
+        let mut wrt = BufWriter::new(param.compressor().writer(&tmp, true)?);
+        writeln!(wrt, "{}\t{}\t{}\t{:x}", CACHE_MAGIC, env!("CARGO_PKG_VERSION"), self.key, sites_digest(param))?;
+        let mut tmp = tmp.into_os_string();
+        tmp.push(".gz");
+        tmp_dir::register_file(&tmp);
+        Ok(CacheWriter {
+            wrt: Some(wrt),
+            tmp: PathBuf::from(tmp),
+            path: self.path.clone(),
+        })
+    }
+}
+
+// Check the header line of a classification file given with --classification (a file written
+// with --classification-out or a cache entry).  A malformed header is an error, while a file
+// made with different cut sites gives a warning (reads matching sites missing from the cut file
+// give an error when the line is parsed).  Files written by earlier versions do not have the
+// cut site digest, so can not be checked
+pub fn check_header(line: &str, param: &Param) -> error::Result<()> {
+    let fd: Vec<_> = line.trim_end().split('\t').collect();
+    let hex = |s: &str| !s.is_empty() && s.bytes().all(|c| c.is_ascii_hexdigit());
+    if !(fd.len() == 3 || fd.len() == 4) || fd[0] != CACHE_MAGIC || !fd[2..].iter().all(|s| hex(s)) {
+        return Err(error::Error::parse(format!("Invalid classification file header: {}", line.trim_end())));
+    }
+    match fd.get(3) {
+        Some(d) => {
+            let digest = format!("{:x}", sites_digest(param));
+            if *d != digest {
+                warn!(
+                    "Classifications (written by ont_demult {}) were made with different cut sites to those given",
+                    fd[1]
+                )
+            }
+        }
+        None => debug!("No cut site digest in classification file header"),
+    }
+    Ok(())
+}
+
+// Create classification file (--classification-out) in the same format as a cache entry,
+// with the key given by the parameter digest alone (so the PAF input can be from stdin).  As
+// for cache entries, the file is written to a temporary file and renamed when complete
+pub fn classification_writer<P: AsRef<Path>>(path: P, param: &Param) -> io::Result<CacheWriter> {
+    let path = path.as_ref();
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(format!(".tmp{}", std::process::id()));
+    let mut wrt = BufWriter::new(param.compressor().writer(&tmp, true)?);
+    writeln!(
+        wrt,
+        "{}\t{}\t{:x}\t{:x}",
+        CACHE_MAGIC,
+        env!("CARGO_PKG_VERSION"),
+        param_digest(param),
+        sites_digest(param)
+    )?;
+    tmp.push(".gz");
+    tmp_dir::register_file(&tmp);
+    Ok(CacheWriter {
+        wrt: Some(wrt),
+        tmp: PathBuf::from(tmp),
+        path: path.to_owned(),
+    })
+}
+
+pub struct CacheWriter {
+    wrt: Option<BufWriter<BoxWriter>>,
+    tmp: PathBuf,
+    path: PathBuf,
+}
+
+impl CacheWriter {
+    pub fn write_line(&mut self, line: &str) -> io::Result<()> {
+        match self.wrt.as_mut() {
+            Some(w) => w.write_all(line.as_bytes()),
+            None => Ok(()),
+        }
+    }
+
+    // Complete entry and move it into place.  If the entry can not be completed, the
+    // temporary file is removed
+    pub fn finish(mut self) -> io::Result<()> {
+        if let Some(w) = self.wrt.take() {
+            // Closing the writer finishes the compressed stream (waiting for any external
+            // compression process to exit) so errors are seen before the entry is moved
+            let res = w
+                .into_inner()
+                .map_err(|e| e.into_error())
+                .and_then(|mut w| w.close())
+                .and_then(|_| fs::rename(&self.tmp, &self.path));
+            if res.is_err() {
+                let _ = fs::remove_file(&self.tmp);
+            }
+            tmp_dir::unregister_file(&self.tmp);
+            res?
+        }
+        Ok(())
+    }
+}
+
+impl Drop for CacheWriter {
+    // Remove incomplete entry
+    fn drop(&mut self) {
+        if self.wrt.take().is_some() {
+            let _ = fs::remove_file(&self.tmp);
+            tmp_dir::unregister_file(&self.tmp);
+        }
+    }
+}
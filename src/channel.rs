@@ -0,0 +1,127 @@
+// Per channel statistics
+//
+// The flowcell channel of a read is taken from a sequencing summary file (if given) or from
+// the ch=<channel> tag in the FASTQ header comment (as written by the ONT basecallers).
+// Counts of reads assigned to each barcode per channel can show up problems localized to
+// part of a flowcell.
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    io::{self, BufRead, Write},
+    path::Path,
+};
+
+use crate::compressor::Compressor;
+use crate::error::{self, Error};
+
+// Read to channel mapping from a sequencing summary file
+#[derive(Debug, Default)]
+pub struct SeqSummary {
+    channels: HashMap<String, u32>,
+}
+
+impl SeqSummary {
+    // Read tab separated sequencing summary file.  The header line is used to find the
+    // read_id and channel columns; other columns are ignored
+    pub fn read<P: AsRef<Path>>(name: P, compressor: &Compressor) -> error::Result<Self> {
+        let name = name.as_ref();
+        let mut rdr = compressor.reader(Some(name))?;
+        let mut buf = String::new();
+        if rdr.read_line(&mut buf)? == 0 {
+            return Err(Error::parse("Empty sequencing summary file").in_file(name));
+        }
+        let hdr: Vec<_> = buf.trim_end_matches(['\r', '\n']).split('\t').collect();
+        let col = |s: &str| {
+            hdr.iter()
+                .position(|x| *x == s)
+                .ok_or_else(|| Error::parse(format!("No {} column in sequencing summary header", s)).in_file(name))
+        };
+        let (id_col, ch_col) = (col("read_id")?, col("channel")?);
+        let mut channels = HashMap::new();
+        let mut line = 1;
+        loop {
+            buf.clear();
+            if rdr.read_line(&mut buf)? == 0 {
+                break;
+            }
+            line += 1;
+            let fd: Vec<_> = buf.trim_end_matches(['\r', '\n']).split('\t').collect();
+            if fd.len() <= id_col.max(ch_col) {
+                if buf.trim().is_empty() {
+                    continue;
+                }
+                return Err(Error::parse("Too few columns").at_line(line).in_file(name));
+            }
+            let ch = fd[ch_col]
+                .parse::<u32>()
+                .map_err(|_| Error::parse("Invalid channel").at_line(line).in_file(name))?;
+            channels.insert(fd[id_col].to_owned(), ch);
+        }
+        info!("Read channels for {} reads from sequencing summary", channels.len());
+        Ok(Self { channels })
+    }
+
+    pub fn channel(&self, id: &str) -> Option<u32> {
+        self.channels.get(id).copied()
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ChannelCounts {
+    counts: BTreeMap<u32, HashMap<String, u64>>, // Reads per barcode for each channel
+    barcodes: BTreeSet<String>,                  // All barcodes seen
+    no_channel: u64,                             // Reads with no channel information
+}
+
+impl ChannelCounts {
+    // Add read with barcode (None if unassigned).  ch is None if the channel is not known
+    pub fn add(&mut self, ch: Option<u32>, barcode: Option<&str>) {
+        let Some(ch) = ch else {
+            self.no_channel += 1;
+            return;
+        };
+        let bc = barcode.unwrap_or("unassigned");
+        let h = self.counts.entry(ch).or_default();
+        match h.get_mut(bc) {
+            Some(x) => *x += 1,
+            None => {
+                h.insert(bc.to_owned(), 1);
+                if barcode.is_some() && !self.barcodes.contains(bc) {
+                    self.barcodes.insert(bc.to_owned());
+                }
+            }
+        }
+    }
+
+    // Write number of reads, number and proportion of reads assigned to a barcode and
+    // the number of reads for each barcode for every channel with at least one read
+    pub fn write_report<W: Write>(&self, wrt: &mut W) -> io::Result<()> {
+        if self.no_channel > 0 {
+            warn!("No channel information for {} reads", self.no_channel)
+        }
+        write!(wrt, "channel\treads\tassigned\tprop. assigned")?;
+        for bc in self.barcodes.iter() {
+            write!(wrt, "\t{}", bc)?;
+        }
+        writeln!(wrt, "\tunassigned")?;
+        for (ch, h) in self.counts.iter() {
+            let get = |bc: &str| h.get(bc).copied().unwrap_or(0);
+            let total: u64 = h.values().sum();
+            let unassigned = get("unassigned");
+            let assigned = total - unassigned;
+            write!(
+                wrt,
+                "{}\t{}\t{}\t{:.4}",
+                ch,
+                total,
+                assigned,
+                assigned as f64 / total as f64
+            )?;
+            for bc in self.barcodes.iter() {
+                write!(wrt, "\t{}", get(bc))?;
+            }
+            writeln!(wrt, "\t{}", unassigned)?;
+        }
+        Ok(())
+    }
+}
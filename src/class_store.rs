@@ -0,0 +1,243 @@
+// Compact store of read classifications for routing the FASTQ input
+//
+// Only what is needed to route a read is kept for each classified read: the match category,
+// the matched site and strand, and whether the read is on a contig without cut sites (for
+// --no-sites-output).  If a memory budget is set (--memory-budget) and the estimated size of
+// the store exceeds it, the classifications are spilled to temporary files partitioned by a
+// hash of the read ID.  The FASTQ input is then split in the same way and routed one
+// partition at a time, with the classifications for the partition loaded back into memory
+// (see FastqRouter::route_partitions() in main.rs).  The temporary directory for the spill
+// files is only created if the store is spilled.
+
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    fs::File,
+    hash::{Hash, Hasher},
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::classify::{Category, MapResult};
+use crate::cut_site::{CutSites, Site};
+use crate::error;
+use crate::id_match::IdMap;
+use crate::no_sites::no_sites_contig;
+use crate::paf::Strand;
+use crate::tmp_dir::TmpDir;
+
+// Number of partitions used when the store is spilled to disk
+pub const N_PARTITIONS: usize = 16;
+
+// Estimated memory use per read in addition to the read ID (entry, value and hash table overhead)
+const ENTRY_OVERHEAD: usize = 40;
+
+// Classification of a read as used for routing
+#[derive(Debug, Clone, Copy)]
+pub struct ReadClass<'a> {
+    category: Category,
+    strand: Strand,         // Strand of match (only meaningful if site is set)
+    no_sites: bool,         // Read is on a contig with no cut sites
+    site: Option<&'a Site>, // Matched site (for Matched and ExcessUnmatched reads)
+}
+
+impl<'a> ReadClass<'a> {
+    pub fn new(mr: &MapResult<'a>, cut_sites: Option<&CutSites>) -> Self {
+        let (site, strand) = match mr {
+            MapResult::Matched(m) | MapResult::ExcessUnmatched(m) => (Some(m.site), m.strand()),
+            _ => (None, Strand::Plus),
+        };
+        Self {
+            category: mr.category(),
+            strand,
+            no_sites: no_sites_contig(mr, cut_sites).is_some(),
+            site,
+        }
+    }
+
+    pub fn unmapped() -> Self {
+        Self {
+            category: Category::Unmapped,
+            strand: Strand::Plus,
+            no_sites: false,
+            site: None,
+        }
+    }
+
+    pub fn category(&self) -> Category {
+        self.category
+    }
+
+    pub fn status(&self) -> &'static str {
+        self.category.name()
+    }
+
+    pub fn site(&self) -> Option<&'a Site> {
+        self.site
+    }
+
+    pub fn strand(&self) -> Strand {
+        self.strand
+    }
+
+    pub fn no_sites(&self) -> bool {
+        self.no_sites
+    }
+}
+
+// Partition of read id when spilled to disk
+pub fn partition(id: &str) -> usize {
+    let mut h = DefaultHasher::new();
+    id.hash(&mut h);
+    (h.finish() % N_PARTITIONS as u64) as usize
+}
+
+// Temporary files for classifications spilled to disk.  Matched sites are recorded by their
+// index in CutSites::site_list()
+struct Spill<'a> {
+    dir: TmpDir,
+    paths: Vec<PathBuf>,
+    files: Vec<BufWriter<File>>,
+    site_ids: HashMap<&'a str, u32>, // Index of each site by name (site names are unique)
+}
+
+pub struct ClassStore<'a> {
+    hash: IdMap<ReadClass<'a>>,
+    cut_sites: Option<&'a CutSites>,
+    budget: Option<usize>,    // Memory budget (if None, no limit)
+    tmp_base: Option<String>, // Base directory for spill files (if None, use $TMPDIR)
+    size: usize,              // Estimated memory use of hash
+    spill: Option<Spill<'a>>,
+}
+
+impl<'a> ClassStore<'a> {
+    pub fn new(cut_sites: Option<&'a CutSites>, budget: Option<usize>, tmp_base: Option<&str>) -> Self {
+        Self {
+            hash: IdMap::default(),
+            cut_sites,
+            budget,
+            tmp_base: tmp_base.map(|s| s.to_owned()),
+            size: 0,
+            spill: None,
+        }
+    }
+
+    pub fn hash(&self) -> &IdMap<ReadClass<'a>> {
+        &self.hash
+    }
+
+    pub fn len(&self) -> usize {
+        self.hash.len()
+    }
+
+    pub fn clear(&mut self) {
+        self.hash.clear();
+        self.size = 0
+    }
+
+    pub fn spilled(&self) -> bool {
+        self.spill.is_some()
+    }
+
+    // Temporary directory holding the spill files (None if not spilled)
+    pub fn spill_dir(&self) -> Option<&Path> {
+        self.spill.as_ref().map(|s| s.dir.path())
+    }
+
+    pub fn insert(&mut self, name: &str, mr: &MapResult<'a>) -> io::Result<()> {
+        if self.hash.insert(name, ReadClass::new(mr, self.cut_sites)) {
+            self.size += name.len() + ENTRY_OVERHEAD
+        }
+        if matches!(self.budget, Some(b) if self.size > b) {
+            self.spill_all()?
+        }
+        Ok(())
+    }
+
+    // Write all classifications in memory to the spill files and clear the store
+    fn spill_all(&mut self) -> io::Result<()> {
+        if self.spill.is_none() {
+            info!("Classifications exceed memory budget: spilling to temporary files");
+            let dir = TmpDir::new(self.tmp_base.as_deref())?;
+            let paths: Vec<_> = (0..N_PARTITIONS).map(|i| dir.path().join(format!("cls_{}.txt", i))).collect();
+            let files = paths
+                .iter()
+                .map(|p| File::create(p).map(BufWriter::new))
+                .collect::<io::Result<Vec<_>>>()?;
+            let site_ids = self
+                .cut_sites
+                .map(|c| c.site_list())
+                .unwrap_or_default()
+                .into_iter()
+                .enumerate()
+                .map(|(i, s)| (s.name.as_str(), i as u32))
+                .collect();
+            self.spill = Some(Spill {
+                dir,
+                paths,
+                files,
+                site_ids,
+            })
+        }
+        let spill = self.spill.as_mut().unwrap();
+        for (name, rc) in self.hash.iter() {
+            let wrt = &mut spill.files[partition(name)];
+            write!(wrt, "{}\t{}\t", name, rc.category.code())?;
+            match rc.site {
+                Some(s) => write!(wrt, "{}", spill.site_ids[s.name.as_str()])?,
+                None => write!(wrt, "*")?,
+            }
+            writeln!(wrt, "\t{}\t{}", rc.strand, rc.no_sites as u8)?
+        }
+        self.hash.clear();
+        self.hash.shrink_to_fit();
+        self.size = 0;
+        Ok(())
+    }
+
+    // Spill the remaining classifications and close the spill files
+    pub fn finish_spill(&mut self) -> io::Result<()> {
+        self.spill_all()?;
+        for f in self.spill.as_mut().unwrap().files.iter_mut() {
+            f.flush()?
+        }
+        Ok(())
+    }
+
+    // Replace the classifications in memory with those spilled to partition i
+    pub fn load(&mut self, i: usize) -> io::Result<()> {
+        let sites = self.cut_sites.map(|c| c.site_list()).unwrap_or_default();
+        let path = self.spill.as_ref().expect("Classifications not spilled").paths[i].clone();
+        let rdr = BufReader::new(File::open(&path)?);
+        self.clear();
+        let mut n = 0;
+        let err = |s: &str, n: usize| -> io::Error {
+            error::Error::parse(format!("Invalid line in spill file: {}", s)).in_file(&path).at_line(n).into()
+        };
+        for line in rdr.lines() {
+            let line = line?;
+            n += 1;
+            let fd: Vec<_> = line.split('\t').collect();
+            if fd.len() != 5 {
+                return Err(err(&line, n));
+            }
+            let category = fd[1].parse().ok().and_then(Category::from_code).ok_or_else(|| err(&line, n))?;
+            let site = match fd[2] {
+                "*" => None,
+                s => Some(*s.parse::<usize>().ok().and_then(|i| sites.get(i)).ok_or_else(|| err(&line, n))?),
+            };
+            let strand = match fd[3] {
+                "+" => Strand::Plus,
+                "-" => Strand::Minus,
+                _ => return Err(err(&line, n)),
+            };
+            let rc = ReadClass {
+                category,
+                strand,
+                no_sites: fd[4] == "1",
+                site,
+            };
+            self.hash.insert(fd[0], rc);
+        }
+        Ok(())
+    }
+}
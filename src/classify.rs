@@ -0,0 +1,582 @@
+// Classification of reads against the cut sites
+//
+// This is the core of ont_demult, and does not depend on any of the file handling or
+// process spawning parts of the crate (see the cli feature), so it can be built for
+// embedding in other programs (i.e., via FFI or WASM).
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    io::{BufRead, Cursor},
+    sync::Arc,
+};
+
+use crate::cut_site::{CutSites, Site};
+use crate::error::{self, Error};
+use crate::paf::{CommonLoc, FindMatch, Location, Match, PafFile, PafRead};
+
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum Select {
+    #[default]
+    Start,
+    Both,
+    Either,
+    Xor,
+    End,
+    Pair,
+}
+
+impl std::str::FromStr for Select {
+    type Err = Error;
+    fn from_str(s: &str) -> error::Result<Self> {
+        let s = s.to_ascii_lowercase();
+        match s.as_str() {
+            "start" => Ok(Self::Start),
+            "both" => Ok(Self::Both),
+            "either" => Ok(Self::Either),
+            "xor" => Ok(Self::Xor),
+            "end" => Ok(Self::End),
+            "pair" => Ok(Self::Pair),
+            _ => Err(Error::config(format!("Invalid Select option {}", s))),
+        }
+    }
+}
+
+impl fmt::Display for Select {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Self::Start => "start",
+            Self::Both => "both",
+            Self::Either => "either",
+            Self::Xor => "xor",
+            Self::End => "end",
+            Self::Pair => "pair",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+// Choice of the mapping record used as the longest match for a read
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum PrimarySelect {
+    #[default]
+    MatchBases,     // Most matching bases
+    AlignmentScore, // Highest alignment score (AS tag, or s1 tag if AS is not present)
+    BlockLength,    // Longest alignment block
+    TpPrimary,      // Primary alignment (tp:A:P), with ties broken by matching bases
+}
+
+impl std::str::FromStr for PrimarySelect {
+    type Err = Error;
+    fn from_str(s: &str) -> error::Result<Self> {
+        let s = s.to_ascii_lowercase();
+        match s.as_str() {
+            "match-bases" => Ok(Self::MatchBases),
+            "alignment-score" => Ok(Self::AlignmentScore),
+            "block-length" => Ok(Self::BlockLength),
+            "tp-primary" => Ok(Self::TpPrimary),
+            _ => Err(Error::config(format!("Invalid PrimarySelect option {}", s))),
+        }
+    }
+}
+
+impl fmt::Display for PrimarySelect {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Self::MatchBases => "match-bases",
+            Self::AlignmentScore => "alignment-score",
+            Self::BlockLength => "block-length",
+            Self::TpPrimary => "tp-primary",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+// Types of alignment (from the tp:A: tag of the PAF records) used for classification.  With
+// PrimaryOnly, only the primary record of a read is used; with PrimarySupplementary, secondary
+// records (tp:A:S or tp:A:i) are ignored (see PafRead::select_alignments)
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum AlnPolicy {
+    #[default]
+    All,
+    PrimarySupplementary,
+    PrimaryOnly,
+}
+
+impl fmt::Display for AlnPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Self::All => "all",
+            Self::PrimarySupplementary => "primary-supplementary",
+            Self::PrimaryOnly => "primary-only",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+// Parameters affecting read classification
+#[derive(Debug, Default)]
+pub struct ClassifyParam {
+    cut_sites: Option<CutSites>,
+    select: Select,
+    primary_select: PrimarySelect,
+    aln_policy: AlnPolicy,
+    mapq_thresh: usize,   // Minimum MAPQ for a mapping to be considered unique
+    max_distance: usize,  // Maximum distance allowed from nearest cut site
+    max_unmatched: usize, // Maximum number of unmatched bases allowed per read
+    margin: usize,        // Extra margin allowed when matching on 'wrong side' of cut site
+    end_padding: usize,   // Distance from the ends of linear contigs treated as adjacent to the terminal sites
+    start_offset: [i64; 2], // Correction for systematic read start offsets (plus, minus strand)
+    site_distance: usize,   // Largest maximum distance including per-site overrides
+    site_margin: usize,     // Largest margin including per-site overrides
+}
+
+impl ClassifyParam {
+    pub fn new(
+        cut_sites: Option<CutSites>,
+        select: Select,
+        mapq_thresh: usize,
+        max_distance: usize,
+        max_unmatched: usize,
+        margin: usize,
+    ) -> Self {
+        let site_distance = cut_sites.as_ref().map(|c| c.max_site_distance(max_distance)).unwrap_or(max_distance);
+        let site_margin = cut_sites.as_ref().map(|c| c.max_site_margin(margin)).unwrap_or(margin);
+        Self {
+            cut_sites,
+            select,
+            mapq_thresh,
+            max_distance,
+            max_unmatched,
+            margin,
+            end_padding: 0,
+            start_offset: [0; 2],
+            primary_select: PrimarySelect::default(),
+            aln_policy: AlnPolicy::default(),
+            site_distance,
+            site_margin,
+        }
+    }
+    pub fn set_primary_select(&mut self, x: PrimarySelect) {
+        self.primary_select = x
+    }
+    pub fn set_aln_policy(&mut self, x: AlnPolicy) {
+        self.aln_policy = x
+    }
+    pub fn set_end_padding(&mut self, x: usize) {
+        self.end_padding = x
+    }
+    pub fn set_start_offset(&mut self, x: [i64; 2]) {
+        self.start_offset = x
+    }
+    pub fn cut_sites(&self) -> Option<&CutSites> {
+        self.cut_sites.as_ref()
+    }
+    pub fn select(&self) -> Select {
+        self.select
+    }
+    pub fn primary_select(&self) -> PrimarySelect {
+        self.primary_select
+    }
+    pub fn aln_policy(&self) -> AlnPolicy {
+        self.aln_policy
+    }
+    pub fn mapq_thresh(&self) -> usize {
+        self.mapq_thresh
+    }
+    pub fn max_distance(&self) -> usize {
+        self.max_distance
+    }
+    pub fn max_unmatched(&self) -> usize {
+        self.max_unmatched
+    }
+    pub fn margin(&self) -> usize {
+        self.margin
+    }
+    // Largest maximum distance and margin allowing for overrides set for individual cut sites
+    pub fn site_distance(&self) -> usize {
+        self.site_distance
+    }
+    pub fn site_margin(&self) -> usize {
+        self.site_margin
+    }
+    pub fn end_padding(&self) -> usize {
+        self.end_padding
+    }
+    pub fn start_offset(&self) -> [i64; 2] {
+        self.start_offset
+    }
+}
+
+// Override of the classification of a read (i.e., from a post classification command)
+#[derive(Debug, Default)]
+pub struct Override {
+    pub status: Option<String>,
+    pub site: Option<String>,
+}
+
+// Classification of reads from PAF file
+#[derive(Debug)]
+pub enum MapResult<'a> {
+    Unmapped(usize),     // Unmapped (normally these are not in the file)
+    LowMapq(usize),      // Low Mapq (no non-unique mapping records)
+    NoCutSites(usize),   // No cut sites
+    Unmatched(Location), // No match to a cut site
+    Matched(Match<'a>),  // Match on strand to a cut site
+    ExcessUnmatched(Match<'a>),
+    MatchBoth(Location),
+    MatchStart(Location),
+    MatchEnd(Location),
+    MisMatch(Location),
+}
+
+// Match status categories
+//
+// The integer codes and canonical names are stable and are shared by the results file, the
+// JSON summary and the library and C APIs.  Existing codes and names will not be changed
+// or reused; any new categories will be given new codes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Category {
+    Unmapped = 0,
+    LowMapQ = 1,
+    NoCutSites = 2,
+    Unmatched = 3,
+    Matched = 4,
+    ExcessUnmatched = 5,
+    MatchBoth = 6,
+    MatchStart = 7,
+    MatchEnd = 8,
+    MisMatch = 9,
+}
+
+impl Category {
+    // All categories in order of code
+    pub const ALL: [Category; 10] = [
+        Self::Unmapped,
+        Self::LowMapQ,
+        Self::NoCutSites,
+        Self::Unmatched,
+        Self::Matched,
+        Self::ExcessUnmatched,
+        Self::MatchBoth,
+        Self::MatchStart,
+        Self::MatchEnd,
+        Self::MisMatch,
+    ];
+
+    pub fn code(self) -> u32 {
+        self as u32
+    }
+
+    // Canonical name, as used in the results file
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Unmapped => "Unmapped",
+            Self::LowMapQ => "LowMapQ",
+            Self::NoCutSites => "NoCutSites",
+            Self::Unmatched => "Unmatched",
+            Self::Matched => "Matched",
+            Self::ExcessUnmatched => "ExcessUnmatched",
+            Self::MatchBoth => "MatchBoth",
+            Self::MatchStart => "MatchStart",
+            Self::MatchEnd => "MatchEnd",
+            Self::MisMatch => "MisMatch",
+        }
+    }
+
+    pub fn from_code(x: u32) -> Option<Self> {
+        Self::ALL.get(x as usize).copied()
+    }
+}
+
+impl fmt::Display for Category {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl std::str::FromStr for Category {
+    type Err = Error;
+    fn from_str(s: &str) -> error::Result<Self> {
+        Self::ALL
+            .iter()
+            .find(|c| c.name() == s)
+            .copied()
+            .ok_or_else(|| Error::parse(format!("Unknown match status {}", s)))
+    }
+}
+
+impl MapResult<'_> {
+    pub fn category(&self) -> Category {
+        match self {
+            Self::Unmapped(_) => Category::Unmapped,
+            Self::LowMapq(_) => Category::LowMapQ,
+            Self::NoCutSites(_) => Category::NoCutSites,
+            Self::Unmatched(_) => Category::Unmatched,
+            Self::MatchBoth(_) => Category::MatchBoth,
+            Self::MatchStart(_) => Category::MatchStart,
+            Self::MatchEnd(_) => Category::MatchEnd,
+            Self::MisMatch(_) => Category::MisMatch,
+            Self::Matched(_) => Category::Matched,
+            Self::ExcessUnmatched(_) => Category::ExcessUnmatched,
+        }
+    }
+
+    // Match status as reported in the results file
+    pub fn status(&self) -> &'static str {
+        self.category().name()
+    }
+
+    // Length of the read
+    pub fn read_len(&self) -> usize {
+        match self {
+            Self::Unmapped(l) | Self::LowMapq(l) | Self::NoCutSites(l) => *l,
+            Self::Matched(m) | Self::ExcessUnmatched(m) => m.read_len(),
+            Self::Unmatched(loc) | Self::MatchBoth(loc) | Self::MatchStart(loc) | Self::MatchEnd(loc) | Self::MisMatch(loc) => {
+                loc.read_len()
+            }
+        }
+    }
+}
+
+impl<'a> MapResult<'a> {
+    // Reconstruct from the fields (after the read name) of a line from the results file
+    pub fn from_fields(
+        fd: &[&str],
+        sites: &HashMap<&str, &'a Site>,
+        ctgs: &mut HashSet<Arc<str>>,
+    ) -> error::Result<Self> {
+        let qlen = || {
+            fd.get(6)
+                .and_then(|s| s.parse::<usize>().ok())
+                .ok_or_else(|| Error::parse("Missing or invalid read length"))
+        };
+        let site = || {
+            sites
+                .get(fd[1])
+                .copied()
+                .ok_or_else(|| Error::parse(format!("Unknown cut site {}", fd[1])))
+        };
+        let mut loc = || -> error::Result<Location> {
+            let contig = match ctgs.get(fd[1]) {
+                Some(c) => c.clone(),
+                None => {
+                    let c: Arc<str> = Arc::from(fd[1]);
+                    ctgs.insert(c.clone());
+                    c
+                }
+            };
+            Ok(Location::new(contig, CommonLoc::from_fields(&fd[3..])?))
+        };
+        if fd.len() < 10 {
+            return Err(Error::parse("Short line"));
+        }
+        Ok(match fd[0] {
+            "Unmapped" => Self::Unmapped(qlen()?),
+            "LowMapQ" => Self::LowMapq(qlen()?),
+            "NoCutSites" => Self::NoCutSites(qlen()?),
+            "Unmatched" => Self::Unmatched(loc()?),
+            "MatchBoth" => Self::MatchBoth(loc()?),
+            "MatchStart" => Self::MatchStart(loc()?),
+            "MatchEnd" => Self::MatchEnd(loc()?),
+            "MisMatch" => Self::MisMatch(loc()?),
+            "Matched" => Self::Matched(Match::from_fields(site()?, &fd[3..])?),
+            "ExcessUnmatched" => Self::ExcessUnmatched(Match::from_fields(site()?, &fd[3..])?),
+            s => return Err(Error::parse(format!("Unknown match status {}", s))),
+        })
+    }
+}
+
+impl<'a> MapResult<'a> {
+    // Apply override from the post classification command.  Reads with a mapping location can
+    // be given any of the location based statuses (with a cut site for Matched and
+    // ExcessUnmatched), and reads without a location can be changed between Unmapped, LowMapQ
+    // and NoCutSites
+    pub fn apply_override(
+        self,
+        ov: &Override,
+        sites: &HashMap<&str, &'a Site>,
+        cut_sites: Option<&CutSites>,
+    ) -> error::Result<Self> {
+        let old_status = self.status();
+        let status = ov.status.as_deref().unwrap_or(old_status);
+        let new_site = match ov.site.as_deref() {
+            Some(s) => Some(
+                sites
+                    .get(s)
+                    .copied()
+                    .ok_or_else(|| Error::parse(format!("Unknown cut site {}", s)))?,
+            ),
+            None => None,
+        };
+        let (len, loc, site) = match self {
+            Self::Unmapped(x) | Self::LowMapq(x) | Self::NoCutSites(x) => (Some(x), None, None),
+            Self::Matched(m) | Self::ExcessUnmatched(m) => {
+                let site = m.site;
+                let contig = cut_sites
+                    .and_then(|cs| cs.site_contig(site))
+                    .cloned()
+                    .ok_or_else(|| Error::parse(format!("Contig not found for cut site {}", site.name)))?;
+                (None, Some((contig, m.into_inner())), Some(site))
+            }
+            Self::Unmatched(l)
+            | Self::MatchBoth(l)
+            | Self::MatchStart(l)
+            | Self::MatchEnd(l)
+            | Self::MisMatch(l) => (None, Some(l.into_parts()), None),
+        };
+        if new_site.is_some() && !matches!(status, "Matched" | "ExcessUnmatched") {
+            return Err(Error::parse(format!("Cut site can not be given for status {}", status)));
+        }
+        let site = || {
+            new_site
+                .or(site)
+                .ok_or_else(|| Error::parse(format!("No cut site given for status {}", status)))
+        };
+        Ok(match (status, len, loc) {
+            ("Unmapped", Some(x), _) => Self::Unmapped(x),
+            ("LowMapQ", Some(x), _) => Self::LowMapq(x),
+            ("NoCutSites", Some(x), _) => Self::NoCutSites(x),
+            ("Matched", _, Some((_, l))) => Self::Matched(Match::new(site()?, l)),
+            ("ExcessUnmatched", _, Some((_, l))) => Self::ExcessUnmatched(Match::new(site()?, l)),
+            ("Unmatched", _, Some((c, l))) => Self::Unmatched(Location::new(c, l)),
+            ("MatchBoth", _, Some((c, l))) => Self::MatchBoth(Location::new(c, l)),
+            ("MatchStart", _, Some((c, l))) => Self::MatchStart(Location::new(c, l)),
+            ("MatchEnd", _, Some((c, l))) => Self::MatchEnd(Location::new(c, l)),
+            ("MisMatch", _, Some((c, l))) => Self::MisMatch(Location::new(c, l)),
+            _ => {
+                return Err(Error::parse(format!(
+                    "Can not change status from {} to {}",
+                    old_status, status
+                )))
+            }
+        })
+    }
+}
+
+impl<'a> fmt::Display for MapResult<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unmapped(x) => write!(f, "Unmapped\t*\t*\t*\t*\t*\t{}\t*\t*\t*", x),
+            Self::LowMapq(x) => write!(f, "LowMapQ\t*\t*\t*\t*\t*\t{}\t*\t*\t*", x),
+            Self::NoCutSites(x) => write!(f, "NoCutSites\t*\t*\t*\t*\t*\t{}\t*\t*\t*", x),
+            // The location is formatted with f so that any precision is passed on
+            Self::Unmatched(l)
+            | Self::MatchBoth(l)
+            | Self::MatchStart(l)
+            | Self::MatchEnd(l)
+            | Self::MisMatch(l) => {
+                write!(f, "{}\t", self.category())?;
+                fmt::Display::fmt(l, f)
+            }
+            Self::Matched(m) | Self::ExcessUnmatched(m) => {
+                write!(f, "{}\t", self.category())?;
+                fmt::Display::fmt(m, f)
+            }
+        }
+    }
+}
+
+// Classify read from PAF file
+pub fn classify<'a>(read: &PafRead, param: &'a ClassifyParam) -> MapResult<'a> {
+    match read.select_alignments(param) {
+        Some(r) => classify_selected(&r, param),
+        None => classify_selected(read, param),
+    }
+}
+
+// Classify read after any filtering of its records by alignment type
+fn classify_selected<'a>(read: &PafRead, param: &'a ClassifyParam) -> MapResult<'a> {
+    if read.is_mapped() {
+        if read.is_unique(param.mapq_thresh()) {
+            if let Some(cut_sites) = param.cut_sites() {
+                if let Some(fm) = read.find_site(cut_sites, param) {
+                    match fm {
+                        FindMatch::Match(m) => MapResult::Matched(m),
+                        FindMatch::ExcessUnmatched(m) => MapResult::ExcessUnmatched(m),
+                        FindMatch::Location(l) => MapResult::Unmatched(l),
+                        FindMatch::MisMatch(l) => MapResult::MisMatch(l),
+                        FindMatch::MatchStart(l) => MapResult::MatchStart(l),
+                        FindMatch::MatchBoth(l) => MapResult::MatchBoth(l),
+                        FindMatch::MatchEnd(l) => MapResult::MatchEnd(l),
+                    }
+                } else {
+                    MapResult::LowMapq(read.qlen)
+                }
+            } else {
+                MapResult::NoCutSites(read.qlen)
+            }
+        } else {
+            MapResult::LowMapq(read.qlen)
+        }
+    } else {
+        MapResult::Unmapped(read.qlen)
+    }
+}
+
+// Read classifier for use from other programs.  This holds the classification parameters
+// (including the cut sites) and classifies reads from their PAF records in the same way as
+// the command line program
+#[derive(Debug, Default)]
+pub struct Classifier {
+    param: ClassifyParam,
+}
+
+impl Classifier {
+    pub fn new(param: ClassifyParam) -> Self {
+        Self { param }
+    }
+    pub fn param(&self) -> &ClassifyParam {
+        &self.param
+    }
+    pub fn cut_sites(&self) -> Option<&CutSites> {
+        self.param.cut_sites()
+    }
+    pub fn set_start_offset(&mut self, x: [i64; 2]) {
+        self.param.set_start_offset(x)
+    }
+    pub fn classify(&self, read: &PafRead) -> MapResult<'_> {
+        classify(read, &self.param)
+    }
+    // Classify a single read from its PAF records (one per line), returning the read name
+    // with the classification.  It is an error if there are records for more than one read
+    pub fn classify_records(&self, paf: &str) -> error::Result<(String, MapResult<'_>)> {
+        let mut paf_file = PafFile::new(Box::new(Cursor::new(paf.as_bytes().to_vec())));
+        let read = paf_file
+            .next_read()?
+            .ok_or_else(|| Error::parse("No PAF records"))?;
+        if paf_file.next_read()?.is_some() {
+            return Err(Error::parse("PAF records for more than one read"));
+        }
+        let mr = self.classify(&read);
+        Ok((read.qname().to_owned(), mr))
+    }
+    // Classify all reads from PAF input (records for a read must be consecutive)
+    pub fn classify_reads(&self, rdr: Box<dyn BufRead>) -> ClassifiedReads<'_> {
+        ClassifiedReads {
+            classifier: self,
+            paf_file: PafFile::new(rdr),
+        }
+    }
+}
+
+// Iterator over reads from PAF input with their classifications (see Classifier::classify_reads())
+pub struct ClassifiedReads<'a> {
+    classifier: &'a Classifier,
+    paf_file: PafFile,
+}
+
+impl<'a> Iterator for ClassifiedReads<'a> {
+    type Item = error::Result<(PafRead, MapResult<'a>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.paf_file.next_read() {
+            Ok(Some(read)) => {
+                let mr = self.classifier.classify(&read);
+                Some(Ok((read, mr)))
+            }
+            Ok(None) => None,
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
@@ -1,31 +1,424 @@
 use anyhow::Context;
 
-use clap::{Command, Arg, ArgMatches, crate_version};
+use clap::{Command, Arg, ArgGroup, ArgMatches, crate_version};
 
 use super::*;
-use crate::cut_site::read_cut_file;
+use crate::aggregate::{aggregate, AggregateFormat};
+use crate::channel::SeqSummary;
+use crate::compressor::{self, Compressor};
+use crate::contam::ContamScreen;
+use crate::cut_site::{read_cut_file_format, site_defs, CollisionPolicy, CutFormat, CutSites};
+use crate::digest::{digest_fasta, Enzyme};
 use crate::log_level::init_log;
+use crate::output::SplitBy;
+use crate::read_filter::ReadFilter;
+use crate::read_group::ReadGroups;
+use crate::sam::InputFormat;
+use crate::salvage::salvage;
+use crate::site_seq::write_site_seqs;
+use crate::snp::SnpSet;
+use crate::stats::stats;
+
+// Log level option (shared between the main command and subcommands)
+fn loglevel_arg() -> Arg<'static> {
+    Arg::new("loglevel")
+        .short('l').long("loglevel")
+        .takes_value(true).value_name("LOGLEVEL")
+        .possible_values(["none", "error", "warn", "info", "debug", "trace"])
+        .ignore_case(true).default_value("info")
+        .help("Set log level")
+}
+
+// Cut sites from cut files and/or inline definitions (shared between the main command and
+// the route subcommand)
+fn sites_group() -> ArgGroup<'static> {
+    ArgGroup::new("sites")
+        .args(&["cut_file", "site", "enzyme"])
+        .multiple(true)
+}
+
+// Options naming input files.  Options not defined for a subcommand are skipped
+const INPUT_FILE_ARGS: [&str; 15] = [
+    "paf_file",
+    "classification",
+    "fastq",
+    "reference",
+    "cut_file",
+    "site_groups",
+    "site_pairs",
+    "sample_sheet",
+    "include_ids",
+    "exclude_ids",
+    "contaminants",
+    "contaminant_paf",
+    "seq_summary",
+    "read_groups",
+    "snps",
+];
+
+// Options defining the cut sites (shared between the main command and the route and site-seq
+// subcommands)
+fn site_args() -> Vec<Arg<'static>> {
+    vec![
+        Arg::new("cut_file")
+            .short('f').long("cut-file")
+            .takes_value(true).value_name("FILE")
+            .multiple_occurrences(true)
+            .help("File with details of cut sites (can be given multiple times, '-' for stdin)"),
+        Arg::new("cut_format")
+            .long("cut-format")
+            .takes_value(true).value_name("FORMAT")
+            .possible_values(["auto", "cut", "bed"])
+            .ignore_case(true).default_value("auto")
+            .help("Format of cut files (auto selects BED for files ending .bed or .bed.gz)"),
+        Arg::new("site")
+            .long("site")
+            .takes_value(true).value_name("CONTIG:POS:NAME:BARCODE")
+            .multiple_occurrences(true)
+            .help("Cut site definition (can be given multiple times, and used with or instead of a cut file)"),
+        Arg::new("reference")
+            .long("reference")
+            .takes_value(true).value_name("FASTA")
+            .help("Reference sequences for finding cut sites of restriction enzymes (with --enzyme), or for aligning the FASTQ input with minimap2 (without --enzyme or a PAF file)"),
+        Arg::new("enzyme")
+            .long("enzyme")
+            .takes_value(true).value_name("NAME[:BARCODE]")
+            .multiple_occurrences(true)
+            .requires("reference")
+            .help("Restriction enzyme (name or recognition sequence with the cut marked by ^) for cut sites from the reference (can be given multiple times)"),
+        Arg::new("site_groups")
+            .long("site-groups")
+            .takes_value(true).value_name("FILE")
+            .requires("sites")
+            .help("File assigning cut sites to groups (i.e., genes or amplicons)"),
+        Arg::new("site_pairs")
+            .long("site-pairs")
+            .takes_value(true).value_name("FILE")
+            .requires("sites")
+            .help("File with pairs of cut sites at the ends of amplicons (for --select pair)"),
+        Arg::new("sample_sheet")
+            .long("sample-sheet")
+            .takes_value(true).value_name("FILE")
+            .requires("sites")
+            .help("Sample sheet (TSV or CSV) mapping barcodes to sample names"),
+    ]
+}
+
+// Input/Output options shared between the main command and the route subcommand
+fn io_args() -> Vec<Arg<'static>> {
+    let mut v = site_args();
+    v.extend([
+        Arg::new("group_fastq")
+            .long("group-fastq")
+            .requires("site_groups")
+            .help("Output FASTQ files for each group of cut sites"),
+        Arg::new("fastq")
+            .short('F').long("fastq")
+            .takes_value(true).value_name("FILE")
+            .help("Input FASTQ file for demultiplexing"),
+        Arg::new("include_ids")
+            .long("include-ids")
+            .takes_value(true).value_name("FILE")
+            .help("Only process reads with IDs listed in FILE"),
+        Arg::new("exclude_ids")
+            .long("exclude-ids")
+            .takes_value(true).value_name("FILE")
+            .help("Do not process reads with IDs listed in FILE"),
+        Arg::new("id_regex")
+            .long("id-regex")
+            .takes_value(true).value_name("REGEX")
+            .help("Only process reads with IDs matching REGEX"),
+        Arg::new("id_match")
+            .long("id-match")
+            .takes_value(true).value_name("MODE")
+            .requires("fastq")
+            .help("Matching of FASTQ IDs to classified reads (exact, prefix, uuid or regex:PATTERN)"),
+        Arg::new("qlen_from")
+            .long("qlen-from")
+            .takes_value(true).value_name("SOURCE")
+            .possible_values(["column", "fastq"]).ignore_case(true)
+            .default_value("column")
+            .help("Source of query length for unused fraction calculations"),
+        Arg::new("skip_reads")
+            .long("skip-reads")
+            .takes_value(true).value_name("N")
+            .help("Skip the first N reads of the PAF and FASTQ inputs"),
+        Arg::new("limit_reads")
+            .long("limit-reads")
+            .takes_value(true).value_name("N")
+            .help("Process at most N reads (after any skipped reads) from the PAF and FASTQ inputs"),
+        Arg::new("max_reads")
+            .long("max-reads")
+            .takes_value(true).value_name("N")
+            .help("Stop after N reads from the PAF or FASTQ input, marking the run as partial"),
+        Arg::new("max_runtime")
+            .long("max-runtime")
+            .takes_value(true).value_name("TIME")
+            .help("Stop if the run takes longer than TIME seconds (m or h suffixes allowed), marking the run as partial"),
+        Arg::new("contaminants")
+            .long("contaminants")
+            .takes_value(true).value_name("FILE")
+            .requires("fastq")
+            .help("FASTA file with contaminant sequences for k-mer screening of reads"),
+        Arg::new("contaminant_paf")
+            .long("contaminant-paf")
+            .takes_value(true).value_name("FILE")
+            .requires("fastq")
+            .help("PAF file with mappings of reads to contaminant sequences"),
+        Arg::new("contam_threshold")
+            .long("contam-threshold")
+            .takes_value(true).value_name("FLOAT").default_value("0.1")
+            .help("Minimum proportion of read k-mers found in contaminant sequences to flag a read"),
+        Arg::new("matched_only")
+            .short('M').long("matched-only")
+            .help("Only output matched FASTQ records [default: Output all FASTQ records]"),
+        Arg::new("also_combined")
+            .long("also-combined")
+            .requires("fastq")
+            .help("Also output FASTQ files with the matched reads for each barcode"),
+        Arg::new("split_by")
+            .long("split-by")
+            .takes_value(true).value_name("MODE")
+            .possible_values(["site", "barcode"])
+            .ignore_case(true).default_value("site")
+            .help("Write FASTQ files of matched reads for each cut site or for each barcode"),
+        Arg::new("split_results")
+            .long("split-results")
+            .help("Write a separate results file for each match status"),
+        Arg::new("no_combined_results")
+            .long("no-combined-results")
+            .requires("split_results")
+            .help("Do not write the combined results file (with --split-results)"),
+        Arg::new("defer_results")
+            .long("defer-results")
+            .help("Write the results files once classification is final (after the FASTQ routing stage)"),
+        Arg::new("float_precision")
+            .long("float-precision")
+            .takes_value(true).value_name("INT").default_value("4")
+            .help("Number of decimal places for the proportion of unused bases in the results files"),
+        Arg::new("no_fastq_output")
+            .long("no-fastq-output")
+            .requires("fastq")
+            .help("Process the FASTQ input for statistics only, without writing any FASTQ output files"),
+        Arg::new("no_sites_output")
+            .long("no-sites-output")
+            .requires("sites")
+            .help("Write reads mapping to contigs with no cut sites to a separate output, with a report of reads per contig"),
+        Arg::new("tag_only")
+            .long("tag-only")
+            .requires("fastq")
+            .conflicts_with_all(&["no_fastq_output", "also_combined", "group_fastq", "run_fastq"])
+            .help("Write all reads to a single FASTQ file, with the classification added to the header"),
+        Arg::new("flag_only")
+            .long("flag-only")
+            .help("Route matched reads failing secondary filters to their cut site, flagging them in the FASTQ header"),
+        Arg::new("orient_reads")
+            .long("orient-reads")
+            .help("Reverse complement matched reads on the minus strand when writing FASTQ output"),
+        Arg::new("seq_summary")
+            .long("seq-summary")
+            .takes_value(true).value_name("FILE")
+            .requires("channel_report")
+            .help("Sequencing summary file giving the channel of each read"),
+        Arg::new("channel_report")
+            .long("channel-report")
+            .help("Report barcode assignment counts per flowcell channel"),
+        Arg::new("margin_report")
+            .long("margin-report")
+            .requires("sites")
+            .help("Report the distribution of cut site overruns for matched reads relying on the margin allowance"),
+        Arg::new("read_groups")
+            .long("read-groups")
+            .takes_value(true).value_name("FILE")
+            .help("File assigning reads to runs by read ID prefix or regular expression (statistics are split by run)"),
+        Arg::new("length_bins")
+            .long("length-bins")
+            .takes_value(true).value_name("LENGTHS")
+            .min_values(0).require_equals(true)
+            .default_missing_value("1000,5000,20000")
+            .help("Split statistics by read length, with bins starting at the comma separated LENGTHS [default: 1000,5000,20000]"),
+        Arg::new("mapq_bins")
+            .long("mapq-bins")
+            .takes_value(true).value_name("MAPQS")
+            .min_values(0).require_equals(true)
+            .default_missing_value("1,10,30,60")
+            .help("Split statistics by MAPQ of the primary record, with bins starting at the comma separated MAPQS [default: 1,10,30,60]"),
+        Arg::new("run_fastq")
+            .long("run-fastq")
+            .requires("read_groups")
+            .help("Output FASTQ files of matched reads for each run and cut site"),
+        Arg::new("hotspots")
+            .long("hotspots")
+            .takes_value(true).value_name("N")
+            .help("Report the top N hotspots of start positions of unmatched reads"),
+        Arg::new("suggest_sites")
+            .long("suggest-sites")
+            .takes_value(true).value_name("FILE")
+            .help("Write candidate cut sites from hotspots of unmatched reads to FILE in cut file format"),
+        Arg::new("suggest_min_reads")
+            .long("suggest-min-reads")
+            .takes_value(true).value_name("N").default_value("10")
+            .help("Minimum number of unmatched reads in a hotspot for a candidate cut site"),
+        Arg::new("end_profile")
+            .long("end-profile")
+            .requires("sites")
+            .help("Report distribution of matched read end positions relative to the opposite cut site"),
+        Arg::new("end_bin_size")
+            .long("end-bin-size")
+            .takes_value(true).value_name("INT").default_value("100")
+            .help("Bin size for end position profile"),
+        Arg::new("second_site")
+            .long("second-site")
+            .requires("sites")
+            .help("Report the second closest cut site and its distance for matched reads"),
+        Arg::new("bed")
+            .long("bed")
+            .requires("sites")
+            .help("Write BED files of matched reads and cut sites for viewing in a genome browser"),
+        Arg::new("bedgraph")
+            .long("bedgraph")
+            .requires("sites")
+            .help("Write bedgraph of the number of matched reads starting at each position"),
+        Arg::new("targets_bed")
+            .long("targets-bed")
+            .takes_value(true).value_name("WINDOW")
+            .requires("sites")
+            .help("Write BED file of target regions (cut sites +/- WINDOW bp) for adaptive sampling"),
+        Arg::new("prefix")
+            .short('p').long("prefix")
+            .takes_value(true).value_name("PREFIX")
+            .default_value(DEFAULT_PREFIX)
+            .help("Prefix for file names"),
+        Arg::new("compress")
+            .short('z').long("compress")
+            .help("Compress output files with gzip (equivalent to --compress-results --compress-fastq)"),
+        Arg::new("compress_results")
+            .long("compress-results")
+            .help("Compress the results file and reports with gzip"),
+        Arg::new("compress_fastq")
+            .long("compress-fastq")
+            .help("Compress FASTQ output files with gzip"),
+        Arg::new("verify_outputs")
+            .long("verify-outputs")
+            .help("Re-read FASTQ output files after closing and check the record counts"),
+        Arg::new("metrics_port")
+            .long("metrics-port")
+            .takes_value(true).value_name("PORT")
+            .help("Serve Prometheus style metrics on PORT"),
+        Arg::new("metrics_bind")
+            .long("metrics-bind")
+            .takes_value(true).value_name("ADDR").default_value("127.0.0.1")
+            .help("Address for the metrics server to listen on (i.e., 0.0.0.0 for all interfaces)"),
+        Arg::new("write_buffer_size")
+            .long("write-buffer-size")
+            .takes_value(true).value_name("SIZE").default_value("8K")
+            .help("Buffer size for output files (K, M or G suffixes allowed)"),
+        Arg::new("flush_interval")
+            .long("flush-interval")
+            .takes_value(true).value_name("SECONDS")
+            .help("Flush output files periodically"),
+        Arg::new("decompress_threads")
+            .long("decompress-threads")
+            .takes_value(true).value_name("INT").default_value("1")
+            .help("Number of threads for decompression of the FASTQ input (or auto)"),
+        Arg::new("fastq_threads")
+            .long("fastq-threads")
+            .takes_value(true).value_name("INT").default_value("1")
+            .help("Number of threads for looking up the classifications of FASTQ reads (or auto)"),
+        Arg::new("ordered")
+            .long("ordered")
+            .help("Keep the records in each FASTQ output in input order with --fastq-threads"),
+        Arg::new("compress_threads")
+            .long("compress-threads")
+            .takes_value(true).value_name("INT").default_value("1")
+            .help("Number of threads for internal gzip compression of output files (or auto)"),
+        Arg::new("max_line_length")
+            .long("max-line-length")
+            .takes_value(true).value_name("SIZE").default_value("256M")
+            .help("Maximum length of an input line; longer PAF lines are truncated and larger FASTQ records give an error (K, M or G suffixes allowed)"),
+        Arg::new("tmp_dir")
+            .long("tmp-dir")
+            .takes_value(true).value_name("DIR")
+            .help("Directory for temporary files [default: $TMPDIR or /tmp]"),
+        Arg::new("skip_if_current")
+            .long("skip-if-current")
+            .help("Skip the run if the outputs of a previous run with the same inputs and options are present"),
+        Arg::new("compressor")
+            .long("compressor")
+            .takes_value(true).value_name("BACKEND").default_value("auto")
+            .help("Compression backend (auto, internal or external:<PATH>)"),
+        Arg::new("on_complete_cmd")
+            .long("on-complete-cmd")
+            .takes_value(true).value_name("COMMAND")
+            .help("Command to run at end of run with JSON summary on stdin"),
+        Arg::new("on_complete_url")
+            .long("on-complete-url")
+            .takes_value(true).value_name("URL")
+            .help("URL to POST JSON summary to at end of run (only http:// URLs are supported, not https://)"),
+    ]);
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    let v = v
+        .into_iter()
+        .chain(std::iter::once(
+            Arg::new("io_uring")
+                .long("io-uring")
+                .help("Use io_uring for uncompressed input and output files"),
+        ))
+        .collect();
+    v
+}
 
 fn command_line() -> ArgMatches {
     Command::new("ont_demult").version(crate_version!()).author("Simon Heath")
        .about("Takes a paf file (from minimap2) and a list of cut sites and will categorize reads based on the starting points relative to sut sites")
+       .arg(loglevel_arg())
        .arg(
-           Arg::new("loglevel")
-              .short('l').long("loglevel")
-              .takes_value(true).value_name("LOGLEVEL")
-              .possible_values(&["none", "error", "warn", "info", "debug", "trace"])
-              .ignore_case(true).default_value("info")
-              .help("Set log level")
+           Arg::new("trace_sample")
+              .long("trace-sample")
+              .takes_value(true).value_name("FRAC")
+              .help("Output trace messages for the classification of a random sample of reads (proportion FRAC)"),
+       )
+       .arg(
+           Arg::new("trace_seed")
+              .long("trace-seed")
+              .takes_value(true).value_name("SEED").default_value("0")
+              .requires("trace_sample")
+              .help("Seed for sampling reads with --trace-sample"),
+       )
+       .arg(
+           Arg::new("threads")
+              .short('t').long("threads")
+              .takes_value(true).value_name("INT").default_value("1")
+              .help("Number of threads used for classification (or auto to set the threads for all stages automatically)"),
        )
        .next_help_heading("Selection")
        .arg(
            Arg::new("select")
               .short('S').long("select")
               .takes_value(true).value_name("STRATEGY")
-              .possible_values(["start", "both", "either", "xor"])
+              .possible_values(["start", "both", "either", "xor", "end", "pair"])
               .ignore_case(true).default_value("start")
               .help("Read selection strategy"),
        )
+       .arg(
+           Arg::new("primary_select")
+              .long("primary-select")
+              .takes_value(true).value_name("METHOD")
+              .possible_values(["match-bases", "alignment-score", "block-length", "tp-primary"])
+              .ignore_case(true).default_value("match-bases")
+              .help("Method used to select the longest mapping record of a read"),
+       )
+       .arg(
+           Arg::new("use_primary_only")
+              .long("use-primary-only")
+              .conflicts_with("include_supplementary")
+              .help("Only use the primary mapping record of a read (secondary and supplementary records are ignored)"),
+       )
+       .arg(
+           Arg::new("include_supplementary")
+              .long("include-supplementary")
+              .help("Use primary and supplementary mapping records of a read (secondary records are ignored)"),
+       )
        .arg(
            Arg::new("mapq_threshold")
               .short('q').long("mapq-threshold")
@@ -50,78 +443,733 @@ fn command_line() -> ArgMatches {
               .takes_value(true).value_name("INT").default_value("10")
               .help("Extra distance at start of reads on 'other side' of cut site"),
        )
+       .arg(
+           Arg::new("site_collisions")
+              .long("site-collisions")
+              .takes_value(true).value_name("POLICY")
+              .possible_values(["error", "warn", "merge", "nearest-wins-with-flag"])
+              .ignore_case(true).default_value("warn")
+              .help("Policy for cut sites of different barcodes within 2 x max-distance of each other"),
+       )
+       .arg(
+           Arg::new("end_padding")
+              .long("end-padding")
+              .takes_value(true).value_name("INT").default_value("0")
+              .help("Treat read positions within INT of the ends of linear contigs as adjacent to the terminal cut sites"),
+       )
+       .arg(
+           Arg::new("auto_offset")
+              .long("auto-offset")
+              .requires_all(&["sites", "paf_file"])
+              .help("Estimate systematic read start offsets from the first reads and correct for them when matching"),
+       )
+       .arg(
+           Arg::new("refine_ends")
+              .long("refine-ends")
+              .takes_value(true).value_name("INT")
+              .min_values(0).require_equals(true)
+              .default_missing_value("8")
+              .help("Refine alignment ends using cs or cg tags, trimming to the first run of at least INT matching bases [default: 8]"),
+       )
+       .arg(
+           Arg::new("verify_deterministic")
+              .long("verify-deterministic")
+              .requires("paf_file")
+              .help("Check that the first reads are classified identically with one and multiple threads before the run"),
+       )
        .next_help_heading("Input/Output")
+       .args(io_args())
+       .group(sites_group())
+       .arg(
+           Arg::new("cache_dir")
+              .long("cache-dir")
+              .takes_value(true).value_name("DIR")
+              .help("Cache read classifications in DIR and reuse them for the same PAF file"),
+       )
+       .arg(
+           Arg::new("synced")
+              .long("synced")
+              .requires("fastq")
+              .conflicts_with_all(&["cache_dir", "id_match"])
+              .help("PAF and FASTQ reads are in the same order: route reads as they are classified without storing all classifications"),
+       )
+       .arg(
+           Arg::new("unsorted_paf")
+              .long("unsorted-paf")
+              .conflicts_with("synced")
+              .help("Records for a read may not be adjacent in the PAF input (i.e., sorted by position): regroup them by read in memory"),
+       )
        .arg(
-           Arg::new("cut_file")
-              .short('f').long("cut-file")
+           Arg::new("lenient")
+              .long("lenient")
+              .help("Skip short or unparseable PAF lines (counted and logged) instead of failing"),
+       )
+       .arg(
+           Arg::new("memory_budget")
+              .long("memory-budget")
+              .takes_value(true).value_name("SIZE")
+              .requires("fastq")
+              .conflicts_with_all(&["synced", "id_match"])
+              .help("Memory for read classifications before spilling to temporary files (K, M or G suffixes allowed)"),
+       )
+       .arg(
+           Arg::new("classification_out")
+              .long("classification-out")
               .takes_value(true).value_name("FILE")
-              .help("File with details of cut sites"),
+              .help("Write read classifications to FILE for use with route --classification-in"),
+       )
+       .arg(
+           Arg::new("classification")
+              .long("classification")
+              .takes_value(true).value_name("FILE")
+              .requires("fastq")
+              .conflicts_with_all(&["paf_file", "cache_dir", "classification_out", "sam", "bam"])
+              .help("Demultiplex the FASTQ file using read classifications from a previous run (as the route subcommand)"),
+       )
+       .arg(
+           Arg::new("max_paf_records")
+              .long("max-paf-records")
+              .takes_value(true).value_name("INT").default_value("1000")
+              .help("Maximum number of PAF records used for a read (further records are ignored and the read reported)"),
        )
        .arg(
-           Arg::new("fastq")
-              .short('F').long("fastq")
+           Arg::new("post_classify_cmd")
+              .long("post-classify-cmd")
+              .takes_value(true).value_name("COMMAND")
+              .conflicts_with("cache_dir")
+              .help("Command to review read classifications (JSONL on stdin) and return overrides on stdout"),
+       )
+       .arg(
+           Arg::new("preview")
+              .long("preview")
+              .takes_value(true).value_name("N")
+              .conflicts_with("cache_dir")
+              .help("Classify the first N reads only and print a summary without writing output files"),
+       )
+       .arg(
+           Arg::new("efficiency_report")
+              .long("efficiency-report")
+              .requires("sites")
+              .conflicts_with("cache_dir")
+              .help("Report cut efficiency (matched reads as a percentage of reads mapped near the site) for each site"),
+       )
+       .arg(
+           Arg::new("chain_out")
+              .long("chain-out")
+              .requires("sites")
+              .conflicts_with("cache_dir")
+              .help("Write a PAF line for the chain of alignments used to classify each matched read"),
+       )
+       .arg(
+           Arg::new("snps")
+              .long("snps")
               .takes_value(true).value_name("FILE")
-              .help("Input FASTQ file for demultiplexing"),
+              .requires("fastq")
+              .conflicts_with("cache_dir")
+              .help("VCF file of SNPs used to split matched reads by allele (requires cs tags in the PAF file)"),
        )
        .arg(
-           Arg::new("matched_only")
-              .short('M').long("matched-only")
-              .help("Only output matched FASTQ records [default: Output all FASTQ records]"),
+           Arg::new("sam")
+              .long("sam")
+              .conflicts_with("bam")
+              .help("Input alignments are in SAM format"),
        )
        .arg(
-           Arg::new("prefix")
-              .short('p').long("prefix")
-              .takes_value(true).value_name("PREFIX")
-              .default_value(DEFAULT_PREFIX)
-              .help("Prefix for file names"),
+           Arg::new("bam")
+              .long("bam")
+              .help("Input alignments are in BAM format"),
        )
        .arg(
-           Arg::new("compress")
-              .short('z').long("compress")
-              .help("Compress output files with gzip"),
+           Arg::new("minimap2")
+              .long("minimap2")
+              .takes_value(true).value_name("PATH").default_value("minimap2")
+              .help("minimap2 executable for aligning the FASTQ input to the reference"),
+       )
+       .arg(
+           Arg::new("minimap2_args")
+              .long("minimap2-args")
+              .takes_value(true).value_name("ARGS").default_value("-x map-ont")
+              .allow_hyphen_values(true)
+              .help("Additional arguments for minimap2"),
        )
        .arg(
            Arg::new("paf_file")
               .takes_value(true).value_name("Input PAF file")
-              .help("Input PAF file [default: <stdin>]"),
+              .help("Input PAF file (or SAM/BAM file with --sam/--bam) [default: <stdin>]"),
+       )
+       .subcommand(
+           Command::new("route")
+              .about("Demultiplex FASTQ file using read classifications from a previous run")
+              .arg(loglevel_arg())
+              .next_help_heading("Input/Output")
+              .arg(
+                  Arg::new("classification")
+                     .long("classification")
+                     .visible_alias("classification-in")
+                     .takes_value(true).value_name("FILE").required(true)
+                     .help("Read classifications (results file or cache entry from a previous run)"),
+              )
+              .args(io_args())
+              .group(sites_group())
+              .mut_arg("fastq", |a| a.required(true)),
+       )
+       .subcommand(
+           Command::new("site-seq")
+              .about("Write the reference sequence around each cut site to FASTA")
+              .arg(loglevel_arg())
+              .args(site_args())
+              .group(sites_group().required(true))
+              .mut_arg("reference", |a| a.required(true).help("Reference sequences (FASTA)"))
+              .arg(
+                  Arg::new("window")
+                     .short('w').long("window")
+                     .takes_value(true).value_name("INT").default_value("500")
+                     .help("Number of bases either side of the cut"),
+              )
+              .arg(
+                  Arg::new("output")
+                     .short('o').long("output")
+                     .takes_value(true).value_name("FILE")
+                     .help("Output FASTA file [default: <stdout>]"),
+              ),
+       )
+       .subcommand(
+           Command::new("salvage")
+              .about("Recover the complete FASTQ records from a truncated compressed output of an interrupted run")
+              .arg(loglevel_arg())
+              .arg(
+                  Arg::new("output")
+                     .short('o').long("output")
+                     .takes_value(true).value_name("FILE")
+                     .help("Output file (compressed) [default: input name with _salvaged added before .fastq]"),
+              )
+              .arg(
+                  Arg::new("input")
+                     .takes_value(true).value_name("FILE").required(true)
+                     .help("Truncated FASTQ file (gzip compressed)"),
+              ),
+       )
+       .subcommand(
+           Command::new("aggregate")
+              .about("Combine the JSON summaries of multiple runs into a long format table (run, barcode, metric, value)")
+              .arg(loglevel_arg())
+              .arg(
+                  Arg::new("format")
+                     .long("format")
+                     .takes_value(true).value_name("FORMAT")
+                     .possible_values(["tsv", "json"])
+                     .ignore_case(true).default_value("tsv")
+                     .help("Output format"),
+              )
+              .arg(
+                  Arg::new("output")
+                     .short('o').long("output")
+                     .takes_value(true).value_name("FILE")
+                     .help("Output file [default: <stdout>]"),
+              )
+              .arg(
+                  Arg::new("summary")
+                     .takes_value(true).value_name("SUMMARY")
+                     .multiple_values(true).required(true)
+                     .help("JSON summary files (<prefix>_summary.json) from ont_demult runs"),
+              ),
+       )
+       .subcommand(
+           Command::new("stats")
+              .about("Report read counts by match status, cut site and barcode from the results files of previous runs")
+              .arg(loglevel_arg())
+              .arg(
+                  Arg::new("format")
+                     .long("format")
+                     .takes_value(true).value_name("FORMAT")
+                     .possible_values(["tsv", "json"])
+                     .ignore_case(true).default_value("tsv")
+                     .help("Output format"),
+              )
+              .arg(
+                  Arg::new("length_bins")
+                     .long("length-bins")
+                     .takes_value(true).value_name("LENGTHS")
+                     .default_value("1000,5000,20000")
+                     .help("Read length bins for matched reads, starting at the comma separated LENGTHS"),
+              )
+              .arg(
+                  Arg::new("output")
+                     .short('o').long("output")
+                     .takes_value(true).value_name("FILE")
+                     .help("Output file [default: <stdout>]"),
+              )
+              .arg(
+                  Arg::new("results")
+                     .takes_value(true).value_name("RESULTS")
+                     .multiple_values(true).required(true)
+                     .help("Results files (<prefix>_res.txt) from ont_demult runs"),
+              ),
        )
        .get_matches()
 }
 
-pub fn process_cli() -> anyhow::Result<Param> {
+// Read cut sites from the cut files, reference digest and inline definitions, and assign
+// groups and samples
+// Error in the argument of an option (so that it is reported as a configuration error)
+fn config_err<E: std::fmt::Display>(e: E) -> error::Error {
+    error::Error::config(e.to_string())
+}
+
+fn load_cut_sites(m: &ArgMatches, compressor: &Compressor) -> anyhow::Result<CutSites> {
+    let format: CutFormat = m.value_of_t("cut_format").map_err(config_err).with_context(|| "Invalid argument to cut_format option")?;
+    if m.values_of("cut_file").into_iter().flatten().filter(|f| *f == "-").count() > 1 {
+        return Err(error::Error::config("The cut file can only be read from stdin once").into());
+    }
+    let mut files = m.values_of("cut_file").into_iter().flatten();
+    let mut cut_sites = match files.next() {
+        Some(file) => read_cut_file_format(file, format, compressor).with_context(|| format!("Error reading cut sites from {}", file))?,
+        None => CutSites::default(),
+    };
+    // Merge additional cut files, dropping duplicate sites
+    for file in files {
+        let cs = read_cut_file_format(file, format, compressor).with_context(|| format!("Error reading cut sites from {}", file))?;
+        let n = cut_sites.merge(cs).with_context(|| format!("Error merging cut sites from {}", file))?;
+        if n > 0 {
+            warn!("{} duplicate cut sites in {} ignored", n, file)
+        }
+    }
+    // Add cut sites from restriction enzyme digestion of the reference
+    if let (Some(file), Some(enz)) = (m.value_of("reference"), m.values_of("enzyme")) {
+        let enzymes = enz
+            .map(Enzyme::from_spec)
+            .collect::<error::Result<Vec<_>>>()
+            .with_context(|| "Invalid argument to enzyme option")?;
+        let cs = digest_fasta(file, &enzymes, compressor).with_context(|| format!("Error finding cut sites in {}", file))?;
+        let n = cut_sites.merge(cs).with_context(|| format!("Error merging cut sites from {}", file))?;
+        if n > 0 {
+            warn!("{} duplicate cut sites from {} ignored", n, file)
+        }
+    }
+    // Add cut sites defined on the command line
+    if let Some(defs) = m.values_of("site") {
+        let v: Vec<_> = defs.collect();
+        let cs = site_defs(&v).with_context(|| "Invalid cut site definition")?;
+        let n = cut_sites.merge(cs).with_context(|| "Error merging cut sites from the command line")?;
+        if n > 0 {
+            warn!("{} duplicate cut sites from the command line ignored", n)
+        }
+    }
+    if let Some(file) = m.value_of("site_groups") {
+        cut_sites.read_group_file(file, compressor).with_context(|| "Error reading site groups from file")?;
+    }
+    if let Some(file) = m.value_of("sample_sheet") {
+        cut_sites.read_sample_sheet_file(file, compressor).with_context(|| "Error reading sample sheet")?;
+    }
+    if let Some(file) = m.value_of("site_pairs") {
+        cut_sites.read_pair_file(file, compressor).with_context(|| "Error reading cut site pairs")?;
+    }
+    Ok(cut_sites)
+}
+
+// Returns None if a subcommand not requiring a Param (i.e., aggregate, stats, site-seq or salvage) has
+// been run
+pub fn process_cli() -> anyhow::Result<Option<Param>> {
 //    let yaml = load_yaml!("cli/cli.yml");
 //    let app = App::from_yaml(yaml).version(crate_version!());
 
-    let m = command_line();
+    let matches = command_line();
+
+    // The aggregate subcommand only reads summaries from previous runs
+    if let Some(("aggregate", sm)) = matches.subcommand() {
+        init_log(sm);
+        let files: Vec<_> = sm.values_of("summary").unwrap().collect();
+        let format: AggregateFormat = sm.value_of_t("format").map_err(config_err).with_context(|| "Invalid argument to format option")?;
+        aggregate(&files, format, sm.value_of("output"))?;
+        return Ok(None);
+    }
+
+    // The stats subcommand only reads results files from previous runs
+    if let Some(("stats", sm)) = matches.subcommand() {
+        init_log(sm);
+        let compressor = Compressor::default();
+        compressor.check().with_context(|| "Error setting up compression backend")?;
+        let files: Vec<_> = sm.values_of("results").unwrap().collect();
+        let format: AggregateFormat = sm.value_of_t("format").map_err(config_err).with_context(|| "Invalid argument to format option")?;
+        let mut bins = sm
+            .value_of("length_bins")
+            .unwrap()
+            .split(',')
+            .map(|x| x.trim().parse::<usize>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(config_err)
+            .with_context(|| "Invalid argument to length_bins option")?;
+        bins.sort_unstable();
+        bins.dedup();
+        bins.retain(|x| *x > 0);
+        stats(&files, &bins, format, sm.value_of("output"), &compressor)?;
+        return Ok(None);
+    }
+
+    // The salvage subcommand works on a single output file from a previous run
+    if let Some(("salvage", sm)) = matches.subcommand() {
+        init_log(sm);
+        let compressor = Compressor::default();
+        compressor.check().with_context(|| "Error setting up compression backend")?;
+        salvage(sm.value_of("input").unwrap(), sm.value_of("output"), &compressor)?;
+        return Ok(None);
+    }
+
+    // The site-seq subcommand only needs the cut sites and the reference
+    if let Some(("site-seq", sm)) = matches.subcommand() {
+        init_log(sm);
+        let compressor = Compressor::default();
+        compressor.check().with_context(|| "Error setting up compression backend")?;
+        let cut_sites = load_cut_sites(sm, &compressor)?;
+        let window: usize = sm.value_of_t("window").map_err(config_err).with_context(|| "Invalid argument to window option")?;
+        write_site_seqs(sm.value_of("reference").unwrap(), &cut_sites, window, sm.value_of("output"), &compressor)?;
+        return Ok(None);
+    }
+
+    // Options for the route subcommand are handled in the same way as for the main command,
+    // except that read classifications are read from a file rather than a PAF file.  The main
+    // command with --classification is handled as the route subcommand
+    let (m, route) = match matches.subcommand() {
+        Some(("route", sm)) => (sm, true),
+        _ => (&matches, matches.is_present("classification")),
+    };
 
     // Setup logging
-    let _ = init_log(&m);
+    init_log(m);
+
+    if !route && m.is_present("trace_sample") {
+        let frac: f64 = m.value_of_t("trace_sample").map_err(config_err).with_context(|| "Invalid argument to trace_sample option")?;
+        if !(frac > 0.0 && frac <= 1.0) {
+            return Err(error::Error::config("Invalid argument to trace_sample option: proportion should be > 0 and <= 1").into());
+        }
+        let seed: u64 = m.value_of_t("trace_seed").map_err(config_err).with_context(|| "Invalid argument to trace_seed option")?;
+        crate::trace_sample::init(frac, seed);
+    }
 
     // Build param structure from options
     let mut pb = ParamBuilder::new();
 
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    if m.is_present("io_uring") {
+        info!("Using io_uring for uncompressed files");
+        crate::uring::enable();
+    }
+
+    // Select compression backend.  This is done first as it is used when reading input files
+    let compressor: Compressor = m.value_of_t("compressor").map_err(config_err).with_context(|| "Invalid argument to compressor option")?;
+    compressor.check().with_context(|| "Error setting up compression backend")?;
+
+    // With --threads auto, the compression, decompression and FASTQ threads are also set
+    // automatically unless given explicitly (see threads.rs)
+    let auto = !route && m.value_of("threads").map(|s| s.eq_ignore_ascii_case("auto")).unwrap_or(false);
+    let stage_threads = |opt: &str| -> anyhow::Result<usize> {
+        if auto && m.occurrences_of(opt) == 0 {
+            Ok(0)
+        } else {
+            parse_threads(m.value_of(opt).unwrap()).with_context(|| format!("Invalid argument to {} option", opt))
+        }
+    };
+
+    // Output files compressed by the internal implementation are compressed in separate threads
+    // if more than one thread is requested
+    if compressor.internal_gzip() {
+        let n = match stage_threads("compress_threads")? {
+            0 => threads::compress_threads(),
+            n => n,
+        };
+        if n > 1 {
+            info!("Using {} threads for compression of output files", n);
+            compressor::set_compress_threads(n);
+        }
+    }
+
     if let Some(file) =  m.value_of("fastq") {
         pb.fastq_file(file);
     }
 
-    if let Some(file) =  m.value_of("paf_file") {
-        pb.paf_file(file);
+    if route {
+        if m.is_present("reference") && !m.is_present("enzyme") {
+            return Err(error::Error::config("Option --reference requires --enzyme with the route subcommand").into());
+        }
+        pb.classification_file(m.value_of("classification").unwrap());
+    } else {
+        if let Some(file) =  m.value_of("paf_file") {
+            pb.paf_file(file);
+        }
+        if m.is_present("sam") {
+            pb.input_format(InputFormat::Sam);
+        } else if m.is_present("bam") {
+            pb.input_format(InputFormat::Bam);
+        }
+        // With a reference but no enzymes or PAF file, align the FASTQ input with minimap2
+        if let (Some(file), false, false) = (m.value_of("reference"), m.is_present("enzyme"), m.is_present("paf_file")) {
+            if !m.is_present("fastq") {
+                return Err(error::Error::config("Aligning with minimap2 (--reference without --enzyme or a PAF file) requires FASTQ input").into());
+            }
+            if m.is_present("sam") || m.is_present("bam") {
+                return Err(error::Error::config("Options --sam and --bam can not be used when aligning with minimap2").into());
+            }
+            pb.align_reference(file)
+                .minimap2(m.value_of("minimap2").unwrap(), m.value_of("minimap2_args").unwrap());
+        } else if !m.is_present("paf_file") && m.values_of("cut_file").into_iter().flatten().any(|f| f == "-") {
+            return Err(error::Error::config("The cut file can not be read from stdin when the PAF input is read from stdin").into());
+        }
     }
 
     // Process cut file if present
-    if let Some(file) = m.value_of("cut_file") {
-        pb.cut_sites(read_cut_file(file).with_context(|| "Error reading cut sites from file")?);
+    if m.is_present("sites") {
+        let mut cut_sites = load_cut_sites(m, &compressor)?;
+        // Check for sites of different barcodes close enough for a read to match either site
+        if !route {
+            let max_dist: usize = m.value_of_t("max_distance").map_err(config_err).with_context(|| "Invalid argument to map_distance option")?;
+            let policy: CollisionPolicy = m.value_of_t("site_collisions").map_err(config_err).with_context(|| "Invalid argument to site_collisions option")?;
+            pb.site_collisions(cut_sites.resolve_collisions(max_dist, policy).with_context(|| "Colliding cut sites in cut file")?);
+        }
+        pb.cut_sites(cut_sites);
+    }
+
+    if m.is_present("metrics_port") {
+        pb.metrics_port(m.value_of_t("metrics_port").map_err(config_err).with_context(|| "Invalid argument to metrics_port option")?);
+        pb.metrics_bind(m.value_of_t("metrics_bind").map_err(config_err).with_context(|| "Invalid argument to metrics_bind option")?);
+    }
+
+    if m.is_present("flush_interval") {
+        pb.flush_interval(m.value_of_t("flush_interval").map_err(config_err).with_context(|| "Invalid argument to flush_interval option")?);
+    }
+
+    if m.is_present("targets_bed") {
+        pb.targets_window(m.value_of_t("targets_bed").map_err(config_err).with_context(|| "Invalid argument to targets_bed option")?);
+    }
+
+    if m.is_present("hotspots") {
+        pb.hotspots(m.value_of_t("hotspots").map_err(config_err).with_context(|| "Invalid argument to hotspots option")?);
+    }
+
+    if let Some(file) = m.value_of("suggest_sites") {
+        pb.suggest_sites(file)
+            .suggest_min_reads(m.value_of_t("suggest_min_reads").map_err(config_err).with_context(|| "Invalid argument to suggest_min_reads option")?);
+    }
+
+    if m.is_present("end_profile") {
+        pb.end_profile(m.value_of_t("end_bin_size").map_err(config_err).with_context(|| "Invalid argument to end_bin_size option")?);
+    }
+
+    if m.is_present("skip_reads") {
+        pb.skip_reads(m.value_of_t("skip_reads").map_err(config_err).with_context(|| "Invalid argument to skip_reads option")?);
+    }
+
+    if m.is_present("limit_reads") {
+        pb.limit_reads(m.value_of_t("limit_reads").map_err(config_err).with_context(|| "Invalid argument to limit_reads option")?);
+    }
+
+    if m.is_present("max_reads") {
+        pb.max_reads(m.value_of_t("max_reads").map_err(config_err).with_context(|| "Invalid argument to max_reads option")?);
+    }
+
+    if let Some(s) = m.value_of("max_runtime") {
+        pb.max_runtime(parse_duration(s).with_context(|| "Invalid argument to max_runtime option")?);
+    }
+
+    pb.decompress_threads(stage_threads("decompress_threads")?)
+        .fastq_threads(stage_threads("fastq_threads")?);
+
+    if let Some(dir) = m.value_of("tmp_dir") {
+        pb.tmp_dir(dir);
+    }
+    pb.skip_if_current(m.is_present("skip_if_current"));
+
+    if !route {
+        if let Some(dir) = m.value_of("cache_dir") {
+            pb.cache_dir(dir);
+        }
+        pb.synced(m.is_present("synced"))
+            .unsorted_paf(m.is_present("unsorted_paf"))
+            .lenient(m.is_present("lenient"));
+        if let Some(s) = m.value_of("memory_budget") {
+            pb.memory_budget(parse_size(s).with_context(|| "Invalid argument to memory_budget option")?);
+        }
+        if let Some(file) = m.value_of("classification_out") {
+            pb.classification_out(file);
+        }
+        if let Some(cmd) = m.value_of("post_classify_cmd") {
+            pb.post_classify_cmd(cmd);
+        }
+        pb.efficiency_report(m.is_present("efficiency_report"))
+           .chain_out(m.is_present("chain_out"));
+        if let Some(file) = m.value_of("snps") {
+            pb.snps(SnpSet::read_vcf(file, &compressor).with_context(|| "Error reading SNP file")?);
+        }
+        if m.is_present("preview") {
+            pb.preview(m.value_of_t("preview").map_err(config_err).with_context(|| "Invalid argument to preview option")?);
+        }
+    }
+
+    if let Some(cmd) = m.value_of("on_complete_cmd") {
+        pb.on_complete_cmd(cmd);
+    }
+
+    if let Some(url) = m.value_of("on_complete_url") {
+        // Check url is valid before starting
+        notify::parse_url(url).map_err(config_err).with_context(|| "Invalid argument to on_complete_url option")?;
+        pb.on_complete_url(url);
+    }
+
+    // Set up selection of reads by ID if requested
+    if m.is_present("include_ids") || m.is_present("exclude_ids") || m.is_present("id_regex") {
+        let mut filter = ReadFilter::default();
+        if let Some(file) = m.value_of("include_ids") {
+            filter.add_include_file(file, &compressor).with_context(|| "Error reading IDs of reads to include")?;
+        }
+        if let Some(file) = m.value_of("exclude_ids") {
+            filter.add_exclude_file(file, &compressor).with_context(|| "Error reading IDs of reads to exclude")?;
+        }
+        if let Some(re) = m.value_of("id_regex") {
+            filter.set_regex(re).map_err(config_err).with_context(|| "Invalid argument to id-regex option")?;
+        }
+        pb.read_filter(filter);
+    }
+    if let Some(s) = m.value_of("id_match") {
+        pb.id_match(s.parse().map_err(config_err).with_context(|| "Invalid argument to id_match option")?);
+    }
+    if let Some(s) = m.value_of("qlen_from") {
+        let qlen_from: QlenFrom = s.parse().map_err(config_err).with_context(|| "Invalid argument to qlen_from option")?;
+        if qlen_from == QlenFrom::Fastq && !m.is_present("fastq") {
+            return Err(error::Error::config("Option --qlen-from fastq requires a FASTQ input file").into());
+        }
+        pb.qlen_from(qlen_from);
+    }
+
+    // Channel information for the channel report
+    if m.is_present("channel_report") && !(m.is_present("seq_summary") || m.is_present("fastq")) {
+        return Err(error::Error::config("Channel report requires a sequencing summary file or FASTQ input").into());
+    }
+    if let Some(file) = m.value_of("seq_summary") {
+        pb.seq_summary(SeqSummary::read(file, &compressor).with_context(|| "Error reading sequencing summary file")?);
+    }
+
+    // Assignment of reads to runs
+    if let Some(file) = m.value_of("read_groups") {
+        pb.read_groups(ReadGroups::read(file, &compressor).with_context(|| "Error reading read group file")?);
+    }
+
+    // Read length bins for statistics
+    if let Some(s) = m.value_of("length_bins") {
+        let bins = s
+            .split(',')
+            .map(|x| x.trim().parse::<usize>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(config_err)
+            .with_context(|| "Invalid argument to length_bins option")?;
+        pb.length_bins(bins);
+    }
+
+    // MAPQ bins for statistics
+    if let Some(s) = m.value_of("mapq_bins") {
+        let bins = s
+            .split(',')
+            .map(|x| x.trim().parse::<usize>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(config_err)
+            .with_context(|| "Invalid argument to mapq_bins option")?;
+        pb.mapq_bins(bins);
+    }
+
+    // Set up contaminant screening if requested
+    if m.is_present("contaminants") || m.is_present("contaminant_paf") {
+        let threshold: f64 = m.value_of_t("contam_threshold").map_err(config_err).with_context(|| "Invalid argument to contam_threshold option")?;
+        if !(threshold > 0.0 && threshold <= 1.0) {
+            return Err(error::Error::config("Argument to --contam-threshold must be greater than 0 and at most 1").into());
+        }
+        let mut screen = ContamScreen::new(threshold);
+        if let Some(file) = m.value_of("contaminants") {
+            screen.add_fasta(file, &compressor).with_context(|| "Error reading contaminant sequences")?;
+        }
+        if let Some(file) = m.value_of("contaminant_paf") {
+            screen.add_paf(file, &compressor).with_context(|| "Error reading contaminant PAF file")?;
+        }
+        pb.contam(screen);
+    }
+
+    pb.compressor(compressor);
+
+    let split_by: SplitBy = m.value_of_t("split_by").map_err(config_err).with_context(|| "Invalid argument to split_by option")?;
+    if split_by == SplitBy::Barcode && m.is_present("also_combined") {
+        return Err(error::Error::config("Option --also-combined can not be used with --split-by barcode").into());
+    }
+    let float_precision: usize = m.value_of_t("float_precision").map_err(config_err).with_context(|| "Invalid argument to float_precision option")?;
+    if float_precision > 17 {
+        return Err(error::Error::config("Argument to --float-precision must be at most 17").into());
     }
 
     pb.prefix(m.value_of("prefix").unwrap())
        .compress(m.is_present("compress"))
+       .compress_results(m.is_present("compress_results"))
+       .compress_fastq(m.is_present("compress_fastq"))
        .matched_only(m.is_present("matched_only"))
-       .mapq_thresh(m.value_of_t("mapq_threshold").with_context(|| "Invalid argument to mapq_threshold option")?)
-       .max_distance(m.value_of_t("max_distance").with_context(|| "Invalid argument to map_distance option")?)
-       .max_unmatched(m.value_of_t("max_unmatched").with_context(|| "Invalid argument to max_unmatched option")?)
-       .margin(m.value_of_t("margin").with_context(|| "Invalid argument to margin option")?)
-       .select(m.value_of_t("select").with_context(|| "Invalid argument to select option")?)
-       ;
-
-   Ok(pb.build())
+       .group_fastq(m.is_present("group_fastq"))
+       .orient_reads(m.is_present("orient_reads"))
+       .flag_only(m.is_present("flag_only"))
+       .verify_outputs(m.is_present("verify_outputs"))
+       .ordered(m.is_present("ordered"))
+       .channel_report(m.is_present("channel_report"))
+       .margin_report(m.is_present("margin_report"))
+       .second_site(m.is_present("second_site"))
+       .bed(m.is_present("bed"))
+       .bedgraph(m.is_present("bedgraph"))
+       .run_fastq(m.is_present("run_fastq"))
+       .no_fastq_output(m.is_present("no_fastq_output"))
+       .tag_only(m.is_present("tag_only"))
+       .no_sites_output(m.is_present("no_sites_output"))
+       .also_combined(m.is_present("also_combined"))
+       .split_by(split_by)
+       .split_results(m.is_present("split_results"))
+       .no_combined_results(m.is_present("no_combined_results"))
+       .defer_results(m.is_present("defer_results"))
+       .float_precision(float_precision)
+       .write_buffer_size(parse_size(m.value_of("write_buffer_size").unwrap()).with_context(|| "Invalid argument to write_buffer_size option")?)
+       .max_line_length(parse_size(m.value_of("max_line_length").unwrap()).with_context(|| "Invalid argument to max_line_length option")?);
+
+    // Classification options
+    if !route {
+        let select: Select = m.value_of_t("select").map_err(config_err).with_context(|| "Invalid argument to select option")?;
+        if select == Select::Pair && !m.is_present("site_pairs") {
+            return Err(error::Error::config("Option --select pair requires --site-pairs").into());
+        }
+        pb.mapq_thresh(m.value_of_t("mapq_threshold").map_err(config_err).with_context(|| "Invalid argument to mapq_threshold option")?)
+           .max_distance(m.value_of_t("max_distance").map_err(config_err).with_context(|| "Invalid argument to map_distance option")?)
+           .max_unmatched(m.value_of_t("max_unmatched").map_err(config_err).with_context(|| "Invalid argument to max_unmatched option")?)
+           .margin(m.value_of_t("margin").map_err(config_err).with_context(|| "Invalid argument to margin option")?)
+           .end_padding(m.value_of_t("end_padding").map_err(config_err).with_context(|| "Invalid argument to end_padding option")?)
+           .auto_offset(m.is_present("auto_offset"))
+           .verify_deterministic(m.is_present("verify_deterministic"))
+           .threads(parse_threads(m.value_of("threads").unwrap()).with_context(|| "Invalid argument to threads option")?)
+           .max_paf_records(m.value_of_t("max_paf_records").map_err(config_err).with_context(|| "Invalid argument to max_paf_records option")?)
+           .select(select)
+           .primary_select(m.value_of_t("primary_select").map_err(config_err).with_context(|| "Invalid argument to primary_select option")?)
+           .aln_policy(if m.is_present("use_primary_only") {
+               AlnPolicy::PrimaryOnly
+           } else if m.is_present("include_supplementary") {
+               AlnPolicy::PrimarySupplementary
+           } else {
+               AlnPolicy::All
+           });
+        if m.is_present("refine_ends") {
+            let n: usize = m.value_of_t("refine_ends").map_err(config_err).with_context(|| "Invalid argument to refine_ends option")?;
+            if n == 0 {
+                return Err(error::Error::config("Argument to --refine-ends must be at least 1").into());
+            }
+            pb.refine_ends(n);
+        }
+    }
+
+    // Record the input files (for the run stamp and the run information file)
+    for id in INPUT_FILE_ARGS {
+        if let Ok(Some(v)) = m.try_get_raw(id) {
+            for f in v.filter_map(|s| s.to_str()).filter(|s| *s != "-") {
+                pb.input_file(id, f);
+            }
+        }
+    }
+
+   Ok(Some(pb.build()))
 }
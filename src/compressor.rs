@@ -0,0 +1,603 @@
+// Selection of compression backend
+//
+// By default (auto) compression and decompression are handled by compress_io, which uses
+// external utilities found on the PATH, falling back to the internal (flate2) gzip
+// implementation if no external gzip utility is available.  Alternatively the internal
+// implementation or a specific external gzip compatible utility can be requested.
+
+use std::{
+    collections::HashMap,
+    env, fmt,
+    fs::File,
+    io::{self, BufRead, BufReader, Error, Read, Write},
+    mem,
+    path::{Path, PathBuf},
+    process::{Child, ChildStdout, Command, Stdio},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{self, Receiver, Sender, SyncSender},
+        Mutex, OnceLock,
+    },
+    thread,
+};
+
+use compress_io::{
+    compress::{CompressIo, Writer},
+    compress_type::CompressType,
+};
+use flate2::{read::MultiGzDecoder, write::GzEncoder, Compression};
+
+use crate::error;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+use crate::uring::{self, UringReader, UringWriter};
+
+// Output writer that can be closed explicitly, returning any error from completing the output
+// (i.e., from finishing a compressed stream in a compression thread).  Writers still complete
+// the output when dropped without being closed, but errors can then only be logged
+pub trait CloseWrite: Write + Send {
+    fn close(&mut self) -> io::Result<()> {
+        self.flush()
+    }
+}
+
+pub type BoxWriter = Box<dyn CloseWrite>;
+
+impl CloseWrite for File {}
+impl CloseWrite for io::Sink {}
+
+// Wait for an external compression process to exit, checking its exit status
+impl CloseWrite for Writer {
+    fn close(&mut self) -> io::Result<()> {
+        self.flush()?;
+        match self.take_child() {
+            Some(mut child) => {
+                // Dropping the writer closes the input to the process
+                drop(mem::replace(self, Writer::from_stdout()));
+                let status = child.wait()?;
+                if status.success() {
+                    Ok(())
+                } else {
+                    Err(Error::other(format!("Compression process failed ({})", status)))
+                }
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+// Internal gzip writer used when there is no pool of compression threads.  Closing the writer
+// completes the compressed stream (writing the gzip trailer)
+struct GzWriter(Option<GzEncoder<File>>);
+
+impl GzWriter {
+    fn encoder(&mut self) -> io::Result<&mut GzEncoder<File>> {
+        self.0.as_mut().ok_or_else(|| Error::other("Write to closed compressed file"))
+    }
+}
+
+impl Write for GzWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.encoder()?.write(data)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.0.as_mut() {
+            Some(enc) => enc.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl CloseWrite for GzWriter {
+    fn close(&mut self) -> io::Result<()> {
+        match self.0.take() {
+            Some(enc) => enc.finish().map(|_| ()),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for GzWriter {
+    // Complete the file if it has not been closed explicitly
+    fn drop(&mut self) {
+        if let Err(e) = self.close() {
+            error!("Error closing compressed output file: {}", e)
+        }
+    }
+}
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+impl CloseWrite for UringWriter {}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum Compressor {
+    #[default]
+    Auto,
+    Internal,
+    External(PathBuf),
+}
+
+impl FromStr for Compressor {
+    type Err = error::Error;
+    fn from_str(s: &str) -> error::Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "auto" => Ok(Self::Auto),
+            "internal" => Ok(Self::Internal),
+            _ => match s.split_once(':') {
+                Some((a, p)) if a.eq_ignore_ascii_case("external") && !p.is_empty() => {
+                    Ok(Self::External(PathBuf::from(p)))
+                }
+                _ => Err(error::Error::config(format!("Invalid compressor option {}", s))),
+            },
+        }
+    }
+}
+
+impl fmt::Display for Compressor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Auto => write!(f, "auto"),
+            Self::Internal => write!(f, "internal"),
+            Self::External(p) => write!(f, "external:{}", p.display()),
+        }
+    }
+}
+
+// Compression type from the initial bytes of a stream
+fn magic_ctype(buf: &[u8]) -> CompressType {
+    if buf.starts_with(&[0x1f, 0x8b]) {
+        CompressType::Gzip
+    } else if buf.starts_with(b"BZh") {
+        CompressType::Bzip2
+    } else if buf.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0]) {
+        CompressType::Xz
+    } else if buf.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+        CompressType::Zstd
+    } else {
+        CompressType::NoFilter
+    }
+}
+
+// Size of blocks passed from the decompression thread and to the compression threads (see
+// ThreadReader and ThreadWriter)
+const THREAD_BLOCK_SIZE: usize = 1 << 20;
+
+// Messages passed to the compression threads (see ThreadWriter)
+enum WriterMsg {
+    Open(GzEncoder<File>),
+    Data(Vec<u8>),
+    // Write out all data sent so far, returning the result
+    Flush(SyncSender<io::Result<()>>),
+    // Complete the compressed stream and close the file, returning the result
+    Close(SyncSender<io::Result<()>>),
+}
+
+// Pool of threads for internal gzip compression of output files (--compress-threads).  Each
+// compressed output file is assigned to one of the threads, which owns the encoder for the
+// file, so that compression of the output files runs in parallel with the main thread
+struct WriterPool {
+    workers: Vec<Mutex<Sender<(usize, WriterMsg)>>>,
+    next: AtomicUsize, // Id of the next file opened (files are assigned to the workers in turn)
+}
+
+static WRITER_POOL: OnceLock<WriterPool> = OnceLock::new();
+
+// Use threads threads for the internal gzip compression of output files.  Has no effect if
+// threads is 0 or if the pool has already been set up
+pub fn set_compress_threads(threads: usize) {
+    if threads > 0 {
+        let _ = WRITER_POOL.set(WriterPool {
+            workers: (0..threads).map(|_| Mutex::new(spawn_compress_thread())).collect(),
+            next: AtomicUsize::new(0),
+        });
+    }
+}
+
+// Number of threads used for the internal gzip compression of output files
+pub fn compress_threads() -> usize {
+    WRITER_POOL.get().map(|p| p.workers.len()).unwrap_or(0)
+}
+
+fn spawn_compress_thread() -> Sender<(usize, WriterMsg)> {
+    let (tx, rx) = mpsc::channel::<(usize, WriterMsg)>();
+    thread::spawn(move || {
+        // Encoder for each open file, with the first error from writing to the file
+        let mut files: HashMap<usize, (GzEncoder<File>, io::Result<()>)> = HashMap::new();
+        for (id, msg) in rx.iter() {
+            match msg {
+                WriterMsg::Open(enc) => {
+                    files.insert(id, (enc, Ok(())));
+                }
+                WriterMsg::Data(buf) => {
+                    if let Some((enc, res @ Ok(_))) = files.get_mut(&id) {
+                        *res = enc.write_all(&buf)
+                    }
+                }
+                WriterMsg::Flush(ack) => {
+                    let r = match files.get_mut(&id) {
+                        Some((enc, res)) => mem::replace(res, Ok(())).and_then(|_| enc.flush()),
+                        None => Ok(()),
+                    };
+                    let _ = ack.send(r);
+                }
+                WriterMsg::Close(ack) => {
+                    let r = match files.remove(&id) {
+                        Some((enc, res)) => res.and_then(|_| enc.finish().map(|_| ())),
+                        None => Ok(()),
+                    };
+                    let _ = ack.send(r);
+                }
+            }
+        }
+    });
+    tx
+}
+
+// Writer that passes blocks of data to be compressed by one of the threads from the pool
+struct ThreadWriter {
+    tx: Sender<(usize, WriterMsg)>,
+    id: usize,
+    buf: Vec<u8>,
+    closed: bool,
+}
+
+impl ThreadWriter {
+    fn new(pool: &WriterPool, enc: GzEncoder<File>) -> Self {
+        let id = pool.next.fetch_add(1, Ordering::Relaxed);
+        let tx = pool.workers[id % pool.workers.len()].lock().unwrap().clone();
+        // If the thread has exited, the error is reported on the next flush
+        let _ = tx.send((id, WriterMsg::Open(enc)));
+        Self {
+            tx,
+            id,
+            buf: Vec::with_capacity(THREAD_BLOCK_SIZE),
+            closed: false,
+        }
+    }
+
+    fn send(&self, msg: WriterMsg) -> io::Result<()> {
+        self.tx
+            .send((self.id, msg))
+            .map_err(|_| Error::other("Compression thread has exited"))
+    }
+
+    fn send_buf(&mut self) -> io::Result<()> {
+        if !self.buf.is_empty() {
+            let buf = mem::replace(&mut self.buf, Vec::with_capacity(THREAD_BLOCK_SIZE));
+            self.send(WriterMsg::Data(buf))?
+        }
+        Ok(())
+    }
+
+    // Send any buffered data followed by msg, and wait for the result
+    fn sync(&mut self, msg: fn(SyncSender<io::Result<()>>) -> WriterMsg) -> io::Result<()> {
+        self.send_buf()?;
+        let (ack, rx) = mpsc::sync_channel(1);
+        self.send(msg(ack))?;
+        rx.recv()
+            .map_err(|_| Error::other("Compression thread has exited"))?
+    }
+}
+
+impl Write for ThreadWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(data);
+        if self.buf.len() >= THREAD_BLOCK_SIZE {
+            self.send_buf()?
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.sync(WriterMsg::Flush)
+    }
+}
+
+impl CloseWrite for ThreadWriter {
+    // Wait for the compressed file to be completed, returning the first error from writing to
+    // or closing the file
+    fn close(&mut self) -> io::Result<()> {
+        if self.closed {
+            return Ok(());
+        }
+        self.closed = true;
+        self.sync(WriterMsg::Close)
+    }
+}
+
+impl Drop for ThreadWriter {
+    // Complete the file if it has not been closed explicitly
+    fn drop(&mut self) {
+        if let Err(e) = self.close() {
+            error!("Error closing compressed output file: {}", e)
+        }
+    }
+}
+
+// Reader for the output of a decompression process.  The exit status of the process is checked
+// at the end of the output, so that a failure (i.e., from a corrupt input) is reported as a
+// read error rather than appearing as the end of the input.  If the input is fed to the process
+// from a separate thread, any error from the thread is also reported
+struct ChildReader {
+    out: ChildStdout,
+    child: Option<Child>,
+    feeder: Option<thread::JoinHandle<io::Result<()>>>,
+    name: String,
+}
+
+impl ChildReader {
+    fn new(mut child: Child, feeder: Option<thread::JoinHandle<io::Result<()>>>, name: &Path) -> Self {
+        let out = child.stdout.take().expect("Pipe error");
+        Self {
+            out,
+            child: Some(child),
+            feeder,
+            name: name.display().to_string(),
+        }
+    }
+
+    fn check_exit(&mut self) -> io::Result<()> {
+        if let Some(mut child) = self.child.take() {
+            let status = child.wait()?;
+            if !status.success() {
+                return Err(Error::other(format!("Decompression process {} failed ({})", self.name, status)));
+            }
+        }
+        if let Some(h) = self.feeder.take() {
+            h.join()
+                .map_err(|_| Error::other("Decompression input thread panicked"))?
+                .map_err(|e| Error::other(format!("Error writing to decompression process: {}", e)))?
+        }
+        Ok(())
+    }
+}
+
+impl Read for ChildReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.out.read(buf)?;
+        if n == 0 && !buf.is_empty() {
+            self.check_exit()?
+        }
+        Ok(n)
+    }
+}
+
+impl Drop for ChildReader {
+    // The output was not read to the end, so the process is stopped
+    fn drop(&mut self) {
+        if let Some(mut child) = self.child.take() {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
+
+// Find executable on the PATH
+fn find_on_path(name: &str) -> Option<PathBuf> {
+    env::var_os("PATH").and_then(|paths| {
+        env::split_paths(&paths)
+            .map(|d| d.join(name))
+            .find(|p| p.is_file())
+    })
+}
+
+// Reader that decompresses its input in a separate thread, passing blocks of decompressed
+// data back through a channel so that decompression runs in parallel with processing
+struct ThreadReader {
+    rx: Receiver<io::Result<Vec<u8>>>,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl ThreadReader {
+    fn new<R: Read + Send + 'static>(mut rdr: R) -> Self {
+        // Allow a few blocks to be buffered ahead of the reader
+        let (tx, rx) = mpsc::sync_channel(4);
+        thread::spawn(move || loop {
+            let mut buf = vec![0; THREAD_BLOCK_SIZE];
+            let mut n = 0;
+            let res = loop {
+                match rdr.read(&mut buf[n..]) {
+                    Ok(0) => break Ok(()),
+                    Ok(k) => {
+                        n += k;
+                        if n == buf.len() {
+                            break Ok(());
+                        }
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                    Err(e) => break Err(e),
+                }
+            };
+            buf.truncate(n);
+            let done = n == 0 || res.is_err();
+            let msg = match res {
+                Ok(()) => Ok(buf),
+                Err(e) => Err(e),
+            };
+            // Stop if the receiver has been dropped or the input is exhausted
+            if tx.send(msg).is_err() || done {
+                break;
+            }
+        });
+        Self {
+            rx,
+            buf: Vec::new(),
+            pos: 0,
+        }
+    }
+}
+
+impl Read for ThreadReader {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.buf.len() {
+            match self.rx.recv() {
+                Ok(Ok(b)) => {
+                    self.buf = b;
+                    self.pos = 0;
+                }
+                Ok(Err(e)) => return Err(e),
+                // Sender has exited after the end of the input
+                Err(_) => return Ok(0),
+            }
+        }
+        let n = out.len().min(self.buf.len() - self.pos);
+        out[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+impl Compressor {
+    // Check that the selected backend is usable and log which backend will be used
+    pub fn check(&self) -> io::Result<()> {
+        match self {
+            Self::Auto => match CompressType::Gzip.get_compress_tool() {
+                Ok(tool) => info!(
+                    "Using external compression ({})",
+                    tool.path().map(|p| p.display().to_string()).unwrap_or_else(|| tool.name().to_owned())
+                ),
+                Err(_) => info!("No external gzip utility found: using internal compression"),
+            },
+            Self::Internal => info!("Using internal compression"),
+            Self::External(p) => {
+                if !p.is_file() {
+                    return Err(error::Error::config(format!("Compression utility {} not found", p.display())).into());
+                }
+                info!("Using external compression ({})", p.display())
+            }
+        }
+        Ok(())
+    }
+
+    // Whether the internal gzip implementation should be used
+    pub fn internal_gzip(&self) -> bool {
+        match self {
+            Self::Auto => CompressType::Gzip.get_compress_tool().is_err(),
+            Self::Internal => true,
+            Self::External(_) => false,
+        }
+    }
+
+    // Open writer to file name, adding the .gz suffix if compress is set
+    pub fn writer<P: AsRef<Path>>(&self, name: P, compress: bool) -> io::Result<BoxWriter> {
+        if !compress {
+            #[cfg(all(feature = "io-uring", target_os = "linux"))]
+            if uring::enabled() {
+                return Ok(Box::new(UringWriter::create(name)?));
+            }
+            return Ok(Box::new(File::create(name)?));
+        }
+        let mut path = name.as_ref().as_os_str().to_owned();
+        path.push(".gz");
+        match self {
+            Self::External(p) => {
+                let child = Command::new(p)
+                    .arg("-c")
+                    .stdin(Stdio::piped())
+                    .stdout(File::create(&path)?)
+                    .spawn()
+                    .map_err(|e| Error::other(format!("Error executing {}: {}", p.display(), e)))?;
+                Ok(Box::new(Writer::from_child(child, false)))
+            }
+            _ if self.internal_gzip() => {
+                let enc = GzEncoder::new(File::create(&path)?, Compression::default());
+                match WRITER_POOL.get() {
+                    Some(pool) => Ok(Box::new(ThreadWriter::new(pool, enc))),
+                    None => Ok(Box::new(GzWriter(Some(enc)))),
+                }
+            }
+            _ => Ok(Box::new(
+                CompressIo::new()
+                    .path(path)
+                    .ctype(CompressType::Gzip)
+                    .writer()?,
+            )),
+        }
+    }
+
+    // Open reader from file using up to threads threads for decompression.  With the auto
+    // backend, gzip and zstd files are decompressed using pigz or zstd if found on the PATH
+    // (zstd uses a single thread).
+    // Otherwise gzip files are decompressed by the internal implementation in a separate
+    // thread so that decompression runs in parallel with processing of the input.  If threads
+    // is <= 1 or an external backend has been selected, this is the same as reader()
+    pub fn threaded_reader<P: AsRef<Path>>(&self, name: P, threads: usize) -> io::Result<Box<dyn BufRead + Send>> {
+        if threads <= 1 || matches!(self, Self::External(_)) {
+            return self.reader(Some(name));
+        }
+        let mut buf = [0; 6];
+        let n = File::open(&name)?.read(&mut buf)?;
+        let ctype = magic_ctype(&buf[..n]);
+        let tool = match (ctype, self) {
+            (CompressType::Gzip, Self::Auto) => find_on_path("pigz").map(|p| (p, Some(format!("-p{}", threads)))),
+            // zstd does not use multiple threads for decompression, but running it as a
+            // separate process still allows decompression in parallel with processing
+            (CompressType::Zstd, Self::Auto) => find_on_path("zstd").map(|p| (p, None)),
+            _ => None,
+        };
+        if let Some((p, opt)) = tool {
+            debug!("Decompressing {} with {}", name.as_ref().display(), p.display());
+            let child = Command::new(&p)
+                .arg("-dc")
+                .args(opt)
+                .arg(name.as_ref())
+                .stdout(Stdio::piped())
+                .spawn()
+                .map_err(|e| Error::other(format!("Error executing {}: {}", p.display(), e)))?;
+            return Ok(Box::new(BufReader::new(ChildReader::new(child, None, &p))));
+        }
+        if ctype == CompressType::Gzip {
+            debug!("Decompressing {} in a separate thread", name.as_ref().display());
+            let rdr = BufReader::new(File::open(&name)?);
+            return Ok(Box::new(BufReader::new(ThreadReader::new(MultiGzDecoder::new(rdr)))));
+        }
+        self.reader(Some(name))
+    }
+
+    // Open reader from file (or stdin if name is None), decompressing if required
+    pub fn reader<P: AsRef<Path>>(&self, name: Option<P>) -> io::Result<Box<dyn BufRead + Send>> {
+        #[cfg(all(feature = "io-uring", target_os = "linux"))]
+        if let Some(p) = name.as_ref() {
+            if uring::enabled() {
+                let mut buf = [0; 6];
+                let n = File::open(p)?.read(&mut buf)?;
+                if magic_ctype(&buf[..n]) == CompressType::NoFilter {
+                    return Ok(Box::new(BufReader::new(UringReader::open(p)?)));
+                }
+            }
+        }
+        if *self == Self::Auto && (name.is_none() || !self.internal_gzip()) {
+            return Ok(Box::new(CompressIo::new().opt_path(name).bufreader()?));
+        }
+        let mut rdr: BufReader<Box<dyn Read + Send>> = match name.as_ref() {
+            Some(p) => BufReader::new(Box::new(File::open(p)?)),
+            None => BufReader::new(Box::new(io::stdin())),
+        };
+        let ctype = magic_ctype(rdr.fill_buf()?);
+        match (ctype, self) {
+            (CompressType::NoFilter, _) => Ok(Box::new(rdr)),
+            (CompressType::Gzip, Self::External(p)) => {
+                let mut child = Command::new(p)
+                    .arg("-dc")
+                    .stdin(Stdio::piped())
+                    .stdout(Stdio::piped())
+                    .spawn()
+                    .map_err(|e| Error::other(format!("Error executing {}: {}", p.display(), e)))?;
+                let mut stdin = child.stdin.take().expect("Pipe error");
+                // Feed input to the child process from a separate thread
+                let feeder = thread::spawn(move || io::copy(&mut rdr, &mut stdin).map(|_| ()));
+                Ok(Box::new(BufReader::new(ChildReader::new(child, Some(feeder), p))))
+            }
+            (CompressType::Gzip, _) => Ok(Box::new(BufReader::new(MultiGzDecoder::new(rdr)))),
+            (ct, Self::Auto) => match name {
+                Some(p) => Ok(Box::new(CompressIo::new().path(p).ctype(ct).bufreader()?)),
+                None => Err(Error::other(format!("Can not decompress {} input from stdin", ct))),
+            },
+            (ct, _) => Err(Error::other(format!(
+                "Compressor {} only supports gzip, but input is {} compressed",
+                self, ct
+            ))),
+        }
+    }
+}
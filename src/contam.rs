@@ -0,0 +1,170 @@
+// Screening of reads against contaminant sequences
+//
+// Reads can be flagged as contaminants either by k-mer matching against a FASTA file of
+// contaminant sequences (i.e., E. coli, human), or by being present in a PAF file of
+// mappings against the contaminant sequences
+
+use std::{
+    collections::{HashMap, HashSet},
+    io::{self, BufRead, Write},
+    path::Path,
+};
+
+use crate::compressor::Compressor;
+use crate::paf::PafFile;
+
+// k-mer length used for screening (must be <= 32 so k-mers fit in a u64)
+pub const CONTAM_KMER: usize = 21;
+
+fn base_code(c: u8) -> Option<u64> {
+    match c {
+        b'A' | b'a' => Some(0),
+        b'C' | b'c' => Some(1),
+        b'G' | b'g' => Some(2),
+        b'T' | b't' => Some(3),
+        _ => None,
+    }
+}
+
+// Iterate over the canonical k-mers of a sequence, skipping k-mers with non ACGT bases
+fn for_each_kmer<F: FnMut(u64)>(seq: &[u8], k: usize, mut f: F) {
+    let mask = if k == 32 { u64::MAX } else { (1u64 << (2 * k)) - 1 };
+    let shift = 2 * (k - 1);
+    let mut fwd = 0u64;
+    let mut rev = 0u64;
+    let mut n = 0;
+    for &c in seq {
+        if let Some(x) = base_code(c) {
+            fwd = ((fwd << 2) | x) & mask;
+            rev = (rev >> 2) | ((3 - x) << shift);
+            n += 1;
+            if n >= k {
+                f(fwd.min(rev))
+            }
+        } else {
+            n = 0
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ContamScreen {
+    kmers: HashSet<u64>,
+    read_ids: HashSet<String>,
+    threshold: f64,
+}
+
+impl ContamScreen {
+    pub fn new(threshold: f64) -> Self {
+        Self {
+            threshold,
+            ..Default::default()
+        }
+    }
+
+    pub fn threshold(&self) -> f64 {
+        self.threshold
+    }
+
+    // Add k-mers from all sequences in a FASTA file
+    pub fn add_fasta<P: AsRef<Path>>(&mut self, name: P, compressor: &Compressor) -> io::Result<()> {
+        let mut rdr = compressor.reader(Some(name))?;
+        let mut buf = String::new();
+        let mut seq = Vec::new();
+        let mut nseq = 0;
+        loop {
+            buf.clear();
+            let l = rdr.read_line(&mut buf)?;
+            if l == 0 || buf.starts_with('>') {
+                if !seq.is_empty() {
+                    for_each_kmer(&seq, CONTAM_KMER, |km| {
+                        self.kmers.insert(km);
+                    });
+                    seq.clear();
+                }
+                if l == 0 {
+                    break;
+                }
+                nseq += 1;
+            } else {
+                seq.extend_from_slice(buf.trim_end().as_bytes())
+            }
+        }
+        info!(
+            "Read {} contaminant sequences ({} distinct {}-mers)",
+            nseq,
+            self.kmers.len(),
+            CONTAM_KMER
+        );
+        Ok(())
+    }
+
+    // Add ids of reads mapped in a PAF file of alignments against contaminant sequences
+    pub fn add_paf<P: AsRef<Path>>(&mut self, name: P, compressor: &Compressor) -> io::Result<()> {
+        let mut paf = PafFile::open(Some(name), compressor)?;
+        while let Some(read) = paf.next_read()? {
+            if read.is_mapped() {
+                self.read_ids.insert(read.qname().to_owned());
+            }
+        }
+        info!("Read {} contaminant read ids from PAF file", self.read_ids.len());
+        Ok(())
+    }
+
+    // Check whether a read is a contaminant.  A read is a contaminant if it appears in
+    // the contaminant PAF file or if the proportion of its k-mers found in the
+    // contaminant sequences is >= the threshold
+    pub fn is_contaminant(&self, id: &str, seq: &[u8]) -> bool {
+        if self.read_ids.contains(id) {
+            return true;
+        }
+        if self.kmers.is_empty() {
+            return false;
+        }
+        let mut total = 0;
+        let mut hits = 0;
+        for_each_kmer(seq, CONTAM_KMER, |km| {
+            total += 1;
+            if self.kmers.contains(&km) {
+                hits += 1
+            }
+        });
+        total > 0 && (hits as f64) / (total as f64) >= self.threshold
+    }
+}
+
+// Counts of screened and contaminated reads per barcode
+#[derive(Debug, Default)]
+pub struct ContamCounts {
+    counts: HashMap<String, [usize; 2]>,
+}
+
+impl ContamCounts {
+    pub fn add(&mut self, barcode: &str, contaminant: bool) {
+        let ct = match self.counts.get_mut(barcode) {
+            Some(c) => c,
+            None => self.counts.entry(barcode.to_owned()).or_default(),
+        };
+        ct[0] += 1;
+        if contaminant {
+            ct[1] += 1
+        }
+    }
+
+    pub fn write_report<W: Write>(&self, wrt: &mut W) -> io::Result<()> {
+        writeln!(wrt, "barcode\treads\tcontaminated\tprop. contaminated")?;
+        let mut v: Vec<_> = self.counts.iter().collect();
+        v.sort_unstable_by_key(|(k, _)| k.as_str());
+        for (bc, ct) in v {
+            writeln!(
+                wrt,
+                "{}\t{}\t{}\t{:.4}",
+                bc,
+                ct[0],
+                ct[1],
+                (ct[1] as f64) / (ct[0] as f64)
+            )?;
+        }
+        Ok(())
+    }
+}
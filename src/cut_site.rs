@@ -1,16 +1,21 @@
 use std::{
-    collections::HashMap,
-    io::{self, BufRead},
-    path::Path,
-    rc::Rc,
+    collections::{HashMap, HashSet},
+    io::{self, BufRead, Write},
+    sync::Arc,
 };
 
-use compress_io::compress::CompressIo;
+use crate::error::{self, Error};
+
+#[cfg(feature = "cli")]
+use std::path::Path;
+
+#[cfg(feature = "cli")]
+use crate::compressor::Compressor;
 
 // Contig definition
 #[derive(Debug)]
 pub struct Contig {
-    pub name: Rc<str>,          // Contig name
+    pub name: Arc<str>,          // Contig name
     pub circular: Option<bool>, // Circular contig flag (None == not circular)
     pub cut_sites: Vec<Site>,   // Vector of sites in numerical order
 }
@@ -21,16 +26,551 @@ pub struct Site {
     pub name: String,    // Identifier for cut site
     pub pos: usize,      // Contig position (1 offset)
     pub barcode: String, // Barcode that matching reads should be assigned to
+    pub sample: Option<String>, // Sample or pool that the site belongs to
+    pub group: Option<String>,  // Group (i.e., gene or amplicon) that the site belongs to
+    pub collision: bool,        // Site collides with a site for a different barcode (reads are flagged)
+    pub max_dist: Option<usize>, // Maximum distance for matching this site (overrides --max-distance)
+    pub margin: Option<usize>,   // Margin for matching this site (overrides --margin)
+    pub pair: Option<String>,    // Site at the other end of the amplicon (with --site-pairs)
+}
+
+// Policy for pairs of sites with different barcodes that are close enough that their
+// matching windows overlap (see CutSites::resolve_collisions())
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum CollisionPolicy {
+    Error,
+    #[default]
+    Warn,
+    Merge,
+    Flag, // Nearest site wins (as normal), but matched reads are flagged
+}
+
+impl std::str::FromStr for CollisionPolicy {
+    type Err = Error;
+    fn from_str(s: &str) -> error::Result<Self> {
+        let s = s.to_ascii_lowercase();
+        match s.as_str() {
+            "error" => Ok(Self::Error),
+            "warn" => Ok(Self::Warn),
+            "merge" => Ok(Self::Merge),
+            "nearest-wins-with-flag" => Ok(Self::Flag),
+            _ => Err(Error::config(format!("Invalid CollisionPolicy option {}", s))),
+        }
+    }
+}
+
+// Format of cut site files
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum CutFormat {
+    #[default]
+    Auto, // BED if the file name ends in .bed (or .bed.gz), otherwise the cut file format
+    Cut,
+    Bed,
+}
+
+impl std::str::FromStr for CutFormat {
+    type Err = Error;
+    fn from_str(s: &str) -> error::Result<Self> {
+        let s = s.to_ascii_lowercase();
+        match s.as_str() {
+            "auto" => Ok(Self::Auto),
+            "cut" => Ok(Self::Cut),
+            "bed" => Ok(Self::Bed),
+            _ => Err(Error::config(format!("Invalid CutFormat option {}", s))),
+        }
+    }
+}
+
+// Pair of colliding sites
+#[derive(Debug, Clone)]
+pub struct Collision {
+    pub contig: Arc<str>,
+    pub sites: [(String, String, usize); 2], // Name, barcode and position of each site
+}
+
+impl Collision {
+    pub fn distance(&self) -> usize {
+        self.sites[1].2 - self.sites[0].2
+    }
 }
 
 // Collection of cut sites
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct CutSites {
-    pub chash: HashMap<Rc<str>, Contig>,
+    pub chash: HashMap<Arc<str>, Contig>,
 }
 
 impl CutSites {
-    // Returns cut site closest to position if the distance is <= max_dist, l is the contig length
+    // Returns the distinct values of f over all sites in sorted order
+    fn distinct<'a, F: Fn(&'a Site) -> Option<&'a str>>(&'a self, f: F) -> Vec<&'a str> {
+        let mut v: Vec<_> = self
+            .chash
+            .values()
+            .flat_map(|c| c.cut_sites.iter())
+            .filter_map(f)
+            .collect();
+        v.sort_unstable();
+        v.dedup();
+        v
+    }
+
+    // Returns the distinct sample names in sorted order (empty if no samples are defined)
+    pub fn samples(&self) -> Vec<&str> {
+        self.distinct(|s| s.sample.as_deref())
+    }
+
+    // Returns the distinct group names in sorted order (empty if no groups are defined)
+    pub fn groups(&self) -> Vec<&str> {
+        self.distinct(|s| s.group.as_deref())
+    }
+
+    // Merge cut sites from another cut file (--cut-file given more than once).  A site with
+    // the same name as an existing site must have an identical definition (i.e., the same row
+    // appears in both files), and is then dropped; a conflicting definition is an error, as is
+    // an inconsistent circular flag for a contig.  Returns the number of duplicate sites dropped
+    pub fn merge(&mut self, other: CutSites) -> error::Result<usize> {
+        let mut names: HashMap<String, Arc<str>> = HashMap::new();
+        for ctg in self.chash.values() {
+            for site in ctg.cut_sites.iter() {
+                names.insert(site.name.clone(), ctg.name.clone());
+            }
+        }
+        let mut n_dup = 0;
+        for (name, ctg) in other.chash {
+            let c = self.chash.entry(name.clone()).or_insert_with(|| Contig {
+                name: name.clone(),
+                circular: None,
+                cut_sites: Vec::new(),
+            });
+            match (c.circular, ctg.circular) {
+                (Some(a), Some(b)) if a != b => {
+                    return Err(Error::config(format!("Inconsistent circular flag for contig {}", name)))
+                }
+                (None, fg) => c.circular = fg,
+                _ => (),
+            }
+            for site in ctg.cut_sites {
+                match names.get(&site.name) {
+                    Some(ctg_name) => {
+                        let dup = self.chash[ctg_name].cut_sites.contains(&site)
+                            && *ctg_name == name;
+                        if !dup {
+                            return Err(Error::config(format!(
+                                "Cut site {} has conflicting definitions",
+                                site.name
+                            )));
+                        }
+                        debug!("Duplicate definition of cut site {} ignored", site.name);
+                        n_dup += 1;
+                    }
+                    None => {
+                        names.insert(site.name.clone(), name.clone());
+                        self.chash.get_mut(&name).unwrap().cut_sites.push(site);
+                    }
+                }
+            }
+        }
+        for ctg in self.chash.values_mut() {
+            ctg.cut_sites.sort_unstable_by_key(|s| s.pos)
+        }
+        Ok(n_dup)
+    }
+
+    //  Read assignment of cut sites to groups
+    //
+    //  The file should have 2 tab separated columns:
+    //    col 1 - name of cut site
+    //    col 2 - name of group
+    //
+    pub fn read_groups<R: BufRead>(&mut self, mut rdr: R) -> error::Result<()> {
+        let mut groups = HashMap::new();
+        let mut buf = String::new();
+        let mut line = 0;
+        loop {
+            buf.clear();
+            if rdr.read_line(&mut buf)? == 0 {
+                break;
+            }
+            line += 1;
+            let fd: Vec<&str> = buf.trim().split('\t').collect();
+            if fd.len() == 1 && fd[0].is_empty() {
+                continue;
+            }
+            if fd.len() < 2 {
+                return Err(Error::parse("Short line (< 2 columns)").at_line(line));
+            }
+            groups.insert(fd[0].to_owned(), fd[1].to_owned());
+        }
+        let mut n = 0;
+        for site in self.chash.values_mut().flat_map(|c| c.cut_sites.iter_mut()) {
+            if let Some(g) = groups.remove(&site.name) {
+                site.group = Some(g);
+                n += 1;
+            }
+        }
+        if let Some(s) = groups.keys().next() {
+            return Err(Error::config(format!("Cut site {} not found in cut file", s)));
+        }
+        info!("Assigned {} cut sites to {} groups", n, self.groups().len());
+        Ok(())
+    }
+
+    //  Read pairs of cut sites (for --select pair)
+    //
+    //  The file should have 2 tab separated columns:
+    //    col 1 - name of the site at one end of the amplicon (or excised fragment)
+    //    col 2 - name of the site at the other end
+    //
+    //  Both sites must be on the same contig and have the same barcode, and a site can only be
+    //  in one pair.  A site can be paired with itself (i.e., for full length reads from a
+    //  circular contig).  Blank lines and lines starting with '#' are ignored
+    //
+    pub fn read_pairs<R: BufRead>(&mut self, mut rdr: R) -> error::Result<()> {
+        let mut pairs = Vec::new();
+        let mut buf = String::new();
+        let mut line = 0;
+        loop {
+            buf.clear();
+            if rdr.read_line(&mut buf)? == 0 {
+                break;
+            }
+            line += 1;
+            if buf.starts_with('#') || buf.trim().is_empty() {
+                continue;
+            }
+            let fd: Vec<&str> = buf.trim().split('\t').collect();
+            if fd.len() < 2 {
+                return Err(Error::parse("Short line (< 2 columns)").at_line(line));
+            }
+            pairs.push((fd[0].to_owned(), fd[1].to_owned()));
+        }
+        // Location (contig, index) and barcode of each site
+        let index: HashMap<String, (Arc<str>, usize, String)> = self
+            .chash
+            .values()
+            .flat_map(|c| {
+                c.cut_sites
+                    .iter()
+                    .enumerate()
+                    .map(|(i, s)| (s.name.clone(), (c.name.clone(), i, s.barcode.clone())))
+            })
+            .collect();
+        let get = |s: &str| {
+            index
+                .get(s)
+                .ok_or_else(|| Error::config(format!("Cut site {} not found in cut file", s)))
+        };
+        for (a, b) in pairs.iter() {
+            let (ctg_a, ix_a, bc_a) = get(a)?;
+            let (ctg_b, ix_b, bc_b) = get(b)?;
+            if ctg_a != ctg_b {
+                return Err(Error::config(format!("Paired cut sites {} and {} are on different contigs", a, b)));
+            }
+            if bc_a != bc_b {
+                return Err(Error::config(format!("Paired cut sites {} and {} have different barcodes", a, b)));
+            }
+            let sites = &mut self.chash.get_mut(ctg_a).unwrap().cut_sites;
+            for (ix, other) in [(*ix_a, b), (*ix_b, a)] {
+                let site = &mut sites[ix];
+                match site.pair.as_ref() {
+                    Some(p) if p != other => {
+                        return Err(Error::config(format!("Cut site {} is in more than one pair", site.name)))
+                    }
+                    _ => site.pair = Some(other.clone()),
+                }
+            }
+        }
+        info!("Read {} pairs of cut sites", pairs.len());
+        Ok(())
+    }
+
+    #[cfg(feature = "cli")]
+    pub fn read_pair_file<S: AsRef<Path>>(&mut self, name: S, compressor: &Compressor) -> error::Result<()> {
+        self.read_pairs(compressor.reader(Some(&name))?)
+            .map_err(|e| e.in_file(name))
+    }
+
+    #[cfg(feature = "cli")]
+    pub fn read_group_file<S: AsRef<Path>>(&mut self, name: S, compressor: &Compressor) -> error::Result<()> {
+        self.read_groups(compressor.reader(Some(&name))?)
+            .map_err(|e| e.in_file(name))
+    }
+
+    //  Read sample sheet mapping barcodes to samples
+    //
+    //  The file should have 2 columns separated by sep:
+    //    col 1 - sample barcode
+    //    col 2 - name of sample
+    //
+    //  An optional header line (with barcode in the first column), blank lines and lines starting
+    //  with '#' are skipped.  All sites with a barcode in the sheet are assigned to the sample,
+    //  replacing any sample from the cut file.  Every barcode in the sheet must be used by a site
+    //
+    pub fn read_sample_sheet<R: BufRead>(&mut self, mut rdr: R, sep: char) -> error::Result<()> {
+        let mut samples: HashMap<String, String> = HashMap::new();
+        let mut buf = String::new();
+        let mut line = 0;
+        loop {
+            buf.clear();
+            if rdr.read_line(&mut buf)? == 0 {
+                break;
+            }
+            line += 1;
+            if buf.starts_with('#') {
+                continue;
+            }
+            let fd: Vec<&str> = buf.trim().split(sep).map(|s| s.trim()).collect();
+            if fd.len() == 1 && fd[0].is_empty() {
+                continue;
+            }
+            if fd.len() < 2 || fd[1].is_empty() {
+                return Err(Error::parse("Short line (< 2 columns)").at_line(line));
+            }
+            if samples.is_empty() && fd[0].eq_ignore_ascii_case("barcode") {
+                continue;
+            }
+            if let Some(s) = samples.insert(fd[0].to_owned(), fd[1].to_owned()) {
+                if s != fd[1] {
+                    return Err(Error::parse(format!(
+                        "Barcode {} assigned to samples {} and {}",
+                        fd[0], s, fd[1]
+                    ))
+                    .at_line(line));
+                }
+            }
+        }
+        let mut unused: HashSet<&str> = samples.keys().map(|s| s.as_str()).collect();
+        let mut n = 0;
+        for site in self.chash.values_mut().flat_map(|c| c.cut_sites.iter_mut()) {
+            if let Some(s) = samples.get(&site.barcode) {
+                if site.sample.as_ref().map(|x| x != s).unwrap_or(false) {
+                    warn!(
+                        "Sample for cut site {} changed from {} to {} by sample sheet",
+                        site.name,
+                        site.sample.as_ref().unwrap(),
+                        s
+                    )
+                }
+                site.sample = Some(s.clone());
+                unused.remove(site.barcode.as_str());
+                n += 1;
+            }
+        }
+        if !unused.is_empty() {
+            let mut v: Vec<_> = unused.into_iter().collect();
+            v.sort_unstable();
+            return Err(Error::config(format!("Barcodes in sample sheet not found in cut sites: {}", v.join(", "))));
+        }
+        info!("Assigned {} cut sites to {} samples from sample sheet", n, self.samples().len());
+        Ok(())
+    }
+
+    // Read sample sheet from file.  Files ending .csv (or .csv.gz etc.) are comma separated and
+    // other files are tab separated
+    #[cfg(feature = "cli")]
+    pub fn read_sample_sheet_file<S: AsRef<Path>>(&mut self, name: S, compressor: &Compressor) -> error::Result<()> {
+        let fname = name.as_ref().to_string_lossy().to_ascii_lowercase();
+        let sep = if fname.ends_with(".csv") || fname.contains(".csv.") { ',' } else { '\t' };
+        self.read_sample_sheet(compressor.reader(Some(&name))?, sep)
+            .map_err(|e| e.in_file(name))
+    }
+
+    // Find pairs of sites on the same contig with different barcodes that are within
+    // 2 * max_dist of each other (or the sum of their maximum distances if set for the sites),
+    // so that a read could match either site, and apply policy.
+    // With Error an error is returned, with Merge the sites in each cluster of colliding sites
+    // are given a common barcode (the barcodes of the sites joined with '+'), and with Flag the
+    // sites are marked so that matched reads can be flagged.  Returns the colliding pairs (before
+    // any merging)
+    pub fn resolve_collisions(&mut self, max_dist: usize, policy: CollisionPolicy) -> error::Result<Vec<Collision>> {
+        let mut collisions = Vec::new();
+        let dist = |s: &Site| s.max_dist.unwrap_or(max_dist);
+        let widest = self.max_site_distance(max_dist);
+        for ctg in self.chash.values_mut() {
+            let v = &mut ctg.cut_sites;
+            // Index pairs of colliding sites.  Sites are sorted by position
+            let mut pairs = Vec::new();
+            for (i, s1) in v.iter().enumerate() {
+                for (j, s2) in v.iter().enumerate().skip(i + 1) {
+                    if s2.pos - s1.pos > dist(s1) + widest {
+                        break;
+                    }
+                    if s1.barcode != s2.barcode && s2.pos - s1.pos <= dist(s1) + dist(s2) {
+                        pairs.push((i, j))
+                    }
+                }
+            }
+            for &(i, j) in pairs.iter() {
+                let x = |s: &Site| (s.name.clone(), s.barcode.clone(), s.pos);
+                collisions.push(Collision {
+                    contig: ctg.name.clone(),
+                    sites: [x(&v[i]), x(&v[j])],
+                })
+            }
+            match policy {
+                CollisionPolicy::Flag => {
+                    for &(i, j) in pairs.iter() {
+                        v[i].collision = true;
+                        v[j].collision = true;
+                    }
+                }
+                CollisionPolicy::Merge if !pairs.is_empty() => {
+                    // Clusters of colliding sites as index ranges
+                    let mut ranges: Vec<(usize, usize)> = Vec::new();
+                    for (i, j) in pairs {
+                        match ranges.last_mut().filter(|r| i <= r.1) {
+                            Some(r) => r.1 = r.1.max(j),
+                            None => ranges.push((i, j)),
+                        }
+                    }
+                    for (a, b) in ranges {
+                        let mut barcodes: Vec<&str> = Vec::new();
+                        for s in v[a..=b].iter() {
+                            if !barcodes.contains(&s.barcode.as_str()) {
+                                barcodes.push(&s.barcode)
+                            }
+                        }
+                        let barcode = barcodes.join("+");
+                        for s in v[a..=b].iter_mut() {
+                            s.barcode.clone_from(&barcode)
+                        }
+                    }
+                }
+                _ => (),
+            }
+        }
+        collisions.sort_unstable_by(|a, b| (&a.contig, a.sites[0].2).cmp(&(&b.contig, b.sites[0].2)));
+        for c in collisions.iter() {
+            let msg = format!(
+                "Cut sites {} ({}) and {} ({}) on {} are {} bp apart",
+                c.sites[0].0,
+                c.sites[0].1,
+                c.sites[1].0,
+                c.sites[1].1,
+                c.contig,
+                c.distance()
+            );
+            if policy == CollisionPolicy::Error {
+                return Err(Error::config(format!(
+                    "{} (within the maximum distances of both sites; {} colliding site pairs in total)",
+                    msg,
+                    collisions.len()
+                )));
+            }
+            warn!("{}", msg)
+        }
+        if !collisions.is_empty() {
+            match policy {
+                CollisionPolicy::Merge => info!("Merged barcodes of colliding cut sites"),
+                CollisionPolicy::Flag => info!("Reads matching colliding cut sites will be flagged"),
+                _ => (),
+            }
+        }
+        Ok(collisions)
+    }
+
+    // Returns the largest maximum distance over all sites, where max_dist is used for
+    // sites without their own maximum distance
+    pub fn max_site_distance(&self, max_dist: usize) -> usize {
+        self.chash
+            .values()
+            .flat_map(|c| c.cut_sites.iter())
+            .filter_map(|s| s.max_dist)
+            .fold(max_dist, usize::max)
+    }
+
+    // Returns the largest margin over all sites, where margin is used for sites without
+    // their own margin
+    pub fn max_site_margin(&self, margin: usize) -> usize {
+        self.chash
+            .values()
+            .flat_map(|c| c.cut_sites.iter())
+            .filter_map(|s| s.margin)
+            .fold(margin, usize::max)
+    }
+
+    // Returns all sites ordered by contig name and then position, so the position of a site in
+    // the list is a stable index for the site (used to refer to sites in the spill files)
+    pub fn site_list(&self) -> Vec<&Site> {
+        let mut ctgs: Vec<_> = self.chash.values().collect();
+        ctgs.sort_unstable_by(|a, b| a.name.cmp(&b.name));
+        ctgs.into_iter().flat_map(|c| c.cut_sites.iter()).collect()
+    }
+
+    // Returns hash of all sites indexed by site name
+    pub fn site_index(&self) -> HashMap<&str, &Site> {
+        self.chash
+            .values()
+            .flat_map(|c| c.cut_sites.iter())
+            .map(|s| (s.name.as_str(), s))
+            .collect()
+    }
+
+    // Returns the contig that site is on
+    pub fn site_contig(&self, site: &Site) -> Option<&Arc<str>> {
+        self.chash
+            .values()
+            .find(|c| c.cut_sites.iter().any(|s| std::ptr::eq(s, site)))
+            .map(|c| &c.name)
+    }
+
+    // Returns true if contig has any cut sites
+    pub fn has_sites<S: AsRef<str>>(&self, contig: S) -> bool {
+        self.chash
+            .get(contig.as_ref())
+            .map(|c| !c.cut_sites.is_empty())
+            .unwrap_or(false)
+    }
+
+    // Write target regions for adaptive sampling in BED format: the region within window bp
+    // either side of each cut site, with overlapping regions merged.  Regions are named by the
+    // sites they include and are not stranded.  The end of a region is not limited to the
+    // length of the contig, as this is not known here
+    pub fn write_targets<W: Write>(&self, window: usize, wrt: &mut W) -> io::Result<()> {
+        let mut ctgs: Vec<_> = self.chash.values().filter(|c| !c.cut_sites.is_empty()).collect();
+        ctgs.sort_unstable_by_key(|c| c.name.clone());
+        for ctg in ctgs {
+            let mut region: Option<(usize, usize, Vec<&str>)> = None;
+            for site in ctg.cut_sites.iter() {
+                let p = site.pos.saturating_sub(1);
+                let (s, e) = (p.saturating_sub(window), p + 1 + window);
+                match region.as_mut() {
+                    Some((_, end, names)) if s <= *end => {
+                        *end = e;
+                        names.push(&site.name)
+                    }
+                    _ => {
+                        if let Some((s, e, names)) = region.take() {
+                            writeln!(wrt, "{}\t{}\t{}\t{}\t0\t.", ctg.name, s, e, names.join(","))?
+                        }
+                        region = Some((s, e, vec![&site.name]))
+                    }
+                }
+            }
+            if let Some((s, e, names)) = region {
+                writeln!(wrt, "{}\t{}\t{}\t{}\t0\t.", ctg.name, s, e, names.join(","))?
+            }
+        }
+        Ok(())
+    }
+
+    // Returns the cut sites on contig with positions in the range start..=end
+    pub fn sites_in_range<S: AsRef<str>>(&self, contig: S, start: usize, end: usize) -> &[Site] {
+        match self.chash.get(contig.as_ref()) {
+            Some(ctg) => {
+                let a = ctg.cut_sites.partition_point(|s| s.pos < start);
+                let b = ctg.cut_sites.partition_point(|s| s.pos <= end);
+                &ctg.cut_sites[a..b.max(a)]
+            }
+            None => &[],
+        }
+    }
+
+    // Returns cut site closest to position if the distance is <= max_dist (or the maximum
+    // distance for the site if set), l is the contig length.
+    // On linear contigs, positions within end_padding of the contig start (end) are treated as
+    // adjacent to the first (last) site, so that reads running off the end of the contig can
+    // still be matched to the terminal sites
     pub fn find_site<S: AsRef<str>>(
         &self,
         contig: S,
@@ -38,6 +578,7 @@ impl CutSites {
         dir: bool,
         max_dist: usize,
         l: usize,
+        end_padding: usize,
     ) -> Option<&Site> {
         debug!("Checking for cut site near {}:{}", contig.as_ref(), pos);
         if let Some(ctg) = self.chash.get(contig.as_ref()) {
@@ -100,8 +641,11 @@ impl CutSites {
                                 }
                             } else if dir {
                                 d1
+                            } else if pos + end_padding >= l {
+                                trace!("Position within end padding of contig end");
+                                d1
                             } else {
-                                Some((0, max_dist + 1))
+                                Some((0, usize::MAX))
                             }
                         }
                         // pos lies before first cut site
@@ -127,7 +671,12 @@ impl CutSites {
                                     d2
                                 }
                             } else if dir {
-                                Some((ctg.cut_sites.len() - 1, max_dist + 1))
+                                if pos <= end_padding {
+                                    trace!("Position within end padding of contig start");
+                                    d2
+                                } else {
+                                    Some((ctg.cut_sites.len() - 1, usize::MAX))
+                                }
                             } else {
                                 d2
                             }
@@ -136,7 +685,7 @@ impl CutSites {
                         _ => panic!("Unexpected case!"),
                     } {
                         // Now test if the closest match is closer than max_dist and if so return corresponding element
-                        if d <= max_dist {
+                        if d <= ctg.cut_sites[i].max_dist.unwrap_or(max_dist) {
                             trace!("Selected match {:?} ({}bp)", ctg.cut_sites[i], d);
                             Some(&ctg.cut_sites[i])
                         } else {
@@ -156,33 +705,40 @@ impl CutSites {
     }
 }
 
-//  Read in cut site definitions from file
+//  Read in cut site definitions
 //
-//  The cut file should have 4 or 5 tab separated columns:
+//  The cut file should have between 4 and 8 tab separated columns:
 //    col 1 - contig name
 //    col 2 - position in contig (1 offset)
 //    col 3 - name of cut site
 //    col 4 - sample barcode
 //    col 5 - circular flag (true/false yes/no 1/0)
+//    col 6 - sample or pool name (optional)
+//    col 7 - maximum distance for the site (optional, overrides --max-distance)
+//    col 8 - margin for the site (optional, overrides --margin)
+//
+//  Empty optional columns (or '.') are treated as absent.  Site names must be unique
 //
 //  Returns a CutSites struct
 //
-pub fn read_cut_file<S: AsRef<Path>>(name: S) -> io::Result<CutSites> {
-    let mut chash: HashMap<Rc<str>, Contig> = HashMap::new();
-    let mut rdr = CompressIo::new().path(name).bufreader()?;
+pub fn read_cut_sites<R: BufRead>(mut rdr: R) -> error::Result<CutSites> {
+    let mut chash: HashMap<Arc<str>, Contig> = HashMap::new();
+    let mut names = HashSet::new();
     let mut buf = String::new();
+    let mut line = 0;
     loop {
         let l = rdr.read_line(&mut buf)?;
         if l == 0 {
             break;
         }
+        line += 1;
         let fd: Vec<&str> = buf.trim().split('\t').collect();
         if fd.len() > 4 {
             // Get contig from hash or create new entry
             let ctg = if let Some(c) = chash.get_mut(fd[0]) {
                 c
             } else {
-                let name: Rc<str> = Rc::from(fd[0]);
+                let name: Arc<str> = Arc::from(fd[0]);
                 let c = Contig {
                     name: name.clone(),
                     cut_sites: Vec::new(),
@@ -192,13 +748,17 @@ pub fn read_cut_file<S: AsRef<Path>>(name: S) -> io::Result<CutSites> {
                 chash.get_mut(fd[0]).unwrap()
             };
             // Handle circular flag
-            if let Some(fg) = fd.get(4).map(|s| match s.to_lowercase().as_str() {
-                "true" | "yes" | "1" => true,
-                "false" | "no" | "0" => false,
-                _ => panic!("Unknown flag for circular status ({})", s),
-            }) {
+            let fg = match fd[4].to_lowercase().as_str() {
+                "" => None,
+                "true" | "yes" | "1" => Some(true),
+                "false" | "no" | "0" => Some(false),
+                s => return Err(Error::parse(format!("Unknown flag for circular status ({})", s)).at_line(line)),
+            };
+            if let Some(fg) = fg {
                 if let Some(fg_old) = ctg.circular {
-                    assert_eq!(fg, fg_old, "Inconsistent circular flag in cut file")
+                    if fg != fg_old {
+                        return Err(Error::parse(format!("Inconsistent circular flag for contig {}", ctg.name)).at_line(line));
+                    }
                 } else {
                     ctg.circular = Some(fg)
                 }
@@ -206,11 +766,30 @@ pub fn read_cut_file<S: AsRef<Path>>(name: S) -> io::Result<CutSites> {
             // Handle position
             let pos = fd[1]
                 .parse::<usize>()
-                .expect("Error paring position in cut site file");
+                .map_err(|_| Error::parse(format!("Invalid position ({}) for cut site {}", fd[1], fd[2])).at_line(line))?;
+            // Optional per-site maximum distance and margin
+            let opt = |i: usize, s: &str| match fd.get(i).filter(|x| !x.is_empty() && **x != ".") {
+                Some(x) => x
+                    .parse::<usize>()
+                    .map(Some)
+                    .map_err(|_| Error::parse(format!("Invalid {} ({}) for cut site {}", s, x, fd[2])).at_line(line)),
+                None => Ok(None),
+            };
+            let max_dist = opt(6, "maximum distance")?;
+            let margin = opt(7, "margin")?;
+            if !names.insert(fd[2].to_owned()) {
+                return Err(Error::parse(format!("Duplicate cut site name {}", fd[2])).at_line(line));
+            }
             // Create new site
             let site = Site {
                 name: fd[2].to_owned(),
                 barcode: fd[3].to_owned(),
+                sample: fd.get(5).filter(|s| !s.is_empty() && **s != ".").map(|s| s.to_string()),
+                group: None,
+                collision: false,
+                pair: None,
+                max_dist,
+                margin,
                 pos,
             };
             ctg.cut_sites.push(site);
@@ -224,3 +803,243 @@ pub fn read_cut_file<S: AsRef<Path>>(name: S) -> io::Result<CutSites> {
 
     Ok(CutSites { chash })
 }
+
+// Read in cut site definitions from a BED file
+//
+//  The BED file should have at least 4 tab separated columns:
+//    col 1 - contig name
+//    col 2 - start of interval (0 offset)
+//    col 3 - end of interval
+//    col 4 - name of cut site
+//    col 5 - sample barcode (BED score field; if absent the site name is used)
+//    col 6 - strand (optional)
+//
+//  The site position is the first base of the interval, or the last base for intervals on
+//  the minus strand, so a cut site at (1 offset) position p is given by the interval p-1..p.
+//  Track, browser and comment lines are ignored.  Contigs are linear unless flagged as
+//  circular in a cut file.  Site names must be unique
+//
+pub fn read_cut_sites_bed<R: BufRead>(mut rdr: R) -> error::Result<CutSites> {
+    let mut chash: HashMap<Arc<str>, Contig> = HashMap::new();
+    let mut names = HashSet::new();
+    let mut buf = String::new();
+    let mut line = 0;
+    loop {
+        buf.clear();
+        if rdr.read_line(&mut buf)? == 0 {
+            break;
+        }
+        line += 1;
+        let l = buf.trim_end();
+        if l.is_empty() || l.starts_with('#') || l.starts_with("track") || l.starts_with("browser") {
+            continue;
+        }
+        let fd: Vec<&str> = l.split('\t').collect();
+        let err = |s: &str| Error::parse(s).at_line(line);
+        if fd.len() < 4 || fd[3].is_empty() {
+            return Err(err("Missing site name"));
+        }
+        let start = fd[1].parse::<usize>().map_err(|_| err("Invalid start position"))?;
+        let end = fd[2].parse::<usize>().map_err(|_| err("Invalid end position"))?;
+        if end <= start {
+            return Err(err("Empty interval"));
+        }
+        let pos = match fd.get(5) {
+            Some(&"-") => end,
+            _ => start + 1,
+        };
+        if !names.insert(fd[3].to_owned()) {
+            return Err(Error::parse(format!("Duplicate cut site name {}", fd[3])).at_line(line));
+        }
+        let barcode = fd.get(4).filter(|s| !s.is_empty() && **s != ".").unwrap_or(&fd[3]);
+        let site = Site {
+            name: fd[3].to_owned(),
+            barcode: barcode.to_string(),
+            sample: None,
+            group: None,
+            collision: false,
+            pair: None,
+            max_dist: None,
+            margin: None,
+            pos,
+        };
+        chash
+            .entry(Arc::from(fd[0]))
+            .or_insert_with_key(|name| Contig {
+                name: name.clone(),
+                circular: None,
+                cut_sites: Vec::new(),
+            })
+            .cut_sites
+            .push(site);
+    }
+    // Sort cut_sites by position within each contig
+    for (_, ctg) in chash.iter_mut() {
+        ctg.cut_sites.sort_unstable_by_key(|s| s.pos)
+    }
+    Ok(CutSites { chash })
+}
+
+// Cut sites from inline definitions (--site) of the form contig:pos:name:barcode.  The contig
+// name may itself contain ':'.  Contigs are linear unless flagged as circular in a cut file
+pub fn site_defs<S: AsRef<str>>(defs: &[S]) -> error::Result<CutSites> {
+    let mut cut_sites = CutSites::default();
+    for def in defs {
+        let def = def.as_ref();
+        let fd: Vec<_> = def.rsplitn(4, ':').collect();
+        let pos = match fd.get(2).and_then(|s| s.parse::<usize>().ok()) {
+            Some(x) if fd.len() == 4 && fd.iter().all(|s| !s.is_empty()) => x,
+            _ => {
+                return Err(Error::config(format!(
+                    "Invalid cut site definition {} (should be contig:pos:name:barcode)",
+                    def
+                )))
+            }
+        };
+        let site = Site {
+            name: fd[1].to_owned(),
+            barcode: fd[0].to_owned(),
+            sample: None,
+            group: None,
+            collision: false,
+            pair: None,
+            max_dist: None,
+            margin: None,
+            pos,
+        };
+        let mut cs = CutSites::default();
+        let name: Arc<str> = Arc::from(fd[3]);
+        cs.chash.insert(
+            name.clone(),
+            Contig {
+                name,
+                circular: None,
+                cut_sites: vec![site],
+            },
+        );
+        cut_sites.merge(cs)?;
+    }
+    Ok(cut_sites)
+}
+
+//  Read in cut site definitions from file (see read_cut_sites())
+#[cfg(feature = "cli")]
+pub fn read_cut_file<S: AsRef<Path>>(name: S, compressor: &Compressor) -> error::Result<CutSites> {
+    read_cut_file_format(name, CutFormat::Auto, compressor)
+}
+
+//  Read in cut site definitions from file in the cut file or BED format (see read_cut_sites()
+//  and read_cut_sites_bed()).  If name is '-' the definitions are read from stdin
+#[cfg(feature = "cli")]
+pub fn read_cut_file_format<S: AsRef<Path>>(name: S, format: CutFormat, compressor: &Compressor) -> error::Result<CutSites> {
+    let stdin = name.as_ref() == Path::new("-");
+    let bed = match format {
+        CutFormat::Auto => {
+            let s = name.as_ref().to_string_lossy().to_ascii_lowercase();
+            s.ends_with(".bed") || s.ends_with(".bed.gz")
+        }
+        CutFormat::Cut => false,
+        CutFormat::Bed => true,
+    };
+    let rdr = compressor.reader(Some(&name).filter(|_| !stdin))?;
+    if bed {
+        read_cut_sites_bed(rdr)
+    } else {
+        read_cut_sites(rdr)
+    }
+    .map_err(|e| if stdin { e.in_file("<stdin>") } else { e.in_file(name) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ErrorKind;
+
+    fn cut_sites(s: &str) -> CutSites {
+        read_cut_sites(s.as_bytes()).unwrap()
+    }
+
+    fn positions<'a>(cs: &'a CutSites, contig: &str) -> Vec<(&'a str, usize)> {
+        cs.chash[contig].cut_sites.iter().map(|s| (s.name.as_str(), s.pos)).collect()
+    }
+
+    #[test]
+    fn read_cut_sites_optional_columns() {
+        let cs = cut_sites("chrM\t6000\tsiteB\tbc02\ttrue\tpool1\t50\t.\nchrM\t1000\tsiteA\tbc01\t\t.\t.\n");
+        assert_eq!(positions(&cs, "chrM"), [("siteA", 1000), ("siteB", 6000)]);
+        assert_eq!(cs.chash["chrM"].circular, Some(true));
+        let [a, b] = &cs.chash["chrM"].cut_sites[..] else { panic!() };
+        assert_eq!((a.sample.as_deref(), a.max_dist), (None, None));
+        assert_eq!((b.sample.as_deref(), b.max_dist, b.margin), (Some("pool1"), Some(50), None));
+    }
+
+    #[test]
+    fn read_cut_sites_duplicate_name() {
+        let e = read_cut_sites("chrM\t1000\tsiteA\tbc01\ttrue\nchr1\t500\tsiteA\tbc02\tfalse\n".as_bytes()).unwrap_err();
+        assert_eq!((e.kind(), e.line()), (ErrorKind::Parse, Some(2)));
+    }
+
+    #[test]
+    fn merge_drops_identical_sites() {
+        let mut cs = cut_sites("chrM\t1000\tsiteA\tbc01\ttrue\n");
+        let n = cs
+            .merge(cut_sites("chrM\t6000\tsiteB\tbc02\ttrue\nchrM\t1000\tsiteA\tbc01\ttrue\nchr1\t10\tsiteC\tbc03\tfalse\n"))
+            .unwrap();
+        assert_eq!(n, 1);
+        assert_eq!(positions(&cs, "chrM"), [("siteA", 1000), ("siteB", 6000)]);
+        assert_eq!(positions(&cs, "chr1"), [("siteC", 10)]);
+    }
+
+    #[test]
+    fn merge_conflicts() {
+        // Same name with a different position
+        let mut cs = cut_sites("chrM\t1000\tsiteA\tbc01\ttrue\n");
+        let e = cs.merge(cut_sites("chrM\t1001\tsiteA\tbc01\ttrue\n")).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::Config);
+        // Same name on a different contig
+        let e = cs.merge(cut_sites("chr1\t1000\tsiteA\tbc01\tfalse\n")).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::Config);
+        // Inconsistent circular flag
+        let mut cs = cut_sites("chrM\t1000\tsiteA\tbc01\ttrue\n");
+        let e = cs.merge(cut_sites("chrM\t6000\tsiteB\tbc02\tfalse\n")).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::Config);
+    }
+
+    #[test]
+    fn read_bed() {
+        let bed = "track name=sites\n# comment\nchr1\t99\t100\tsiteA\tbc01\t+\n\
+                   chr1\t199\t210\tsiteB\t.\t-\nchr1\t49\t50\tsiteC\n";
+        let cs = read_cut_sites_bed(bed.as_bytes()).unwrap();
+        assert_eq!(positions(&cs, "chr1"), [("siteC", 50), ("siteA", 100), ("siteB", 210)]);
+        let barcodes: Vec<_> = cs.chash["chr1"].cut_sites.iter().map(|s| s.barcode.as_str()).collect();
+        assert_eq!(barcodes, ["siteC", "bc01", "siteB"]);
+        assert_eq!(cs.chash["chr1"].circular, None);
+    }
+
+    #[test]
+    fn read_bed_errors() {
+        let err = |s: &str| read_cut_sites_bed(s.as_bytes()).unwrap_err();
+        // Line numbers include the skipped lines
+        assert_eq!(err("# comment\nchr1\t10\t10\tsiteA\n").line(), Some(2));
+        assert_eq!(err("chr1\t10\t11\n").kind(), ErrorKind::Parse);
+        assert_eq!(err("chr1\tx\t11\tsiteA\n").kind(), ErrorKind::Parse);
+        assert_eq!(err("chr1\t10\t11\tsiteA\nchr2\t20\t21\tsiteA\n").line(), Some(2));
+    }
+
+    #[test]
+    fn find_site_end_padding() {
+        let cs = cut_sites("chr1\t1000\tsiteA\tbc01\tfalse\nchr1\t5000\tsiteB\tbc02\tfalse\n");
+        let find = |pos, dir, pad| cs.find_site("chr1", pos, dir, 1000, 6000, pad).map(|s| s.name.as_str());
+        // Reads running off the start of the contig
+        assert_eq!(find(10, true, 20), Some("siteA"));
+        assert_eq!(find(30, true, 20), None);
+        assert_eq!(find(10, true, 0), None);
+        assert_eq!(find(10, false, 0), Some("siteA"));
+        // Reads running off the end of the contig
+        assert_eq!(find(5990, false, 20), Some("siteB"));
+        assert_eq!(find(5970, false, 20), None);
+        assert_eq!(find(5990, true, 0), Some("siteB"));
+        // The site distance limit still applies
+        assert_eq!(cs.find_site("chr1", 10, true, 500, 6000, 20), None);
+    }
+}
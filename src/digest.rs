@@ -0,0 +1,240 @@
+// Cut sites from restriction enzyme digestion of a reference (--reference, --enzyme)
+//
+// Instead of preparing a cut file by hand, the cut sites can be found by scanning the
+// sequences of a FASTA reference for the recognition sequences of one or more restriction
+// enzymes.  Enzymes can be given by name (from a table of common enzymes) or as a recognition
+// sequence with the cut position marked by ^ (i.e., G^AATTC for EcoRI).  IUPAC ambiguity
+// codes can be used in recognition sequences.  Each site found gives a cut site named
+// <enzyme>_<contig>_<pos>, where pos (1 offset) is the position just after the cut on the
+// forward strand (as for the cut file).  Sites are assigned to the enzyme name as barcode
+// unless a barcode is given with the enzyme.  Contigs are linear, and sites spanning the end
+// of a contig are not found.
+
+use std::{
+    collections::HashMap,
+    io::BufRead,
+    sync::Arc,
+};
+
+#[cfg(feature = "cli")]
+use std::path::Path;
+
+#[cfg(feature = "cli")]
+use crate::compressor::Compressor;
+use crate::cut_site::{Contig, CutSites, Site};
+use crate::error::{self, Error};
+
+// Recognition sequences of common enzymes, with the cut position on the forward strand
+// marked by ^
+const ENZYMES: [(&str, &str); 40] = [
+    ("AluI", "AG^CT"),
+    ("ApaI", "GGGCC^C"),
+    ("AscI", "GG^CGCGCC"),
+    ("BamHI", "G^GATCC"),
+    ("BglII", "A^GATCT"),
+    ("ClaI", "AT^CGAT"),
+    ("CviQI", "G^TAC"),
+    ("DpnI", "GA^TC"),
+    ("DpnII", "^GATC"),
+    ("EcoRI", "G^AATTC"),
+    ("EcoRV", "GAT^ATC"),
+    ("HaeIII", "GG^CC"),
+    ("HhaI", "GCG^C"),
+    ("HindIII", "A^AGCTT"),
+    ("HinfI", "G^ANTC"),
+    ("HpaII", "C^CGG"),
+    ("KpnI", "GGTAC^C"),
+    ("MboI", "^GATC"),
+    ("MluI", "A^CGCGT"),
+    ("MseI", "T^TAA"),
+    ("MspI", "C^CGG"),
+    ("NcoI", "C^CATGG"),
+    ("NdeI", "CA^TATG"),
+    ("NheI", "G^CTAGC"),
+    ("NlaIII", "CATG^"),
+    ("NotI", "GC^GGCCGC"),
+    ("PacI", "TTAAT^TAA"),
+    ("PstI", "CTGCA^G"),
+    ("PvuII", "CAG^CTG"),
+    ("SacI", "GAGCT^C"),
+    ("SalI", "G^TCGAC"),
+    ("Sau3AI", "^GATC"),
+    ("ScaI", "AGT^ACT"),
+    ("SmaI", "CCC^GGG"),
+    ("SpeI", "A^CTAGT"),
+    ("SphI", "GCATG^C"),
+    ("SwaI", "ATTT^AAAT"),
+    ("TaqI", "T^CGA"),
+    ("XbaI", "T^CTAGA"),
+    ("XhoI", "C^TCGAG"),
+];
+
+// Bases matched by an IUPAC code (as a bit mask with A = 1, C = 2, G = 4, T = 8)
+fn iupac_mask(c: u8) -> u8 {
+    match c.to_ascii_uppercase() {
+        b'A' => 1,
+        b'C' => 2,
+        b'G' => 4,
+        b'T' | b'U' => 8,
+        b'R' => 5,
+        b'Y' => 10,
+        b'S' => 6,
+        b'W' => 9,
+        b'K' => 12,
+        b'M' => 3,
+        b'B' => 14,
+        b'D' => 13,
+        b'H' => 11,
+        b'V' => 7,
+        b'N' => 15,
+        _ => 0,
+    }
+}
+
+// Complement of a set of bases
+fn complement_mask(m: u8) -> u8 {
+    ((m & 1) << 3) | ((m & 2) << 1) | ((m & 4) >> 1) | ((m & 8) >> 3)
+}
+
+// Restriction enzyme
+#[derive(Debug, Clone)]
+pub struct Enzyme {
+    name: String,    // Enzyme name (or recognition sequence for user defined enzymes)
+    site: Vec<u8>,   // Recognition sequence as base masks (see iupac_mask())
+    cut: usize,      // Number of bases of the recognition sequence before the cut on the forward strand
+    barcode: String, // Barcode for sites of this enzyme
+}
+
+impl Enzyme {
+    // Parse enzyme from NAME or SEQ (with the cut marked by ^), optionally followed by :BARCODE
+    pub fn from_spec(spec: &str) -> error::Result<Self> {
+        let (e, barcode) = match spec.split_once(':') {
+            Some((e, bc)) if !bc.is_empty() => (e, Some(bc)),
+            _ => (spec, None),
+        };
+        let (name, seq) = match ENZYMES.iter().find(|(n, _)| n.eq_ignore_ascii_case(e)) {
+            Some((n, s)) => (n.to_string(), *s),
+            None if e.contains('^') => (e.replace('^', "").to_ascii_uppercase(), e),
+            None => {
+                return Err(Error::config(format!(
+                    "Unknown enzyme {} (give the recognition sequence with the cut position marked by ^)",
+                    e
+                )))
+            }
+        };
+        let cut = seq.find('^').unwrap();
+        let site: Vec<u8> = seq.bytes().filter(|c| *c != b'^').map(iupac_mask).collect();
+        if site.is_empty() || site.contains(&0) || seq.matches('^').count() != 1 {
+            return Err(Error::config(format!("Invalid recognition sequence {}", seq)));
+        }
+        Ok(Self {
+            barcode: barcode.map(|s| s.to_owned()).unwrap_or_else(|| name.clone()),
+            name,
+            site,
+            cut,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    // Positions (1 offset) just after the forward strand cuts for all sites in seq.  Sites
+    // on the reverse strand of non-palindromic recognition sequences are also found
+    pub fn cut_positions(&self, seq: &[u8]) -> Vec<usize> {
+        let k = self.site.len();
+        let rc: Vec<u8> = self.site.iter().rev().map(|m| complement_mask(*m)).collect();
+        // A reference base matches if it is a subset of the bases allowed at that position
+        let matches = |s: &[u8], w: &[u8]| {
+            s.iter().zip(w).all(|(m, c)| {
+                let x = iupac_mask(*c);
+                x != 0 && x & !m == 0
+            })
+        };
+        let mut v = Vec::new();
+        if seq.len() >= k {
+            for (i, w) in seq.windows(k).enumerate() {
+                if matches(&self.site, w) {
+                    v.push(i + self.cut + 1)
+                }
+                if rc != self.site && matches(&rc, w) {
+                    v.push(i + k - self.cut + 1)
+                }
+            }
+        }
+        v.sort_unstable();
+        v.dedup();
+        v
+    }
+}
+
+// Cut sites for all enzymes from the sequences in a FASTA file
+pub fn digest_sequences<R: BufRead>(mut rdr: R, enzymes: &[Enzyme]) -> error::Result<CutSites> {
+    let mut chash: HashMap<Arc<str>, Contig> = HashMap::new();
+    let mut counts = vec![0; enzymes.len()];
+    let mut buf = String::new();
+    let mut name: Option<String> = None;
+    let mut seq = Vec::new();
+    let mut line = 0;
+    loop {
+        buf.clear();
+        let l = rdr.read_line(&mut buf)?;
+        line += 1;
+        if l == 0 || buf.starts_with('>') {
+            if let Some(ctg) = name.take() {
+                let mut sites = Vec::new();
+                for (e, n) in enzymes.iter().zip(counts.iter_mut()) {
+                    for pos in e.cut_positions(&seq) {
+                        sites.push(Site {
+                            name: format!("{}_{}_{}", e.name, ctg, pos),
+                            pos,
+                            barcode: e.barcode.clone(),
+                            sample: None,
+                            group: None,
+                            collision: false,
+                            pair: None,
+                            max_dist: None,
+                            margin: None,
+                        });
+                        *n += 1
+                    }
+                }
+                if !sites.is_empty() {
+                    sites.sort_unstable_by_key(|s| s.pos);
+                    let ctg: Arc<str> = Arc::from(ctg);
+                    if chash.contains_key(&ctg) {
+                        return Err(Error::parse(format!("Duplicate sequence {} in reference", ctg)).at_line(line));
+                    }
+                    chash.insert(
+                        ctg.clone(),
+                        Contig {
+                            name: ctg,
+                            circular: None,
+                            cut_sites: sites,
+                        },
+                    );
+                }
+            }
+            seq.clear();
+            if l == 0 {
+                break;
+            }
+            name = buf[1..].split_whitespace().next().map(|s| s.to_owned());
+            if name.is_none() {
+                return Err(Error::parse("Missing sequence name in reference").at_line(line));
+            }
+        } else {
+            seq.extend(buf.trim_end().bytes().map(|c| c.to_ascii_uppercase()))
+        }
+    }
+    for (e, n) in enzymes.iter().zip(counts) {
+        info!("Found {} cut sites for {}", n, e.name);
+    }
+    Ok(CutSites { chash })
+}
+
+// Cut sites for all enzymes from a FASTA file (see digest_sequences())
+#[cfg(feature = "cli")]
+pub fn digest_fasta<S: AsRef<Path>>(name: S, enzymes: &[Enzyme], compressor: &Compressor) -> error::Result<CutSites> {
+    digest_sequences(compressor.reader(Some(&name))?, enzymes).map_err(|e| e.in_file(name))
+}
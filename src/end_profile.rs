@@ -0,0 +1,84 @@
+// Profile of end positions of matched reads
+//
+// For a matched read, the opposite cut site is the nearest site on the same contig in the
+// direction of the read with the same barcode (i.e., the other guide of a pair used to excise
+// a fragment).  The distance of the read end from the opposite site (negative if the read
+// ends before the site) is recorded in a histogram for each barcode, showing truncated
+// molecules (large negative distances) and incomplete digestion (large positive distances).
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::{self, Write},
+};
+
+use crate::cut_site::{CutSites, Site};
+use crate::paf::{Match, Strand};
+
+#[derive(Debug)]
+pub struct EndProfile<'a> {
+    opposite: HashMap<&'a str, [Option<&'a Site>; 2]>, // Opposite sites for plus and minus strand reads
+    bin_size: usize,
+    counts: BTreeMap<&'a str, BTreeMap<i64, u64>>, // Reads per distance bin for each barcode
+    no_site: u64,                                  // Matched reads with no opposite site
+}
+
+impl<'a> EndProfile<'a> {
+    pub fn new(cut_sites: &'a CutSites, bin_size: usize) -> Self {
+        let mut opposite = HashMap::new();
+        for ctg in cut_sites.chash.values() {
+            // Sites are sorted by position
+            let v = &ctg.cut_sites;
+            for (i, site) in v.iter().enumerate() {
+                let plus = v[i + 1..].iter().find(|s| s.barcode == site.barcode);
+                let minus = v[..i].iter().rev().find(|s| s.barcode == site.barcode);
+                opposite.insert(site.name.as_str(), [plus, minus]);
+            }
+        }
+        Self {
+            opposite,
+            bin_size: bin_size.max(1),
+            counts: BTreeMap::new(),
+            no_site: 0,
+        }
+    }
+
+    pub fn add(&mut self, m: &Match<'a>) {
+        let ix = if m.strand() == Strand::Plus { 0 } else { 1 };
+        let Some(opp) = self.opposite.get(m.site.name.as_str()).and_then(|x| x[ix]) else {
+            self.no_site += 1;
+            return;
+        };
+        let (end, pos) = (m.end() as i64, opp.pos as i64);
+        let d = if ix == 0 { end - pos } else { pos - end };
+        *self
+            .counts
+            .entry(m.site.barcode.as_str())
+            .or_default()
+            .entry(d.div_euclid(self.bin_size as i64))
+            .or_insert(0) += 1
+    }
+
+    // Write number of reads in each distance bin (with at least one read) for each barcode
+    pub fn write_report<W: Write>(&self, wrt: &mut W) -> io::Result<()> {
+        if self.no_site > 0 {
+            info!("{} matched reads had no opposite cut site for the end profile", self.no_site)
+        }
+        writeln!(wrt, "barcode\tfrom\tto\treads\tprop. reads")?;
+        let sz = self.bin_size as i64;
+        for (bc, h) in self.counts.iter() {
+            let total = h.values().sum::<u64>() as f64;
+            for (bin, n) in h.iter() {
+                writeln!(
+                    wrt,
+                    "{}\t{}\t{}\t{}\t{:.4}",
+                    bc,
+                    bin * sz,
+                    bin * sz + sz - 1,
+                    n,
+                    *n as f64 / total
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
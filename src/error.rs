@@ -0,0 +1,128 @@
+// Error type for the classification core
+//
+// Errors are divided into I/O errors, parse errors in input files (with the file name and
+// line number where known) and configuration errors (invalid or inconsistent options, cut
+// site definitions etc.), so that library users and pipeline wrappers can tell them apart
+// (see kind()).  The command line program uses a distinct exit status for each kind.
+
+use std::{fmt, io, path::Path};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    Io,
+    Parse,
+    Config,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("{msg}{}", Position(file.as_deref(), *line))]
+    Parse {
+        msg: String,
+        file: Option<String>, // Input file (if known)
+        line: Option<usize>,  // Line number in file (1 offset, if known)
+    },
+    #[error("{0}")]
+    Config(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+// Position of a parse error for display (i.e., " at line 3 of cut.txt")
+struct Position<'a>(Option<&'a str>, Option<usize>);
+
+impl fmt::Display for Position<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.0, self.1) {
+            (Some(file), Some(line)) => write!(f, " at line {} of {}", line, file),
+            (None, Some(line)) => write!(f, " at line {}", line),
+            (Some(file), None) => write!(f, " in {}", file),
+            (None, None) => Ok(()),
+        }
+    }
+}
+
+impl Error {
+    pub fn parse<S: Into<String>>(msg: S) -> Self {
+        Self::Parse {
+            msg: msg.into(),
+            file: None,
+            line: None,
+        }
+    }
+
+    pub fn config<S: Into<String>>(msg: S) -> Self {
+        Self::Config(msg.into())
+    }
+
+    // Set line number of a parse error (if not already set)
+    pub fn at_line(mut self, n: usize) -> Self {
+        if let Self::Parse { line, .. } = &mut self {
+            line.get_or_insert(n);
+        }
+        self
+    }
+
+    // Set file name of a parse error (if not already set)
+    pub fn in_file<P: AsRef<Path>>(mut self, name: P) -> Self {
+        if let Self::Parse { file, .. } = &mut self {
+            if file.is_none() {
+                *file = Some(name.as_ref().display().to_string())
+            }
+        }
+        self
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Self::Io(_) => ErrorKind::Io,
+            Self::Parse { .. } => ErrorKind::Parse,
+            Self::Config(_) => ErrorKind::Config,
+        }
+    }
+
+    pub fn file(&self) -> Option<&str> {
+        match self {
+            Self::Parse { file, .. } => file.as_deref(),
+            _ => None,
+        }
+    }
+
+    pub fn line(&self) -> Option<usize> {
+        match self {
+            Self::Parse { line, .. } => *line,
+            _ => None,
+        }
+    }
+
+    // Kind of the first error from this crate in the chain of errors starting from e,
+    // including errors wrapped in io::Error (i.e., from a reader)
+    pub fn kind_of(e: &(dyn std::error::Error + 'static)) -> Option<ErrorKind> {
+        let mut e = Some(e);
+        while let Some(x) = e {
+            if let Some(err) = x.downcast_ref::<Error>() {
+                return Some(err.kind());
+            }
+            if let Some(err) = x.downcast_ref::<io::Error>().and_then(|z| z.get_ref()) {
+                if let Some(err) = err.downcast_ref::<Error>() {
+                    return Some(err.kind());
+                }
+            }
+            e = x.source()
+        }
+        None
+    }
+}
+
+// For use from io::Read implementations (and other code returning io::Result).  The error is
+// kept so that it can be recovered with kind_of()
+impl From<Error> for io::Error {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::Io(e) => e,
+            e => io::Error::other(e),
+        }
+    }
+}
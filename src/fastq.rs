@@ -1,85 +1,314 @@
 // Read and parse FASTQ file
+//
+// The input is read in large blocks, and record boundaries are found by scanning for
+// newlines with memchr (which uses SIMD instructions where available).  Records are
+// parsed in place in the block buffer, avoiding per line reads and copies.  Records can be
+// copied into a FastqBatch to be passed to other threads (see the FASTQ pipeline in
+// pipeline.rs), and are accessed through FastqRec in both cases.
 
 use std::{
-    io::{self, BufRead, BufWriter, Error, ErrorKind, Write},
+    io::{self, BufRead, Write},
+    ops::Range,
     path::Path,
+    str,
 };
 
-use compress_io::compress::{CompressIo, Writer};
+use crate::compressor::Compressor;
+use crate::error::Error;
+use crate::sam::{BamToFastq, BAM_MAGIC};
+
+// Initial size of block buffer.  The buffer will grow if a record is larger than this
+const BLOCK_SIZE: usize = 1 << 20;
+
+// Complement of IUPAC base code (preserving case)
+fn complement(c: u8) -> u8 {
+    match c {
+        b'A' => b'T',
+        b'C' => b'G',
+        b'G' => b'C',
+        b'T' | b'U' => b'A',
+        b'a' => b't',
+        b'c' => b'g',
+        b'g' => b'c',
+        b't' | b'u' => b'a',
+        b'R' => b'Y',
+        b'Y' => b'R',
+        b'K' => b'M',
+        b'M' => b'K',
+        b'B' => b'V',
+        b'V' => b'B',
+        b'D' => b'H',
+        b'H' => b'D',
+        b'r' => b'y',
+        b'y' => b'r',
+        b'k' => b'm',
+        b'm' => b'k',
+        b'b' => b'v',
+        b'v' => b'b',
+        b'd' => b'h',
+        b'h' => b'd',
+        _ => c,
+    }
+}
 
 fn gen_err(s: &str, line: usize) -> io::Error {
-    Error::new(ErrorKind::Other, format!("{} at line {}", s, line))
+    Error::parse(s).at_line(line).into()
 }
 
 pub struct FastqFile {
-    rdr: Box<dyn BufRead>,
-    buf: [String; 3],
+    rdr: Box<dyn BufRead + Send>,
+    buf: Vec<u8>,          // Block buffer
+    pos: usize,            // Start of unprocessed data in buf
+    end: usize,            // End of valid data in buf
+    eof: bool,             // Set when the reader is exhausted
+    lines: [Range<usize>; 4], // Ranges in buf of the lines of the current record (without line endings)
     line: usize,
+    lossy_header: Option<String>, // Header of current record with invalid UTF-8 replaced (if required)
+    invalid_utf8: usize,          // Number of records with invalid UTF-8 in the header
+    max_record: usize,            // Maximum record size (the buffer will not grow beyond this)
 }
 
 impl FastqFile {
-    pub fn open<P: AsRef<Path>>(name: P) -> io::Result<Self> {
+    // Open FASTQ file, using up to threads threads for decompression (see
+    // Compressor::threaded_reader()).  Unaligned BAM input is detected and converted to FASTQ
+    // records (see BamToFastq)
+    pub fn open<P: AsRef<Path>>(name: P, compressor: &Compressor, threads: usize) -> io::Result<Self> {
+        let mut rdr = compressor.threaded_reader(name, threads)?;
+        if rdr.fill_buf()?.starts_with(BAM_MAGIC) {
+            debug!("FASTQ input is in BAM format");
+            rdr = Box::new(BamToFastq::new(rdr)?)
+        }
         Ok(Self {
-            rdr: CompressIo::new().path(name).bufreader().map(Box::new)?,
-            buf: [String::new(), String::new(), String::new()],
+            rdr,
+            buf: vec![0; BLOCK_SIZE],
+            pos: 0,
+            end: 0,
+            eof: false,
+            lines: [0..0, 0..0, 0..0, 0..0],
             line: 0,
+            lossy_header: None,
+            invalid_utf8: 0,
+            max_record: usize::MAX,
         })
     }
 
-    // Get next line from fastq file
-    fn next_line(&mut self, ix: usize) -> io::Result<usize> {
-        self.buf[ix].clear();
-        self.line += 1;
-        self.rdr.read_line(&mut self.buf[ix])
+    // Set maximum size of a record in bytes.  A larger record (i.e., from a corrupt file
+    // with missing newlines) will give an error rather than growing the buffer indefinitely
+    pub fn set_max_record(&mut self, max_record: usize) {
+        self.max_record = max_record.max(1)
+    }
+
+    // Move unprocessed data to the start of the buffer (growing the buffer if it is full)
+    // and fill the rest of the buffer from the input
+    fn refill(&mut self) -> io::Result<()> {
+        if self.pos > 0 {
+            self.buf.copy_within(self.pos..self.end, 0);
+            self.end -= self.pos;
+            self.pos = 0;
+        } else if self.end == self.buf.len() {
+            let l = self.buf.len();
+            if l >= self.max_record {
+                return Err(gen_err(
+                    &format!("Record larger than maximum size ({} bytes)", self.max_record),
+                    self.line + 1,
+                ));
+            }
+            self.buf.resize((l * 2).min(self.max_record.max(BLOCK_SIZE)), 0);
+        }
+        while self.end < self.buf.len() {
+            let n = self.rdr.read(&mut self.buf[self.end..])?;
+            if n == 0 {
+                self.eof = true;
+                break;
+            }
+            self.end += n;
+        }
+        Ok(())
+    }
+
+    // Find the next 4 lines starting from self.pos, returning the line ranges and the
+    // position after the last line, or None if the buffer does not contain 4 complete lines
+    fn scan_record(&self) -> Option<([Range<usize>; 4], usize)> {
+        let mut lines = [0..0, 0..0, 0..0, 0..0];
+        let mut p = self.pos;
+        for (i, ln) in lines.iter_mut().enumerate() {
+            let (e, next) = match memchr::memchr(b'\n', &self.buf[p..self.end]) {
+                Some(j) => (p + j, p + j + 1),
+                // Allow for missing newline at the end of the last record
+                None if self.eof && i == 3 && p < self.end => (self.end, self.end),
+                None => return None,
+            };
+            let e = if e > p && self.buf[e - 1] == b'\r' { e - 1 } else { e };
+            *ln = p..e;
+            p = next;
+        }
+        Some((lines, p))
     }
 
     // Get next read from fastq file (i.e., the id, seq and qual lines)
     // Returns Err on failure, Ok(false) on EOF and Ok(true) on success
     pub fn next_read(&mut self) -> io::Result<bool> {
-        // Get line with read tag
-        if self.next_line(0)? == 0 {
-            return Ok(false);
-        }
-        if !self.buf[0].starts_with('@') {
+        let (lines, next) = loop {
+            if let Some(x) = self.scan_record() {
+                break x;
+            }
+            if self.eof {
+                // Skip trailing empty lines
+                if self.buf[self.pos..self.end].iter().all(|c| c.is_ascii_whitespace()) {
+                    self.pos = self.end;
+                    return Ok(false);
+                }
+                let n = memchr::memchr_iter(b'\n', &self.buf[self.pos..self.end]).count();
+                return Err(gen_err("Incomplete record", self.line + n + 1));
+            }
+            self.refill()?;
+        };
+        let line = self.line;
+        self.line += 4;
+        if self.buf.get(lines[0].start) != Some(&b'@') {
             return Err(gen_err(
                 "Unexpected character (expected '@' at start of line)",
-                self.line,
+                line + 1,
             ));
         }
-        // Get sequence line
-        if self.next_line(1)? == 0 {
-            return Err(gen_err("Incomplete record", self.line));
-        }
-        // Get line 3 (just check for initial '+')
-        if self.next_line(2)? == 0 {
-            return Err(gen_err("Incomplete record", self.line));
-        }
-        if !self.buf[2].starts_with('+') {
+        // Invalid UTF-8 in the header (i.e., a corrupt byte) does not abort the run.  The
+        // invalid bytes are replaced when the header is used as text, but the record is
+        // written out unchanged
+        self.lossy_header = match str::from_utf8(&self.buf[lines[0].clone()]) {
+            Ok(_) => None,
+            Err(_) => {
+                debug!("Invalid UTF-8 in read header at line {}", line + 1);
+                self.invalid_utf8 += 1;
+                Some(String::from_utf8_lossy(&self.buf[lines[0].clone()]).into_owned())
+            }
+        };
+        if self.buf.get(lines[2].start) != Some(&b'+') || lines[2].is_empty() {
             return Err(gen_err(
                 "Unexpected character (expected '+' at start of line)",
-                self.line,
+                line + 3,
             ));
         }
-        // Get quality line
-        if self.next_line(2)? == 0 {
-            return Err(gen_err("Incomplete record", self.line));
-        }
-        if self.buf[1].len() != self.buf[2].len() {
+        if lines[1].len() != lines[3].len() {
             return Err(gen_err(
                 "Sequence and quality lines are different lengths",
-                self.line,
+                line + 4,
             ));
         }
+        self.lines = lines;
+        self.pos = next;
         Ok(true)
     }
 
-    // Returns read_id
+    // Current record
+    pub fn record(&self) -> FastqRec<'_> {
+        FastqRec {
+            buf: &self.buf,
+            lines: &self.lines,
+            lossy_header: self.lossy_header.as_deref(),
+        }
+    }
+
+    // Number of records with invalid UTF-8 in the header
+    pub fn invalid_utf8(&self) -> usize {
+        self.invalid_utf8
+    }
+
     pub fn read_id(&self) -> &str {
+        self.record().read_id()
+    }
+
+    pub fn read_len(&self) -> usize {
+        self.lines[1].len()
+    }
+
+    pub fn write_rec<W: Write>(&self, wrt: &mut W, rc: bool, flag: Option<&str>, tags: Option<&str>) -> io::Result<usize> {
+        self.record().write_rec(wrt, rc, flag, tags)
+    }
+}
+
+// Batch of records copied out of the block buffer of a FastqFile so that they can be passed
+// between threads.  The header, sequence and quality lines of each record are stored
+// contiguously in buf (the '+' line is not kept)
+#[derive(Default)]
+pub struct FastqBatch {
+    buf: Vec<u8>,
+    recs: Vec<BatchRec>,
+}
+
+struct BatchRec {
+    lines: [Range<usize>; 4],
+    lossy_header: Option<Box<str>>,
+}
+
+impl FastqBatch {
+    pub fn with_capacity(n: usize) -> Self {
+        Self {
+            buf: Vec::with_capacity(n * 256),
+            recs: Vec::with_capacity(n),
+        }
+    }
+
+    // Copy the current record from fq_file
+    pub fn push(&mut self, fq_file: &FastqFile) {
+        let mut lines = [0..0, 0..0, 0..0, 0..0];
+        for i in [0, 1, 3] {
+            let start = self.buf.len();
+            self.buf.extend_from_slice(&fq_file.buf[fq_file.lines[i].clone()]);
+            lines[i] = start..self.buf.len();
+        }
+        self.recs.push(BatchRec {
+            lines,
+            lossy_header: fq_file.lossy_header.as_deref().map(Box::from),
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.recs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.recs.is_empty()
+    }
+
+    pub fn get(&self, i: usize) -> FastqRec<'_> {
+        let r = &self.recs[i];
+        FastqRec {
+            buf: &self.buf,
+            lines: &r.lines,
+            lossy_header: r.lossy_header.as_deref(),
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = FastqRec<'_>> {
+        (0..self.recs.len()).map(|i| self.get(i))
+    }
+}
+
+// FASTQ record, either in the block buffer of a FastqFile or in a FastqBatch
+#[derive(Copy, Clone)]
+pub struct FastqRec<'a> {
+    buf: &'a [u8],
+    lines: &'a [Range<usize>; 4],
+    lossy_header: Option<&'a str>, // Header with invalid UTF-8 replaced (if required)
+}
+
+impl<'a> FastqRec<'a> {
+    fn header(&self) -> &'a str {
+        // Header has been checked for valid UTF-8 in next_read()
+        match self.lossy_header {
+            Some(s) => s,
+            None => str::from_utf8(&self.buf[self.lines[0].clone()]).unwrap_or(""),
+        }
+    }
+
+    // Returns read_id
+    pub fn read_id(&self) -> &'a str {
         // Removes initial '@' and splits on first white space character (or returns whole line if not present)
-        let tag = self.buf[0][1..]
+        let hdr = &self.header()[1..];
+        let tag = hdr
             .split_once(char::is_whitespace)
             .map(|(a, _)| a)
-            .unwrap_or(&self.buf[0]);
+            .unwrap_or(hdr);
         // Remove end tag if present
         match tag.rsplit_once('/') {
             Some((a, "1" | "2")) => a,
@@ -87,11 +316,75 @@ impl FastqFile {
         }
     }
 
+    // Returns value of tag=value field in the header comment (i.e., ch=123) if present.  SAM
+    // style tags (i.e., ch:i:123 from samtools fastq -T or BAM input) are also recognized
+    pub fn header_tag(&self, tag: &str) -> Option<&'a str> {
+        self.header().split_whitespace().skip(1).find_map(|s| {
+            s.split_once('=')
+                .filter(|(k, _)| *k == tag)
+                .map(|(_, v)| v)
+                .or_else(|| {
+                    s.strip_prefix(tag)
+                        .and_then(|x| x.strip_prefix(':'))
+                        .and_then(|x| x.split_once(':'))
+                        .filter(|(t, _)| t.len() == 1)
+                        .map(|(_, v)| v)
+                })
+        })
+    }
+
+    // Check for base modification tags (MM:Z: or Mm:Z:) in the header comment, i.e., from
+    // samtools fastq -T MM,ML
+    pub fn has_mod_tags(&self) -> bool {
+        self.header()
+            .split_whitespace()
+            .skip(1)
+            .any(|s| s.starts_with("MM:Z:") || s.starts_with("Mm:Z:"))
+    }
+
+    pub fn seq(&self) -> &'a [u8] {
+        &self.buf[self.lines[1].clone()]
+    }
+
+    pub fn qual(&self) -> &'a [u8] {
+        &self.buf[self.lines[3].clone()]
+    }
+
     pub fn read_len(&self) -> usize {
-        self.buf[1].trim().len()
+        self.lines[1].len()
     }
 
-    pub fn write_rec(&self, wrt: &mut BufWriter<Writer>) -> io::Result<()> {
-        write!(wrt, "{}{}+\n{}", self.buf[0], self.buf[1], self.buf[2])
+    // Write FASTQ record, returning the number of bytes written.  If rc is set then the
+    // sequence is reverse complemented and the quality reversed.  If flag is set then
+    // ont_demult_flag=<flag> is added to the header line, and if tags is set it is appended
+    // to the header line as is
+    pub fn write_rec<W: Write>(&self, wrt: &mut W, rc: bool, flag: Option<&str>, tags: Option<&str>) -> io::Result<usize> {
+        let hdr = &self.buf[self.lines[0].clone()];
+        let seq = &self.buf[self.lines[1].clone()];
+        let qual = &self.buf[self.lines[3].clone()];
+        wrt.write_all(hdr)?;
+        let mut n = hdr.len() + seq.len() + qual.len() + 5;
+        if let Some(f) = flag {
+            write!(wrt, " ont_demult_flag={}", f)?;
+            n += f.len() + 17;
+        }
+        if let Some(t) = tags {
+            wrt.write_all(t.as_bytes())?;
+            n += t.len();
+        }
+        wrt.write_all(b"\n")?;
+        if rc {
+            let seq: Vec<u8> = seq.iter().rev().map(|c| complement(*c)).collect();
+            wrt.write_all(&seq)?;
+            wrt.write_all(b"\n+\n")?;
+            let qual: Vec<u8> = qual.iter().rev().copied().collect();
+            wrt.write_all(&qual)?;
+        } else {
+            wrt.write_all(seq)?;
+            wrt.write_all(b"\n+\n")?;
+            wrt.write_all(qual)?;
+        }
+        wrt.write_all(b"\n")?;
+        Ok(n)
     }
 }
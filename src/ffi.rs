@@ -0,0 +1,343 @@
+// C API for the read classifier
+//
+// Built with the capi feature, this allows reads to be classified from C or C++ using exactly
+// the same logic as the command line program.  The C header (include/ont_demult.h) is generated
+// from this file using cbindgen (see cbindgen.toml), so the doc comments here end up in the header.
+//
+// Functions that fail return NULL or a negative value, and the error message and kind can be
+// retrieved with ont_demult_last_error() and ont_demult_last_error_kind().  Panics are caught so that they do not unwind into the caller.
+
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    ffi::{c_char, c_int, CStr, CString},
+    fmt,
+    io::Cursor,
+    panic::{self, AssertUnwindSafe},
+    ptr,
+    sync::OnceLock,
+};
+
+use crate::classify::{Category, Classifier, ClassifyParam, MapResult, Select};
+use crate::cut_site::read_cut_sites;
+use crate::error::{self, Error, ErrorKind};
+use crate::paf::Strand;
+
+// Category names as C strings (indexed by code)
+static STATUS_NAMES: OnceLock<Vec<CString>> = OnceLock::new();
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+    static LAST_ERROR_KIND: Cell<OntDemultErrorKind> = const { Cell::new(OntDemultErrorKind::OntDemultErrorNone) };
+}
+
+fn set_error<E: fmt::Display>(e: E, kind: OntDemultErrorKind) {
+    let s = CString::new(e.to_string().replace('\0', " ")).ok();
+    LAST_ERROR.with(|x| *x.borrow_mut() = s);
+    LAST_ERROR_KIND.with(|x| x.set(kind))
+}
+
+// Run f, catching panics and storing any error message
+fn guard<T, F: FnOnce() -> error::Result<T>>(f: F) -> Option<T> {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(x)) => Some(x),
+        Ok(Err(e)) => {
+            let kind = match e.kind() {
+                ErrorKind::Io => OntDemultErrorKind::OntDemultErrorIo,
+                ErrorKind::Parse => OntDemultErrorKind::OntDemultErrorParse,
+                ErrorKind::Config => OntDemultErrorKind::OntDemultErrorConfig,
+            };
+            set_error(e, kind);
+            None
+        }
+        Err(p) => {
+            let msg = p
+                .downcast_ref::<&str>()
+                .map(|s| s.to_string())
+                .or_else(|| p.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| "Unknown error".to_string());
+            set_error(format!("Internal error: {}", msg), OntDemultErrorKind::OntDemultErrorInternal);
+            None
+        }
+    }
+}
+
+/// Kind of the last error (see ont_demult_last_error_kind())
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OntDemultErrorKind {
+    OntDemultErrorNone = 0,
+    OntDemultErrorIo = 1,
+    OntDemultErrorParse = 2,
+    OntDemultErrorConfig = 3,
+    OntDemultErrorInternal = 4,
+}
+
+/// Read selection strategy (see the --select option)
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OntDemultSelect {
+    OntDemultSelectStart = 0,
+    OntDemultSelectBoth = 1,
+    OntDemultSelectEither = 2,
+    OntDemultSelectXor = 3,
+    OntDemultSelectEnd = 4,
+    OntDemultSelectPair = 5,
+}
+
+/// Match status of a classified read.  The values are the stable category codes used in all
+/// ont_demult outputs
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OntDemultStatus {
+    OntDemultUnmapped = 0,
+    OntDemultLowMapQ = 1,
+    OntDemultNoCutSites = 2,
+    OntDemultUnmatched = 3,
+    OntDemultMatched = 4,
+    OntDemultExcessUnmatched = 5,
+    OntDemultMatchBoth = 6,
+    OntDemultMatchStart = 7,
+    OntDemultMatchEnd = 8,
+    OntDemultMisMatch = 9,
+}
+
+impl From<Category> for OntDemultStatus {
+    fn from(c: Category) -> Self {
+        use OntDemultStatus::*;
+        match c {
+            Category::Unmapped => OntDemultUnmapped,
+            Category::LowMapQ => OntDemultLowMapQ,
+            Category::NoCutSites => OntDemultNoCutSites,
+            Category::Unmatched => OntDemultUnmatched,
+            Category::Matched => OntDemultMatched,
+            Category::ExcessUnmatched => OntDemultExcessUnmatched,
+            Category::MatchBoth => OntDemultMatchBoth,
+            Category::MatchStart => OntDemultMatchStart,
+            Category::MatchEnd => OntDemultMatchEnd,
+            Category::MisMatch => OntDemultMisMatch,
+        }
+    }
+}
+
+/// Classification of a read
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct OntDemultResult {
+    /// Match status
+    pub status: OntDemultStatus,
+    /// Index of the matched cut site (see ont_demult_site_name()) for Matched and
+    /// ExcessUnmatched reads, otherwise -1
+    pub site: i64,
+    /// Strand of the match (1 = plus, -1 = minus) for Matched and ExcessUnmatched reads,
+    /// otherwise 0
+    pub strand: c_int,
+    /// Read relative offset of the matched cut site for Matched and ExcessUnmatched reads,
+    /// otherwise 0
+    pub anchor: i64,
+}
+
+/// Read classifier holding a cut site table and classification parameters
+pub struct OntDemultClassifier {
+    classifier: Classifier,
+    site_names: Vec<CString>,           // Cut site names in sorted order
+    site_index: HashMap<String, usize>, // Index of each cut site in site_names
+}
+
+impl OntDemultClassifier {
+    fn result(&self, mr: &MapResult) -> OntDemultResult {
+        let status = OntDemultStatus::from(mr.category());
+        let m = match mr {
+            MapResult::Matched(m) | MapResult::ExcessUnmatched(m) => Some(m),
+            _ => None,
+        };
+        match m {
+            Some(m) => OntDemultResult {
+                status,
+                site: self.site_index[&m.site.name] as i64,
+                strand: if m.strand() == Strand::Plus { 1 } else { -1 },
+                anchor: m.anchor(),
+            },
+            None => OntDemultResult {
+                status,
+                site: -1,
+                strand: 0,
+                anchor: 0,
+            },
+        }
+    }
+}
+
+unsafe fn to_str<'a>(s: *const c_char, what: &str) -> error::Result<&'a str> {
+    if s.is_null() {
+        Err(Error::config(format!("{} is NULL", what)))
+    } else {
+        CStr::from_ptr(s)
+            .to_str()
+            .map_err(|e| Error::parse(format!("{} is not valid UTF-8: {}", what, e)))
+    }
+}
+
+/// Create a new classifier.  cut_table has the contents of a cut file (see the README), or can
+/// be NULL if there are no cut sites.  The remaining arguments correspond to the command line
+/// options of the same names.  Returns NULL on error.
+///
+/// # Safety
+///
+/// cut_table must be NULL or point to a NUL terminated string.  The returned classifier must
+/// be freed with ont_demult_classifier_free().
+#[no_mangle]
+pub unsafe extern "C" fn ont_demult_classifier_new(
+    cut_table: *const c_char,
+    select: OntDemultSelect,
+    mapq_thresh: usize,
+    max_distance: usize,
+    max_unmatched: usize,
+    margin: usize,
+) -> *mut OntDemultClassifier {
+    guard(|| {
+        let cut_sites = if cut_table.is_null() {
+            None
+        } else {
+            let s = to_str(cut_table, "cut_table")?;
+            Some(read_cut_sites(Cursor::new(s.as_bytes()))?)
+        };
+        let mut names: Vec<_> = cut_sites
+            .iter()
+            .flat_map(|cs| cs.site_index().into_keys())
+            .map(|s| s.to_owned())
+            .collect();
+        names.sort_unstable();
+        let site_index = names.iter().enumerate().map(|(i, s)| (s.clone(), i)).collect();
+        let site_names = names
+            .into_iter()
+            .map(|s| CString::new(s).map_err(|e| Error::parse(format!("Invalid cut site name: {}", e))))
+            .collect::<Result<_, _>>()?;
+        let select = match select {
+            OntDemultSelect::OntDemultSelectStart => Select::Start,
+            OntDemultSelect::OntDemultSelectBoth => Select::Both,
+            OntDemultSelect::OntDemultSelectEither => Select::Either,
+            OntDemultSelect::OntDemultSelectXor => Select::Xor,
+            OntDemultSelect::OntDemultSelectEnd => Select::End,
+            OntDemultSelect::OntDemultSelectPair => Select::Pair,
+        };
+        Ok(Box::into_raw(Box::new(OntDemultClassifier {
+            classifier: Classifier::new(ClassifyParam::new(
+                cut_sites,
+                select,
+                mapq_thresh,
+                max_distance,
+                max_unmatched,
+                margin,
+            )),
+            site_names,
+            site_index,
+        })))
+    })
+    .unwrap_or(ptr::null_mut())
+}
+
+/// Free a classifier created with ont_demult_classifier_new()
+///
+/// # Safety
+///
+/// cls must be NULL or a classifier returned by ont_demult_classifier_new() that has not
+/// already been freed.
+#[no_mangle]
+pub unsafe extern "C" fn ont_demult_classifier_free(cls: *mut OntDemultClassifier) {
+    if !cls.is_null() {
+        drop(Box::from_raw(cls))
+    }
+}
+
+/// Number of cut sites in the classifier
+///
+/// # Safety
+///
+/// cls must be a valid classifier.
+#[no_mangle]
+pub unsafe extern "C" fn ont_demult_n_sites(cls: *const OntDemultClassifier) -> usize {
+    cls.as_ref().map(|c| c.site_names.len()).unwrap_or(0)
+}
+
+/// Name of the cut site with index ix (cut sites are indexed in order of name).  Returns NULL if
+/// ix is out of range.  The string is owned by the classifier.
+///
+/// # Safety
+///
+/// cls must be a valid classifier.
+#[no_mangle]
+pub unsafe extern "C" fn ont_demult_site_name(cls: *const OntDemultClassifier, ix: usize) -> *const c_char {
+    cls.as_ref()
+        .and_then(|c| c.site_names.get(ix))
+        .map(|s| s.as_ptr())
+        .unwrap_or(ptr::null())
+}
+
+/// Classify a read.  paf has all of the PAF records for a single read (one per line).  If
+/// result is not NULL the classification is stored in result.  If buf is not NULL, the line of
+/// the results file for the read (without the trailing newline) is written to buf, truncated
+/// if necessary to fit in buf_len bytes (including the terminating NUL).  Returns the length
+/// of the full results line, or -1 on error.
+///
+/// # Safety
+///
+/// cls must be a valid classifier, paf must point to a NUL terminated string, result must be
+/// NULL or point to an OntDemultResult, and buf must be NULL or point to at least buf_len bytes.
+#[no_mangle]
+pub unsafe extern "C" fn ont_demult_classify_record(
+    cls: *const OntDemultClassifier,
+    paf: *const c_char,
+    result: *mut OntDemultResult,
+    buf: *mut c_char,
+    buf_len: usize,
+) -> i64 {
+    guard(|| {
+        let cls = cls.as_ref().ok_or_else(|| Error::config("Classifier is NULL"))?;
+        let paf = to_str(paf, "paf")?;
+        let (qname, mr) = cls.classifier.classify_records(paf)?;
+        if let Some(r) = result.as_mut() {
+            *r = cls.result(&mr)
+        }
+        let line = format!("{}\t{}", qname, mr);
+        if !buf.is_null() && buf_len > 0 {
+            let n = line.len().min(buf_len - 1);
+            ptr::copy_nonoverlapping(line.as_ptr() as *const c_char, buf, n);
+            *buf.add(n) = 0;
+        }
+        Ok(line.len() as i64)
+    })
+    .unwrap_or(-1)
+}
+
+/// Canonical name of a match status (as used in the results file).  Returns NULL for an
+/// unknown status.  The string is static.
+#[no_mangle]
+pub extern "C" fn ont_demult_status_name(status: c_int) -> *const c_char {
+    let names = STATUS_NAMES.get_or_init(|| {
+        Category::ALL
+            .iter()
+            .map(|c| CString::new(c.name()).unwrap())
+            .collect()
+    });
+    usize::try_from(status)
+        .ok()
+        .and_then(|i| names.get(i))
+        .map(|s| s.as_ptr())
+        .unwrap_or(ptr::null())
+}
+
+/// Message for the last error in the current thread (or NULL if there has been no error).  The
+/// string is valid until the next error in the same thread.
+#[no_mangle]
+pub extern "C" fn ont_demult_last_error() -> *const c_char {
+    LAST_ERROR.with(|x| x.borrow().as_ref().map(|s| s.as_ptr()).unwrap_or(ptr::null()))
+}
+
+/// Kind of the last error in the current thread: an I/O error, a parse error in the input, a
+/// configuration error (invalid arguments or cut site definitions) or an internal error (a
+/// caught panic).  Returns OntDemultErrorNone if there has been no error.
+#[no_mangle]
+pub extern "C" fn ont_demult_last_error_kind() -> OntDemultErrorKind {
+    LAST_ERROR_KIND.with(|x| x.get())
+}
@@ -0,0 +1,187 @@
+// Post classification hook
+//
+// With --post-classify-cmd, the classification of each read is sent as a line of JSON to the
+// standard input of a user command, which must write one line to its standard output for
+// each input line (in the same order).  An empty line or an empty object leaves the
+// classification unchanged, otherwise the object can give a new match status and/or cut site
+// for the read.  Classifications are sent in chunks and the responses for a chunk are read
+// before the next chunk is sent, so the command must flush its output after each line.
+
+use std::{
+    io::{self, BufRead, BufReader, Error, Write},
+    process::{Child, ChildStdout, Command, Stdio},
+    sync::mpsc::{self, Sender},
+    thread::{self, JoinHandle},
+};
+
+use serde_json::{json, Map, Value};
+
+use crate::classify::Override;
+use crate::error;
+
+// Parse override returned by the hook command for a read
+fn parse_override(s: &str) -> error::Result<Option<Override>> {
+    let s = s.trim();
+    if s.is_empty() {
+        return Ok(None);
+    }
+    let obj = match serde_json::from_str(s) {
+        Ok(Value::Object(o)) => o,
+        Ok(_) => return Err(error::Error::parse(format!("Expected JSON object, got {}", s))),
+        Err(e) => return Err(error::Error::parse(format!("Invalid JSON ({}): {}", e, s))),
+    };
+    let get = |k: &str| -> error::Result<Option<String>> {
+        match obj.get(k) {
+            None | Some(Value::Null) => Ok(None),
+            Some(Value::String(x)) => Ok(Some(x.clone())),
+            Some(x) => Err(error::Error::parse(format!("Invalid value for {}: {}", k, x))),
+        }
+    };
+    let ov = Override {
+        status: get("status")?,
+        site: get("site")?,
+    };
+    Ok(if ov.status.is_none() && ov.site.is_none() {
+        None
+    } else {
+        Some(ov)
+    })
+}
+
+// Convert line from results file to a JSON object
+fn line_to_json(line: &str) -> Value {
+    let fd: Vec<_> = line.trim_end_matches(['\r', '\n']).split('\t').collect();
+    let get = |i: usize| fd.get(i).copied().filter(|s| *s != "*");
+    let num = |i: usize| get(i).and_then(|s| s.parse::<i64>().ok());
+    let status = get(1).unwrap_or("");
+    let target = if matches!(status, "Matched" | "ExcessUnmatched") {
+        "cut_site"
+    } else {
+        "contig"
+    };
+    let splits: Vec<_> = fd
+        .get(11..)
+        .unwrap_or_default()
+        .chunks(2)
+        .filter(|x| x.len() == 2)
+        .map(|x| json!([x[0].parse::<usize>().ok(), x[1].parse::<usize>().ok()]))
+        .collect();
+    let mut m = Map::new();
+    m.insert("read_name".to_string(), json!(get(0)));
+    m.insert("match_status".to_string(), json!(status));
+    m.insert(target.to_string(), json!(get(2)));
+    m.insert("barcode".to_string(), json!(get(3)));
+    m.insert("strand".to_string(), json!(get(4)));
+    m.insert("start".to_string(), json!(num(5)));
+    m.insert("end".to_string(), json!(num(6)));
+    m.insert("length".to_string(), json!(num(7)));
+    m.insert("unused".to_string(), json!(num(8)));
+    m.insert("anchor".to_string(), json!(num(10)));
+    m.insert("splits".to_string(), json!(splits));
+    Value::Object(m)
+}
+
+pub struct ClassifyHook {
+    child: Child,
+    tx: Option<Sender<String>>,
+    writer: Option<JoinHandle<io::Result<()>>>,
+    rdr: BufReader<ChildStdout>,
+    buf: String,
+    cmd: String,  // Command (for error messages)
+    line: usize,  // Number of lines read from the command
+}
+
+impl ClassifyHook {
+    // Start command with the shell.  Input to the command is written from a separate thread
+    // so that a full pipe can not block reading the responses
+    pub fn spawn(cmd: &str) -> io::Result<Self> {
+        debug!("Starting post classification command {}", cmd);
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(cmd)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let mut stdin = child.stdin.take().unwrap();
+        let rdr = BufReader::new(child.stdout.take().unwrap());
+        let (tx, rx) = mpsc::channel::<String>();
+        let writer = thread::spawn(move || {
+            for s in rx {
+                stdin.write_all(s.as_bytes())?;
+                stdin.flush()?
+            }
+            Ok(())
+        });
+        Ok(Self {
+            child,
+            tx: Some(tx),
+            writer: Some(writer),
+            rdr,
+            buf: String::new(),
+            cmd: cmd.to_owned(),
+            line: 0,
+        })
+    }
+
+    // Send lines from the results file to the command and return the overrides for each line
+    pub fn process<'a, I: IntoIterator<Item = &'a str>>(&mut self, lines: I) -> io::Result<Vec<Option<Override>>> {
+        let mut s = String::new();
+        let mut n = 0;
+        for line in lines {
+            s.push_str(&line_to_json(line).to_string());
+            s.push('\n');
+            n += 1;
+        }
+        if let Some(tx) = self.tx.as_ref() {
+            // If the send fails, the writer thread has exited and the error will be picked up
+            // when the thread is joined
+            let _ = tx.send(s);
+        }
+        let mut v = Vec::with_capacity(n);
+        for _ in 0..n {
+            self.buf.clear();
+            if self.rdr.read_line(&mut self.buf)? == 0 {
+                // Any error from the writer thread (i.e., a broken pipe) is a consequence of
+                // the command exiting, so is not reported
+                let _ = self.check_writer();
+                return Err(Error::other("Post classification command ended before all reads were processed"));
+            }
+            self.line += 1;
+            let ov = parse_override(&self.buf)
+                .map_err(|e| e.in_file(format!("output of {}", self.cmd)).at_line(self.line))?;
+            v.push(ov);
+        }
+        Ok(v)
+    }
+
+    fn check_writer(&mut self) -> io::Result<()> {
+        self.tx.take();
+        match self.writer.take().map(|h| h.join()) {
+            Some(Ok(r)) => r,
+            Some(Err(_)) => Err(Error::other("Post classification writer thread panicked")),
+            None => Ok(()),
+        }
+    }
+
+    // Close input to the command and wait for it to finish
+    pub fn finish(mut self) -> io::Result<()> {
+        self.check_writer()?;
+        let mut extra = 0;
+        self.buf.clear();
+        while self.rdr.read_line(&mut self.buf)? > 0 {
+            if !self.buf.trim().is_empty() {
+                extra += 1
+            }
+            self.buf.clear();
+        }
+        if extra > 0 {
+            warn!("Ignored {} extra lines of output from post classification command", extra)
+        }
+        let status = self.child.wait()?;
+        if status.success() {
+            Ok(())
+        } else {
+            Err(Error::other(format!("Post classification command exited with {}", status)))
+        }
+    }
+}
@@ -0,0 +1,117 @@
+// Hotspots of unmatched reads
+//
+// Reads that start at the same position but do not match a cut site often indicate a cut
+// site that is missing from the cut file.  The start positions of Unmatched reads are
+// collected for each contig and strand, and clustered so that positions within
+// HOTSPOT_WINDOW bases of the previous position in the cluster are merged.  Hotspots with
+// enough reads can be written out as candidate cut sites in the cut file format.
+
+use std::{
+    collections::HashMap,
+    io::{self, Write},
+    sync::Arc,
+};
+
+use crate::cut_site::CutSites;
+use crate::paf::{Location, Strand};
+
+// Maximum gap between start positions in the same hotspot
+pub const HOTSPOT_WINDOW: usize = 10;
+
+struct Cluster<'a> {
+    contig: &'a str,
+    strand: Strand,
+    pos: usize,   // Most frequent start position
+    start: usize, // Range of start positions
+    end: usize,
+    reads: usize,
+}
+
+#[derive(Debug, Default)]
+pub struct Hotspots {
+    starts: HashMap<(Arc<str>, Strand), Vec<usize>>, // Start positions of unmatched reads
+}
+
+impl Hotspots {
+    pub fn add(&mut self, loc: &Location) {
+        self.starts
+            .entry((loc.contig().clone(), loc.strand()))
+            .or_default()
+            .push(loc.start())
+    }
+
+    fn clusters(&self) -> Vec<Cluster<'_>> {
+        let mut v = Vec::new();
+        for ((ctg, strand), starts) in self.starts.iter() {
+            let mut starts = starts.clone();
+            starts.sort_unstable();
+            for cl in starts.chunk_by(|a, b| b - a <= HOTSPOT_WINDOW) {
+                // Find the most frequent position (the first if tied)
+                let pos = cl
+                    .chunk_by(|a, b| a == b)
+                    .fold((0, 0), |(p, n), x| if x.len() > n { (x[0], x.len()) } else { (p, n) })
+                    .0;
+                v.push(Cluster {
+                    contig: ctg,
+                    strand: *strand,
+                    pos,
+                    start: cl[0],
+                    end: cl[cl.len() - 1],
+                    reads: cl.len(),
+                })
+            }
+        }
+        v
+    }
+
+    // Write the top n hotspots ordered by the number of reads
+    pub fn write_report<W: Write>(&self, n: usize, wrt: &mut W) -> io::Result<()> {
+        writeln!(wrt, "contig\tstrand\tposition\tstart\tend\treads")?;
+        let mut v = self.clusters();
+        v.sort_unstable_by(|a, b| {
+            b.reads
+                .cmp(&a.reads)
+                .then_with(|| a.contig.cmp(b.contig))
+                .then_with(|| a.pos.cmp(&b.pos))
+                .then_with(|| (a.strand == Strand::Minus).cmp(&(b.strand == Strand::Minus)))
+        });
+        for c in v.iter().take(n) {
+            writeln!(
+                wrt,
+                "{}\t{}\t{}\t{}\t{}\t{}",
+                c.contig, c.strand, c.pos, c.start, c.end, c.reads
+            )?;
+        }
+        Ok(())
+    }
+
+    // Write hotspots with at least min_reads reads as candidate cut sites in the cut file
+    // format.  Hotspots on opposite strands within HOTSPOT_WINDOW bases (i.e., reads going in
+    // both directions from a cut) are merged.  The suggested name (also used as the barcode)
+    // is made from the contig and position.  The circular flag is taken from cut_sites if the
+    // contig is present, otherwise the contig is marked as linear
+    pub fn write_suggestions<W: Write>(
+        &self,
+        min_reads: usize,
+        cut_sites: Option<&CutSites>,
+        wrt: &mut W,
+    ) -> io::Result<usize> {
+        let mut v: Vec<_> = self.clusters().into_iter().filter(|c| c.reads >= min_reads).collect();
+        v.sort_unstable_by(|a, b| a.contig.cmp(b.contig).then_with(|| a.pos.cmp(&b.pos)));
+        let mut n = 0;
+        for cl in v.chunk_by(|a, b| a.contig == b.contig && b.pos - a.pos <= HOTSPOT_WINDOW) {
+            let c = cl.iter().max_by_key(|c| c.reads).unwrap();
+            // Start positions are 0 offset, whereas the cut file uses the 1 offset position
+            // after the cut
+            let pos = c.pos + 1;
+            let circular = cut_sites
+                .and_then(|cs| cs.chash.get(c.contig))
+                .and_then(|ctg| ctg.circular)
+                .unwrap_or(false);
+            let name = format!("{}_{}", c.contig, pos);
+            writeln!(wrt, "{}\t{}\t{}\t{}\t{}", c.contig, pos, name, name, circular)?;
+            n += 1;
+        }
+        Ok(n)
+    }
+}
@@ -0,0 +1,370 @@
+// Matching of FASTQ read IDs to classified (PAF) read IDs (--id-match)
+//
+// Some basecaller and aligner combinations change the read names (i.e., adding a suffix or
+// truncating the name), so that the FASTQ IDs are not found in the classifications.  With
+// prefix matching, a FASTQ ID not found exactly is matched to the longest classified ID that
+// is a prefix of it, or else to a unique classified ID that it is a prefix of (in both cases
+// the longer ID must continue with a character that is not alphanumeric, so read1 does not
+// match read10).  With uuid matching, IDs are matched by the first UUID found in them, and
+// with regex:PATTERN by the first match of PATTERN (or of its first capture group if it has
+// one).  Exact matches are always tried first.
+
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap, HashSet},
+    hash::{Hash, Hasher},
+    io::{self, Write},
+    ops::Index,
+};
+
+use regex::Regex;
+
+// Number of example IDs given in the report
+const N_EXAMPLES: usize = 10;
+
+#[derive(Debug, Default)]
+pub enum IdMatch {
+    #[default]
+    Exact,
+    Prefix,
+    Uuid,
+    Regex(Regex),
+}
+
+impl std::str::FromStr for IdMatch {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        if let Some(p) = s.strip_prefix("regex:") {
+            return Ok(Self::Regex(Regex::new(p)?));
+        }
+        match s.to_ascii_lowercase().as_str() {
+            "exact" => Ok(Self::Exact),
+            "prefix" => Ok(Self::Prefix),
+            "uuid" => Ok(Self::Uuid),
+            _ => Err(anyhow!("Invalid IdMatch option {}", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for IdMatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Exact => write!(f, "exact"),
+            Self::Prefix => write!(f, "prefix"),
+            Self::Uuid => write!(f, "uuid"),
+            Self::Regex(r) => write!(f, "regex:{}", r.as_str()),
+        }
+    }
+}
+
+impl IdMatch {
+    // Key used to match read ID for uuid and regex matching (None if not found)
+    fn key<'b>(&self, id: &'b str) -> Option<&'b str> {
+        match self {
+            Self::Uuid => find_uuid(id),
+            Self::Regex(r) => r.captures(id).and_then(|c| c.get(1).or_else(|| c.get(0))).map(|m| m.as_str()),
+            _ => None,
+        }
+    }
+}
+
+// Find first UUID (8-4-4-4-12 hex digits) in s
+fn find_uuid(s: &str) -> Option<&str> {
+    const GROUPS: [usize; 5] = [8, 4, 4, 4, 12];
+    let b = s.as_bytes();
+    (0..(b.len().saturating_sub(35))).map(|i| &b[i..i + 36]).position(|w| {
+        let mut j = 0;
+        GROUPS.iter().enumerate().all(|(k, n)| {
+            let ok = w[j..j + n].iter().all(|c| c.is_ascii_hexdigit()) && (k == 4 || w[j + n] == b'-');
+            j += n + 1;
+            ok
+        })
+    })
+    .map(|i| &s[i..i + 36])
+}
+
+// Marks the end of a hash chain in IdMap
+const NO_ENTRY: u32 = u32::MAX;
+
+// Entry of IdMap: start of the ID in the ID buffer (the ID ends at the start of the next
+// entry), next entry with the same ID hash and the value
+#[derive(Debug)]
+struct IdEntry<V> {
+    start: usize,
+    next: u32,
+    value: V,
+}
+
+// Map from read IDs to values.  The IDs are interned in a single buffer rather than allocated
+// separately, and are indexed by a 32 bit hash of the ID, with entries having the same hash
+// chained together.  This needs much less memory per read than a HashMap<Box<str>, V>
+#[derive(Debug)]
+pub struct IdMap<V> {
+    ids: String,
+    entries: Vec<IdEntry<V>>,
+    index: HashMap<u32, u32>, // First entry for each ID hash
+}
+
+impl<V> Default for IdMap<V> {
+    fn default() -> Self {
+        Self {
+            ids: String::new(),
+            entries: Vec::new(),
+            index: HashMap::new(),
+        }
+    }
+}
+
+fn id_hash(id: &str) -> u32 {
+    let mut h = DefaultHasher::new();
+    id.hash(&mut h);
+    h.finish() as u32
+}
+
+impl<V> IdMap<V> {
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn clear(&mut self) {
+        self.ids.clear();
+        self.entries.clear();
+        self.index.clear()
+    }
+
+    pub fn shrink_to_fit(&mut self) {
+        self.ids.shrink_to_fit();
+        self.entries.shrink_to_fit();
+        self.index.shrink_to_fit()
+    }
+
+    // ID of entry i
+    fn key(&self, i: usize) -> &str {
+        let end = self.entries.get(i + 1).map(|e| e.start).unwrap_or(self.ids.len());
+        &self.ids[self.entries[i].start..end]
+    }
+
+    // Index of the entry for id
+    fn find(&self, id: &str) -> Option<usize> {
+        let mut i = *self.index.get(&id_hash(id))?;
+        while i != NO_ENTRY {
+            if self.key(i as usize) == id {
+                return Some(i as usize);
+            }
+            i = self.entries[i as usize].next
+        }
+        None
+    }
+
+    // Insert value for id, returning true if id was not already present (otherwise the
+    // existing value is replaced)
+    pub fn insert(&mut self, id: &str, value: V) -> bool {
+        if let Some(i) = self.find(id) {
+            self.entries[i].value = value;
+            return false;
+        }
+        let ix = u32::try_from(self.entries.len()).ok().filter(|i| *i != NO_ENTRY).expect("Too many read IDs");
+        let next = self.index.insert(id_hash(id), ix).unwrap_or(NO_ENTRY);
+        self.entries.push(IdEntry {
+            start: self.ids.len(),
+            next,
+            value,
+        });
+        self.ids.push_str(id);
+        true
+    }
+
+    pub fn get(&self, id: &str) -> Option<&V> {
+        self.find(id).map(|i| &self.entries[i].value)
+    }
+
+    pub fn get_key_value(&self, id: &str) -> Option<(&str, &V)> {
+        self.find(id).map(|i| (self.key(i), &self.entries[i].value))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &V)> {
+        self.entries.iter().enumerate().map(|(i, e)| (self.key(i), &e.value))
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &str> {
+        self.iter().map(|(k, _)| k)
+    }
+}
+
+impl<V> Index<&str> for IdMap<V> {
+    type Output = V;
+    fn index(&self, id: &str) -> &V {
+        self.get(id).expect("Read ID not found")
+    }
+}
+
+// Result of looking up a FASTQ read ID
+pub enum Lookup<'a, V> {
+    Exact(&'a str, &'a V),
+    Matched(&'a str, &'a V), // Matched to a different classified ID
+    Ambiguous,               // Matches more than one classified ID
+    NotFound,
+}
+
+impl<'a, V> Lookup<'a, V> {
+    pub fn value(&self) -> Option<&'a V> {
+        match self {
+            Self::Exact(_, v) | Self::Matched(_, v) => Some(v),
+            _ => None,
+        }
+    }
+}
+
+pub struct IdLookup<'a, V> {
+    mode: &'a IdMatch,
+    hash: &'a IdMap<V>,
+    index: HashMap<&'a str, Option<&'a str>>, // Classified ID for each key (None if ambiguous) for uuid and regex matching
+    sorted: Vec<&'a str>,                     // Sorted classified IDs for prefix matching
+}
+
+impl<'a, V> IdLookup<'a, V> {
+    pub fn new(mode: &'a IdMatch, hash: &'a IdMap<V>) -> Self {
+        let mut index = HashMap::new();
+        let mut sorted = Vec::new();
+        match mode {
+            IdMatch::Exact => (),
+            IdMatch::Prefix => {
+                sorted = hash.keys().collect();
+                sorted.sort_unstable();
+            }
+            _ => {
+                for name in hash.keys() {
+                    if let Some(k) = mode.key(name) {
+                        index
+                            .entry(k)
+                            .and_modify(|e| *e = None)
+                            .or_insert(Some(name));
+                    }
+                }
+            }
+        }
+        Self {
+            mode,
+            hash,
+            index,
+            sorted,
+        }
+    }
+
+    pub fn get(&self, id: &str) -> Lookup<'a, V> {
+        if let Some((k, v)) = self.hash.get_key_value(id) {
+            return Lookup::Exact(k, v);
+        }
+        let found = |name: &'a str| Lookup::Matched(name, &self.hash[name]);
+        let boundary = |s: &str, n: usize| s[n..].chars().next().map(|c| !c.is_alphanumeric()).unwrap_or(false);
+        match self.mode {
+            IdMatch::Exact => Lookup::NotFound,
+            IdMatch::Prefix => {
+                // Longest classified ID that is a prefix of id
+                for (i, _) in id.char_indices().rev().filter(|(i, _)| *i > 0 && boundary(id, *i)) {
+                    if let Some((k, _)) = self.hash.get_key_value(&id[..i]) {
+                        return found(k);
+                    }
+                }
+                // Classified IDs starting with id
+                let ix = self.sorted.partition_point(|k| *k < id);
+                let mut it = self.sorted[ix..]
+                    .iter()
+                    .take_while(|k| k.starts_with(id))
+                    .filter(|k| boundary(k, id.len()));
+                match (it.next(), it.next()) {
+                    (Some(k), None) => found(k),
+                    (Some(_), Some(_)) => Lookup::Ambiguous,
+                    _ => Lookup::NotFound,
+                }
+            }
+            _ => match self.mode.key(id).and_then(|k| self.index.get(k)) {
+                Some(Some(k)) => found(k),
+                Some(None) => Lookup::Ambiguous,
+                None => Lookup::NotFound,
+            },
+        }
+    }
+}
+
+// Counts and examples of FASTQ reads matched and not matched to classifications.  The report
+// is accumulated over all the sets of classifications that the FASTQ input is routed with
+// (i.e., each chunk of the PAF input with --synced, or each partition with --memory-budget)
+#[derive(Debug, Default)]
+pub struct IdMatchReport {
+    exact: u64,
+    matched: u64,
+    ambiguous: u64,
+    not_found: u64,
+    unused: u64,                            // Classified reads not matched to a FASTQ read
+    matched_examples: Vec<(String, String)>, // FASTQ ID and matching classified ID
+    ambiguous_examples: Vec<String>,
+    not_found_examples: Vec<String>,
+    unused_examples: Vec<String>,
+}
+
+impl IdMatchReport {
+    // Add the lookup result for FASTQ read id, recording the classified ID matched in used
+    pub fn add<'a, V>(&mut self, id: &str, res: &Lookup<'a, V>, used: &mut HashSet<&'a str>) {
+        let example = |v: &mut Vec<String>| {
+            if v.len() < N_EXAMPLES {
+                v.push(id.to_owned())
+            }
+        };
+        match res {
+            Lookup::Exact(name, _) => {
+                self.exact += 1;
+                used.insert(name);
+            }
+            Lookup::Matched(name, _) => {
+                self.matched += 1;
+                if self.matched_examples.len() < N_EXAMPLES {
+                    self.matched_examples.push((id.to_owned(), name.to_string()))
+                }
+                used.insert(name);
+            }
+            Lookup::Ambiguous => {
+                self.ambiguous += 1;
+                example(&mut self.ambiguous_examples)
+            }
+            Lookup::NotFound => {
+                self.not_found += 1;
+                example(&mut self.not_found_examples)
+            }
+        }
+    }
+
+    // Count the classified IDs in hash not matched to a FASTQ read (those not in used) once
+    // the FASTQ reads have been routed with hash
+    pub fn add_unused<V>(&mut self, hash: &IdMap<V>, used: &HashSet<&str>) {
+        let mut unused: Vec<_> = hash.keys().filter(|k| !used.contains(k)).collect();
+        unused.sort_unstable();
+        self.unused += unused.len() as u64;
+        let n = N_EXAMPLES - self.unused_examples.len();
+        self.unused_examples.extend(unused.iter().take(n).map(|k| k.to_string()));
+    }
+
+    // Write counts of FASTQ reads by match type and of classified reads not matched to a FASTQ
+    // read, followed by examples of each
+    pub fn write_report<W: Write>(&self, wrt: &mut W) -> io::Result<()> {
+        writeln!(wrt, "match\treads")?;
+        writeln!(wrt, "exact\t{}", self.exact)?;
+        writeln!(wrt, "matched\t{}", self.matched)?;
+        writeln!(wrt, "ambiguous\t{}", self.ambiguous)?;
+        writeln!(wrt, "not_found\t{}", self.not_found)?;
+        writeln!(wrt, "classified_not_in_fastq\t{}", self.unused)?;
+        writeln!(wrt, "\nexample\tfastq_id\tclassified_id")?;
+        for (id, name) in self.matched_examples.iter() {
+            writeln!(wrt, "matched\t{}\t{}", id, name)?;
+        }
+        for id in self.ambiguous_examples.iter() {
+            writeln!(wrt, "ambiguous\t{}\t*", id)?;
+        }
+        for id in self.not_found_examples.iter() {
+            writeln!(wrt, "not_found\t{}\t*", id)?;
+        }
+        for name in self.unused_examples.iter() {
+            writeln!(wrt, "classified_not_in_fastq\t*\t{}", name)?;
+        }
+        Ok(())
+    }
+}
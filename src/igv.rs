@@ -0,0 +1,153 @@
+// Genome browser tracks of matched reads (--bed, --bedgraph)
+//
+// With --bed, the span on the contig of each matched read (from the read start to the read
+// end) is written as a BED record named by the read and coloured by barcode, and the cut sites
+// are written to a separate BED file, so that assignments can be checked against the reference
+// in IGV.  With --bedgraph, the number of matched reads starting at each position is written
+// as a bedgraph track.  BED and bedgraph coordinates are 0 offset and half open, so a cut
+// site at (1 offset) position p is written as p-1..p.
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    io::{self, Write},
+};
+
+use crate::cut_site::CutSites;
+use crate::output::{close_file, open_output_file, OutWriter};
+use crate::paf::{Match, Strand};
+use crate::params::Param;
+
+// Colours for barcodes (reused cyclically)
+const PALETTE: [&str; 8] = [
+    "31,119,180",
+    "255,127,14",
+    "44,160,44",
+    "214,39,40",
+    "148,103,189",
+    "140,86,75",
+    "227,119,194",
+    "23,190,207",
+];
+
+pub struct IgvTracks<'a> {
+    contigs: HashMap<&'a str, &'a str>,      // Contig for each site
+    colours: HashMap<&'a str, &'static str>, // Colour for each barcode
+    bed: Option<OutWriter>,
+    starts: Option<BTreeMap<(&'a str, usize), u64>>, // Matched reads starting at each position
+}
+
+impl<'a> IgvTracks<'a> {
+    // Set up tracks, writing the cut site BED file if the matched read BED file is requested
+    pub fn new(cut_sites: &'a CutSites, param: &Param) -> io::Result<Self> {
+        let mut contigs = HashMap::new();
+        let mut barcodes = BTreeSet::new();
+        for ctg in cut_sites.chash.values() {
+            for site in ctg.cut_sites.iter() {
+                contigs.insert(site.name.as_str(), &*ctg.name);
+                barcodes.insert(site.barcode.as_str());
+            }
+        }
+        let colours: HashMap<_, _> = barcodes
+            .into_iter()
+            .zip(PALETTE.iter().cycle())
+            .map(|(bc, c)| (bc, *c))
+            .collect();
+        let bed = if param.bed() {
+            Self::write_sites(cut_sites, &colours, param)?;
+            let mut wrt = open_output_file("matched.bed", param)?;
+            writeln!(
+                wrt,
+                "track name=\"{} matched reads\" description=\"Matched reads coloured by barcode\" itemRgb=\"On\"",
+                param.prefix()
+            )?;
+            Some(wrt)
+        } else {
+            None
+        };
+        Ok(Self {
+            contigs,
+            colours,
+            bed,
+            starts: param.bedgraph().then(BTreeMap::new),
+        })
+    }
+
+    fn write_sites(cut_sites: &CutSites, colours: &HashMap<&str, &str>, param: &Param) -> io::Result<()> {
+        let mut wrt = open_output_file("cut_sites.bed", param)?;
+        writeln!(
+            wrt,
+            "track name=\"{} cut sites\" description=\"Cut sites coloured by barcode\" itemRgb=\"On\"",
+            param.prefix()
+        )?;
+        let mut ctgs: Vec<_> = cut_sites.chash.values().collect();
+        ctgs.sort_unstable_by_key(|c| c.name.clone());
+        for ctg in ctgs {
+            for site in ctg.cut_sites.iter() {
+                let p = site.pos.saturating_sub(1);
+                writeln!(
+                    wrt,
+                    "{}\t{}\t{}\t{}:{}\t0\t.\t{}\t{}\t{}",
+                    ctg.name,
+                    p,
+                    p + 1,
+                    site.name,
+                    site.barcode,
+                    p,
+                    p + 1,
+                    colours[site.barcode.as_str()]
+                )?;
+            }
+        }
+        close_file(wrt)
+    }
+
+    pub fn add(&mut self, name: &str, m: &Match<'a>) -> io::Result<()> {
+        let Some(ctg) = self.contigs.get(m.site.name.as_str()).copied() else {
+            return Ok(());
+        };
+        // For minus strand reads the start is the end of the alignment on the contig, so the
+        // first base of the read is at start - 1
+        let (s, e, strand, first) = match m.strand() {
+            Strand::Plus => (m.start(), m.end(), '+', m.start()),
+            Strand::Minus => (m.end(), m.start(), '-', m.start().saturating_sub(1)),
+        };
+        if let Some(h) = self.starts.as_mut() {
+            *h.entry((ctg, first)).or_insert(0) += 1
+        }
+        if let Some(wrt) = self.bed.as_mut() {
+            writeln!(
+                wrt,
+                "{}\t{}\t{}\t{}\t0\t{}\t{}\t{}\t{}",
+                ctg,
+                s.min(e),
+                s.max(e),
+                name,
+                strand,
+                first,
+                first + 1,
+                self.colours[m.site.barcode.as_str()]
+            )?;
+        }
+        Ok(())
+    }
+
+    // Complete the matched read BED file and write the read start bedgraph
+    pub fn finish(mut self, param: &Param) -> io::Result<()> {
+        if let Some(wrt) = self.bed.take() {
+            close_file(wrt)?
+        }
+        if let Some(h) = self.starts.take() {
+            let mut wrt = open_output_file("starts.bedgraph", param)?;
+            writeln!(
+                wrt,
+                "track type=bedGraph name=\"{} read starts\" description=\"Start positions of matched reads\"",
+                param.prefix()
+            )?;
+            for ((ctg, pos), n) in h.iter() {
+                writeln!(wrt, "{}\t{}\t{}\t{}", ctg, pos, pos + 1, n)?;
+            }
+            close_file(wrt)?
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,23 @@
+// Classification core of ont_demult
+//
+// The classification of reads against the cut sites is available as a library.  The file
+// handling (including the external compression utilities) is only built with the cli
+// feature (enabled by default), so building with --no-default-features gives a core
+// without any process spawning that can be used for FFI or WASM builds.  The C API is
+// built with the capi feature (see ffi.rs).
+
+#[macro_use]
+extern crate log;
+
+pub mod classify;
+#[cfg(feature = "cli")]
+pub mod compressor;
+pub mod cut_site;
+pub mod digest;
+pub mod error;
+#[cfg(feature = "capi")]
+pub mod ffi;
+pub mod paf;
+pub mod sam;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+pub mod uring;
@@ -1,7 +1,12 @@
 use std::fmt;
+use std::io::{self, IsTerminal};
 use std::str::FromStr;
 
 use clap::ArgMatches;
+use log::LevelFilter;
+use stderrlog::ColorChoice;
+
+use crate::warnings;
 
 #[derive(Debug, Clone, Copy)]
 pub struct LogLevel {
@@ -52,8 +57,22 @@ pub fn init_log(m: &ArgMatches) {
     let verbose: LogLevel = m.value_of_t("loglevel")
         .unwrap_or_else(|_| LogLevel::from_str("info").expect("Could not set loglevel info"));
 
-    stderrlog::new()
-        .verbosity(verbose.get_level())
-        .init()
-        .unwrap();
+    let level = [
+        LevelFilter::Error,
+        LevelFilter::Warn,
+        LevelFilter::Info,
+        LevelFilter::Debug,
+        LevelFilter::Trace,
+    ][verbose.get_level()];
+    // Colours are only used if stderr is a terminal
+    let color = if io::stderr().is_terminal() {
+        ColorChoice::Auto
+    } else {
+        ColorChoice::Never
+    };
+    let mut log = stderrlog::new();
+    // The level is applied by the wrapping logger, as trace messages for sampled reads are
+    // output whatever the level (see trace_sample.rs)
+    log.verbosity(4).color(color);
+    warnings::init(log, level).unwrap();
 }
@@ -4,159 +4,1585 @@ extern crate log;
 extern crate anyhow;
 
 use std::{
-    collections::HashMap,
-    fmt,
-    io::Write,
+    collections::{HashMap, HashSet},
+    fs::{self, File},
+    io::{self, BufRead, BufWriter, Write},
+    mem,
+    path::Path,
+    sync::Arc,
+    time::{Duration, Instant},
 };
 
 use anyhow::Context;
+use rayon::prelude::*;
 
+mod aggregate;
+mod align;
+mod cache;
+mod channel;
+mod class_store;
 mod cli;
-pub mod cut_site;
+mod contam;
+mod end_profile;
 mod fastq;
+mod hook;
+mod hotspot;
+mod id_match;
+mod igv;
 pub mod log_level;
+mod metrics;
+mod mod_stats;
+mod no_sites;
+mod notify;
+mod offset;
 mod output;
-mod paf;
+mod pipeline;
+mod qlen;
+mod read_filter;
+mod read_group;
+mod read_stats;
+mod run_info;
+mod salvage;
+mod second_site;
+mod site_seq;
+mod snp;
 pub mod params;
+mod stamp;
+mod stats;
+mod summary;
+mod threads;
+mod tmp_dir;
+mod trace_sample;
+mod warnings;
 
+use ont_demult::{classify, compressor, cut_site, digest, error, paf, sam};
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+use ont_demult::uring;
+
+use cache::{CacheWriter, ClassCache};
+use channel::{ChannelCounts, SeqSummary};
+use class_store::{ClassStore, ReadClass, N_PARTITIONS};
+use read_group::ReadGroups;
+use read_stats::ReadStats;
+use run_info::RunInfo;
+use classify::*;
+use contam::ContamCounts;
+use end_profile::EndProfile;
 use fastq::*;
+use hook::ClassifyHook;
+use hotspot::Hotspots;
+use id_match::{IdLookup, IdMatch, IdMatchReport, Lookup};
+use igv::IgvTracks;
+use metrics::Metrics;
+use mod_stats::ModStats;
+use no_sites::NoSiteCounts;
+use offset::OffsetCounts;
 use output::*;
+use second_site::SecondSite;
+use stamp::Stamp;
+use snp::Allele;
 use paf::*;
+use qlen::{FastqLengths, QlenFrom};
 use params::*;
+use pipeline::FastqInput;
+use summary::RunSummary;
+use threads::FastqThreads;
 
 pub const DEFAULT_PREFIX: &str = "ont_demult";
 
-// Classification of reads from PAF file
-#[derive(Debug)]
-enum MapResult<'a> {
-    Unmapped(usize),     // Unmapped (normally these are not in the file)
-    LowMapq(usize),      // Low Mapq (no non-unique mapping records)
-    NoCutSites(usize),   // No cut sites
-    Unmatched(Location), // No match to a cut site
-    Matched(Match<'a>),  // Match on strand to a cut site
-    ExcessUnmatched(Match<'a>),
-    MatchBoth(Location),
-    MatchStart(Location),
-    MatchEnd(Location),
-    MisMatch(Location),
-}
-
-impl<'a> fmt::Display for MapResult<'a> {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            Self::Unmapped(x) => write!(f, "Unmapped\t*\t*\t*\t*\t*\t{}\t*\t*", x),
-            Self::LowMapq(x) => write!(f, "LowMapQ\t*\t*\t*\t*\t*\t{}\t*\t*", x),
-            Self::NoCutSites(x) => write!(f, "NoCutSites\t*\t*\t*\t*\t*\t{}\t*\t*", x),
-            Self::Unmatched(l) => write!(f, "Unmatched\t{}", l),
-            Self::MatchBoth(l) => write!(f, "MatchBoth\t{}", l),
-            Self::MatchStart(l) => write!(f, "MatchStart\t{}", l),
-            Self::MatchEnd(l) => write!(f, "MatchEnd\t{}", l),
-            Self::MisMatch(l) => write!(f, "MisMatch\t{}", l),
-            Self::Matched(m) => write!(f, "Matched\t{}", m),
-            Self::ExcessUnmatched(m) => write!(f, "ExcessUnmatched\t{}", m),
-        }
-    }
-}
-
-fn main() -> anyhow::Result<()> {
-    // Process command line arguments
-    let param = cli::process_cli().with_context(|| "ont_demult initialization failed")?;
+// Exit status for runs that complete with an empty PAF or FASTQ input
+const EMPTY_INPUT_EXIT_CODE: i32 = 3;
+
+// Exit status for runs stopped early by --max-reads or --max-runtime
+const PARTIAL_RUN_EXIT_CODE: i32 = 4;
+
+// Exit status for errors parsing an input file and for invalid configuration (i.e.,
+// inconsistent options or cut site definitions).  Other errors give an exit status of 1
+const PARSE_ERROR_EXIT_CODE: i32 = 5;
+const CONFIG_ERROR_EXIT_CODE: i32 = 6;
+
+// Number of reads from the PAF file processed as a chunk
+const PAF_CHUNK_SIZE: usize = 10000;
+
+// Destinations for read classifications
+struct ResultSink<'a, 'b> {
+    output: OutWriter,
+    cache: Option<CacheWriter>,
+    cls_out: Option<CacheWriter>,
+    summary: &'b mut RunSummary,
+    metrics: Option<Arc<Metrics>>,
+    read_hash: Option<ClassStore<'a>>,
+    channels: Option<ChannelCounts>,
+    hotspots: Option<Hotspots>,
+    no_sites: Option<NoSiteCounts<'a>>,
+    end_profile: Option<EndProfile<'a>>,
+    second_site: Option<SecondSite<'a>>,
+    igv: Option<IgvTracks<'a>>,
+    chains: Option<OutWriter>,
+    alleles: Option<HashMap<String, Allele>>,
+    seq_summary: Option<&'a SeqSummary>,
+    read_groups: Option<&'a ReadGroups>,
+    length_bins: Option<&'a [usize]>,
+    split: Option<SplitResults<'a>>,
+    deferred: Option<DeferredResults>,
+}
+
+impl<'a> ResultSink<'a, '_> {
+    // Write line to the results file (and the file for the match status with --split-results).
+    // With --defer-results the line is stored until write_deferred() is called
+    fn write_line(&mut self, cat: Category, line: &str) -> anyhow::Result<()> {
+        if let Some(d) = self.deferred.as_mut() {
+            return d.write(cat, line).with_context(|| "Error writing to deferred results file");
+        }
+        self.output
+            .write_all(line.as_bytes())
+            .with_context(|| "Error writing to output file")?;
+        if let Some(s) = self.split.as_mut() {
+            s.write(cat, line)
+                .with_context(|| "Error writing to split results file")?
+        }
+        Ok(())
+    }
+
+    // Write the results lines held back with --defer-results, once classification is final
+    fn write_deferred(&mut self, param: &Param) -> anyhow::Result<()> {
+        if let Some(d) = self.deferred.take() {
+            debug!("Writing deferred results");
+            if param.combined_results() {
+                self.output = open_output_file("res.txt", param)
+                    .with_context(|| "Error opening output file")?;
+                writeln!(self.output, "{}", RESULTS_HEADER)
+                    .with_context(|| "Error writing to output file")?;
+            }
+            d.replay(|cat, line| self.write_line(cat, line))
+                .with_context(|| "Error writing deferred results")?
+        }
+        Ok(())
+    }
+
+    // Close the results files
+    fn close(&mut self) -> anyhow::Result<()> {
+        let output: compressor::BoxWriter = Box::new(io::sink());
+        close_file(mem::replace(&mut self.output, BufWriter::new(output))).with_context(|| "Error writing to output file")?;
+        if let Some(s) = self.split.as_mut() {
+            s.close().with_context(|| "Error writing to split results file")?
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        self.output.flush().with_context(|| "Error writing to output file")?;
+        if let Some(s) = self.split.as_mut() {
+            s.flush().with_context(|| "Error writing to split results file")?
+        }
+        Ok(())
+    }
+
+    // Record classification of read.  line is the corresponding line for the results file
+    fn add(&mut self, name: &str, map_result: MapResult<'a>, line: &str) -> anyhow::Result<()> {
+        self.write_line(map_result.category(), line)?;
+        if let Some(c) = self.cache.as_mut() {
+            c.write_line(line)
+                .with_context(|| "Error writing to cache file")?;
+        }
+        if let Some(c) = self.cls_out.as_mut() {
+            c.write_line(line)
+                .with_context(|| "Error writing to classification file")?;
+        }
+        self.summary.add_read(map_result.status(), map_result.read_len());
+        if let MapResult::Matched(m) = &map_result {
+            self.summary.add_match(&m.site.name);
+            self.summary.add_overrun(m.overrun());
+            self.summary.add_start_offset(m)
+        }
+        if let Some(m) = self.metrics.as_ref() {
+            m.add_read(map_result.status())
+        }
+        if let Some(rg) = self.read_groups {
+            let site = match &map_result {
+                MapResult::Matched(m) => Some(m.site.name.as_str()),
+                _ => None,
+            };
+            self.summary.add_run_read(rg.run(name), map_result.status(), site)
+        }
+        if let Some(bins) = self.length_bins {
+            let site = match &map_result {
+                MapResult::Matched(m) => Some(m.site.name.as_str()),
+                _ => None,
+            };
+            self.summary.add_length_read(bins, map_result.read_len(), map_result.status(), site)
+        }
+        // If a FASTQ file is being demultiplexed, channel counts are collected when routing
+        if let (Some(cc), None) = (self.channels.as_mut(), self.read_hash.as_ref()) {
+            let barcode = match &map_result {
+                MapResult::Matched(m) => Some(m.site.barcode.as_str()),
+                _ => None,
+            };
+            cc.add(self.seq_summary.and_then(|s| s.channel(name)), barcode)
+        }
+        if let (Some(h), MapResult::Unmatched(loc)) = (self.hotspots.as_mut(), &map_result) {
+            h.add(loc)
+        }
+        if let Some(ns) = self.no_sites.as_mut() {
+            ns.add(&map_result)
+        }
+        if let (Some(ep), MapResult::Matched(m)) = (self.end_profile.as_mut(), &map_result) {
+            ep.add(m)
+        }
+        if let (Some(ss), MapResult::Matched(m)) = (self.second_site.as_mut(), &map_result) {
+            ss.add(name, m)
+                .with_context(|| "Error writing to second site report")?
+        }
+        if let (Some(t), MapResult::Matched(m)) = (self.igv.as_mut(), &map_result) {
+            t.add(name, m)
+                .with_context(|| "Error writing to matched read BED file")?
+        }
+        if let Some(rh) = self.read_hash.as_mut() {
+            rh.insert(name, &map_result)
+                .with_context(|| "Error writing classifications to temporary file")?;
+        }
+        Ok(())
+    }
+}
+
+// Open the alignment input: the PAF (or SAM/BAM) file or stdin, or the output of minimap2 when
+// aligning the FASTQ input to a reference (see align.rs)
+fn open_paf(param: &Param) -> io::Result<PafFile> {
+    let mut paf_file = match (param.align_reference(), param.fastq_file()) {
+        (Some(r), Some(fq)) => PafFile::new(align::minimap2_reader(param.minimap2(), r, fq, param.minimap2_args())?),
+        _ => PafFile::open_format(param.paf_file(), param.compressor(), param.input_format())?,
+    };
+    paf_file.set_limits(param.max_paf_records(), param.max_line_length());
+    paf_file.set_unsorted(param.unsorted_paf());
+    paf_file.set_lenient(param.lenient());
+    Ok(paf_file)
+}
 
+// Pairs of colliding cut sites found when loading the cut file (see CutSites::resolve_collisions())
+fn write_collision_report<W: Write>(v: &[cut_site::Collision], wrt: &mut W) -> io::Result<()> {
+    writeln!(wrt, "contig\tsite1\tbarcode1\tpos1\tsite2\tbarcode2\tpos2\tdistance")?;
+    for c in v {
+        write!(wrt, "{}", c.contig)?;
+        for (name, barcode, pos) in c.sites.iter() {
+            write!(wrt, "\t{}\t{}\t{}", name, barcode, pos)?;
+        }
+        writeln!(wrt, "\t{}", c.distance())?;
+    }
+    Ok(())
+}
+
+// Estimate systematic read start offsets (--auto-offset) from the matched reads in the first
+// chunk of the PAF file (after any skipped reads)
+fn estimate_offsets(param: &Param) -> anyhow::Result<[i64; 2]> {
+    let mut paf_file =
+        open_paf(param).with_context(|| "Error opening paf file")?;
+    let mut counts = OffsetCounts::default();
+    let mut n = 0;
+    let limit = PAF_CHUNK_SIZE.min(param.read_limit().unwrap_or(usize::MAX));
+    let mut skip = param.skip_reads();
+    while n < limit {
+        let mut read = match paf_file
+            .next_read()
+            .with_context(|| "Error reading from paf file")?
+        {
+            Some(read) => read,
+            None => break,
+        };
+        if skip > 0 {
+            skip -= 1;
+            continue;
+        }
+        if param.read_filter().map(|f| f.pass(read.qname())).unwrap_or(true) {
+            n += 1;
+            if let Some(x) = param.refine_ends() {
+                read.refine_ends(x);
+            }
+            if let MapResult::Matched(m) = param.classifier().classify(&read) {
+                counts.add(&m)
+            }
+        }
+    }
+    let shifts = counts.shifts();
+    info!(
+        "Start offset correction from first {} reads: {} bp (+ strand), {} bp (- strand)",
+        n, shifts[0], shifts[1]
+    );
+    Ok(shifts)
+}
+
+// Parse and classify a read, returning the read, the classification, the line for the results
+// file and the sites covered by the read (for the cut efficiency report)
+fn classify_raw_read<'a>(raw: &PafRawRead, param: &'a Param) -> io::Result<(PafRead, MapResult<'a>, String, Vec<&'a cut_site::Site>)> {
+    let (read, map_result) = trace_sample::with_read(raw.qname(), || {
+        raw.parse().map(|mut read| {
+            if let Some(fl) = param.fastq_lengths() {
+                fl.apply(&mut read)
+            }
+            if let Some(r) = read.select_alignments(param.classify_param()) {
+                read = r
+            }
+            if let Some(n) = param.refine_ends() {
+                read.refine_ends(n);
+            }
+            let m = param.classifier().classify(&read);
+            (read, m)
+        })
+    })?;
+    let line = format!("{}\t{:.*}\n", read.qname(), param.float_precision(), map_result);
+    let covered = match param.cut_sites() {
+        Some(cs) if param.efficiency_report() => read.covered_sites(cs, param.classify_param()),
+        _ => Vec::new(),
+    };
+    Ok((read, map_result, line, covered))
+}
+
+// Check that classification is deterministic (--verify-deterministic) by classifying the
+// first chunk of the PAF file (after any skipped reads) with a single thread and with multiple
+// threads, and comparing the results lines and covered sites of each read
+fn verify_deterministic(param: &Param) -> anyhow::Result<()> {
+    let mut paf_file =
+        open_paf(param).with_context(|| "Error opening paf file")?;
+    for _ in 0..param.skip_reads() {
+        if paf_file.next_raw_read().with_context(|| "Error reading from paf file")?.is_none() {
+            break;
+        }
+    }
+    let limit = PAF_CHUNK_SIZE.min(param.read_limit().unwrap_or(usize::MAX));
+    let mut chunk = Vec::with_capacity(limit);
+    while chunk.len() < limit {
+        match paf_file.next_raw_read().with_context(|| "Error reading from paf file")? {
+            Some(raw) => {
+                if param.read_filter().map(|f| f.pass(raw.qname())).unwrap_or(true) {
+                    chunk.push(raw)
+                }
+            }
+            None => break,
+        }
+    }
+    let classify = |raw: &PafRawRead| {
+        classify_raw_read(raw, param).map(|(_, _, line, covered)| {
+            let covered: Vec<_> = covered.iter().map(|s| s.name.as_str()).collect();
+            (line, covered)
+        })
+    };
+    let single: Vec<_> = chunk
+        .iter()
+        .map(classify)
+        .collect::<io::Result<_>>()
+        .with_context(|| "Error reading from paf file")?;
+    let threads = param.threads().unwrap_or_else(available_cores).max(2);
+    let multi: Vec<_> = rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .build()
+        .with_context(|| "Error creating thread pool")?
+        .install(|| chunk.par_iter().with_max_len(1).map(classify).collect::<io::Result<_>>())
+        .with_context(|| "Error reading from paf file")?;
+    let diffs: Vec<_> = chunk
+        .iter()
+        .zip(single.iter().zip(multi.iter()))
+        .filter(|(_, (a, b))| a != b)
+        .map(|(raw, _)| raw.qname())
+        .collect();
+    if let Some(name) = diffs.first() {
+        return Err(anyhow!(
+            "Classification is not deterministic: {} of {} reads classified differently with 1 and {} threads (first is {})",
+            diffs.len(),
+            chunk.len(),
+            threads,
+            name
+        ));
+    }
+    info!(
+        "Classification verified as deterministic: {} reads classified identically with 1 and {} threads",
+        chunk.len(),
+        threads
+    );
+    Ok(())
+}
+
+// Classify reads from the PAF file.  With --synced, the FASTQ reads for each chunk are routed by
+// router as soon as the chunk has been classified
+fn classify_paf<'a>(
+    param: &'a Param,
+    sink: &mut ResultSink<'a, '_>,
+    flush_timer: &mut FlushTimer,
+    mut router: Option<&mut FastqRouter<'a>>,
+) -> anyhow::Result<()> {
     debug!("Opening PAF input");
     // Open input file (or stdin)
     let mut paf_file =
-        PafFile::open(param.paf_file()).with_context(|| "Error opening paf file")?;
+        open_paf(param).with_context(|| "Error opening paf file")?;
     info!("PAF input opened OK");
 
-    // Hash to store read classifications if we will be demultiplexing a FASTQ
-    let mut read_hash: Option<HashMap<String, MapResult>> = if param.fastq_file().is_some() {
-        Some(HashMap::new())
-    } else {
-        None
+    // Thread pool for classification (if more than one thread requested).  With automatic
+    // selection, the first chunk is classified with a single thread and the number of threads
+    // is then chosen from the relative time taken to read and classify the chunk
+    let make_pool = |n: usize| {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(n)
+            .build()
+            .with_context(|| "Error creating thread pool")
     };
+    let mut pool = match param.threads() {
+        Some(n) if n > 1 => Some(make_pool(n)?),
+        _ => None,
+    };
+    let mut calibrate = param.threads().is_none() && available_cores() > 1;
 
-    // Main output file
-    debug!("Opening main output");
-    let mut output = open_output_file("res.txt", &param)
-        .with_context(|| "Error opening output file")?;
-    writeln!(output, "read_name\tmatch_status\tcut_site/contig\tbarcode\tstrand\tstart\tend\tlength\tunused\tprop. unused\tsplits")
-    .with_context(|| "Error writing to output file")?;
-
-    // Process PAF reads
+    // Process PAF reads in read aligned chunks.  The records for the reads in a chunk are parsed
+    // and the reads classified (in parallel if a thread pool is available), and the results are
+    // then written out in input order
     info!("Reading from PAF file");
-    while let Some(read) = paf_file
-        .next_read()
-        .with_context(|| "Error reading from paf file")?
-    {
-        let map_result = if read.is_mapped() {
-            if read.is_unique(param.mapq_thresh()) {
-                if let Some(cut_sites) = param.cut_sites() {
-                    if let Some(fm) = read.find_site(cut_sites, &param) {
-                        match fm {
-                            FindMatch::Match(m) => MapResult::Matched(m),
-                            FindMatch::ExcessUnmatched(m) => MapResult::ExcessUnmatched(m),
-                            FindMatch::Location(l) => MapResult::Unmatched(l),
-                            FindMatch::MisMatch(l) => MapResult::MisMatch(l),
-                            FindMatch::MatchStart(l) => MapResult::MatchStart(l),
-                            FindMatch::MatchBoth(l) => MapResult::MatchBoth(l),
-                            FindMatch::MatchEnd(l) => MapResult::MatchEnd(l),
-                        }
-                    } else {
-                        MapResult::LowMapq(read.qlen)
+    let classify_read = |raw: &PafRawRead| classify_raw_read(raw, param);
+    // Start post classification command if requested
+    let mut hook = param
+        .post_classify_cmd()
+        .map(ClassifyHook::spawn)
+        .transpose()
+        .with_context(|| "Error starting post classification command")?;
+    let sites = param
+        .cut_sites()
+        .map(|c| c.site_index())
+        .unwrap_or_default();
+    let mut n_overrides = 0;
+    // Skip reads at the start of the input and limit the number of reads classified if requested
+    let mut empty = true;
+    for _ in 0..param.skip_reads() {
+        if paf_file
+            .next_raw_read()
+            .with_context(|| "Error reading from paf file")?
+            .is_none()
+        {
+            break;
+        }
+        empty = false
+    }
+    let mut remaining = param.read_limit().unwrap_or(usize::MAX);
+    let mut chunk = Vec::with_capacity(PAF_CHUNK_SIZE);
+    // Number of reads classified
+    let mut n_reads = 0;
+    loop {
+        chunk.clear();
+        // Stop if a safety limit has been reached.  The run is only marked as partial if there
+        // is more input
+        if remaining > 0 {
+            if let Some(limit) = param.run_limit(n_reads) {
+                if paf_file
+                    .next_raw_read()
+                    .with_context(|| "Error reading from paf file")?
+                    .is_some()
+                {
+                    sink.summary.set_stopped(limit)
+                }
+                break;
+            }
+        }
+        let budget = param.max_reads().map(|m| m - n_reads).unwrap_or(usize::MAX);
+        let t_read = Instant::now();
+        while chunk.len() < PAF_CHUNK_SIZE.min(remaining).min(budget) {
+            match paf_file
+                .next_raw_read()
+                .with_context(|| "Error reading from paf file")?
+            {
+                Some(raw) => {
+                    empty = false;
+                    if param.read_filter().map(|f| f.pass(raw.qname())).unwrap_or(true) {
+                        chunk.push(raw)
                     }
-                } else {
-                    MapResult::NoCutSites(read.qlen)
                 }
-            } else {
-                MapResult::LowMapq(read.qlen)
+                None => break,
             }
-        } else {
-            MapResult::Unmapped(read.qlen)
+        }
+        if chunk.is_empty() {
+            break;
+        }
+        remaining -= chunk.len();
+        n_reads += chunk.len();
+        let t_read = t_read.elapsed();
+        let t_class = Instant::now();
+        let results: Vec<_> = match pool.as_ref() {
+            Some(p) => p.install(|| chunk.par_iter().map(classify_read).collect::<io::Result<_>>()),
+            None => chunk.iter().map(classify_read).collect(),
+        }
+        .with_context(|| "Error reading from paf file")?;
+        if calibrate {
+            calibrate = false;
+            let n = threads::classify_threads(t_read, t_class.elapsed());
+            info!("Using {} threads for classification", n);
+            if n > 1 {
+                pool = Some(make_pool(n)?)
+            }
+        }
+        let overrides = match hook.as_mut() {
+            Some(h) => Some(
+                h.process(results.iter().map(|(_, _, line, _)| line.as_str()))
+                    .with_context(|| "Error running post classification command")?,
+            ),
+            None => None,
         };
-        writeln!(output, "{}\t{}", read.qname(), map_result)
-            .with_context(|| "Error writing to output file")?;
-        if let Some(rh) = read_hash.as_mut() {
-            rh.insert(read.qname().to_owned(), map_result);
+        for (i, (read, mut map_result, mut line, covered)) in results.into_iter().enumerate() {
+            for site in covered {
+                sink.summary.add_coverage(&site.name)
+            }
+            if let Some(ov) = overrides.as_ref().and_then(|v| v[i].as_ref()) {
+                map_result = map_result
+                    .apply_override(ov, &sites, param.cut_sites())
+                    .with_context(|| format!("Invalid override for read {}", read.qname()))?;
+                line = format!("{}\t{:.*}\n", read.qname(), param.float_precision(), map_result);
+                n_overrides += 1;
+            }
+            if let (Some(wrt), MapResult::Matched(m)) = (sink.chains.as_mut(), &map_result) {
+                if let Some(l) = read.chain_paf(param.classify_param()) {
+                    writeln!(wrt, "{}\tst:Z:{}\tbc:Z:{}", l, m.site.name, m.site.barcode)
+                        .with_context(|| "Error writing to chain file")?
+                }
+            }
+            if let (Some(snps), Some(h), MapResult::Matched(_)) = (param.snps(), sink.alleles.as_mut(), &map_result) {
+                if let Some(a) = snps.allele(&read, param.mapq_thresh()) {
+                    h.insert(read.qname().to_owned(), a);
+                }
+            }
+            if let Some(bins) = param.mapq_bins() {
+                let mapq = read.primary_mapq(param.classify_param());
+                sink.summary.add_mapq_read(bins, mapq, map_result.status())
+            }
+            sink.add(read.qname(), map_result, &line)?;
         }
+        if let Some(r) = router.as_deref_mut() {
+            r.route(param, sink, flush_timer, true)?
+        }
+        if flush_timer.due() {
+            sink.flush()?;
+        }
+    }
+    if empty {
+        sink.summary.add_empty_input("PAF")
+    }
+    if paf_file.invalid_utf8() > 0 {
+        warn!("{} PAF lines had invalid UTF-8 (invalid bytes replaced)", paf_file.invalid_utf8());
+        sink.summary.add_anomalies("paf_invalid_utf8", paf_file.invalid_utf8());
+    }
+    if paf_file.unmapped_records() > 0 {
+        info!("{} PAF records for unmapped reads", paf_file.unmapped_records())
+    }
+    if paf_file.excess_records() > 0 {
+        warn!(
+            "{} reads had more than {} PAF records (extra records ignored)",
+            paf_file.excess_records(),
+            param.max_paf_records()
+        );
+        sink.summary.add_anomalies("paf_excess_records", paf_file.excess_records());
+    }
+    if paf_file.long_lines() > 0 {
+        warn!(
+            "{} PAF lines were longer than {} bytes (lines truncated)",
+            paf_file.long_lines(),
+            param.max_line_length()
+        );
+        sink.summary.add_anomalies("paf_long_lines", paf_file.long_lines());
+    }
+    match paf_file.skipped_lines() {
+        (0, _) => (),
+        (n, first) => {
+            warn!("{} malformed PAF lines skipped (first at line {})", n, first);
+            sink.summary.add_anomalies("paf_malformed_lines", n);
+        }
+    }
+    if let Some(fl) = param.fastq_lengths() {
+        fl.report(sink.summary)
     }
+    if let Some(h) = sink.alleles.as_ref() {
+        let n_ref = h.values().filter(|a| **a == Allele::Ref).count();
+        info!("Matched reads assigned to alleles: {} ref, {} alt", n_ref, h.len() - n_ref);
+    }
+    if let Some(h) = hook {
+        h.finish()
+            .with_context(|| "Error running post classification command")?;
+        info!("{} read classifications overridden by post classification command", n_overrides);
+    }
+    Ok(())
+}
 
-    // Process FastQ file if specified
-    if let Some(fq) = param.fastq_file() {
+// Read classifications from a cache entry or a results file from a previous run
+fn read_classifications<'a, P: AsRef<Path>>(
+    file: P,
+    mut rdr: Box<dyn BufRead>,
+    param: &'a Param,
+    sink: &mut ResultSink<'a, '_>,
+    flush_timer: &mut FlushTimer,
+) -> anyhow::Result<()> {
+    let sites = param
+        .cut_sites()
+        .map(|c| c.site_index())
+        .unwrap_or_default();
+    if param.mapq_bins().is_some() {
+        warn!("MAPQ statistics are not available when reading previous classifications")
+    }
+    let mut ctgs = HashSet::new();
+    let mut buf = String::new();
+    let mut line = 0;
+    loop {
+        buf.clear();
+        if rdr.read_line(&mut buf)? == 0 {
+            break;
+        }
+        line += 1;
+        // Check the header of a classification file (--classification-out) and skip the
+        // header of a results file
+        if buf.starts_with('#') {
+            cache::check_header(&buf, param).map_err(|e| e.in_file(&file).at_line(line))?;
+            continue;
+        }
+        if buf.starts_with("read_name\t") {
+            continue;
+        }
+        if !buf.ends_with('\n') {
+            buf.push('\n')
+        }
+        let fd: Vec<_> = buf.trim_end_matches(['\r', '\n']).split('\t').collect();
+        if !param.read_filter().map(|f| f.pass(fd[0])).unwrap_or(true) {
+            continue;
+        }
+        let map_result = MapResult::from_fields(&fd[1..], &sites, &mut ctgs)
+            .with_context(|| format!("Error at line {}", line))?;
+        sink.add(fd[0], map_result, &buf)?;
+        if flush_timer.due() {
+            sink.flush()?;
+        }
+    }
+    Ok(())
+}
+
+// Skip the first n reads of a FASTQ file
+fn skip_fastq(fq_file: &mut FastqFile, n: usize) -> anyhow::Result<()> {
+    for _ in 0..n {
+        if !fq_file
+            .next_read()
+            .with_context(|| "Error reading from fastq file")?
+        {
+            break;
+        }
+    }
+    Ok(())
+}
+
+// Demultiplexing of a FASTQ file using the read classifications in the read hash of a
+// ResultSink.  Normally all classifications are collected before the FASTQ file is read.  With
+// --synced, the FASTQ reads are in the same order as the PAF reads, so the reads for each chunk
+// of the PAF file are routed as soon as the chunk has been classified, and the read hash is
+// then cleared (see route())
+struct FastqRouter<'a> {
+    input: FastqInput,
+    state: RouteState<'a>,
+    n_found: usize,      // Number of input reads found in the classifications
+    n_classified: usize, // Number of classified reads
+    invalid_utf8: usize, // Records with invalid UTF-8 in previous FASTQ inputs
+    fastq_threads: usize, // Number of lookup threads for the FASTQ pipeline
+}
+
+// Outputs and counts for FASTQ routing (kept separate from the input so that records can be
+// routed while borrowed from the input)
+struct RouteState<'a> {
+    ofiles: OutputFiles<'a>,
+    contam_counts: Option<ContamCounts>,
+    id_report: Option<IdMatchReport>, // FASTQ ID match report (with --id-match)
+    n_dropped: u64,        // Number of reads not written to any output (i.e., with --matched-only or --no-fastq-output)
+    n_mod_rc: usize,       // Number of reverse complemented reads with base modification tags
+    read_stats: ReadStats, // Length and quality statistics for each output
+    mod_stats: ModStats,   // Base modification calls for each barcode
+}
+
+impl<'a> FastqRouter<'a> {
+    fn open(fq: &str, param: &'a Param) -> anyhow::Result<Self> {
         debug!("Opening demultiplexed FastQ output files");
         // Prepare output files
-        let mut ofiles = OutputFiles::open(&param)
+        let ofiles = OutputFiles::open(param)
             .with_context(|| "Error opening FastQ output files")?;
 
         // Open input FastQ file
         debug!("Opening FastQ input");
+        let threads = FastqThreads::new(param, ofiles.n_files());
+        if param.decompress_threads().is_none() {
+            info!("Using {} threads for FASTQ decompression", threads.decompress)
+        }
+        if param.fastq_threads().is_none() {
+            info!("Using {} threads for FASTQ lookup", threads.lookup)
+        }
         let mut fq_file =
-            FastqFile::open(fq).with_context(|| "Error opening fastq file")?;
-        info!("Reading from FastQ file");
-        // Process FastQ reads
-        let rh = read_hash.as_ref().unwrap();
-        while fq_file
-            .next_read()
-            .with_context(|| "Error reading from fastq fil")?
+            FastqFile::open(fq, param.compressor(), threads.decompress).with_context(|| "Error opening fastq file")?;
+        fq_file.set_max_record(param.max_fastq_record());
+        skip_fastq(&mut fq_file, param.skip_reads())?;
+        let fastq_threads = threads.lookup;
+        Ok(Self {
+            input: FastqInput::new(fq_file, param),
+            state: RouteState {
+                ofiles,
+                contam_counts: param.contam().map(|_| ContamCounts::default()),
+                id_report: param.id_match().map(|_| IdMatchReport::default()),
+                n_dropped: 0,
+                n_mod_rc: 0,
+                read_stats: ReadStats::default(),
+                mod_stats: ModStats::default(),
+            },
+            n_found: 0,
+            n_classified: 0,
+            invalid_utf8: 0,
+            fastq_threads,
+        })
+    }
+
+    // Route FASTQ reads using the classifications in the read hash of sink.  If synced is set,
+    // reads are only routed until all classified reads have been found, after which the read
+    // hash is cleared.  Otherwise the rest of the FASTQ input is routed, using the pipeline in
+    // pipeline.rs if more than one FASTQ thread has been requested
+    fn route(
+        &mut self,
+        param: &Param,
+        sink: &mut ResultSink<'a, '_>,
+        flush_timer: &mut FlushTimer,
+        synced: bool,
+    ) -> anyhow::Result<()> {
+        // The read hash is taken from the sink while routing (and put back at the end)
+        let mut rh = sink.read_hash.take().unwrap();
+        let exact = IdMatch::Exact;
+        let lookup = IdLookup::new(param.id_match().unwrap_or(&exact), rh.hash());
+        let mut used = HashSet::new();
+        let mut n_found = 0;
+        let state = &mut self.state;
+        if self.fastq_threads > 1 && !synced {
+            state.ofiles.start_threads().with_context(|| "Error starting fastq output threads")?;
+            pipeline::run(&mut self.input, param, &lookup, self.fastq_threads, |seq, batch, found| {
+                if param.ordered() {
+                    state.ofiles.start_batch(seq).with_context(|| "Error writing to fastq output")?;
+                }
+                for (rec, (found, contam)) in batch.iter().zip(found) {
+                    n_found += found.value().is_some() as usize;
+                    state.route_read(rec, &found, contam, &mut used, param, sink)?;
+                }
+                if param.ordered() {
+                    state.ofiles.end_batch().with_context(|| "Error writing to fastq output")?;
+                }
+                if flush_timer.due() {
+                    state.ofiles.flush().with_context(|| "Error writing to fastq output")?;
+                    sink.flush()?;
+                }
+                Ok(())
+            })?;
+            state.ofiles.stop_threads().with_context(|| "Error writing to fastq output")?;
+        } else {
+            while !(synced && n_found == rh.len()) && self.input.next_read(param)? {
+                let rec = self.input.fq_file.record();
+                let (found, contam) = pipeline::find(rec.read_id(), rec.seq(), &lookup, param);
+                n_found += found.value().is_some() as usize;
+                state.route_read(rec, &found, contam, &mut used, param, sink)?;
+                if flush_timer.due() {
+                    state.ofiles.flush().with_context(|| "Error writing to fastq output")?;
+                    sink.flush()?;
+                }
+            }
+        }
+        if let Some(limit) = self.input.take_stopped() {
+            sink.summary.set_stopped(limit)
+        }
+        // With --synced, the FASTQ input ending before all classified reads of a chunk have been
+        // found indicates that the inputs are not synchronized
+        if synced && n_found < rh.len() && sink.summary.stopped().is_none() && param.read_limit().is_none() {
+            return Err(anyhow!(
+                "{} of {} classified reads were not found in FASTQ input (reads not in the same order in the PAF and FASTQ inputs?)",
+                rh.len() - n_found,
+                rh.len()
+            ));
+        }
+        self.n_found += n_found;
+        self.n_classified += rh.len();
+
+        if let Some(r) = self.state.id_report.as_mut() {
+            r.add_unused(rh.hash(), &used)
+        }
+        drop(used);
+        drop(lookup);
+        if synced {
+            rh.clear()
+        }
+        sink.read_hash = Some(rh);
+        Ok(())
+    }
+
+    // Route the FASTQ input when the classifications have been spilled to disk (see
+    // class_store.rs).  The FASTQ input is first split into partitions in the same way as the
+    // classifications, and each partition is then routed with its classifications loaded
+    fn route_partitions(
+        &mut self,
+        param: &Param,
+        sink: &mut ResultSink<'a, '_>,
+        flush_timer: &mut FlushTimer,
+    ) -> anyhow::Result<()> {
+        let rh = sink.read_hash.as_mut().unwrap();
+        rh.finish_spill()
+            .with_context(|| "Error writing classifications to temporary file")?;
+        // The FASTQ partitions are written to the same temporary directory as the classifications
+        let dir = rh.spill_dir().unwrap().to_owned();
+        info!("Splitting FASTQ input into {} partitions", N_PARTITIONS);
+        let paths: Vec<_> = (0..N_PARTITIONS).map(|i| dir.join(format!("fastq_{}.fq", i))).collect();
+        let mut files = paths
+            .iter()
+            .map(|p| File::create(p).map(BufWriter::new))
+            .collect::<io::Result<Vec<_>>>()
+            .with_context(|| "Error creating temporary FASTQ file")?;
+        while self.input.next_read(param)? {
+            let fq_file = &self.input.fq_file;
+            let wrt = &mut files[class_store::partition(fq_file.read_id())];
+            fq_file
+                .write_rec(wrt, false, None, None)
+                .with_context(|| "Error writing to temporary FASTQ file")?;
+        }
+        if let Some(limit) = self.input.take_stopped() {
+            sink.summary.set_stopped(limit)
+        }
+        for mut f in files.drain(..) {
+            f.flush().with_context(|| "Error writing to temporary FASTQ file")?
+        }
+        self.input.prefiltered = true;
+        for (i, path) in paths.iter().enumerate() {
+            debug!("Routing FASTQ partition {}", i);
+            sink.read_hash
+                .as_mut()
+                .unwrap()
+                .load(i)
+                .with_context(|| "Error reading classifications from temporary file")?;
+            let mut fq_file = FastqFile::open(path, param.compressor(), 1).with_context(|| "Error opening fastq partition")?;
+            fq_file.set_max_record(param.max_fastq_record());
+            self.invalid_utf8 += std::mem::replace(&mut self.input.fq_file, fq_file).invalid_utf8();
+            self.route(param, sink, flush_timer, false)?;
+            let _ = fs::remove_file(path);
+        }
+        Ok(())
+    }
+
+    // Check the read counts, close the output files and write the FASTQ reports
+    fn finish(self, param: &Param, sink: &mut ResultSink) -> anyhow::Result<()> {
+        let invalid_utf8 = self.invalid_utf8 + self.input.fq_file.invalid_utf8();
+        if invalid_utf8 > 0 {
+            warn!("{} FASTQ records had invalid UTF-8 in the header (invalid bytes replaced)", invalid_utf8);
+            sink.summary.add_anomalies("fastq_invalid_utf8", invalid_utf8);
+        }
+        let n_input = self.input.n_input;
+        if n_input == 0 && sink.summary.stopped().is_none() {
+            sink.summary.add_empty_input("FASTQ")
+        }
+        self.state.check_counts(n_input)?;
+
+        // Classified reads missing from the FASTQ input suggest that the FASTQ file is truncated
+        // or does not correspond to the PAF file (unless only part of the input was processed)
+        if self.n_found < self.n_classified
+            && param.skip_reads() == 0
+            && param.read_limit().is_none()
+            && sink.summary.stopped().is_none()
         {
-            let unmapped = MapResult::Unmapped(fq_file.read_len());
-            let mr = rh.get(fq_file.read_id()).unwrap_or_else(|| {
-                writeln!(output, "{}\t{}", fq_file.read_id(), &unmapped)
-                    .expect("Error writing to output file {}");
-                &unmapped
-            });
+            warn!(
+                "{} of {} classified reads were not found in FASTQ input (truncated or mismatched file?)",
+                self.n_classified - self.n_found,
+                self.n_classified
+            )
+        }
+        self.state.finish(param)
+    }
+}
+
+impl<'a> RouteState<'a> {
+    // Write FASTQ record rec to the output(s) for its classification found.  Reads not found
+    // in the classifications are recorded as unmapped.  contam is the result of the
+    // contamination screen (with --contam)
+    fn route_read<'h>(
+        &mut self,
+        rec: FastqRec,
+        found: &Lookup<'h, ReadClass<'a>>,
+        contam: Option<bool>,
+        used: &mut HashSet<&'h str>,
+        param: &Param,
+        sink: &mut ResultSink,
+    ) -> anyhow::Result<()> {
+        if let Some(r) = self.id_report.as_mut() {
+            r.add(rec.read_id(), found, used)
+        }
+        let unmapped_cls;
+        let cls = match found.value() {
+            Some(rc) => rc,
+            None => {
+                let unmapped = MapResult::Unmapped(rec.read_len());
+                let line = format!("{}\t{}\n", rec.read_id(), &unmapped);
+                sink.write_line(unmapped.category(), &line)?;
+                sink.summary.add_read(unmapped.status(), rec.read_len());
+                if let Some(m) = sink.metrics.as_ref() {
+                    m.add_read(unmapped.status())
+                }
+                if let Some(rg) = sink.read_groups {
+                    sink.summary.add_run_read(rg.run(rec.read_id()), unmapped.status(), None)
+                }
+                if let Some(bins) = sink.length_bins {
+                    sink.summary.add_length_read(bins, rec.read_len(), unmapped.status(), None)
+                }
+                unmapped_cls = ReadClass::unmapped();
+                &unmapped_cls
+            }
+        };
+        let ofiles = &mut self.ofiles;
+
+        // Site match used for routing.  With --flag-only, reads matching a site but failing
+        // secondary filters are routed to the site and flagged in the header.  Reads matching
+        // colliding sites (with --site-collisions nearest-wins-with-flag) are also flagged
+        let (site_match, flag) = match (cls.category(), cls.site()) {
+            (Category::Matched, Some(s)) if s.collision => (Some(s), Some("SiteCollision")),
+            (Category::Matched, Some(s)) => (Some(s), None),
+            (Category::ExcessUnmatched, Some(s)) if param.flag_only() => (Some(s), Some(cls.status())),
+            _ => (None, None),
+        };
+
+        let barcode = match site_match {
+            Some(site) => site.barcode.as_str(),
+            None => "unassigned",
+        };
+        if let (Some(contam), Some(ct)) = (contam, self.contam_counts.as_mut()) {
+            ct.add(barcode, contam);
+        }
+
+        // Summarize base modification calls (the tags are lost if reads are demultiplexed
+        // without them, so the calls are counted here)
+        let tag = |a, b| rec.header_tag(a).or_else(|| rec.header_tag(b));
+        if let (Some(mm), Some(ml)) = (tag("MM", "Mm"), tag("ML", "Ml")) {
+            self.mod_stats.add(barcode, mm, ml)
+        }
+
+        if let Some(cc) = sink.channels.as_mut() {
+            let ch = match sink.seq_summary {
+                Some(s) => s.channel(rec.read_id()),
+                None => rec.header_tag("ch").and_then(|x| x.parse().ok()),
+            };
+            cc.add(ch, site_match.map(|site| site.barcode.as_str()));
+        }
+
+        let (name, wrt) = match (site_match, cls.category()) {
+            (Some(site), _) => {
+                let name = param.split_by().output_name(site);
+                (name, ofiles.site_hash.get_mut(name))
+            }
+            (_, Category::Unmapped) => ("unmapped", ofiles.unmapped.as_mut()),
+            (_, Category::LowMapQ) => ("low_mapq", ofiles.low_mapq.as_mut()),
+            _ if param.no_sites_output() && cls.no_sites() => {
+                ("no_sites", ofiles.no_sites.as_mut())
+            }
+            _ => ("unmatched", ofiles.unmatched.as_mut()),
+        };
+        // With --tag-only all reads (or only matched reads with --matched-only) are written to a
+        // single output with the classification added to the header
+        let tags = ofiles.tagged.is_some().then(|| match site_match {
+            Some(site) => format!(
+                " barcode={} site={} strand={} status={}",
+                site.barcode,
+                site.name,
+                cls.strand(),
+                cls.status()
+            ),
+            None => format!(" barcode=* site=* strand=* status={}", cls.status()),
+        });
+        let wrt = match ofiles.tagged.as_mut() {
+            Some(w) if site_match.is_some() || !param.matched_only() => Some(w),
+            Some(_) => None,
+            None => wrt,
+        };
+        self.read_stats.add(name, rec.qual());
+        // Reverse complement minus strand matched reads if requested
+        let rc = param.orient_reads() && site_match.map(|_| cls.strand()) == Some(Strand::Minus);
+        if rc && rec.has_mod_tags() {
+            self.n_mod_rc += 1
+        }
+        if let Some(wrt) = wrt {
+            let n = wrt
+                .write_rec(rec, rc, flag, tags.as_deref())
+                .with_context(|| "Error writing to fastq output")?;
+            if let Some(m) = sink.metrics.as_ref() {
+                m.add_bytes(name, n)
+            }
+        } else {
+            self.n_dropped += 1;
+        }
+        // Matched reads are also written to the outputs for the sample and group (if defined)
+        if let Some(site) = site_match {
+            let allele = sink.alleles.as_ref().and_then(|h| h.get(rec.read_id())).copied();
+            let run = sink.read_groups.and_then(|rg| rg.run(rec.read_id()));
+            for (name, wrt) in ofiles.site_outputs(site, allele, run) {
+                let n = wrt
+                    .write_rec(rec, rc, flag, None)
+                    .with_context(|| "Error writing to fastq output")?;
+                if let Some(m) = sink.metrics.as_ref() {
+                    m.add_bytes(name, n)
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Check that all input reads have been accounted for
+    fn check_counts(&self, n_input: u64) -> anyhow::Result<()> {
+        let n_written = self.ofiles.primary_records();
+        info!(
+            "Read {} FASTQ records: {} written, {} not output",
+            n_input, n_written, self.n_dropped
+        );
+        if n_written + self.n_dropped != n_input {
+            return Err(anyhow!(
+                "Read count mismatch: {} FASTQ records read but {} written and {} not output",
+                n_input,
+                n_written,
+                self.n_dropped
+            ));
+        }
+        Ok(())
+    }
+
+    // Close the output files and write the FASTQ reports
+    fn finish(mut self, param: &Param) -> anyhow::Result<()> {
+        // Base modification tags give positions relative to the original read orientation, so
+        // are not valid for reverse complemented reads
+        if self.n_mod_rc > 0 {
+            warn!(
+                "{} reverse complemented reads have base modification (MM/ML) tags that refer to the original read orientation",
+                self.n_mod_rc
+            )
+        }
+
+        self.ofiles
+            .close(param.verify_outputs())
+            .with_context(|| "Error closing fastq output")?;
+        if param.verify_outputs() {
+            info!("FASTQ outputs verified")
+        }
+
+        let mut wrt = open_output_file("read_stats.txt", param)
+            .with_context(|| "Error opening read statistics report")?;
+        self.read_stats
+            .write_report(&mut wrt)
+            .and_then(|_| close_file(wrt))
+            .with_context(|| "Error writing read statistics report")?;
+
+        // Write base modification report if any reads had modification calls
+        if self.mod_stats.n_invalid() > 0 {
+            warn!("{} FASTQ records had malformed base modification (MM/ML) tags", self.mod_stats.n_invalid())
+        }
+        if self.mod_stats.n_reads() > 0 {
+            info!("{} reads with base modification calls", self.mod_stats.n_reads());
+            let mut wrt = open_output_file("mod_bases.txt", param)
+                .with_context(|| "Error opening base modification report")?;
+            self.mod_stats
+                .write_report(&mut wrt)
+                .and_then(|_| close_file(wrt))
+                .with_context(|| "Error writing base modification report")?;
+        }
+
+        if let Some(r) = self.id_report {
+            let mut wrt = open_output_file("id_match.txt", param)
+                .with_context(|| "Error opening ID match report")?;
+            r.write_report(&mut wrt)
+                .and_then(|_| close_file(wrt))
+                .with_context(|| "Error writing ID match report")?;
+        }
+
+        // Write contamination report
+        if let Some(ct) = self.contam_counts {
+            let mut wrt = open_output_file("contamination.txt", param)
+                .with_context(|| "Error opening contamination report")?;
+            ct.write_report(&mut wrt)
+                .and_then(|_| close_file(wrt))
+                .with_context(|| "Error writing contamination report")?;
+        }
+
+        Ok(())
+    }
+}
+
+// Classify the first n reads from the PAF file and print a summary to stdout.  If a FASTQ
+// file is given, the first n reads from the FASTQ file are checked against the
+// classifications.  No output files are written
+fn preview(param: &Param, summary: &mut RunSummary, n: usize) -> anyhow::Result<()> {
+    info!("Preview of first {} reads", n);
+    let output: compressor::BoxWriter = Box::new(io::sink());
+    let mut sink = ResultSink {
+        output: BufWriter::new(output),
+        cache: None,
+        cls_out: None,
+        summary,
+        metrics: None,
+        read_hash: param.fastq_file().map(|_| ClassStore::new(param.cut_sites(), None, None)),
+        channels: None,
+        hotspots: None,
+        no_sites: None,
+        end_profile: None,
+        second_site: None,
+        igv: None,
+        chains: None,
+        alleles: None,
+        seq_summary: None,
+        read_groups: None,
+        length_bins: None,
+        split: None,
+        deferred: None,
+    };
+    classify_paf(param, &mut sink, &mut FlushTimer::new(param), None)?;
+
+    let stdout = io::stdout();
+    let mut wrt = stdout.lock();
+    sink.summary
+        .write_status_report(&mut wrt)
+        .with_context(|| "Error writing preview")?;
+    if param.cut_sites().is_some() {
+        writeln!(wrt).with_context(|| "Error writing preview")?;
+        sink.summary
+            .write_site_report(param, &mut wrt)
+            .with_context(|| "Error writing preview")?;
+    }
+    if let (Some(fq), Some(rh)) = (param.fastq_file(), sink.read_hash.as_ref()) {
+        let mut fq_file =
+            FastqFile::open(fq, param.compressor(), FastqThreads::new(param, 0).decompress).with_context(|| "Error opening fastq file")?;
+        fq_file.set_max_record(param.max_fastq_record());
+        skip_fastq(&mut fq_file, param.skip_reads())?;
+        let exact = IdMatch::Exact;
+        let lookup = IdLookup::new(param.id_match().unwrap_or(&exact), rh.hash());
+        let (mut n_fq, mut n_found) = (0, 0);
+        while n_fq < n
+            && fq_file
+                .next_read()
+                .with_context(|| "Error reading from fastq file")?
+        {
+            if !param.read_filter().map(|f| f.pass(fq_file.read_id())).unwrap_or(true) {
+                continue;
+            }
+            n_fq += 1;
+            if lookup.get(fq_file.read_id()).value().is_some() {
+                n_found += 1
+            }
+        }
+        writeln!(
+            wrt,
+            "\n{} of the first {} FASTQ reads were found in the classified PAF reads",
+            n_found, n_fq
+        )
+        .with_context(|| "Error writing preview")?;
+    }
+    Ok(())
+}
+
+fn main() {
+    if let Err(e) = demult() {
+        eprintln!("Error: {:?}", e);
+        let code = match error::Error::kind_of(e.as_ref()) {
+            Some(error::ErrorKind::Parse) => PARSE_ERROR_EXIT_CODE,
+            Some(error::ErrorKind::Config) => CONFIG_ERROR_EXIT_CODE,
+            _ => 1,
+        };
+        std::process::exit(code)
+    }
+}
+
+fn demult() -> anyhow::Result<()> {
+    let run_info = RunInfo::new();
+    // Process command line arguments
+    let mut param = match cli::process_cli().with_context(|| "ont_demult initialization failed")? {
+        Some(p) => p,
+        None => return Ok(()),
+    };
+    if param.qlen_from() == QlenFrom::Fastq {
+        let fl = FastqLengths::read(&param).with_context(|| "Error collecting FASTQ read lengths")?;
+        param.set_fastq_lengths(fl);
+    }
+    if param.auto_offset() {
+        let x = estimate_offsets(&param).with_context(|| "Error estimating read start offsets")?;
+        param.set_start_offset(x);
+    }
+    if param.verify_deterministic() {
+        verify_deterministic(&param).with_context(|| "Error verifying deterministic classification")?;
+    }
+
+    let mut summary = RunSummary::default();
+    if let Some(n) = param.preview() {
+        return preview(&param, &mut summary, n);
+    }
+    // Skip the run if the outputs are up to date with the inputs and options
+    let stdin = param.paf_file().is_none() && param.align_reference().is_none() && param.classification_file().is_none();
+    let stamp = if param.skip_if_current() && stdin {
+        warn!("Option --skip-if-current can not be used with PAF input from stdin");
+        None
+    } else if param.skip_if_current() {
+        let stamp = Stamp::new(&param).with_context(|| "Error checking input files")?;
+        if stamp.is_current(&param) {
+            info!("Outputs with prefix {} are up to date: skipping run", param.prefix());
+            return Ok(());
+        }
+        Some(stamp)
+    } else {
+        None
+    };
+    Stamp::remove(&param).with_context(|| "Error removing stamp file")?;
+    let res = run(&param, &mut summary);
+    let err = res.as_ref().err().map(|e| format!("{:#}", e));
+    if let Err(e) = run_info.write(&param, &summary, err.as_deref()) {
+        warn!("Error writing run information file: {}", e)
+    }
+    if let (Ok(_), Some(s), None) = (&res, stamp, summary.stopped()) {
+        if let Err(e) = s.write(&param) {
+            warn!("Error writing stamp file: {}", e)
+        }
+    }
 
-            if let Some(wrt) = match mr {
-                MapResult::Unmapped(_) => ofiles.unmapped.as_mut(),
-                MapResult::LowMapq(_) => ofiles.low_mapq.as_mut(),
-                MapResult::Matched(m) => ofiles.site_hash.get_mut(m.site.name.as_str()),
-                _ => ofiles.unmatched.as_mut(),
-            } {
-                fq_file
-                    .write_rec(wrt)
-                    .with_context(|| "Error writing to fastq output")?
+    // Notify external processes of completion (or failure) if requested
+    if param.on_complete_cmd().is_some() || param.on_complete_url().is_some() {
+        let json = summary.to_json(&param, err.as_deref()).to_string();
+        if let Some(cmd) = param.on_complete_cmd() {
+            if let Err(e) = notify::run_cmd(cmd, &json) {
+                warn!("Error running completion command: {}", e)
+            }
+        }
+        if let Some(url) = param.on_complete_url() {
+            if let Err(e) = notify::post_url(url, &json) {
+                warn!("Error sending completion notification: {}", e)
             }
         }
     }
+    // Distinct exit status if the run completed but an input was empty, or if the run was
+    // stopped early by a safety limit
+    if res.is_ok() && !summary.empty_inputs().is_empty() {
+        std::process::exit(EMPTY_INPUT_EXIT_CODE)
+    }
+    if res.is_ok() && summary.stopped().is_some() {
+        std::process::exit(PARTIAL_RUN_EXIT_CODE)
+    }
+    res
+}
+
+fn run(param: &Param, summary: &mut RunSummary) -> anyhow::Result<()> {
+    // Start metrics server if requested
+    let metrics = param
+        .metrics_port()
+        .map(|port| Metrics::serve(param.metrics_bind(), port))
+        .transpose()
+        .with_context(|| "Error starting metrics server")?;
+
+    // Hash to store read classifications if we will be demultiplexing a FASTQ
+    let read_hash = param
+        .fastq_file()
+        .map(|_| ClassStore::new(param.cut_sites(), param.memory_budget(), param.tmp_dir()));
+
+    // Main output file (with --defer-results, this is opened after the routing stage)
+    debug!("Opening main output");
+    let mut output = if param.combined_results() && !param.defer_results() {
+        open_output_file("res.txt", param)
+            .with_context(|| "Error opening output file")?
+    } else {
+        let output: compressor::BoxWriter = Box::new(io::sink());
+        BufWriter::new(output)
+    };
+    writeln!(output, "{}", RESULTS_HEADER)
+    .with_context(|| "Error writing to output file")?;
+    let deferred = if param.defer_results() {
+        Some(DeferredResults::new(param.tmp_dir()).with_context(|| "Error creating deferred results file")?)
+    } else {
+        None
+    };
+
+    let mut sink = ResultSink {
+        output,
+        cache: None,
+        cls_out: None,
+        summary,
+        metrics,
+        read_hash,
+        channels: if param.channel_report() {
+            Some(ChannelCounts::default())
+        } else {
+            None
+        },
+        hotspots: if param.hotspots().is_some() || param.suggest_sites().is_some() {
+            Some(Hotspots::default())
+        } else {
+            None
+        },
+        no_sites: param.no_sites_output().then(|| NoSiteCounts::new(param.cut_sites())),
+        end_profile: param
+            .cut_sites()
+            .zip(param.end_profile())
+            .map(|(cs, bin_size)| EndProfile::new(cs, bin_size)),
+        second_site: match param.cut_sites().filter(|_| param.second_site()) {
+            Some(cs) => Some(
+                open_output_file("second_site.txt", param)
+                    .and_then(|wrt| SecondSite::new(cs, wrt))
+                    .with_context(|| "Error opening second site report")?,
+            ),
+            None => None,
+        },
+        igv: match param.cut_sites().filter(|_| param.bed() || param.bedgraph()) {
+            Some(cs) => Some(IgvTracks::new(cs, param).with_context(|| "Error opening BED file")?),
+            None => None,
+        },
+        chains: if param.chain_out() {
+            Some(open_output_file("chains.paf", param).with_context(|| "Error opening chain file")?)
+        } else {
+            None
+        },
+        alleles: param.snps().and(param.fastq_file()).map(|_| HashMap::new()),
+        seq_summary: param.seq_summary(),
+        read_groups: param.read_groups(),
+        length_bins: param.length_bins(),
+        split: param.split_results().then(|| SplitResults::new(param)),
+        deferred,
+    };
+    let mut flush_timer = FlushTimer::new(param);
+
+    if let Some(file) = param.classification_out() {
+        info!("Writing classifications to {}", file);
+        sink.cls_out = Some(
+            cache::classification_writer(file, param).with_context(|| "Error creating classification file")?,
+        );
+    }
+
+    // With --synced, the FASTQ file is routed while the PAF file is being classified
+    let mut router = match param.fastq_file().filter(|_| param.synced()) {
+        Some(fq) => Some(FastqRouter::open(fq, param)?),
+        None => None,
+    };
+
+    // Classification stage.  Read classifications are either read from a previous run or
+    // from the cache (if available), or obtained from the PAF file
+    if let Some(file) = param.classification_file() {
+        info!("Reading classifications from {}", file);
+        let rdr = param
+            .compressor()
+            .reader(Some(file))
+            .with_context(|| "Error opening classification file")?;
+        read_classifications(file, rdr, param, &mut sink, &mut flush_timer)
+            .with_context(|| format!("Error reading classifications from {}", file))?
+    } else {
+        let cache = param
+            .cache_dir()
+            .map(|d| ClassCache::new(d, param))
+            .transpose()
+            .with_context(|| "Error setting up classification cache")?
+            .flatten();
+
+        match cache {
+            Some(c) if c.is_present() => {
+                info!("Reading classifications from cache {}", c.path().display());
+                let rdr = c.reader(param).with_context(|| "Error opening cache file")?;
+                read_classifications(c.path(), rdr, param, &mut sink, &mut flush_timer)
+                    .with_context(|| format!("Error reading from cache file {}", c.path().display()))?
+            }
+            c => {
+                if let Some(c) = c {
+                    info!("Writing classifications to cache {}", c.path().display());
+                    sink.cache = Some(c.writer(param).with_context(|| "Error creating cache file")?);
+                }
+                classify_paf(param, &mut sink, &mut flush_timer, router.as_mut())?;
+                if let Some(c) = sink.cache.take() {
+                    // Incomplete classifications are not cached (dropping the writer removes the entry)
+                    if sink.summary.stopped().is_some() {
+                        info!("Classifications of partial run not added to cache")
+                    } else {
+                        c.finish().with_context(|| "Error writing to cache file")?;
+                    }
+                }
+            }
+        }
+    }
+    if let Some(c) = sink.cls_out.take() {
+        c.finish().with_context(|| "Error writing to classification file")?;
+    }
+
+    // Routing stage (with --synced, only reads not in the PAF input are left to route)
+    if let Some(fq) = param.fastq_file() {
+        let mut router = match router {
+            Some(r) => r,
+            None => FastqRouter::open(fq, param)?,
+        };
+        if sink.read_hash.as_ref().map(|rh| rh.spilled()).unwrap_or(false) {
+            router.route_partitions(param, &mut sink, &mut flush_timer)?
+        } else {
+            router.route(param, &mut sink, &mut flush_timer, false)?
+        }
+        router.finish(param, &mut sink)?
+    }
+    sink.write_deferred(param)?;
+    sink.close()?;
+
+    if let Some(ss) = sink.second_site.take() {
+        ss.finish().with_context(|| "Error writing second site report")?
+    }
+    if let Some(wrt) = sink.chains.take() {
+        close_file(wrt).with_context(|| "Error writing to chain file")?
+    }
+    if let Some(t) = sink.igv.take() {
+        t.finish(param).with_context(|| "Error writing BED/bedgraph files")?
+    }
+
+    // Per run statistics
+    if param.read_groups().is_some() {
+        let mut wrt = open_output_file("runs.txt", param)
+            .with_context(|| "Error opening run report")?;
+        sink.summary
+            .write_run_report(&mut wrt)
+            .and_then(|_| close_file(wrt))
+            .with_context(|| "Error writing run report")?;
+        if param.cut_sites().is_some() {
+            let mut wrt = open_output_file("run_sites.txt", param)
+                .with_context(|| "Error opening run site report")?;
+            sink.summary
+                .write_run_site_report(param, &mut wrt)
+                .and_then(|_| close_file(wrt))
+                .with_context(|| "Error writing run site report")?;
+        }
+    }
+
+    // Statistics by read length
+    if param.length_bins().is_some() {
+        let mut wrt = open_output_file("length_bins.txt", param)
+            .with_context(|| "Error opening read length report")?;
+        sink.summary
+            .write_length_report(&mut wrt)
+            .and_then(|_| close_file(wrt))
+            .with_context(|| "Error writing read length report")?;
+        if param.cut_sites().is_some() {
+            let mut wrt = open_output_file("length_bin_barcodes.txt", param)
+                .with_context(|| "Error opening read length barcode report")?;
+            sink.summary
+                .write_length_barcode_report(param, &mut wrt)
+                .and_then(|_| close_file(wrt))
+                .with_context(|| "Error writing read length barcode report")?;
+        }
+    }
+
+    // Statistics by MAPQ
+    if param.mapq_bins().is_some() {
+        let mut wrt = open_output_file("mapq_bins.txt", param)
+            .with_context(|| "Error opening MAPQ report")?;
+        sink.summary
+            .write_mapq_report(&mut wrt)
+            .and_then(|_| close_file(wrt))
+            .with_context(|| "Error writing MAPQ report")?;
+    }
+
+    // Per channel statistics
+    if let Some(cc) = sink.channels.as_ref() {
+        let mut wrt = open_output_file("channels.txt", param)
+            .with_context(|| "Error opening channel report")?;
+        cc.write_report(&mut wrt)
+            .and_then(|_| close_file(wrt))
+            .with_context(|| "Error writing channel report")?;
+    }
+
+    // Hotspots of unmatched reads
+    if let (Some(h), Some(n)) = (sink.hotspots.as_ref(), param.hotspots()) {
+        let mut wrt = open_output_file("hotspots.txt", param)
+            .with_context(|| "Error opening hotspot report")?;
+        h.write_report(n, &mut wrt)
+            .and_then(|_| close_file(wrt))
+            .with_context(|| "Error writing hotspot report")?;
+    }
+
+    // Reads mapping to contigs with no cut sites
+    if let Some(ns) = sink.no_sites.as_ref() {
+        let mut wrt = open_output_file("no_sites.txt", param)
+            .with_context(|| "Error opening no sites report")?;
+        ns.write_report(&mut wrt)
+            .and_then(|_| close_file(wrt))
+            .with_context(|| "Error writing no sites report")?;
+    }
+
+    // End position profile of matched reads
+    if let Some(ep) = sink.end_profile.as_ref() {
+        let mut wrt = open_output_file("end_profile.txt", param)
+            .with_context(|| "Error opening end profile report")?;
+        ep.write_report(&mut wrt)
+            .and_then(|_| close_file(wrt))
+            .with_context(|| "Error writing end profile report")?;
+    }
+
+    // Candidate cut sites from hotspots
+    if let (Some(h), Some(file)) = (sink.hotspots.as_ref(), param.suggest_sites()) {
+        let mut wrt = param
+            .compressor()
+            .writer(file, false)
+            .with_context(|| "Error opening suggested sites file")?;
+        let n = h
+            .write_suggestions(param.suggest_min_reads(), param.cut_sites(), &mut wrt)
+            .and_then(|n| wrt.close().map(|_| n))
+            .with_context(|| "Error writing suggested sites file")?;
+        info!("Wrote {} candidate cut sites to {}", n, file);
+    }
+
+    // Per site and per sample statistics
+    if let Some(cs) = param.cut_sites() {
+        sink.summary.check_balance(param);
+        let mut wrt = open_output_file("sites.txt", param)
+            .with_context(|| "Error opening site report")?;
+        sink.summary
+            .write_site_report(param, &mut wrt)
+            .and_then(|_| close_file(wrt))
+            .with_context(|| "Error writing site report")?;
+        if param.efficiency_report() {
+            let mut wrt = open_output_file("efficiency.txt", param)
+                .with_context(|| "Error opening cut efficiency report")?;
+            sink.summary
+                .write_efficiency_report(param, &mut wrt)
+                .and_then(|_| close_file(wrt))
+                .with_context(|| "Error writing cut efficiency report")?;
+        }
+        sink.summary.log_margin_usage(param);
+        sink.summary.log_start_offsets(param);
+        if param.margin_report() {
+            let mut wrt = open_output_file("margin.txt", param)
+                .with_context(|| "Error opening margin report")?;
+            sink.summary
+                .write_margin_report(param, &mut wrt)
+                .and_then(|_| close_file(wrt))
+                .with_context(|| "Error writing margin report")?;
+        }
+        if let Some(w) = param.targets_window() {
+            // (never compressed, as MinKNOW needs an uncompressed BED file)
+            let mut wrt = open_file("targets.bed", param, false)
+                .with_context(|| "Error opening target BED file")?;
+            cs.write_targets(w, &mut wrt)
+                .and_then(|_| close_file(wrt))
+                .with_context(|| "Error writing target BED file")?;
+        }
+        if !param.site_collisions().is_empty() {
+            let mut wrt = open_output_file("site_collisions.txt", param)
+                .with_context(|| "Error opening site collision report")?;
+            write_collision_report(param.site_collisions(), &mut wrt)
+                .and_then(|_| close_file(wrt))
+                .with_context(|| "Error writing site collision report")?;
+        }
+        if !cs.samples().is_empty() {
+            let mut wrt = open_output_file("samples.txt", param)
+                .with_context(|| "Error opening sample report")?;
+            sink.summary
+                .write_sample_report(param, &mut wrt)
+                .and_then(|_| close_file(wrt))
+                .with_context(|| "Error writing sample report")?;
+        }
+        if !cs.groups().is_empty() {
+            let mut wrt = open_output_file("groups.txt", param)
+                .with_context(|| "Error opening group report")?;
+            sink.summary
+                .write_group_report(param, &mut wrt)
+                .and_then(|_| close_file(wrt))
+                .with_context(|| "Error writing group report")?;
+        }
+    }
+
+    // JSON summary of the run
+    let mut wrt = open_output_file("summary.json", param)
+        .with_context(|| "Error opening JSON summary")?;
+    sink.summary
+        .write_json(param, &mut wrt)
+        .and_then(|_| close_file(wrt))
+        .with_context(|| "Error writing JSON summary")?;
 
     info!("Done");
 
@@ -0,0 +1,124 @@
+// Prometheus style metrics served over HTTP for monitoring long runs
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write as FmtWrite,
+    io::{self, Read, Write},
+    net::{IpAddr, TcpListener, TcpStream},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+// Timeout for reading the request and writing the response, so a stalled client can not
+// block the metrics server
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+// Escape a label value for the Prometheus text format (backslash, double quote and newline)
+fn escape_label(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[derive(Debug)]
+pub struct Metrics {
+    reads: Mutex<BTreeMap<String, u64>>, // Reads processed per category
+    bytes: Mutex<BTreeMap<String, u64>>, // Bytes written per output
+    start: Instant,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        Self {
+            reads: Mutex::new(BTreeMap::new()),
+            bytes: Mutex::new(BTreeMap::new()),
+            start: Instant::now(),
+        }
+    }
+
+    // Start metrics server listening on addr:port in a background thread
+    pub fn serve(addr: IpAddr, port: u16) -> io::Result<Arc<Self>> {
+        let listener = TcpListener::bind((addr, port))?;
+        info!("Serving metrics on {}", listener.local_addr()?);
+        let metrics = Arc::new(Self::new());
+        let m = metrics.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(s) => {
+                        if let Err(e) = m.respond(s) {
+                            debug!("Error responding to metrics request: {}", e)
+                        }
+                    }
+                    Err(e) => debug!("Error accepting metrics connection: {}", e),
+                }
+            }
+        });
+        Ok(metrics)
+    }
+
+    fn respond(&self, mut s: TcpStream) -> io::Result<()> {
+        s.set_read_timeout(Some(TIMEOUT))?;
+        s.set_write_timeout(Some(TIMEOUT))?;
+        // We serve the same page whatever the request, so just consume the request header
+        let mut buf = [0; 4096];
+        let _ = s.read(&mut buf)?;
+        let body = self.render();
+        write!(
+            s,
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        )?;
+        s.flush()
+    }
+
+    pub fn add_read(&self, category: &str) {
+        let mut h = self.reads.lock().unwrap();
+        match h.get_mut(category) {
+            Some(x) => *x += 1,
+            None => {
+                h.insert(category.to_owned(), 1);
+            }
+        }
+    }
+
+    pub fn add_bytes(&self, output: &str, n: usize) {
+        let mut h = self.bytes.lock().unwrap();
+        match h.get_mut(output) {
+            Some(x) => *x += n as u64,
+            None => {
+                h.insert(output.to_owned(), n as u64);
+            }
+        }
+    }
+
+    // Generate metrics in the Prometheus text exposition format
+    fn render(&self) -> String {
+        let mut s = String::new();
+        let reads = self.reads.lock().unwrap();
+        let _ = writeln!(s, "# HELP ont_demult_reads_total Reads processed per category");
+        let _ = writeln!(s, "# TYPE ont_demult_reads_total counter");
+        for (k, v) in reads.iter() {
+            let _ = writeln!(s, "ont_demult_reads_total{{category=\"{}\"}} {}", escape_label(k), v);
+        }
+        let total: u64 = reads.values().sum();
+        drop(reads);
+        let _ = writeln!(s, "# HELP ont_demult_bytes_written_total Bytes written per output file");
+        let _ = writeln!(s, "# TYPE ont_demult_bytes_written_total counter");
+        for (k, v) in self.bytes.lock().unwrap().iter() {
+            let _ = writeln!(s, "ont_demult_bytes_written_total{{output=\"{}\"}} {}", escape_label(k), v);
+        }
+        let elapsed = self.start.elapsed().as_secs_f64();
+        let _ = writeln!(s, "# HELP ont_demult_elapsed_seconds Time since start of run");
+        let _ = writeln!(s, "# TYPE ont_demult_elapsed_seconds gauge");
+        let _ = writeln!(s, "ont_demult_elapsed_seconds {:.3}", elapsed);
+        let _ = writeln!(s, "# HELP ont_demult_reads_per_second Mean read processing throughput");
+        let _ = writeln!(s, "# TYPE ont_demult_reads_per_second gauge");
+        let _ = writeln!(
+            s,
+            "ont_demult_reads_per_second {:.3}",
+            if elapsed > 0.0 { total as f64 / elapsed } else { 0.0 }
+        );
+        s
+    }
+}
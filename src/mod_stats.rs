@@ -0,0 +1,126 @@
+// Summary of base modification calls for each barcode
+//
+// Base modification calls are given by the MM and ML tags in the FASTQ header comments (from
+// samtools fastq -T MM,ML, dorado basecaller --emit-fastq or unaligned BAM input, see
+// BamToFastq).  The MM tag gives, for each modification (i.e., C+m), the positions of the called
+// bases as skips over the bases of that type, and the ML tag gives the probability (scaled to
+// 0-255) of each call, in the order of the positions in MM with the calls for combined
+// modifications (i.e., C+mh) interleaved.  For each barcode and modification the number of reads
+// with calls, the number of calls, the number of calls with probability >= 0.5 and the mean
+// call probability are collected.
+
+use std::{
+    collections::BTreeMap,
+    io::{self, Write},
+};
+
+#[derive(Debug, Default)]
+struct ModCounts {
+    reads: u64,
+    calls: u64,
+    modified: u64, // Calls with probability >= 0.5 (ML >= 128)
+    ml_sum: u64,   // Sum of ML values
+}
+
+#[derive(Debug, Default)]
+pub struct ModStats {
+    counts: BTreeMap<(String, String), ModCounts>, // Counts for each (barcode, modification)
+    n_reads: usize,                                // Reads with modification calls
+    n_invalid: usize,                              // Reads with malformed MM/ML tags
+}
+
+// Calls for one modification of a read: base and strand (i.e., C+), modification code (i.e., m),
+// number of calls, number of calls with probability >= 0.5 and sum of the ML values
+type ModCalls<'a> = (&'a str, &'a str, u64, u64, u64);
+
+// Parse the MM and ML tags of a read, returning None if the tags are malformed or the number of
+// ML values does not match the number of calls in MM
+fn parse_mods<'a>(mm: &'a str, ml: &str) -> Option<Vec<ModCalls<'a>>> {
+    // The ML tag is an array of uint8 (i.e., C,12,255,...)
+    let ml = match ml.strip_prefix("C,") {
+        Some(s) => s.split(',').map(|x| x.parse::<u8>().ok()).collect::<Option<Vec<_>>>()?,
+        None if ml == "C" => Vec::new(),
+        None => return None,
+    };
+    let mut v = Vec::new();
+    let mut k = 0;
+    for entry in mm.split(';').filter(|s| !s.is_empty()) {
+        let mut it = entry.split(',');
+        let head = it.next()?.trim_end_matches(['.', '?']);
+        // Base and strand (i.e., C+), followed by the modification codes: either a ChEBI
+        // number or one or more single letter codes
+        let (base, codes) = (head.get(..2)?, head.get(2..)?);
+        if !matches!(base.as_bytes()[1], b'+' | b'-') || codes.is_empty() {
+            return None;
+        }
+        let n_codes = if codes.bytes().all(|c| c.is_ascii_digit()) { 1 } else { codes.len() };
+        let mut n_pos = 0;
+        for d in it {
+            d.parse::<u64>().ok()?;
+            n_pos += 1
+        }
+        let calls = ml.get(k..k + n_pos * n_codes)?;
+        k += n_pos * n_codes;
+        for j in 0..n_codes {
+            let code = if n_codes == 1 { codes } else { &codes[j..j + 1] };
+            let (mut n_mod, mut sum) = (0, 0);
+            for x in calls.iter().skip(j).step_by(n_codes) {
+                n_mod += (*x >= 128) as u64;
+                sum += *x as u64;
+            }
+            v.push((base, code, n_pos as u64, n_mod, sum))
+        }
+    }
+    (k == ml.len()).then_some(v)
+}
+
+impl ModStats {
+    // Add the modification calls from the MM and ML tags of a read assigned to barcode
+    pub fn add(&mut self, barcode: &str, mm: &str, ml: &str) {
+        let Some(mods) = parse_mods(mm, ml) else {
+            self.n_invalid += 1;
+            return;
+        };
+        self.n_reads += 1;
+        for (base, code, calls, modified, ml_sum) in mods {
+            let key = (barcode.to_owned(), format!("{}{}", base, code));
+            let ct = self.counts.entry(key).or_default();
+            ct.reads += 1;
+            ct.calls += calls;
+            ct.modified += modified;
+            ct.ml_sum += ml_sum;
+        }
+    }
+
+    // Number of reads with modification calls
+    pub fn n_reads(&self) -> usize {
+        self.n_reads
+    }
+
+    // Number of reads with malformed MM/ML tags
+    pub fn n_invalid(&self) -> usize {
+        self.n_invalid
+    }
+
+    // Write the number of reads, calls and modified calls and the mean call probability for
+    // each barcode and modification.  The probability of an ML value x is taken as the centre
+    // of its range, (x + 0.5) / 256
+    pub fn write_report<W: Write>(&self, wrt: &mut W) -> io::Result<()> {
+        writeln!(wrt, "barcode\tmodification\treads\tcalls\tmodified calls\tprop. modified\tmean probability")?;
+        for ((barcode, m), ct) in self.counts.iter() {
+            let n = ct.calls.max(1) as f64;
+            writeln!(
+                wrt,
+                "{}\t{}\t{}\t{}\t{}\t{:.4}\t{:.4}",
+                barcode,
+                m,
+                ct.reads,
+                ct.calls,
+                ct.modified,
+                ct.modified as f64 / n,
+                (ct.ml_sum as f64 + 0.5 * ct.calls as f64) / (256.0 * n)
+            )?;
+        }
+        Ok(())
+    }
+}
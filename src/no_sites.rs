@@ -0,0 +1,63 @@
+// Reads mapping to contigs with no cut sites (--no-sites-output)
+//
+// With a cut file, a read that maps uniquely to a contig without any cut sites is classified
+// as Unmatched, and would normally be written to the unmatched output along with reads that
+// map near (but not to) a cut site.  These reads often represent off-target capture, so with
+// --no-sites-output they are written to a separate output and counted for each contig.
+// Reads classified as NoCutSites (i.e., from a classification override) are counted under *.
+
+use std::{
+    collections::HashMap,
+    io::{self, Write},
+};
+
+use crate::classify::MapResult;
+use crate::cut_site::CutSites;
+
+// Contig of read mapping to a contig with no cut sites (* for NoCutSites reads)
+pub fn no_sites_contig<'b>(mr: &'b MapResult, cut_sites: Option<&CutSites>) -> Option<&'b str> {
+    match mr {
+        MapResult::NoCutSites(_) => Some("*"),
+        MapResult::Unmatched(loc) if !cut_sites.map(|cs| cs.has_sites(loc.contig())).unwrap_or(false) => {
+            Some(loc.contig())
+        }
+        _ => None,
+    }
+}
+
+#[derive(Debug)]
+pub struct NoSiteCounts<'a> {
+    cut_sites: Option<&'a CutSites>,
+    counts: HashMap<String, (u64, u64)>, // Number of reads and bases for each contig
+}
+
+impl<'a> NoSiteCounts<'a> {
+    pub fn new(cut_sites: Option<&'a CutSites>) -> Self {
+        Self {
+            cut_sites,
+            counts: HashMap::new(),
+        }
+    }
+
+    pub fn add(&mut self, mr: &MapResult) {
+        if let Some(ctg) = no_sites_contig(mr, self.cut_sites) {
+            let e = match self.counts.get_mut(ctg) {
+                Some(e) => e,
+                None => self.counts.entry(ctg.to_owned()).or_default(),
+            };
+            e.0 += 1;
+            e.1 += mr.read_len() as u64;
+        }
+    }
+
+    // Write number of reads and bases for each contig, ordered by the number of reads
+    pub fn write_report<W: Write>(&self, wrt: &mut W) -> io::Result<()> {
+        writeln!(wrt, "contig\treads\tbases")?;
+        let mut v: Vec<_> = self.counts.iter().collect();
+        v.sort_unstable_by(|a, b| b.1 .0.cmp(&a.1 .0).then_with(|| a.0.cmp(b.0)));
+        for (ctg, (reads, bases)) in v {
+            writeln!(wrt, "{}\t{}\t{}", ctg, reads, bases)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,96 @@
+// Notification of external processes on completion of a run
+
+use std::{
+    io::{self, BufRead, BufReader, Error, Write},
+    net::{TcpStream, ToSocketAddrs},
+    process::{Command, Stdio},
+    time::Duration,
+};
+
+// Run command with the shell, passing the JSON summary on stdin
+pub fn run_cmd(cmd: &str, json: &str) -> io::Result<()> {
+    debug!("Running completion command {}", cmd);
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(cmd)
+        .stdin(Stdio::piped())
+        .spawn()?;
+    // If the command exits without reading all of its input, the write fails (EPIPE), but we
+    // still wait for the command so that it is not left as a zombie
+    let res = match child.stdin.take() {
+        Some(mut stdin) => stdin.write_all(json.as_bytes()),
+        None => Ok(()),
+    };
+    let status = child.wait()?;
+    if !status.success() {
+        Err(Error::other(format!("Completion command exited with {}", status)))
+    } else {
+        res.map_err(|e| Error::other(format!("Error writing summary to completion command: {}", e)))
+    }
+}
+
+// Connect to host:port, trying each address in turn with a timeout
+fn connect(host: &str, port: u16, timeout: Duration) -> io::Result<TcpStream> {
+    let mut err = Error::other(format!("Could not resolve host {}", host));
+    for addr in (host, port).to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(s) => return Ok(s),
+            Err(e) => err = e,
+        }
+    }
+    Err(err)
+}
+
+// Split url into host, port and path.  Only plain http urls are supported
+pub fn parse_url(url: &str) -> io::Result<(&str, u16, &str)> {
+    let s = url
+        .strip_prefix("http://")
+        .ok_or_else(|| Error::other(format!("Unsupported url {} (only http:// urls are supported)", url)))?;
+    let (host_port, path) = match s.find('/') {
+        Some(i) => (&s[..i], &s[i..]),
+        None => (s, "/"),
+    };
+    let (host, port) = match host_port.rsplit_once(':') {
+        Some((h, p)) => (
+            h,
+            p.parse::<u16>()
+                .map_err(|e| Error::other(format!("Invalid port in url {}: {}", url, e)))?,
+        ),
+        None => (host_port, 80),
+    };
+    if host.is_empty() {
+        Err(Error::other(format!("Missing host in url {}", url)))
+    } else {
+        Ok((host, port, path))
+    }
+}
+
+// POST the JSON summary to url
+pub fn post_url(url: &str, json: &str) -> io::Result<()> {
+    debug!("Sending completion notification to {}", url);
+    let (host, port, path) = parse_url(url)?;
+    let timeout = Duration::from_secs(30);
+    let mut s = connect(host, port, timeout)?;
+    s.set_read_timeout(Some(timeout))?;
+    s.set_write_timeout(Some(timeout))?;
+    write!(
+        s,
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        path,
+        host,
+        json.len(),
+        json
+    )?;
+    s.flush()?;
+    // Check status line of response
+    let mut status = String::new();
+    BufReader::new(s).read_line(&mut status)?;
+    match status.split_whitespace().nth(1) {
+        Some(code) if code.starts_with('2') => Ok(()),
+        _ => Err(Error::other(format!(
+            "Unexpected response from {}: {}",
+            url,
+            status.trim()
+        ))),
+    }
+}
@@ -0,0 +1,104 @@
+// Systematic offsets between read starts and matched cut sites
+//
+// The start offset of a matched read is the signed distance of the read start from the
+// matched site in the direction of the read (positive if the read starts after the site).  It
+// is measured from the position used for matching, so a read starting exactly at the site has
+// an offset of 0.  If most reads on a strand have the same non-zero offset (i.e., from adapter
+// remnants) this indicates a systematic shift, which can be corrected with --auto-offset.
+
+use std::collections::HashMap;
+
+use serde_json::{json, Value};
+
+use crate::paf::{Match, Strand};
+
+// Minimum number of matched reads on a strand for a shift to be detected
+const MIN_READS: u64 = 100;
+
+// Reads with offsets within this distance of the mode are counted as supporting it
+const WINDOW: i64 = 2;
+
+// Minimum proportion of reads supporting the modal offset for a shift to be detected
+const MIN_SUPPORT: f64 = 0.5;
+
+const STRANDS: [&str; 2] = ["+", "-"];
+
+#[derive(Debug, Default)]
+pub struct OffsetCounts {
+    counts: [HashMap<i64, u64>; 2], // Matched reads per start offset for the plus and minus strands
+}
+
+impl OffsetCounts {
+    pub fn add(&mut self, m: &Match) {
+        let (ix, off) = match m.strand() {
+            Strand::Plus => (0, m.start() as i64 - m.site.pos as i64),
+            Strand::Minus => (1, m.site.pos as i64 - m.start() as i64),
+        };
+        *self.counts[ix].entry(off).or_insert(0) += 1
+    }
+
+    // Modal offset for strand ix (the offset with the most reads within WINDOW of it), with
+    // the number of reads supporting it and the total number of reads
+    fn mode(&self, ix: usize) -> Option<(i64, u64, u64)> {
+        let h = &self.counts[ix];
+        let total = h.values().sum();
+        let support = |off: i64| -> u64 { (off - WINDOW..=off + WINDOW).filter_map(|x| h.get(&x)).sum() };
+        h.keys()
+            .map(|off| (*off, support(*off)))
+            .max_by_key(|(off, n)| (*n, h[off], -off.abs()))
+            .map(|(off, n)| (off, n, total))
+    }
+
+    // Systematic shift for strand ix (0 if no consistent shift is detected)
+    fn shift(&self, ix: usize) -> i64 {
+        match self.mode(ix) {
+            Some((off, support, total))
+                if total >= MIN_READS && support as f64 >= MIN_SUPPORT * total as f64 =>
+            {
+                off
+            }
+            _ => 0,
+        }
+    }
+
+    // Systematic shifts for the plus and minus strands
+    pub fn shifts(&self) -> [i64; 2] {
+        [self.shift(0), self.shift(1)]
+    }
+
+    // Log the modal offsets, warning about systematic shifts that have not been corrected.
+    // applied is the correction used for classification
+    pub fn log_report(&self, applied: [i64; 2]) {
+        for (ix, strand) in STRANDS.iter().enumerate() {
+            if let Some((off, support, total)) = self.mode(ix) {
+                info!(
+                    "Modal start offset for {} strand matched reads: {} bp ({} of {} reads within {} bp)",
+                    strand, off, support, total, WINDOW
+                );
+                let shift = self.shift(ix);
+                if shift != 0 && applied[ix] == 0 {
+                    warn!(
+                        "Systematic start offset of {} bp detected for {} strand reads (use --auto-offset to correct)",
+                        shift, strand
+                    )
+                }
+            }
+        }
+    }
+
+    pub fn to_json(&self, applied: [i64; 2]) -> Value {
+        let v: serde_json::Map<_, _> = STRANDS
+            .iter()
+            .enumerate()
+            .filter_map(|(ix, strand)| {
+                self.mode(ix).map(|(off, support, total)| {
+                    (
+                        strand.to_string(),
+                        json!({"mode": off, "support": support, "reads": total, "correction": applied[ix]}),
+                    )
+                })
+            })
+            .collect();
+        Value::Object(v)
+    }
+}
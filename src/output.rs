@@ -1,47 +1,518 @@
-use std::collections::HashMap;
-use std::io::{self, BufWriter};
-
-use compress_io::{
-    compress::{CompressIo, Writer},
-    compress_type::CompressType
-};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Error, Write};
+use std::mem;
+use std::path::PathBuf;
+use std::sync::mpsc::{self, SyncSender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
+use crate::classify::Category;
+use crate::compressor::{BoxWriter, Compressor};
+use crate::cut_site::Site;
+use crate::fastq::FastqRec;
 use crate::params::Param;
+use crate::tmp_dir::TmpDir;
+use crate::snp::Allele;
+
+pub type OutWriter = BufWriter<BoxWriter>;
+
+// Path of output file (including the .gz suffix if compress is set)
+pub fn output_path<S: AsRef<str>>(name: S, param: &Param, compress: bool) -> String {
+    if compress {
+        format!("{}_{}.gz", param.prefix(), name.as_ref())
+    } else {
+        format!("{}_{}", param.prefix(), name.as_ref())
+    }
+}
 
-pub fn open_output_file<S: AsRef<str>>(name: S, param: &Param) -> io::Result<BufWriter<Writer>> {
+// Open output file PREFIX_name (compressed if compress is set)
+pub fn open_file<S: AsRef<str>>(name: S, param: &Param, compress: bool) -> io::Result<OutWriter> {
     let fname = format!("{}_{}", param.prefix(), name.as_ref());
-    let mut c = CompressIo::new();
-    if param.compress() {
-        c.ctype(CompressType::Gzip);
+    param
+        .compressor()
+        .writer(fname, compress)
+        .map(|w| BufWriter::with_capacity(param.write_buffer_size(), w))
+}
+
+// Flush and close output file, returning any error from completing the output
+pub fn close_file(wrt: OutWriter) -> io::Result<()> {
+    wrt.into_inner().map_err(|e| e.into_error())?.close()
+}
+
+// Open results file or report (compressed with --compress or --compress-results)
+pub fn open_output_file<S: AsRef<str>>(name: S, param: &Param) -> io::Result<OutWriter> {
+    open_file(name, param, param.compress_results())
+}
+
+// Header line for the results file
+pub const RESULTS_HEADER: &str = "read_name\tmatch_status\tcut_site/contig\tbarcode\tstrand\tstart\tend\tlength\tunused\tprop. unused\tanchor\tsplits";
+
+// Results tables for each match status (--split-results).  The file for a status
+// (<prefix>_res_<status>.txt) is opened when the first read with that status is seen
+pub struct SplitResults<'a> {
+    param: &'a Param,
+    files: HashMap<Category, OutWriter>,
+}
+
+impl<'a> SplitResults<'a> {
+    pub fn new(param: &'a Param) -> Self {
+        Self {
+            param,
+            files: HashMap::new(),
+        }
+    }
+
+    pub fn write(&mut self, cat: Category, line: &str) -> io::Result<()> {
+        let wrt = match self.files.get_mut(&cat) {
+            Some(w) => w,
+            None => {
+                let name = format!("res_{}.txt", cat.name().to_ascii_lowercase());
+                let mut w = open_output_file(name, self.param)?;
+                writeln!(w, "{}", RESULTS_HEADER)?;
+                self.files.entry(cat).or_insert(w)
+            }
+        };
+        wrt.write_all(line.as_bytes())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        for w in self.files.values_mut() {
+            w.flush()?
+        }
+        Ok(())
+    }
+
+    pub fn close(&mut self) -> io::Result<()> {
+        for (_, w) in self.files.drain() {
+            close_file(w)?
+        }
+        Ok(())
+    }
+}
+
+// Results lines held back until classification is final (--defer-results).  Lines are stored
+// with their match status code in a file in a temporary directory (created under tmp_base, or
+// $TMPDIR if not set), and are replayed to the results files after the routing stage
+pub struct DeferredResults {
+    _dir: TmpDir,
+    path: PathBuf,
+    wrt: BufWriter<File>,
+}
+
+impl DeferredResults {
+    pub fn new(tmp_base: Option<&str>) -> io::Result<Self> {
+        let dir = TmpDir::new(tmp_base)?;
+        let path = dir.path().join("deferred_res.txt");
+        let wrt = BufWriter::new(File::create(&path)?);
+        Ok(Self { _dir: dir, path, wrt })
+    }
+
+    pub fn write(&mut self, cat: Category, line: &str) -> io::Result<()> {
+        write!(self.wrt, "{}\t{}", cat.code(), line)
+    }
+
+    // Pass the stored lines (in the order written) to f, removing the file afterwards
+    pub fn replay<F>(self, mut f: F) -> anyhow::Result<()>
+    where
+        F: FnMut(Category, &str) -> anyhow::Result<()>,
+    {
+        let Self { _dir, path, wrt } = self;
+        wrt.into_inner().map_err(|e| e.into_error())?;
+        let mut rdr = BufReader::new(File::open(&path)?);
+        let mut buf = String::new();
+        while rdr.read_line(&mut buf)? > 0 {
+            let (code, line) = buf
+                .split_once('\t')
+                .and_then(|(c, l)| c.parse().ok().and_then(Category::from_code).map(|c| (c, l)))
+                .ok_or_else(|| anyhow!("Corrupt deferred results file {}", path.display()))?;
+            f(code, line)?;
+            buf.clear();
+        }
+        fs::remove_file(&path)?;
+        Ok(())
+    }
+}
+
+// Timer to trigger periodic flushing of output files
+pub struct FlushTimer {
+    interval: Option<Duration>,
+    last: Instant,
+}
+
+impl FlushTimer {
+    pub fn new(param: &Param) -> Self {
+        Self {
+            interval: param.flush_interval().map(Duration::from_secs),
+            last: Instant::now(),
+        }
+    }
+
+    // Returns true (and resets the timer) if a flush is due
+    pub fn due(&mut self) -> bool {
+        match self.interval {
+            Some(d) if self.last.elapsed() >= d => {
+                self.last = Instant::now();
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+// Size of the blocks of records passed to the writer thread of a FASTQ output
+const THREAD_BLOCK_SIZE: usize = 1 << 16;
+
+// Messages to the writer thread of a FASTQ output
+enum OutMsg {
+    Data(u64, Vec<u8>), // Block of records from a batch
+    End(u64),           // End of the records from a batch
+    Flush,
+}
+
+// Writer thread for a FASTQ output (see FastqOut::start_thread()).  Records are collected in buf
+// and passed to the thread in blocks, labelled with the number of the batch of input records
+// (see pipeline.rs) that they come from
+struct OutThread {
+    buf: Vec<u8>,
+    batch: u64,
+    tx: SyncSender<OutMsg>,
+    handle: JoinHandle<io::Result<OutWriter>>,
+}
+
+// Write the blocks received by a writer thread.  Blocks from the next batch in input order are
+// written immediately, and blocks from later batches are held until all previous batches have
+// been ended.  If the batches are not used (all blocks are from batch 0 and no batch is ended)
+// then all blocks are written as they are received
+fn write_blocks(wrt: &mut OutWriter, rx: mpsc::Receiver<OutMsg>) -> io::Result<()> {
+    let mut next = 0;
+    let mut pending: BTreeMap<u64, Vec<u8>> = BTreeMap::new();
+    let mut ended = BTreeSet::new();
+    for msg in rx {
+        match msg {
+            OutMsg::Data(batch, buf) if batch == next => wrt.write_all(&buf)?,
+            OutMsg::Data(batch, buf) => pending.entry(batch).or_default().extend_from_slice(&buf),
+            OutMsg::End(batch) => {
+                ended.insert(batch);
+                while ended.remove(&next) {
+                    next += 1;
+                    if let Some(buf) = pending.remove(&next) {
+                        wrt.write_all(&buf)?
+                    }
+                }
+            }
+            OutMsg::Flush => wrt.flush()?,
+        }
+    }
+    Ok(())
+}
+
+// FASTQ output file, keeping count of the records and bytes written so that the file can
+// be verified after it has been closed
+pub struct FastqOut {
+    path: String,
+    wrt: OutWriter,
+    records: u64,
+    bytes: u64,
+    thread: Option<OutThread>,
+}
+
+impl FastqOut {
+    // Open FASTQ output (compressed with --compress or --compress-fastq)
+    fn open<S: AsRef<str>>(name: S, param: &Param) -> io::Result<Self> {
+        Ok(Self {
+            path: output_path(&name, param, param.compress_fastq()),
+            wrt: open_file(name, param, param.compress_fastq())?,
+            records: 0,
+            bytes: 0,
+            thread: None,
+        })
+    }
+
+    // Write record (see FastqRec::write_rec()), returning the number of bytes written
+    pub fn write_rec(
+        &mut self,
+        rec: FastqRec,
+        rc: bool,
+        flag: Option<&str>,
+        tags: Option<&str>,
+    ) -> io::Result<usize> {
+        let n = match self.thread.as_mut() {
+            Some(t) => {
+                let n = rec.write_rec(&mut t.buf, rc, flag, tags)?;
+                if t.buf.len() >= THREAD_BLOCK_SIZE {
+                    self.send_block()?
+                }
+                n
+            }
+            None => rec.write_rec(&mut self.wrt, rc, flag, tags)?,
+        };
+        self.records += 1;
+        self.bytes += n as u64;
+        Ok(n)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        match self.thread.is_some() {
+            true => self.send_block().and_then(|_| self.send(OutMsg::Flush)),
+            false => self.wrt.flush(),
+        }
+    }
+
+    // Hand the writer over to a separate thread.  Until stop_thread() is called, records are
+    // collected in blocks that are written by the thread
+    fn start_thread(&mut self) -> io::Result<()> {
+        if self.thread.is_some() {
+            return Ok(());
+        }
+        let empty: BoxWriter = Box::new(io::sink());
+        let mut wrt = mem::replace(&mut self.wrt, BufWriter::new(empty));
+        let (tx, rx) = mpsc::sync_channel(4);
+        let handle = thread::Builder::new()
+            .name(format!("write {}", self.path))
+            .spawn(move || write_blocks(&mut wrt, rx).map(|_| wrt))?;
+        self.thread = Some(OutThread {
+            buf: Vec::with_capacity(THREAD_BLOCK_SIZE),
+            batch: 0,
+            tx,
+            handle,
+        });
+        Ok(())
+    }
+
+    // Send message to the writer thread (if running)
+    fn send(&mut self, msg: OutMsg) -> io::Result<()> {
+        if let Some(t) = self.thread.as_mut() {
+            if t.tx.send(msg).is_err() {
+                // The thread only exits early after a write error
+                self.stop_thread()?;
+                return Err(Error::other(format!("Writer thread for {} exited", self.path)));
+            }
+        }
+        Ok(())
+    }
+
+    // Pass the collected records to the writer thread
+    fn send_block(&mut self) -> io::Result<()> {
+        match self.thread.as_mut() {
+            Some(t) if !t.buf.is_empty() => {
+                let buf = mem::replace(&mut t.buf, Vec::with_capacity(THREAD_BLOCK_SIZE));
+                let batch = t.batch;
+                self.send(OutMsg::Data(batch, buf))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // Set the batch for the following records (with --ordered)
+    fn start_batch(&mut self, batch: u64) -> io::Result<()> {
+        self.send_block()?;
+        if let Some(t) = self.thread.as_mut() {
+            t.batch = batch
+        }
+        Ok(())
+    }
+
+    // Mark the end of the records for the current batch (with --ordered)
+    fn end_batch(&mut self) -> io::Result<()> {
+        self.send_block()?;
+        match self.thread.as_ref().map(|t| t.batch) {
+            Some(batch) => self.send(OutMsg::End(batch)),
+            None => Ok(()),
+        }
+    }
+
+    // Wait for the writer thread to write the remaining records and take back the writer
+    fn stop_thread(&mut self) -> io::Result<()> {
+        if let Some(OutThread { buf, batch, tx, handle }) = self.thread.take() {
+            if !buf.is_empty() {
+                let _ = tx.send(OutMsg::Data(batch, buf));
+            }
+            drop(tx);
+            self.wrt = handle
+                .join()
+                .map_err(|_| Error::other(format!("Writer thread for {} panicked", self.path)))??;
+        }
+        Ok(())
+    }
+
+    // Close file and, if verify is set, re-read it and check that the number of records and
+    // bytes match what was written.  Compressed files are read with the internal gzip
+    // implementation, which also checks the CRC and length in the gzip trailer
+    fn close(mut self, verify: bool) -> io::Result<()> {
+        self.stop_thread()?;
+        let Self {
+            path,
+            wrt,
+            records,
+            bytes,
+            ..
+        } = self;
+        // Closing the writer finishes the compressed stream (waiting for any compression
+        // thread or external compression process to exit)
+        close_file(wrt)?;
+        if verify {
+            let mut rdr = Compressor::Internal
+                .reader(Some(&path))
+                .map_err(|e| Error::other(format!("Error opening {} for verification: {}", path, e)))?;
+            let (mut nl, mut nb) = (0, 0);
+            let mut buf = Vec::new();
+            loop {
+                buf.clear();
+                let l = rdr
+                    .read_until(b'\n', &mut buf)
+                    .map_err(|e| Error::other(format!("Error verifying {}: {}", path, e)))?;
+                if l == 0 {
+                    break;
+                }
+                nl += 1;
+                nb += l as u64;
+            }
+            if nl != records * 4 || nb != bytes {
+                return Err(Error::other(format!(
+                    "Verification failed for {}: wrote {} records ({} bytes) but read back {} lines ({} bytes)",
+                    path, records, bytes, nl, nb
+                )));
+            }
+            debug!("Verified {}: {} records", path, records);
+        }
+        Ok(())
+    }
+}
+
+// Primary output files for matched reads (--split-by)
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum SplitBy {
+    #[default]
+    Site,    // One file for each cut site
+    Barcode, // One file for each barcode (pooling all cut sites with the barcode)
+}
+
+impl std::str::FromStr for SplitBy {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let s = s.to_ascii_lowercase();
+        match s.as_str() {
+            "site" => Ok(Self::Site),
+            "barcode" => Ok(Self::Barcode),
+            _ => Err(anyhow!("Invalid SplitBy option {}", s)),
+        }
+    }
+}
+
+impl SplitBy {
+    // Name of the primary output for reads matched to site
+    pub fn output_name<'a>(&self, site: &'a Site) -> &'a str {
+        match self {
+            Self::Site => site.name.as_str(),
+            Self::Barcode => site.barcode.as_str(),
+        }
     }
-    c.path(fname).bufwriter()
 }
 
 pub struct OutputFiles<'a> {
-    pub unmapped: Option<BufWriter<Writer>>,
-    pub low_mapq: Option<BufWriter<Writer>>,
-    pub unmatched: Option<BufWriter<Writer>>,
-    pub site_hash: HashMap<&'a str, BufWriter<Writer>>,
+    pub unmapped: Option<FastqOut>,
+    pub low_mapq: Option<FastqOut>,
+    pub unmatched: Option<FastqOut>,
+    pub no_sites: Option<FastqOut>, // Reads mapping to contigs with no cut sites (with --no-sites-output)
+    pub tagged: Option<FastqOut>,   // Single output for all reads with --tag-only
+    pub site_hash: HashMap<&'a str, FastqOut>, // Primary output for each cut site (or barcode with --split-by barcode)
+    pub sample_hash: HashMap<&'a str, (String, FastqOut)>, // Output name and file for each sample
+    pub group_hash: HashMap<&'a str, (String, FastqOut)>,  // Output name and file for each group
+    pub allele_hash: HashMap<&'a str, [(String, FastqOut); 2]>, // Output names and files for each site by allele (ref, alt)
+    pub run_hash: HashMap<&'a str, HashMap<&'a str, (String, FastqOut)>>, // Output names and files for each site by run
+    pub barcode_hash: HashMap<&'a str, (String, FastqOut)>, // Output name and file for each barcode
 }
 
 impl<'a> OutputFiles<'a> {
+    // Open output files.  With --no-fastq-output no files are opened, and with --tag-only only
+    // the single tagged output is opened
     pub fn open(param: &'a Param) -> io::Result<OutputFiles<'a>> {
+        if param.no_fastq_output() || param.tag_only() {
+            let tagged = if param.tag_only() {
+                Some(FastqOut::open("tagged.fastq", param)?)
+            } else {
+                None
+            };
+            return Ok(Self {
+                unmapped: None,
+                low_mapq: None,
+                unmatched: None,
+                no_sites: None,
+                tagged,
+                site_hash: HashMap::new(),
+                sample_hash: HashMap::new(),
+                group_hash: HashMap::new(),
+                allele_hash: HashMap::new(),
+                run_hash: HashMap::new(),
+                barcode_hash: HashMap::new(),
+            });
+        }
         let (unmapped, low_mapq, unmatched) = if !param.matched_only() {
             (
-                Some(open_output_file("unmapped.fastq", param)?),
-                Some(open_output_file("low_mapq.fastq", param)?),
-                Some(open_output_file("unmatched.fastq", param)?),
+                Some(FastqOut::open("unmapped.fastq", param)?),
+                Some(FastqOut::open("low_mapq.fastq", param)?),
+                Some(FastqOut::open("unmatched.fastq", param)?),
             )
         } else {
             (None, None, None)
         };
+        let no_sites = if param.no_sites_output() && !param.matched_only() {
+            Some(FastqOut::open("no_sites.fastq", param)?)
+        } else {
+            None
+        };
         let mut site_hash = HashMap::new();
+        let mut sample_hash = HashMap::new();
+        let mut group_hash = HashMap::new();
+        let mut allele_hash = HashMap::new();
+        let mut run_hash = HashMap::new();
+        let mut barcode_hash = HashMap::new();
+        let mut seen = HashSet::new();
         if let Some(cut_sites) = param.cut_sites() {
+            for sample in cut_sites.samples() {
+                let name = format!("sample_{}", sample);
+                let wrt = FastqOut::open(format!("{}.fastq", name), param)?;
+                sample_hash.insert(sample, (name, wrt));
+            }
+            if param.group_fastq() {
+                for group in cut_sites.groups() {
+                    let name = format!("group_{}", group);
+                    let wrt = FastqOut::open(format!("{}.fastq", name), param)?;
+                    group_hash.insert(group, (name, wrt));
+                }
+            }
             for (_, csites) in cut_sites.chash.iter() {
                 for site in csites.cut_sites.iter() {
-                    if !site_hash.contains_key(site.name.as_str()) {
-                        let wrt = open_output_file(format!("{}.fastq", site.name), param)?;
-                        site_hash.insert(site.name.as_str(), wrt);
+                    let name = param.split_by().output_name(site);
+                    if !site_hash.contains_key(name) {
+                        let wrt = FastqOut::open(format!("{}.fastq", name), param)?;
+                        site_hash.insert(name, wrt);
+                    }
+                    if seen.insert(site.name.as_str()) {
+                        if param.also_combined() && !barcode_hash.contains_key(site.barcode.as_str()) {
+                            let name = format!("barcode_{}", site.barcode);
+                            let wrt = FastqOut::open(format!("{}.fastq", name), param)?;
+                            barcode_hash.insert(site.barcode.as_str(), (name, wrt));
+                        }
+                        if param.snps().is_some() {
+                            let open = |a: Allele| -> io::Result<(String, FastqOut)> {
+                                let name = format!("{}_{}", site.name, a.name());
+                                let wrt = FastqOut::open(format!("{}.fastq", name), param)?;
+                                Ok((name, wrt))
+                            };
+                            allele_hash.insert(site.name.as_str(), [open(Allele::Ref)?, open(Allele::Alt)?]);
+                        }
+                        if let Some(rg) = param.read_groups().filter(|_| param.run_fastq()) {
+                            for run in rg.runs() {
+                                let name = format!("run_{}_{}", run, site.name);
+                                let wrt = FastqOut::open(format!("{}.fastq", name), param)?;
+                                run_hash
+                                    .entry(run.as_str())
+                                    .or_insert_with(HashMap::new)
+                                    .insert(site.name.as_str(), (name, wrt));
+                            }
+                        }
                     }
                 }
             }
@@ -50,7 +521,138 @@ impl<'a> OutputFiles<'a> {
             unmapped,
             low_mapq,
             unmatched,
+            no_sites,
+            tagged: None,
             site_hash,
+            sample_hash,
+            group_hash,
+            allele_hash,
+            run_hash,
+            barcode_hash,
         })
     }
+
+    // All output files
+    fn files_mut(&mut self) -> Vec<&mut FastqOut> {
+        [&mut self.unmapped, &mut self.low_mapq, &mut self.unmatched, &mut self.no_sites, &mut self.tagged]
+            .into_iter()
+            .flatten()
+            .chain(self.site_hash.values_mut())
+            .chain(
+                self.sample_hash
+                    .values_mut()
+                    .chain(self.group_hash.values_mut())
+                    .chain(self.allele_hash.values_mut().flatten())
+                    .chain(self.run_hash.values_mut().flat_map(|h| h.values_mut()))
+                    .chain(self.barcode_hash.values_mut())
+                    .map(|(_, w)| w),
+            )
+            .collect()
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        for w in self.files_mut() {
+            w.flush()?
+        }
+        Ok(())
+    }
+
+    // Start a writer thread for each output file (see FastqOut::start_thread())
+    pub fn start_threads(&mut self) -> io::Result<()> {
+        for w in self.files_mut() {
+            w.start_thread()?
+        }
+        Ok(())
+    }
+
+    // Start and end a batch of records on all outputs (see FastqOut::start_batch()).  With
+    // --ordered each output writes the records from each batch in batch order
+    pub fn start_batch(&mut self, batch: u64) -> io::Result<()> {
+        for w in self.files_mut() {
+            w.start_batch(batch)?
+        }
+        Ok(())
+    }
+
+    pub fn end_batch(&mut self) -> io::Result<()> {
+        for w in self.files_mut() {
+            w.end_batch()?
+        }
+        Ok(())
+    }
+
+    // Stop the writer threads, returning the first error from any of the threads
+    pub fn stop_threads(&mut self) -> io::Result<()> {
+        let mut res = Ok(());
+        for w in self.files_mut() {
+            let r = w.stop_thread();
+            if res.is_ok() {
+                res = r
+            }
+        }
+        res
+    }
+
+    // Additional (sample, group, allele, run and barcode) outputs for reads matched to site
+    pub fn site_outputs(
+        &mut self,
+        site: &Site,
+        allele: Option<Allele>,
+        run: Option<&str>,
+    ) -> impl Iterator<Item = &mut (String, FastqOut)> {
+        let sample = site.sample.as_deref().and_then(|s| self.sample_hash.get_mut(s));
+        let group = site.group.as_deref().and_then(|g| self.group_hash.get_mut(g));
+        let allele = allele.and_then(|a| {
+            self.allele_hash
+                .get_mut(site.name.as_str())
+                .map(|v| &mut v[if a == Allele::Ref { 0 } else { 1 }])
+        });
+        let run = run
+            .and_then(|r| self.run_hash.get_mut(r))
+            .and_then(|h| h.get_mut(site.name.as_str()));
+        let barcode = self.barcode_hash.get_mut(site.barcode.as_str());
+        sample.into_iter().chain(group).chain(allele).chain(run).chain(barcode)
+    }
+
+    // Number of output files
+    pub fn n_files(&self) -> usize {
+        [&self.unmapped, &self.low_mapq, &self.unmatched, &self.no_sites, &self.tagged]
+            .into_iter()
+            .flatten()
+            .count()
+            + self.site_hash.len()
+            + self.sample_hash.len()
+            + self.group_hash.len()
+            + 2 * self.allele_hash.len()
+            + self.run_hash.values().map(|h| h.len()).sum::<usize>()
+            + self.barcode_hash.len()
+    }
+
+    // Total number of records written to the primary (unmapped, low_mapq, unmatched, no_sites,
+    // tagged and cut site) outputs.  Each input read is written to at most one of these
+    pub fn primary_records(&self) -> u64 {
+        [&self.unmapped, &self.low_mapq, &self.unmatched, &self.no_sites, &self.tagged]
+            .into_iter()
+            .flatten()
+            .chain(self.site_hash.values())
+            .map(|w| w.records)
+            .sum()
+    }
+
+    // Close all files, verifying them if requested (see FastqOut::close())
+    pub fn close(self, verify: bool) -> io::Result<()> {
+        for w in [self.unmapped, self.low_mapq, self.unmatched, self.no_sites, self.tagged]
+            .into_iter()
+            .flatten()
+            .chain(self.site_hash.into_values())
+            .chain(self.sample_hash.into_values().map(|(_, w)| w))
+            .chain(self.group_hash.into_values().map(|(_, w)| w))
+            .chain(self.allele_hash.into_values().flatten().map(|(_, w)| w))
+            .chain(self.run_hash.into_values().flat_map(|h| h.into_values()).map(|(_, w)| w))
+            .chain(self.barcode_hash.into_values().map(|(_, w)| w))
+        {
+            w.close(verify)?
+        }
+        Ok(())
+    }
 }
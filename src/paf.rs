@@ -1,37 +1,40 @@
 // Read and parse Paf file
 
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::fmt;
-use std::io::{self, BufRead, Error, ErrorKind};
-use std::path::Path;
-use std::rc::Rc;
+use std::io::{self, BufRead, Read};
+use std::sync::Arc;
 
-use compress_io::{
-    compress::CompressIo,
-};
+#[cfg(feature = "cli")]
+use std::path::Path;
 
+use crate::classify::{AlnPolicy, ClassifyParam, PrimarySelect, Select};
+#[cfg(feature = "cli")]
+use crate::compressor::Compressor;
 use crate::cut_site::{CutSites, Site};
-use crate::params::{Param, Select};
+use crate::error::{self, Error};
+#[cfg(feature = "cli")]
+use crate::sam::{InputFormat, SamToPaf};
+
+// Default number of decimal places for the proportion of unused bases in the results file
+pub const DEFAULT_FLOAT_PRECISION: usize = 4;
 
-fn parse_usize(s: &str, msg: &str) -> io::Result<usize> {
+fn parse_usize(s: &str, msg: &str) -> error::Result<usize> {
     s.parse::<usize>()
-        .map_err(|e| Error::new(ErrorKind::Other, format!("Parse error for {}: {}", msg, e)))
+        .map_err(|e| Error::parse(format!("Parse error for {}: {}", msg, e)))
 }
 
 // Split line on tabs
-fn split(buf: &str, line: usize) -> io::Result<Vec<&str>> {
+fn split(buf: &str, line: usize) -> error::Result<Vec<&str>> {
     let fd: Vec<_> = buf.trim().split('\t').collect();
     if fd.len() < 12 {
-        Err(Error::new(
-            ErrorKind::Other,
-            format!("Short line (< 12 columns) at line {}", line),
-        ))
+        Err(Error::parse("Short line (< 12 columns)").at_line(line))
     } else {
         Ok(fd)
     }
 }
 
-#[derive(PartialEq, Debug, Copy, Clone)]
+#[derive(PartialEq, Eq, Hash, Debug, Copy, Clone)]
 pub enum Strand {
     Plus,
     Minus,
@@ -58,11 +61,8 @@ pub struct Match<'a> {
 
 impl<'a> fmt::Display for Match<'a> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(
-            f,
-            "{}\t{}\t{}",
-            self.site.name, self.site.barcode, self.inner
-        )
+        write!(f, "{}\t{}\t", self.site.name, self.site.barcode)?;
+        self.inner.write_fields(f, Some(self.anchor()))
     }
 }
 
@@ -72,15 +72,28 @@ pub struct InteriorSplit {
     to: usize,
 }
 
+impl InteriorSplit {
+    // Target position of the end of the alignment before the split
+    pub fn from(&self) -> usize {
+        self.from
+    }
+
+    // Target position of the start of the alignment after the split
+    pub fn to(&self) -> usize {
+        self.to
+    }
+}
+
 #[derive(Debug)]
 pub struct Location {
-    contig: Rc<str>,
+    contig: Arc<str>,
     inner: CommonLoc,
 }
 
 impl fmt::Display for Location {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}\t*\t{}", self.contig, self.inner)
+        write!(f, "{}\t*\t", self.contig)?;
+        self.inner.write_fields(f, None)
     }
 }
 
@@ -91,26 +104,248 @@ pub struct CommonLoc {
     end: [usize; 2],
     length: usize,
     unused: usize,
+    qstart: usize, // Position in read of first mapped base
     splits: Vec<InteriorSplit>,
 }
 
 impl fmt::Display for CommonLoc {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.write_fields(f, None)
+    }
+}
+
+impl CommonLoc {
+    // Write location fields for results file.  anchor is the read relative offset of the
+    // matched cut site (if any).  The proportion of unused bases is written with the precision
+    // of the formatter (i.e., format!("{:.2}", loc)) if given, otherwise with
+    // DEFAULT_FLOAT_PRECISION decimal places, and as '*' if it is undefined
+    fn write_fields(&self, f: &mut fmt::Formatter<'_>, anchor: Option<i64>) -> fmt::Result {
         write!(
             f,
-            "{}\t{}\t{}\t{}\t{}\t{:.4}",
+            "{}\t{}\t{}\t{}\t{}\t",
             self.strand,
             self.start[0],
             self.end[0],
             self.length,
             self.unused,
-            (self.unused as f64) / (self.length as f64)
         )?;
+        match self.unused_fraction() {
+            Some(x) => write!(f, "{:.*}\t", f.precision().unwrap_or(DEFAULT_FLOAT_PRECISION), x)?,
+            None => write!(f, "*\t")?,
+        }
+        match anchor {
+            Some(x) => write!(f, "{}", x)?,
+            None => write!(f, "*")?,
+        }
         for split in self.splits.iter() {
             write!(f, "\t{}\t{}", split.from, split.to)?;
         }
         Ok(())
     }
+
+    pub fn strand(&self) -> Strand {
+        self.strand
+    }
+
+    // Target position of the start of the read
+    pub fn start(&self) -> usize {
+        self.start[0]
+    }
+
+    // Target position of the end of the read
+    pub fn end(&self) -> usize {
+        self.end[0]
+    }
+
+    pub fn read_len(&self) -> usize {
+        self.length
+    }
+
+    // Number of read bases not used in the alignment
+    pub fn unused(&self) -> usize {
+        self.unused
+    }
+
+    // Proportion of read bases not used in the alignment (None for zero length reads)
+    pub fn unused_fraction(&self) -> Option<f64> {
+        (self.length > 0).then(|| (self.unused as f64) / (self.length as f64))
+    }
+
+    // Position in the read of the first mapped base
+    pub fn qstart(&self) -> usize {
+        self.qstart
+    }
+
+    // Splits within the read (in read order)
+    pub fn splits(&self) -> &[InteriorSplit] {
+        &self.splits
+    }
+
+    // Offset of cut site from the target position of the first mapped base, in the
+    // direction of the read
+    fn site_offset(&self, site: &Site) -> i64 {
+        let p = site.pos as i64 - 1;
+        match self.strand {
+            Strand::Plus => p - self.start[0] as i64,
+            Strand::Minus => self.start[0] as i64 - p,
+        }
+    }
+
+    // Reconstruct from the fields written by write_fields() (used when reading back cached
+    // classifications).  Only the reported start and end positions are available, so these
+    // are used for both elements of the start and end arrays.  The anchor field is ignored
+    // here (see Match::from_fields())
+    pub fn from_fields(fd: &[&str]) -> error::Result<Self> {
+        if fd.len() < 7 || !(fd.len() - 7).is_multiple_of(2) {
+            return Err(Error::parse("Wrong number of location fields"));
+        }
+        let strand = match fd[0] {
+            "+" => Strand::Plus,
+            "-" => Strand::Minus,
+            s => return Err(Error::parse(format!("Invalid strand {}", s))),
+        };
+        let start = parse_usize(fd[1], "start")?;
+        let end = parse_usize(fd[2], "end")?;
+        let splits = fd[7..]
+            .chunks(2)
+            .map(|x| {
+                Ok(InteriorSplit {
+                    from: parse_usize(x[0], "split from")?,
+                    to: parse_usize(x[1], "split to")?,
+                })
+            })
+            .collect::<error::Result<Vec<_>>>()?;
+        Ok(Self {
+            strand,
+            start: [start, start],
+            end: [end, end],
+            length: parse_usize(fd[3], "length")?,
+            unused: parse_usize(fd[4], "unused")?,
+            qstart: 0,
+            splits,
+        })
+    }
+}
+
+impl<'a> Match<'a> {
+    // Reconstruct from location fields (see CommonLoc::from_fields()), using the anchor
+    // field to recover the position in the read of the first mapped base
+    pub fn from_fields(site: &'a Site, fd: &[&str]) -> error::Result<Self> {
+        let mut inner = CommonLoc::from_fields(fd)?;
+        let anchor = fd[6]
+            .parse::<i64>()
+            .map_err(|e| Error::parse(format!("Parse error for anchor: {}", e)))?;
+        inner.qstart = usize::try_from(anchor - inner.site_offset(site))
+            .map_err(|_| Error::parse("Inconsistent anchor position"))?;
+        Ok(Self { site, inner })
+    }
+
+    pub fn new(site: &'a Site, inner: CommonLoc) -> Self {
+        Self { site, inner }
+    }
+
+    pub fn into_inner(self) -> CommonLoc {
+        self.inner
+    }
+
+    pub fn inner(&self) -> &CommonLoc {
+        &self.inner
+    }
+
+    // Matched cut site
+    pub fn site(&self) -> &'a Site {
+        self.site
+    }
+
+    pub fn strand(&self) -> Strand {
+        self.inner.strand()
+    }
+
+    // Target position of the start of the read
+    pub fn start(&self) -> usize {
+        self.inner.start()
+    }
+
+    // Target position of the end of the read
+    pub fn end(&self) -> usize {
+        self.inner.end()
+    }
+
+    pub fn read_len(&self) -> usize {
+        self.inner.read_len()
+    }
+
+    pub fn unused(&self) -> usize {
+        self.inner.unused()
+    }
+
+    pub fn splits(&self) -> &[InteriorSplit] {
+        self.inner.splits()
+    }
+
+    // Read relative offset of the cut site, i.e., the number of bases in the read before
+    // the cut site (negative if the read starts after the cut site)
+    pub fn anchor(&self) -> i64 {
+        self.inner.qstart as i64 + self.inner.site_offset(self.site)
+    }
+
+    // Number of bases by which the read end nearest to the cut site extends past the site
+    // (0 if the read end is on the expected side of the site).  A non-zero value means that
+    // the match relied on the --margin allowance
+    pub fn overrun(&self) -> usize {
+        let p = self.site.pos as i64;
+        let (s, e) = (self.inner.start[0] as i64, self.inner.end[0] as i64);
+        let (ds, de) = match self.inner.strand {
+            Strand::Plus => (p - s, e - p),
+            Strand::Minus => (s - p, p - e),
+        };
+        let d = if ds.abs() <= de.abs() { ds } else { de };
+        d.max(0) as usize
+    }
+}
+
+impl Location {
+    pub fn new(contig: Arc<str>, inner: CommonLoc) -> Self {
+        Self { contig, inner }
+    }
+
+    pub fn into_parts(self) -> (Arc<str>, CommonLoc) {
+        (self.contig, self.inner)
+    }
+
+    pub fn contig(&self) -> &Arc<str> {
+        &self.contig
+    }
+
+    pub fn inner(&self) -> &CommonLoc {
+        &self.inner
+    }
+
+    pub fn strand(&self) -> Strand {
+        self.inner.strand()
+    }
+
+    // Target position of the start of the read
+    pub fn start(&self) -> usize {
+        self.inner.start()
+    }
+
+    // Target position of the end of the read
+    pub fn end(&self) -> usize {
+        self.inner.end()
+    }
+
+    pub fn read_len(&self) -> usize {
+        self.inner.read_len()
+    }
+
+    pub fn unused(&self) -> usize {
+        self.inner.unused()
+    }
+
+    pub fn splits(&self) -> &[InteriorSplit] {
+        self.inner.splits()
+    }
 }
 
 #[derive(Debug)]
@@ -137,54 +372,185 @@ impl<'a> fmt::Display for FindMatch<'a> {
     }
 }
 
+#[derive(Clone)]
 pub struct PafRecord {
     qstart: usize,
     qend: usize,
     strand: Strand,
-    target_name: Rc<str>,
+    target_name: Arc<str>,
     target_length: usize,
     target_start: usize,
     target_end: usize,
     matching_bases: usize,
+    block_length: usize,
     mapq: usize,
+    score: Option<i64>,   // Alignment score from AS:i: tag (or chaining score from s1:i: tag) if present
+    primary: bool,        // Primary alignment (tp:A:P tag, or no tp tag)
+    secondary: bool,      // Secondary alignment (tp:A:S or tp:A:i tag)
+    cs: Option<Box<str>>, // Difference string from cs:Z: tag (if present)
+    cg: Option<Box<str>>, // CIGAR string from cg:Z: tag (if present)
+    aln_start: usize,     // Target start of the cs/cg alignment (before any end refinement)
+}
+
+// Alignment operations as (target bases, query bases, match) from a cs difference string.
+// Mismatches (*), insertions (+), deletions (-) and introns (~) are not matches.  Returns None
+// if the string can not be parsed
+fn cs_ops(cs: &str) -> Option<Vec<(usize, usize, bool)>> {
+    let b = cs.as_bytes();
+    let mut v = Vec::new();
+    let mut i = 0;
+    while i < b.len() {
+        let op = b[i];
+        i += 1;
+        let j = b[i..]
+            .iter()
+            .position(|c| matches!(c, b':' | b'=' | b'*' | b'+' | b'-' | b'~'))
+            .map(|k| i + k)
+            .unwrap_or(b.len());
+        let arg = &b[i..j];
+        i = j;
+        v.push(match op {
+            b':' => {
+                let n = std::str::from_utf8(arg).ok()?.parse::<usize>().ok()?;
+                (n, n, true)
+            }
+            b'=' => (arg.len(), arg.len(), true),
+            b'*' => (1, 1, false),
+            b'+' => (0, arg.len(), false),
+            b'-' => (arg.len(), 0, false),
+            b'~' => (
+                arg.iter()
+                    .filter(|c| c.is_ascii_digit())
+                    .fold(0, |n, c| n * 10 + (c - b'0') as usize),
+                0,
+                false,
+            ),
+            _ => return None,
+        })
+    }
+    Some(v)
+}
+
+// Alignment operations as (target bases, query bases, match) from a CIGAR string (cg tag).
+// M operations are counted as matches.  Returns None if the string can not be parsed
+fn cg_ops(cg: &str) -> Option<Vec<(usize, usize, bool)>> {
+    let mut v = Vec::new();
+    let mut n = 0;
+    for c in cg.bytes() {
+        if c.is_ascii_digit() {
+            n = n * 10 + (c - b'0') as usize;
+            continue;
+        }
+        v.push(match c {
+            b'M' | b'=' => (n, n, true),
+            b'X' => (n, n, false),
+            b'I' => (0, n, false),
+            b'D' | b'N' => (n, 0, false),
+            _ => return None,
+        });
+        n = 0;
+    }
+    Some(v)
+}
+
+// Target and query bases before the first run of at least min_anchor matching bases, or
+// None if there is no such run
+fn end_trim<'b, I: Iterator<Item = &'b (usize, usize, bool)>>(ops: I, min_anchor: usize) -> Option<(usize, usize)> {
+    let (mut t, mut q) = (0, 0);
+    for (dt, dq, m) in ops {
+        if *m && *dt >= min_anchor {
+            return Some((t, q));
+        }
+        t += dt;
+        q += dq;
+    }
+    None
+}
+
+// Find the base aligned to target position pos from a cs difference string (minimap2 --cs)
+// for an alignment starting at target position tstart.  Returns ref_base for positions
+// matching the reference, or None if the position is deleted in the read (or not covered)
+fn cs_base(cs: &str, tstart: usize, pos: usize, ref_base: u8) -> Option<u8> {
+    let b = cs.as_bytes();
+    let mut i = 0;
+    let mut t = tstart;
+    while i < b.len() {
+        let op = b[i];
+        i += 1;
+        let j = b[i..]
+            .iter()
+            .position(|c| matches!(c, b':' | b'=' | b'*' | b'+' | b'-' | b'~'))
+            .map(|k| i + k)
+            .unwrap_or(b.len());
+        let arg = &b[i..j];
+        i = j;
+        let n = match op {
+            b':' => std::str::from_utf8(arg).ok()?.parse::<usize>().ok()?,
+            b'=' | b'-' => arg.len(),
+            b'*' => 1,
+            b'+' => 0,
+            // Intron, i.e., ~gt123ag
+            b'~' => arg
+                .iter()
+                .filter(|c| c.is_ascii_digit())
+                .fold(0, |n, c| n * 10 + (c - b'0') as usize),
+            _ => return None,
+        };
+        if pos < t + n {
+            return match op {
+                b':' => Some(ref_base),
+                b'=' => Some(arg[pos - t].to_ascii_uppercase()),
+                b'*' => arg.get(1).map(|c| c.to_ascii_uppercase()),
+                _ => None,
+            };
+        }
+        t += n;
+    }
+    None
 }
 
 impl PafRecord {
     // Make new Paf record from string slice
     // ctgs stores the contigs seen (so we don't have to keep allocating strings to store the name)
-    fn from_str_slice(v: &[&str], ctgs: &mut HashSet<Rc<str>>) -> io::Result<Self> {
+    fn from_str_slice(v: &[&str], ctgs: &mut HashSet<Arc<str>>) -> error::Result<Self> {
         assert!(v.len() >= 12);
+        if v[5] == "*" {
+            return Ok(Self::unmapped(ctgs));
+        }
         let qstart = parse_usize(v[2], "query start")?;
         let qend = parse_usize(v[3], "query end")?;
         let strand = match v[4] {
             "+" => Strand::Plus,
             "-" => Strand::Minus,
             _ => {
-                return Err(Error::new(
-                    ErrorKind::Other,
-                    format!("Parse error for strand: unrecognized string '{}'", v[4]),
-                ))
+                return Err(Error::parse(format!("Parse error for strand: unrecognized string '{}'", v[4])))
             }
         };
         let target_name = match ctgs.get(v[5]) {
             Some(s) => s.clone(),
             None => {
-                let name: Rc<str> = Rc::from(v[5]);
+                let name: Arc<str> = Arc::from(v[5]);
                 ctgs.insert(name.clone());
                 name
             }
         };
         if qend <= qstart {
-            return Err(Error::new(
-                ErrorKind::Other,
-                format!("Parse error for {}, query start >= query end", target_name),
-            ));
+            return Err(Error::parse(format!("Parse error for {}, query start >= query end", target_name)));
         }
         let target_length = parse_usize(v[6], "target length")?;
         let target_start = parse_usize(v[7], "target start")?;
         let target_end = parse_usize(v[8], "target end")?;
         let matching_bases = parse_usize(v[9], "matching bases")?;
+        let block_length = parse_usize(v[10], "block length")?;
         let mapq = parse_usize(v[11], "mapq")?;
+        let tag = |t: &str| v[12..].iter().find_map(|s| s.strip_prefix(t));
+        let cs = tag("cs:Z:").map(Box::from);
+        let cg = tag("cg:Z:").map(Box::from);
+        let score = tag("AS:i:")
+            .or_else(|| tag("s1:i:"))
+            .and_then(|s| s.parse::<i64>().ok());
+        let primary = tag("tp:A:").map(|s| s == "P").unwrap_or(true);
+        let secondary = tag("tp:A:").map(|s| s == "S" || s == "i").unwrap_or(false);
         trace!("PAF record {}: {} qstart: {} qend: {} mapq: {}", v[0], target_name, qstart, qend, mapq);
         Ok(Self {
             qstart,
@@ -195,9 +561,100 @@ impl PafRecord {
             target_start,
             target_end,
             matching_bases,
+            block_length,
             mapq,
+            score,
+            primary,
+            secondary,
+            cs,
+            cg,
+            aln_start: target_start,
         })
     }
+    // Record for an unmapped query (target name '*').  Apart from the query name and length,
+    // the fields of these records are not used (and are often '*'), so they are not parsed
+    fn unmapped(ctgs: &mut HashSet<Arc<str>>) -> Self {
+        let target_name = match ctgs.get("*") {
+            Some(s) => s.clone(),
+            None => {
+                let name: Arc<str> = Arc::from("*");
+                ctgs.insert(name.clone());
+                name
+            }
+        };
+        Self {
+            qstart: 0,
+            qend: 0,
+            strand: Strand::Plus,
+            target_name,
+            target_length: 0,
+            target_start: 0,
+            target_end: 0,
+            matching_bases: 0,
+            block_length: 0,
+            mapq: 0,
+            score: None,
+            primary: false,
+            secondary: false,
+            cs: None,
+            cg: None,
+            aln_start: 0,
+        }
+    }
+    fn is_mapped(&self) -> bool {
+        self.target_name.as_ref() != "*"
+    }
+    // Move the ends of the record in to the first run of at least min_anchor matching bases at
+    // each end of the alignment (from the cs tag, or else the cg tag), so that short matches
+    // separated from the rest of the alignment by indels or mismatches are treated as unused
+    // read bases.  Returns true if the record was changed
+    fn refine_ends(&mut self, min_anchor: usize) -> bool {
+        let ops = match (self.cs.as_deref(), self.cg.as_deref()) {
+            (Some(cs), _) => cs_ops(cs),
+            (None, Some(cg)) => cg_ops(cg),
+            _ => None,
+        };
+        let Some(ops) = ops else {
+            return false;
+        };
+        // Check that the alignment agrees with the record coordinates
+        let (tlen, qlen) = ops.iter().fold((0, 0), |(t, q), (dt, dq, _)| (t + dt, q + dq));
+        if tlen != self.target_end - self.target_start || qlen != self.qend - self.qstart {
+            return false;
+        }
+        let (Some((t0, q0)), Some((t1, q1))) = (end_trim(ops.iter(), min_anchor), end_trim(ops.iter().rev(), min_anchor)) else {
+            return false;
+        };
+        if t0 + q0 + t1 + q1 == 0 || t0 + t1 >= tlen || q0 + q1 >= qlen {
+            return false;
+        }
+        self.target_start += t0;
+        self.target_end -= t1;
+        // The cs/cg alignment is in target order, so for minus strand records the start of the
+        // alignment is the end of the query
+        match self.strand {
+            Strand::Plus => {
+                self.qstart += q0;
+                self.qend -= q1;
+            }
+            Strand::Minus => {
+                self.qstart += q1;
+                self.qend -= q0;
+            }
+        }
+        true
+    }
+    // Sort key for choosing the longest match of a read (see PrimarySelect).  Records without
+    // an alignment score fall back to the number of matching bases
+    fn select_key(&self, ps: PrimarySelect) -> (bool, i64) {
+        let mb = self.matching_bases as i64;
+        match ps {
+            PrimarySelect::MatchBases => (true, mb),
+            PrimarySelect::AlignmentScore => (true, self.score.unwrap_or(mb)),
+            PrimarySelect::BlockLength => (true, self.block_length as i64),
+            PrimarySelect::TpPrimary => (self.primary, mb),
+        }
+    }
 }
 
 pub struct PafRead {
@@ -209,16 +666,17 @@ pub struct PafRead {
 impl PafRead {
     // Make new Paf read from string slice with first mapping record
     // ctgs stores the contigs seen (so we don't have to keep allocating strings to store the name)
-    fn from_str_slice(v: &[&str], ctgs: &mut HashSet<Rc<str>>) -> io::Result<Self> {
+    fn from_str_slice(v: &[&str], ctgs: &mut HashSet<Arc<str>>) -> error::Result<Self> {
         assert!(v.len() >= 12);
         let qname = v[0].to_owned();
-        let qlen = parse_usize(v[1], "query length")?;
+        // The query length can be '*' for unmapped records
+        let qlen = match v[1] {
+            "*" if v[5] == "*" => 0,
+            s => parse_usize(s, "query length")?,
+        };
         let records = vec![PafRecord::from_str_slice(v, ctgs)?];
         if records[0].qend > qlen {
-            return Err(Error::new(
-                ErrorKind::Other,
-                format!("Parse error for {}, query start > query len", qname),
-            ));
+            return Err(Error::parse(format!("Parse error for {}, query start > query len", qname)));
         }
         Ok(Self {
             qname,
@@ -227,54 +685,111 @@ impl PafRead {
         })
     }
     // Add subsequent records to Paf read
-    fn add_record(&mut self, v: &[&str], ctgs: &mut HashSet<Rc<str>>) -> io::Result<()> {
+    fn add_record(&mut self, v: &[&str], ctgs: &mut HashSet<Arc<str>>) -> error::Result<()> {
         assert!(v.len() >= 12);
         assert_eq!(self.qname, v[0]);
         let rec = PafRecord::from_str_slice(v, ctgs)?;
         if rec.qend > self.qlen {
-            return Err(Error::new(
-                ErrorKind::Other,
-                format!("Parse error for {}, query start > query len", self.qname),
-            ));
+            return Err(Error::parse(format!("Parse error for {}, query start > query len", self.qname)));
+        }
+        // A read is either unmapped (all records have target '*') or mapped, so unmapped
+        // records are dropped if the read has mapping records
+        match (rec.is_mapped(), self.is_mapped()) {
+            (false, true) => (),
+            (true, false) => self.records = vec![rec],
+            _ => self.records.push(rec),
         }
-        self.records.push(rec);
         Ok(())
     }
     pub fn qname(&self) -> &str {
         &self.qname
     }
+    // Refine the ends of the mapping records using the cs or cg tags (see PafRecord::refine_ends).
+    // Returns the number of records changed
+    pub fn refine_ends(&mut self, min_anchor: usize) -> usize {
+        self.records
+            .iter_mut()
+            .filter(|r| r.is_mapped())
+            .map(|r| r.refine_ends(min_anchor))
+            .filter(|x| *x)
+            .count()
+    }
+    // Replace the query length (i.e., with the length from the FASTQ).  Returns false and leaves
+    // the query length unchanged if the new length is shorter than the query end of a record
+    pub fn set_qlen(&mut self, qlen: usize) -> bool {
+        if self.records.iter().any(|r| r.qend > qlen) {
+            false
+        } else {
+            self.qlen = qlen;
+            true
+        }
+    }
     // Check if read is mapped
     pub fn is_mapped(&self) -> bool {
-        self.records.iter().all(|r| r.target_name.as_ref() != "*")
+        self.records.iter().all(|r| r.is_mapped())
     }
     // Check if read has one mapping with mapq >= threshold
     pub fn is_unique(&self, threshold: usize) -> bool {
         self.records.iter().any(|r| r.mapq >= threshold)
     }
+    // Target ranges (contig, start, end) of the mapping records with mapq >= threshold
+    pub fn mapped_ranges(&self, threshold: usize) -> impl Iterator<Item = (&str, usize, usize)> {
+        self.records
+            .iter()
+            .filter(move |r| r.mapq >= threshold && r.target_name.as_ref() != "*")
+            .map(|r| (r.target_name.as_ref(), r.target_start, r.target_end))
+    }
+    // Base of the read aligned to target position pos (0 offset) on contig, taken from the cs
+    // tag of the highest mapq record covering the position.  ref_base is the reference base at
+    // the position.  Returns None if no record with a cs tag covers the position
+    pub fn base_at(&self, contig: &str, pos: usize, ref_base: u8) -> Option<u8> {
+        self.records
+            .iter()
+            .filter(|r| r.cs.is_some() && r.target_name.as_ref() == contig)
+            .filter(|r| r.target_start <= pos && pos < r.target_end)
+            .max_by_key(|r| r.mapq)
+            .and_then(|r| cs_base(r.cs.as_deref().unwrap(), r.aln_start, pos, ref_base))
+    }
+    // Returns the cut sites within max_distance of a mapping record with mapq >= threshold
+    // (each site is returned once, and the maximum distance for the site is used if set).
+    // Used to find the number of reads covering each site
+    pub fn covered_sites<'b>(&self, cut_sites: &'b CutSites, param: &ClassifyParam) -> Vec<&'b Site> {
+        let max_dist = param.site_distance();
+        let mut v: Vec<&Site> = Vec::new();
+        for r in self.records.iter().filter(|r| r.mapq >= param.mapq_thresh()) {
+            for site in cut_sites.sites_in_range(
+                &r.target_name,
+                r.target_start.saturating_sub(max_dist),
+                r.target_end + max_dist,
+            ) {
+                let d = site.max_dist.unwrap_or(param.max_distance());
+                if site.pos + d >= r.target_start && site.pos <= r.target_end + d && !v.iter().any(|s| std::ptr::eq(*s, site)) {
+                    v.push(site)
+                }
+            }
+        }
+        v
+    }
     // Check for match to cut-site
     // Strategy - look for mapping records that can be assembled to cover more or less
     // the whole read where at least 1 record has a mapq > threshold and the others are on
     // the same contig strand
-    pub fn find_site<'a, 'b>(&'a self, cut_sites: &'b CutSites, param: &Param) -> Option<FindMatch<'b>> {
-        debug!("Checking matches for read {}", self.qname);
+    // Chain of records used for classification: the longest uniquely mapping record (filtering
+    // out reads much longer than the reference) together with the other records on the same
+    // contig and strand with mapq > 0, sorted by position in the read.  By default the longest
+    // record is the one with the most matching bases
+    fn chain(&self, param: &ClassifyParam) -> Option<(&PafRecord, Vec<&PafRecord>)> {
         let threshold = param.mapq_thresh();
-        let max_dist = param.max_distance();
-        let select = param.select();
-        let margin = param.margin();
-
-        // Find longest uniquely mapping record, filtering out reads much longer than the reference
+        let primary_select = param.primary_select();
         self.records
             .iter()
             .filter(|r| r.mapq >= threshold && self.qlen < r.target_length + 150)
-            .max_by_key(|r| r.matching_bases).and_then(|r| {
+            .max_by_key(|r| r.select_key(primary_select))
+            .map(|r| {
                 trace!(
                     "Found longest match: query: {} {} {} {} target: {} {} {}",
                     self.qlen, r.qstart, r.qend, r.strand, r.target_name, r.target_start, r.target_end
                 );
-
-                let strand = r.strand;
-
-                // Select other records on same contig strand as longest match with mapq > 0
                 let mut recs: Vec<_> = self
                     .records
                     .iter()
@@ -282,8 +797,95 @@ impl PafRead {
                         s.target_name == r.target_name && s.strand == r.strand && s.mapq > 0
                     })
                     .collect();
-
                 recs.sort_unstable_by_key(|s| s.qstart);
+                (r, recs)
+            })
+    }
+
+    // Copy of the read with only the mapping records allowed by the alignment policy (see
+    // AlnPolicy), or None if all records are allowed.  The primary record is the longest (as
+    // chosen by the primary_select option) record that is not secondary, and the other records
+    // that are not secondary are supplementary
+    pub fn select_alignments(&self, param: &ClassifyParam) -> Option<PafRead> {
+        let policy = param.aln_policy();
+        if policy == AlnPolicy::All || !self.is_mapped() || self.records.len() < 2 {
+            return None;
+        }
+        let records: Vec<_> = match policy {
+            AlnPolicy::PrimaryOnly => {
+                let primary_select = param.primary_select();
+                self.records
+                    .iter()
+                    .max_by_key(|r| (!r.secondary, r.select_key(primary_select)))
+                    .into_iter()
+                    .cloned()
+                    .collect()
+            }
+            _ => {
+                if self.records.iter().all(|r| r.secondary) {
+                    return None;
+                }
+                self.records.iter().filter(|r| !r.secondary).cloned().collect()
+            }
+        };
+        (records.len() < self.records.len()).then(|| PafRead {
+            qname: self.qname.clone(),
+            qlen: self.qlen,
+            records,
+        })
+    }
+
+    // MAPQ of the primary record of the read: the record with the tp:A:P tag or, if there is
+    // none, the longest record (as chosen by the primary_select option) whatever its MAPQ.
+    // Unmapped reads have MAPQ 0
+    pub fn primary_mapq(&self, param: &ClassifyParam) -> usize {
+        let primary_select = param.primary_select();
+        self.records
+            .iter()
+            .max_by_key(|r| (r.primary, r.select_key(primary_select)))
+            .map(|r| r.mapq)
+            .unwrap_or(0)
+    }
+
+    // PAF line (without the trailing newline) summarizing the chain of records used for
+    // classification (see chain()), spanning from the start of the first record to the end of
+    // the last record in the read.  The matching bases and block length are summed over the
+    // records, the mapq is that of the longest record, and the number of records in the chain is
+    // given by the nr:i: tag
+    pub fn chain_paf(&self, param: &ClassifyParam) -> Option<String> {
+        let (r, recs) = self.chain(param)?;
+        let qstart = recs.iter().map(|s| s.qstart).min()?;
+        let qend = recs.iter().map(|s| s.qend).max()?;
+        let tstart = recs.iter().map(|s| s.target_start).min()?;
+        let tend = recs.iter().map(|s| s.target_end).max()?;
+        Some(format!(
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\tnr:i:{}",
+            self.qname,
+            self.qlen,
+            qstart,
+            qend,
+            r.strand,
+            r.target_name,
+            r.target_length,
+            tstart,
+            tend,
+            recs.iter().map(|s| s.matching_bases).sum::<usize>(),
+            recs.iter().map(|s| s.block_length).sum::<usize>(),
+            r.mapq,
+            recs.len()
+        ))
+    }
+
+    pub fn find_site<'b>(&self, cut_sites: &'b CutSites, param: &ClassifyParam) -> Option<FindMatch<'b>> {
+        debug!("Checking matches for read {}", self.qname);
+        let max_dist = param.max_distance();
+        let select = param.select();
+        let margin = param.margin();
+        let site_margin = param.site_margin();
+
+        self.chain(param)
+            .and_then(|(r, recs)| {
+                let strand = r.strand;
 
                 // Find record that starts earliest in the read
                 let s = &recs[0];
@@ -318,53 +920,63 @@ impl PafRead {
                 };
 
                 if !skip {
-                    // Increase starting position by margin to allow for 'overrun'
-                    let (start, spos) = match s.strand {
-                        Strand::Plus => (s.target_start, s.target_start + margin),
-                        Strand::Minus => (
-                            s.target_end,
-                            if margin <= s.target_end {
-                                s.target_end - margin
-                            } else {
-                                0
-                            },
-                        ),
+                    // Correct starting position for any systematic offset (--auto-offset) and
+                    // increase by margin m to allow for 'overrun'
+                    let off = param.start_offset();
+                    let start_pos = |m: usize| match s.strand {
+                        Strand::Plus => (s.target_start as i64 - off[0]).max(0) as usize + m,
+                        Strand::Minus => ((s.target_end as i64 + off[1]).max(0) as usize).saturating_sub(m),
                     };
+                    let start = match s.strand {
+                        Strand::Plus => s.target_start,
+                        Strand::Minus => s.target_end,
+                    };
+                    let spos = start_pos(margin);
                     trace!("Using starting position {}", spos);
 
                     // Find record that ends latest in read
                     let s1 = recs.iter().max_by_key(|s| s.qend).unwrap();
 
-                    // Increase starting position and reduce ending position by margin to allow for 'overrun'
-
-                    let (end, send) = match s1.strand {
-                        Strand::Plus => (
-                            s1.target_end,
-                            if margin <= s1.target_end {
-                                s1.target_end - margin
-                            } else {
-                                0
-                            },
-                        ),
-                        Strand::Minus => (s1.target_start, s1.target_start + margin),
+                    // Reduce ending position by margin m to allow for 'overrun'
+                    let end_pos = |m: usize| match s1.strand {
+                        Strand::Plus => s1.target_end.saturating_sub(m),
+                        Strand::Minus => s1.target_start + m,
+                    };
+                    let end = match s1.strand {
+                        Strand::Plus => s1.target_end,
+                        Strand::Minus => s1.target_start,
                     };
+                    let send = end_pos(margin);
 
                     trace!("Using ending position {}", send);
-                    // Look for matching cut site
-                    let start_site = cut_sites.find_site(
-                        s.target_name.as_ref(),
-                        spos,
-                        strand == Strand::Plus,
-                        max_dist,
-                        s.target_length,
-                    );
-                    let end_site = cut_sites.find_site(
-                        s.target_name.as_ref(),
-                        send,
-                        strand == Strand::Minus,
-                        max_dist,
-                        s.target_length,
-                    );
+                    // Look for matching cut site.  If margins are set for individual sites, a site
+                    // found using the default margin (or failing that the largest margin) is only
+                    // accepted if it is also found using the margin for the site
+                    let lookup = |pos: &dyn Fn(usize) -> usize, dir: bool| {
+                        let find = |m: usize| {
+                            cut_sites.find_site(
+                                s.target_name.as_ref(),
+                                pos(m),
+                                dir,
+                                max_dist,
+                                s.target_length,
+                                param.end_padding(),
+                            )
+                        };
+                        let check = |site: &'b Site, used: usize| match site.margin.unwrap_or(margin) {
+                            m if m == used => Some(site),
+                            m => find(m).filter(|x| std::ptr::eq(*x, site)),
+                        };
+                        find(margin).and_then(|x| check(x, margin)).or_else(|| {
+                            if site_margin > margin {
+                                find(site_margin).and_then(|x| check(x, site_margin))
+                            } else {
+                                None
+                            }
+                        })
+                    };
+                    let start_site = lookup(&start_pos, strand == Strand::Plus);
+                    let end_site = lookup(&end_pos, strand == Strand::Minus);
                     trace!("start_site: {:?}, end_site: {:?}", start_site, end_site);
 
                     // Get splits
@@ -391,6 +1003,7 @@ impl PafRead {
                         end: [end, send],
                         length: self.qlen,
                         unused,
+                        qstart: s.qstart,
                         splits,
                     };
                     let check_match = |m| {
@@ -402,6 +1015,20 @@ impl PafRead {
                     };
 
                     Some(match (start_site, end_site, select) {
+                        // With pair the two ends must match the two sites of a pair
+                        (Some(m1), Some(m2), Select::Pair) => {
+                            if m1.pair.as_deref() == Some(m2.name.as_str()) {
+                                check_match(Match {
+                                    site: m1,
+                                    inner: cloc,
+                                })
+                            } else {
+                                FindMatch::MisMatch(Location {
+                                    contig: s.target_name.clone(),
+                                    inner: cloc,
+                                })
+                            }
+                        }
                         (Some(m1), Some(m2), sel) => {
                             if m1 == m2 {
                                 if sel == Select::Xor {
@@ -422,7 +1049,7 @@ impl PafRead {
                                 })
                             }
                         }
-                        (Some(_), None, Select::Both) => FindMatch::MatchStart(Location {
+                        (Some(_), None, Select::Both) | (Some(_), None, Select::End) | (Some(_), None, Select::Pair) => FindMatch::MatchStart(Location {
                             contig: s.target_name.clone(),
                             inner: cloc,
                         }),
@@ -430,7 +1057,7 @@ impl PafRead {
                             site: m,
                             inner: cloc,
                         }),
-                        (None, Some(m), Select::Either) |  (None, Some(m), Select::Xor) => check_match(Match {
+                        (None, Some(m), Select::Either) |  (None, Some(m), Select::Xor) | (None, Some(m), Select::End) => check_match(Match {
                             site: m,
                             inner: cloc,
                         }),
@@ -453,29 +1080,218 @@ impl PafRead {
 pub struct PafFile {
     rdr: Box<dyn BufRead>,
     buf: String,
-    ctgs: HashSet<Rc<str>>,
+    bytes: Vec<u8>, // Raw input line
+    ctgs: HashSet<Arc<str>>,
     line: usize,
     eof: bool,
+    invalid_utf8: usize,     // Number of lines with invalid UTF-8
+    max_records: usize,      // Maximum number of records used for a read
+    max_line: usize,         // Maximum line length (longer lines are truncated)
+    excess_records: usize,   // Number of reads with more than max_records records
+    long_lines: usize,       // Number of lines truncated
+    unmapped_records: usize, // Number of records for unmapped queries (target '*')
+    recent: VecDeque<Arc<str>>, // Names of the most recent reads (to detect records for a read that are not adjacent)
+    recent_set: HashSet<Arc<str>>, // The names in recent, for lookup
+    unsorted: bool,          // Regroup records by read name in memory
+    regrouped: Option<std::vec::IntoIter<PafRawRead>>, // Regrouped reads (with unsorted)
+    lenient: bool,           // Skip malformed lines rather than failing
+    skipped_lines: usize,    // Number of malformed lines skipped (with lenient)
+    first_skipped: usize,    // Line number of first malformed line skipped
+}
+
+// Number of malformed lines reported individually with lenient parsing
+const N_REPORT_SKIPPED: usize = 10;
+
+// Check that a PAF line can be parsed.  If qlen is given (for subsequent records of a read),
+// the query end must not be past the query length of the read.  Returns the query length
+fn check_record(fd: &[&str], ctgs: &mut HashSet<Arc<str>>, qlen: Option<usize>) -> error::Result<usize> {
+    let r = PafRead::from_str_slice(fd, ctgs)?;
+    match qlen {
+        Some(l) if r.records[0].qend > l => Err(Error::parse(format!("Parse error for {}, query start > query len", r.qname))),
+        _ => Ok(r.qlen),
+    }
 }
 
+// Number of recent read names checked to detect records for a read that are not adjacent.  The
+// check is bounded so that memory use does not grow with the size of the input; records split
+// by a position sort are normally close enough together to be caught
+const ADJACENCY_WINDOW: usize = 4096;
+
 impl PafFile {
-    pub fn open<P: AsRef<Path>>(name: Option<P>) -> io::Result<Self> {
-        Ok(Self {
-            rdr: CompressIo::new().opt_path(name).bufreader().map(Box::new)?,
+    pub fn new(rdr: Box<dyn BufRead>) -> Self {
+        Self {
+            rdr,
             buf: String::new(),
+            bytes: Vec::new(),
             ctgs: HashSet::new(),
             line: 0,
             eof: false,
+            invalid_utf8: 0,
+            max_records: usize::MAX,
+            max_line: usize::MAX,
+            excess_records: 0,
+            long_lines: 0,
+            unmapped_records: 0,
+            recent: VecDeque::with_capacity(ADJACENCY_WINDOW),
+            recent_set: HashSet::with_capacity(ADJACENCY_WINDOW),
+            unsorted: false,
+            regrouped: None,
+            lenient: false,
+            skipped_lines: 0,
+            first_skipped: 0,
+        }
+    }
+    // Skip short or unparseable lines (which are counted and logged) rather than failing.
+    // Each line is then fully parsed when it is read, as well as when the read is classified
+    pub fn set_lenient(&mut self, yes: bool) {
+        self.lenient = yes
+    }
+    // Number of malformed lines skipped, and the line number of the first of these
+    pub fn skipped_lines(&self) -> (usize, usize) {
+        (self.skipped_lines, self.first_skipped)
+    }
+    // Check the current line.  With lenient parsing the line is fully parsed (using qlen as
+    // for check_record()), and Ok(None) is returned for a malformed line.  Otherwise only the
+    // number of columns is checked
+    fn check_line(&mut self, qlen: Option<usize>) -> error::Result<Option<usize>> {
+        let res = split(&self.buf, self.line).and_then(|fd| {
+            if self.lenient {
+                check_record(&fd, &mut self.ctgs, qlen)
+            } else {
+                Ok(0)
+            }
+        });
+        match res {
+            Ok(l) => Ok(Some(l)),
+            Err(e) if self.lenient => {
+                self.skipped_lines += 1;
+                if self.skipped_lines == 1 {
+                    self.first_skipped = self.line
+                }
+                if self.skipped_lines <= N_REPORT_SKIPPED {
+                    warn!("Skipping malformed PAF line {}: {}", self.line, e)
+                } else {
+                    debug!("Skipping malformed PAF line {}: {}", self.line, e)
+                }
+                Ok(None)
+            }
+            Err(e) => Err(e),
+        }
+    }
+    // Query length of read qname if the current line is a record for it (only needed for
+    // lenient parsing)
+    fn same_read(&self, qname: &str, qlen: usize) -> Option<usize> {
+        if self.lenient {
+            split(&self.buf, self.line).ok().filter(|fd| fd[0] == qname).map(|_| qlen)
+        } else {
+            None
+        }
+    }
+    // Allow input where the records for a read are not adjacent (i.e., PAF files sorted by
+    // position).  The whole input is read and the records grouped by read name in memory
+    // before the first read is returned, and reads are then returned in order of their first
+    // record.  Otherwise records for a read that are not adjacent are an error
+    pub fn set_unsorted(&mut self, yes: bool) {
+        self.unsorted = yes
+    }
+    // Set limits to guard against pathological input.  Only the first max_records records
+    // are used for a read, and lines longer than max_line bytes are truncated
+    pub fn set_limits(&mut self, max_records: usize, max_line: usize) {
+        self.max_records = max_records.max(1);
+        self.max_line = max_line.max(1);
+    }
+    // Number of reads with records skipped because of the max_records limit
+    pub fn excess_records(&self) -> usize {
+        self.excess_records
+    }
+    // Number of lines truncated because of the max_line limit
+    pub fn long_lines(&self) -> usize {
+        self.long_lines
+    }
+    // Number of records for unmapped queries (i.e., from minimap2 --paf-no-hit)
+    pub fn unmapped_records(&self) -> usize {
+        self.unmapped_records
+    }
+    // Number of input lines with invalid UTF-8.  Invalid bytes in these lines are replaced
+    // by the Unicode replacement character rather than aborting the run
+    pub fn invalid_utf8(&self) -> usize {
+        self.invalid_utf8
+    }
+    #[cfg(feature = "cli")]
+    pub fn open<P: AsRef<Path>>(name: Option<P>, compressor: &Compressor) -> io::Result<Self> {
+        Ok(Self::new(compressor.reader(name)?))
+    }
+    // Open alignment file in the given format.  SAM and BAM input is converted to PAF records
+    // as it is read (see sam.rs)
+    #[cfg(feature = "cli")]
+    pub fn open_format<P: AsRef<Path>>(
+        name: Option<P>,
+        compressor: &Compressor,
+        format: InputFormat,
+    ) -> io::Result<Self> {
+        let rdr = compressor.reader(name)?;
+        Ok(match format {
+            InputFormat::Paf => Self::new(rdr),
+            InputFormat::Sam => Self::new(Box::new(SamToPaf::new(rdr, false)?)),
+            InputFormat::Bam => Self::new(Box::new(SamToPaf::new(rdr, true)?)),
         })
     }
     // Get next line from paf file
     fn next_line(&mut self) -> io::Result<usize> {
         self.buf.clear();
+        self.bytes.clear();
         self.line += 1;
-        self.rdr.read_line(&mut self.buf)
+        let mut n = (&mut self.rdr)
+            .take((self.max_line as u64).saturating_add(1))
+            .read_until(b'\n', &mut self.bytes)?;
+        if self.bytes.len() > self.max_line && self.bytes.last() != Some(&b'\n') {
+            // Discard the rest of the line
+            loop {
+                let buf = self.rdr.fill_buf()?;
+                if buf.is_empty() {
+                    break;
+                }
+                let (k, done) = match buf.iter().position(|c| *c == b'\n') {
+                    Some(i) => (i + 1, true),
+                    None => (buf.len(), false),
+                };
+                self.rdr.consume(k);
+                n += k;
+                if done {
+                    break;
+                }
+            }
+            debug!("PAF line {} truncated to {} bytes", self.line, self.max_line);
+            self.long_lines += 1;
+            // Remove any partial field at the end of the truncated line
+            self.bytes.truncate(self.max_line);
+            if let Some(i) = self.bytes.iter().rposition(|c| *c == b'\t') {
+                self.bytes.truncate(i)
+            }
+        }
+        match std::str::from_utf8(&self.bytes) {
+            Ok(s) => self.buf.push_str(s),
+            Err(_) => {
+                debug!("Invalid UTF-8 in PAF file at line {}", self.line);
+                self.invalid_utf8 += 1;
+                self.buf.push_str(&String::from_utf8_lossy(&self.bytes))
+            }
+        }
+        Ok(n)
     }
     // Get next read from paf file (i.e., all mapping records corresponding to a read)
-    pub fn next_read(&mut self) -> io::Result<Option<PafRead>> {
+    pub fn next_read(&mut self) -> error::Result<Option<PafRead>> {
+        match self.next_raw_read()? {
+            Some(raw) => raw.parse_records(&mut self.ctgs).map(Some),
+            None => Ok(None),
+        }
+    }
+    // Get the unparsed records for the next read.  The lines are only checked for the number
+    // of columns here; the records are parsed with PafRawRead::parse()
+    pub fn next_raw_read(&mut self) -> error::Result<Option<PafRawRead>> {
+        if self.unsorted {
+            return self.next_regrouped_read();
+        }
         if self.eof {
             return Ok(None);
         }
@@ -483,24 +1299,230 @@ impl PafFile {
         if self.buf.is_empty() && self.next_line()? == 0 {
             return Ok(None);
         }
+        // Skip any malformed lines (with lenient parsing)
+        let qlen = loop {
+            if let Some(l) = self.check_line(None)? {
+                break l;
+            }
+            if self.next_line()? == 0 {
+                self.eof = true;
+                return Ok(None);
+            }
+        };
         // Split on tabs
         let fd = split(&self.buf, self.line)?;
-        // Parse first mapping record
-        let mut paf_read = PafRead::from_str_slice(&fd, &mut self.ctgs)?;
-        // Add additional reads
+        if fd[5] == "*" {
+            self.unmapped_records += 1
+        }
+        let qname = fd[0].to_owned();
+        if self.recent_set.contains(qname.as_str()) {
+            return Err(Error::parse(format!(
+                "Records for read {} are not adjacent in PAF input.  The input may have been sorted by position; use --unsorted-paf to regroup the records by read",
+                qname
+            ))
+            .at_line(self.line));
+        }
+        if self.recent.len() == ADJACENCY_WINDOW {
+            if let Some(old) = self.recent.pop_front() {
+                self.recent_set.remove(&old);
+            }
+        }
+        let name: Arc<str> = Arc::from(qname.as_str());
+        self.recent.push_back(name.clone());
+        self.recent_set.insert(name);
+        let mut raw = PafRawRead { buf: String::new() };
+        raw.push(&self.buf);
+        // Add additional records (up to max_records)
+        let mut n = 1;
+        let mut skipped = false;
         loop {
             if self.next_line()? == 0 {
                 self.eof = true;
                 break;
             }
+            if self.check_line(self.same_read(&qname, qlen))?.is_none() {
+                continue;
+            }
             // Split on tabs
             let fd = split(&self.buf, self.line)?;
-            if fd[0] == paf_read.qname {
-                paf_read.add_record(&fd, &mut self.ctgs)?;
+            if fd[0] == qname {
+                if fd[5] == "*" {
+                    self.unmapped_records += 1
+                }
+                if n < self.max_records {
+                    raw.push(&self.buf);
+                    n += 1
+                } else if !skipped {
+                    debug!(
+                        "Read {} has more than {} PAF records: extra records ignored",
+                        qname, self.max_records
+                    );
+                    self.excess_records += 1;
+                    skipped = true;
+                }
             } else {
                 break;
             }
         }
-        Ok(Some(paf_read))
+        Ok(Some(raw))
+    }
+    // Get the next read after regrouping all records by read name (see set_unsorted())
+    fn next_regrouped_read(&mut self) -> error::Result<Option<PafRawRead>> {
+        if self.regrouped.is_none() {
+            let mut reads: Vec<(PafRawRead, usize, usize)> = Vec::new();
+            let mut index: HashMap<String, usize> = HashMap::new();
+            let mut n_records = 0;
+            while self.next_line()? != 0 {
+                let prev = match self.lenient {
+                    true => split(&self.buf, self.line).ok().and_then(|fd| index.get(fd[0]).map(|i| reads[*i].2)),
+                    false => None,
+                };
+                let Some(qlen) = self.check_line(prev)? else { continue };
+                let fd = split(&self.buf, self.line)?;
+                if fd[5] == "*" {
+                    self.unmapped_records += 1
+                }
+                let i = match index.get(fd[0]) {
+                    Some(i) => *i,
+                    None => {
+                        index.insert(fd[0].to_owned(), reads.len());
+                        reads.push((PafRawRead { buf: String::new() }, 0, qlen));
+                        reads.len() - 1
+                    }
+                };
+                let (raw, n, _) = &mut reads[i];
+                if *n < self.max_records {
+                    raw.push(&self.buf);
+                } else if *n == self.max_records {
+                    debug!(
+                        "Read {} has more than {} PAF records: extra records ignored",
+                        fd[0], self.max_records
+                    );
+                    self.excess_records += 1;
+                }
+                *n += 1;
+                n_records += 1
+            }
+            self.buf.clear();
+            self.eof = true;
+            debug!("Regrouped {} PAF records for {} reads", n_records, reads.len());
+            self.regrouped = Some(reads.into_iter().map(|(r, _, _)| r).collect::<Vec<_>>().into_iter())
+        }
+        Ok(self.regrouped.as_mut().and_then(|it| it.next()))
+    }
+}
+
+// Unparsed PAF records for a read (one per line).  This allows the input to be read in one
+// thread with the records being parsed by the threads classifying the reads
+pub struct PafRawRead {
+    buf: String,
+}
+
+impl PafRawRead {
+    fn push(&mut self, line: &str) {
+        self.buf.push_str(line.trim_end_matches(['\r', '\n']));
+        self.buf.push('\n')
+    }
+    pub fn qname(&self) -> &str {
+        self.buf.trim_start().split('\t').next().unwrap_or("")
+    }
+    // Parse records to make PafRead
+    pub fn parse(&self) -> error::Result<PafRead> {
+        self.parse_records(&mut HashSet::new())
+    }
+    fn parse_records(&self, ctgs: &mut HashSet<Arc<str>>) -> error::Result<PafRead> {
+        let mut it = self.buf.lines().map(|l| l.trim().split('\t').collect::<Vec<_>>());
+        let fd = it.next().ok_or_else(|| Error::parse("No PAF records for read"))?;
+        let mut paf_read = PafRead::from_str_slice(&fd, ctgs)?;
+        for fd in it {
+            paf_read.add_record(&fd, ctgs)?
+        }
+        Ok(paf_read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Parse a single PAF line
+    fn record(line: &str) -> PafRecord {
+        let v: Vec<_> = line.split('\t').collect();
+        PafRecord::from_str_slice(&v, &mut HashSet::new()).unwrap()
+    }
+
+    #[test]
+    fn cs_ops_all_operations() {
+        assert_eq!(
+            cs_ops(":10=ACG*ag+tt-c~gt100ag").unwrap(),
+            [(10, 10, true), (3, 3, true), (1, 1, false), (0, 2, false), (1, 0, false), (100, 0, false)]
+        );
+        assert_eq!(cs_ops("").unwrap(), []);
+    }
+
+    #[test]
+    fn cs_ops_malformed() {
+        assert!(cs_ops(":x").is_none());
+        assert!(cs_ops(":10?").is_none());
+        assert!(cs_ops("10").is_none());
+        assert!(cs_ops(":5/ac").is_none());
+    }
+
+    #[test]
+    fn cg_ops_all_operations() {
+        assert_eq!(
+            cg_ops("10M2I3D5N4=1X").unwrap(),
+            [(10, 10, true), (0, 2, false), (3, 0, false), (5, 0, false), (4, 4, true), (1, 1, false)]
+        );
+    }
+
+    #[test]
+    fn cg_ops_malformed() {
+        // Clipping is given by the query coordinates of the record, so is not expected in cg
+        assert!(cg_ops("5S10M").is_none());
+        assert!(cg_ops("10M2Z").is_none());
+    }
+
+    #[test]
+    fn end_trim_anchor() {
+        let ops = cs_ops(":3*ag:80+tt:5").unwrap();
+        assert_eq!(end_trim(ops.iter(), 10), Some((4, 4)));
+        assert_eq!(end_trim(ops.iter().rev(), 10), Some((5, 7)));
+        // An anchor at the end of the alignment needs no trimming
+        assert_eq!(end_trim(ops.iter(), 3), Some((0, 0)));
+        assert_eq!(end_trim(ops.iter(), 100), None);
+    }
+
+    #[test]
+    fn refine_ends_soft_clipped_plus() {
+        // Query bases 0..5 and 96..100 are soft clipped
+        let mut r = record("r1\t100\t5\t96\t+\tchr1\t5000\t1000\t1089\t85\t91\t60\tcs:Z::3*ag:80+tt:5");
+        assert!(r.refine_ends(10));
+        assert_eq!((r.target_start, r.target_end), (1004, 1084));
+        assert_eq!((r.qstart, r.qend), (9, 89));
+    }
+
+    #[test]
+    fn refine_ends_minus() {
+        // The alignment is in target order, so the trimmed start of the alignment is removed
+        // from the end of the query
+        let mut r = record("r1\t100\t5\t96\t-\tchr1\t5000\t1000\t1089\t85\t91\t60\tcg:Z:3M1X80M2I5M");
+        assert!(r.refine_ends(10));
+        assert_eq!((r.target_start, r.target_end), (1004, 1084));
+        assert_eq!((r.qstart, r.qend), (12, 92));
+    }
+
+    #[test]
+    fn refine_ends_unchanged() {
+        // Alignment does not agree with the record coordinates
+        let mut r = record("r1\t100\t5\t96\t+\tchr1\t5000\t1000\t1090\t85\t91\t60\tcs:Z::3*ag:80+tt:5");
+        assert!(!r.refine_ends(10));
+        assert_eq!((r.target_start, r.qstart), (1000, 5));
+        // Malformed tag
+        let mut r = record("r1\t100\t0\t10\t+\tchr1\t5000\t1000\t1010\t10\t10\t60\tcg:Z:10S");
+        assert!(!r.refine_ends(5));
+        // No anchor long enough
+        let mut r = record("r1\t100\t0\t10\t+\tchr1\t5000\t1000\t1010\t9\t10\t60\tcs:Z::4*ag:5");
+        assert!(!r.refine_ends(10));
     }
 }
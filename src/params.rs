@@ -1,63 +1,272 @@
 use super::*;
-use crate::cut_site::CutSites;
-
-#[derive(Debug, Copy, Clone, PartialEq, Eq)]
-pub enum Select {
-    Start,
-    Both,
-    Either,
-    Xor,
-}
+use crate::classify::{AlnPolicy, Classifier, ClassifyParam, PrimarySelect, Select};
+use crate::compressor::Compressor;
+use crate::channel::SeqSummary;
+use crate::contam::ContamScreen;
+use crate::output::SplitBy;
+use crate::id_match::IdMatch;
+use crate::qlen::{FastqLengths, QlenFrom};
+use crate::read_filter::ReadFilter;
+use crate::read_group::ReadGroups;
+use crate::sam::InputFormat;
+use crate::snp::SnpSet;
+use crate::cut_site::{Collision, CutSites};
+use crate::error::Error;
+use std::net::{IpAddr, Ipv4Addr};
+
+pub const DEFAULT_WRITE_BUFFER_SIZE: usize = 8192;
+pub const DEFAULT_MAX_PAF_RECORDS: usize = 1000;
+pub const DEFAULT_MAX_LINE_LENGTH: usize = 256 << 20;
 
-impl Default for Select {
-    fn default() -> Self { Self::Start }
+// Number of cores available to the process
+pub fn available_cores() -> usize {
+    std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1)
 }
 
-impl std::str::FromStr for Select {
-    type Err = anyhow::Error;
-    fn from_str(s: &str) -> anyhow::Result<Self> {
-        let s = s.to_ascii_lowercase();
-        match s.as_str() {
-            "start" => Ok(Self::Start),
-            "both" => Ok(Self::Both),
-            "either" => Ok(Self::Either),
-            "xor" => Ok(Self::Xor),
-            _ => Err(anyhow!("Invalid Select option {}", s)),
-        }
+// Parse thread count, returning 0 for auto
+pub fn parse_threads(s: &str) -> error::Result<usize> {
+    if s.eq_ignore_ascii_case("auto") {
+        Ok(0)
+    } else {
+        let x = s
+            .parse::<usize>()
+            .map_err(|e| Error::config(format!("Invalid thread count {}: {}", s, e)))?;
+        Ok(x.max(1))
     }
 }
 
+// Parse size with optional K, M or G suffix (i.e., 64K)
+pub fn parse_size(s: &str) -> error::Result<usize> {
+    let s = s.trim();
+    let (num, mult) = match s.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&s[..s.len() - 1], 1 << 10),
+        Some('M') => (&s[..s.len() - 1], 1 << 20),
+        Some('G') => (&s[..s.len() - 1], 1 << 30),
+        _ => (s, 1),
+    };
+    let x = num
+        .parse::<usize>()
+        .map_err(|e| Error::config(format!("Invalid size {}: {}", s, e)))?;
+    x.checked_mul(mult).ok_or_else(|| Error::config(format!("Invalid size {}: value too large", s)))
+}
+
+// Parse duration in seconds with optional s, m or h suffix (i.e., 90m)
+pub fn parse_duration(s: &str) -> error::Result<u64> {
+    let s = s.trim();
+    let (num, mult) = match s.chars().last().map(|c| c.to_ascii_lowercase()) {
+        Some('s') => (&s[..s.len() - 1], 1),
+        Some('m') => (&s[..s.len() - 1], 60),
+        Some('h') => (&s[..s.len() - 1], 3600),
+        _ => (s, 1),
+    };
+    let x = num
+        .parse::<u64>()
+        .map_err(|e| Error::config(format!("Invalid duration {}: {}", s, e)))?;
+    x.checked_mul(mult).ok_or_else(|| Error::config(format!("Invalid duration {}: value too large", s)))
+}
+
 #[derive(Debug, Default)]
 pub struct ParamBuilder {
     paf_file: Option<String>,
+    input_format: InputFormat,
+    align_reference: Option<String>,
+    minimap2: String,
+    minimap2_args: String,
+    classification_file: Option<String>,
+    classification_out: Option<String>,
     fastq_file: Option<String>,
     cut_sites: Option<CutSites>,
+    site_collisions: Vec<Collision>,
+    contam: Option<ContamScreen>,
+    read_filter: Option<ReadFilter>,
+    id_match: Option<IdMatch>,
+    qlen_from: QlenFrom,
+    seq_summary: Option<SeqSummary>,
+    read_groups: Option<ReadGroups>,
+    length_bins: Option<Vec<usize>>,
+    mapq_bins: Option<Vec<usize>>,
+    snps: Option<SnpSet>,
     prefix: Option<String>,
     compress: bool,
+    compress_results: bool,
+    compress_fastq: bool,
+    compressor: Compressor,
     matched_only: bool,
+    group_fastq: bool,
+    orient_reads: bool,
+    flag_only: bool,
+    verify_outputs: bool,
+    ordered: bool,
+    channel_report: bool,
+    efficiency_report: bool,
+    chain_out: bool,
+    margin_report: bool,
+    second_site: bool,
+    bed: bool,
+    bedgraph: bool,
+    targets_window: Option<usize>,
+    run_fastq: bool,
+    no_fastq_output: bool,
+    tag_only: bool,
+    no_sites_output: bool,
+    also_combined: bool,
+    split_by: SplitBy,
+    split_results: bool,
+    no_combined_results: bool,
+    defer_results: bool,
+    hotspots: Option<usize>,
+    suggest_sites: Option<String>,
+    suggest_min_reads: usize,
+    end_profile: Option<usize>,
     select: Select,
+    primary_select: PrimarySelect,
+    aln_policy: AlnPolicy,
     mapq_thresh: usize,
     max_distance: usize,
     max_unmatched: usize,
     margin: usize,
+    end_padding: usize,
+    auto_offset: bool,
+    refine_ends: Option<usize>,
+    verify_deterministic: bool,
+    metrics_port: Option<u16>,
+    metrics_bind: Option<IpAddr>,
+    on_complete_cmd: Option<String>,
+    on_complete_url: Option<String>,
+    tmp_dir: Option<String>,
+    cache_dir: Option<String>,
+    synced: bool,
+    unsorted_paf: bool,
+    lenient: bool,
+    memory_budget: Option<usize>,
+    skip_if_current: bool,
+    post_classify_cmd: Option<String>,
+    preview: Option<usize>,
+    skip_reads: usize,
+    limit_reads: Option<usize>,
+    max_reads: Option<usize>,
+    max_runtime: Option<u64>,
+    threads: usize,            // 0 for automatic selection
+    decompress_threads: usize, // 0 for automatic selection
+    fastq_threads: usize,      // 0 for automatic selection
+    write_buffer_size: usize,
+    float_precision: Option<usize>,
+    flush_interval: Option<u64>,
+    max_paf_records: usize,
+    max_line_length: usize,
+    input_files: Vec<(String, String)>,
 }
 
 impl ParamBuilder {
     pub fn new() -> Self { Self::default() }
 
     pub fn build(self) -> Param {
+        let mut classify = ClassifyParam::new(
+            self.cut_sites,
+            self.select,
+            self.mapq_thresh,
+            self.max_distance,
+            self.max_unmatched,
+            self.margin,
+        );
+        classify.set_end_padding(self.end_padding);
+        classify.set_primary_select(self.primary_select);
+        classify.set_aln_policy(self.aln_policy);
         Param {
             paf_file: self.paf_file,
+            input_format: self.input_format,
+            align_reference: self.align_reference,
+            minimap2: self.minimap2,
+            minimap2_args: self.minimap2_args,
+            classification_file: self.classification_file,
+            classification_out: self.classification_out,
             fastq_file: self.fastq_file,
-            cut_sites: self.cut_sites,
+            classifier: Classifier::new(classify),
+            auto_offset: self.auto_offset,
+            refine_ends: self.refine_ends,
+            verify_deterministic: self.verify_deterministic,
+            site_collisions: self.site_collisions,
+            contam: self.contam,
+            read_filter: self.read_filter,
+            id_match: self.id_match,
+            qlen_from: self.qlen_from,
+            fastq_lengths: None,
+            seq_summary: self.seq_summary,
+            read_groups: self.read_groups,
+            length_bins: self.length_bins,
+            mapq_bins: self.mapq_bins,
+            snps: self.snps,
             prefix: self.prefix.unwrap_or(DEFAULT_PREFIX.to_string()),
-            compress: self.compress,
+            compress_results: self.compress || self.compress_results,
+            compress_fastq: self.compress || self.compress_fastq,
+            compressor: self.compressor,
             matched_only: self.matched_only,
-            select: self.select,
-            mapq_thresh: self.mapq_thresh,
-            max_distance: self.max_distance,
-            max_unmatched: self.max_unmatched,
-            margin: self.margin,
+            group_fastq: self.group_fastq,
+            orient_reads: self.orient_reads,
+            flag_only: self.flag_only,
+            verify_outputs: self.verify_outputs,
+            ordered: self.ordered,
+            channel_report: self.channel_report,
+            efficiency_report: self.efficiency_report,
+            chain_out: self.chain_out,
+            margin_report: self.margin_report,
+            second_site: self.second_site,
+            bed: self.bed,
+            bedgraph: self.bedgraph,
+            targets_window: self.targets_window,
+            run_fastq: self.run_fastq,
+            no_fastq_output: self.no_fastq_output,
+            tag_only: self.tag_only,
+            no_sites_output: self.no_sites_output,
+            also_combined: self.also_combined,
+            split_by: self.split_by,
+            split_results: self.split_results,
+            combined_results: !self.no_combined_results,
+            defer_results: self.defer_results,
+            hotspots: self.hotspots,
+            suggest_sites: self.suggest_sites,
+            suggest_min_reads: self.suggest_min_reads,
+            end_profile: self.end_profile,
+            metrics_port: self.metrics_port,
+            metrics_bind: self.metrics_bind,
+            on_complete_cmd: self.on_complete_cmd,
+            on_complete_url: self.on_complete_url,
+            tmp_dir: self.tmp_dir,
+            cache_dir: self.cache_dir,
+            synced: self.synced,
+            unsorted_paf: self.unsorted_paf,
+            lenient: self.lenient,
+            memory_budget: self.memory_budget,
+            skip_if_current: self.skip_if_current,
+            post_classify_cmd: self.post_classify_cmd,
+            preview: self.preview,
+            skip_reads: self.skip_reads,
+            limit_reads: self.limit_reads,
+            max_reads: self.max_reads,
+            // The run time limit is measured from when the parameters are set up
+            // A deadline too far in the future to be represented is no limit
+            deadline: self.max_runtime.and_then(|t| Instant::now().checked_add(Duration::from_secs(t))),
+            threads: (self.threads > 0).then_some(self.threads),
+            decompress_threads: (self.decompress_threads > 0).then_some(self.decompress_threads),
+            fastq_threads: (self.fastq_threads > 0).then_some(self.fastq_threads),
+            float_precision: self.float_precision.unwrap_or(DEFAULT_FLOAT_PRECISION),
+            write_buffer_size: if self.write_buffer_size > 0 {
+                self.write_buffer_size
+            } else {
+                DEFAULT_WRITE_BUFFER_SIZE
+            },
+            flush_interval: self.flush_interval,
+            input_files: self.input_files,
+            max_paf_records: if self.max_paf_records > 0 {
+                self.max_paf_records
+            } else {
+                DEFAULT_MAX_PAF_RECORDS
+            },
+            max_line_length: if self.max_line_length > 0 {
+                self.max_line_length
+            } else {
+                DEFAULT_MAX_LINE_LENGTH
+            },
         }
     }
 
@@ -66,6 +275,31 @@ impl ParamBuilder {
         self
     }
 
+    pub fn classification_file<S: AsRef<str>>(&mut self, file: S) -> &mut Self {
+        self.classification_file = Some(file.as_ref().to_owned());
+        self
+    }
+
+    pub fn input_format(&mut self, format: InputFormat) -> &mut Self {
+        self.input_format = format;
+        self
+    }
+
+    pub fn align_reference<S: AsRef<str>>(&mut self, file: S) -> &mut Self {
+        self.align_reference = Some(file.as_ref().to_owned());
+        self
+    }
+
+    pub fn minimap2<S: AsRef<str>>(&mut self, prog: S, args: S) -> &mut Self {
+        self.minimap2 = prog.as_ref().to_owned();
+        self.minimap2_args = args.as_ref().to_owned();
+        self
+    }
+    pub fn classification_out<S: AsRef<str>>(&mut self, file: S) -> &mut Self {
+        self.classification_out = Some(file.as_ref().to_owned());
+        self
+    }
+
     pub fn fastq_file<S: AsRef<str>>(&mut self, file: S) -> &mut Self {
         self.fastq_file = Some(file.as_ref().to_owned());
         self
@@ -76,11 +310,79 @@ impl ParamBuilder {
         self
     }
 
+    pub fn site_collisions(&mut self, v: Vec<Collision>) -> &mut Self {
+        self.site_collisions = v;
+        self
+    }
+
+    pub fn read_filter(&mut self, filter: ReadFilter) -> &mut Self {
+        self.read_filter = Some(filter);
+        self
+    }
+
+    pub fn id_match(&mut self, x: IdMatch) -> &mut Self {
+        self.id_match = Some(x);
+        self
+    }
+
+    pub fn qlen_from(&mut self, x: QlenFrom) -> &mut Self {
+        self.qlen_from = x;
+        self
+    }
+
+    pub fn seq_summary(&mut self, seq_summary: SeqSummary) -> &mut Self {
+        self.seq_summary = Some(seq_summary);
+        self
+    }
+
+    pub fn read_groups(&mut self, read_groups: ReadGroups) -> &mut Self {
+        self.read_groups = Some(read_groups);
+        self
+    }
+
+    // Boundaries between read length bins (sorted, duplicates and zero are removed)
+    pub fn length_bins(&mut self, mut bins: Vec<usize>) -> &mut Self {
+        bins.retain(|x| *x > 0);
+        bins.sort_unstable();
+        bins.dedup();
+        self.length_bins = Some(bins);
+        self
+    }
+
+    // Boundaries between MAPQ bins (sorted, duplicates and zero are removed)
+    pub fn mapq_bins(&mut self, mut bins: Vec<usize>) -> &mut Self {
+        bins.retain(|x| *x > 0);
+        bins.sort_unstable();
+        bins.dedup();
+        self.mapq_bins = Some(bins);
+        self
+    }
+
+    pub fn snps(&mut self, snps: SnpSet) -> &mut Self {
+        self.snps = Some(snps);
+        self
+    }
+
+    pub fn contam(&mut self, contam: ContamScreen) -> &mut Self {
+        self.contam = Some(contam);
+        self
+    }
+
     pub fn select(&mut self, select: Select) -> &mut Self {
         self.select = select;
         self
     }
 
+    pub fn primary_select(&mut self, x: PrimarySelect) -> &mut Self {
+        self.primary_select = x;
+        self
+    }
+
+    pub fn aln_policy(&mut self, x: AlnPolicy) -> &mut Self {
+        self.aln_policy = x;
+        self
+    }
+
     pub fn prefix<S: AsRef<str>>(&mut self, prefix: S) -> &mut Self {
         self.prefix = Some(prefix.as_ref().to_owned());
         self
@@ -91,11 +393,156 @@ impl ParamBuilder {
         self
     }
 
+    pub fn compress_results(&mut self, yes: bool) -> &mut Self {
+        self.compress_results = yes;
+        self
+    }
+
+    pub fn compress_fastq(&mut self, yes: bool) -> &mut Self {
+        self.compress_fastq = yes;
+        self
+    }
+
+    pub fn compressor(&mut self, compressor: Compressor) -> &mut Self {
+        self.compressor = compressor;
+        self
+    }
+
     pub fn matched_only(&mut self, yes: bool) -> &mut Self {
         self.matched_only = yes;
         self
     }
 
+    pub fn group_fastq(&mut self, yes: bool) -> &mut Self {
+        self.group_fastq = yes;
+        self
+    }
+
+    pub fn orient_reads(&mut self, yes: bool) -> &mut Self {
+        self.orient_reads = yes;
+        self
+    }
+
+    pub fn flag_only(&mut self, yes: bool) -> &mut Self {
+        self.flag_only = yes;
+        self
+    }
+
+    pub fn verify_outputs(&mut self, yes: bool) -> &mut Self {
+        self.verify_outputs = yes;
+        self
+    }
+
+    pub fn ordered(&mut self, yes: bool) -> &mut Self {
+        self.ordered = yes;
+        self
+    }
+
+    pub fn channel_report(&mut self, yes: bool) -> &mut Self {
+        self.channel_report = yes;
+        self
+    }
+
+    pub fn efficiency_report(&mut self, yes: bool) -> &mut Self {
+        self.efficiency_report = yes;
+        self
+    }
+
+    pub fn chain_out(&mut self, yes: bool) -> &mut Self {
+        self.chain_out = yes;
+        self
+    }
+
+    pub fn margin_report(&mut self, yes: bool) -> &mut Self {
+        self.margin_report = yes;
+        self
+    }
+
+    pub fn second_site(&mut self, yes: bool) -> &mut Self {
+        self.second_site = yes;
+        self
+    }
+
+    pub fn bed(&mut self, yes: bool) -> &mut Self {
+        self.bed = yes;
+        self
+    }
+
+    pub fn bedgraph(&mut self, yes: bool) -> &mut Self {
+        self.bedgraph = yes;
+        self
+    }
+
+    pub fn targets_window(&mut self, x: usize) -> &mut Self {
+        self.targets_window = Some(x);
+        self
+    }
+
+    pub fn run_fastq(&mut self, yes: bool) -> &mut Self {
+        self.run_fastq = yes;
+        self
+    }
+
+    pub fn no_fastq_output(&mut self, yes: bool) -> &mut Self {
+        self.no_fastq_output = yes;
+        self
+    }
+
+    pub fn tag_only(&mut self, yes: bool) -> &mut Self {
+        self.tag_only = yes;
+        self
+    }
+
+    pub fn no_sites_output(&mut self, yes: bool) -> &mut Self {
+        self.no_sites_output = yes;
+        self
+    }
+
+    pub fn also_combined(&mut self, yes: bool) -> &mut Self {
+        self.also_combined = yes;
+        self
+    }
+
+    pub fn split_by(&mut self, x: SplitBy) -> &mut Self {
+        self.split_by = x;
+        self
+    }
+
+    pub fn split_results(&mut self, yes: bool) -> &mut Self {
+        self.split_results = yes;
+        self
+    }
+
+    pub fn no_combined_results(&mut self, yes: bool) -> &mut Self {
+        self.no_combined_results = yes;
+        self
+    }
+
+    pub fn defer_results(&mut self, yes: bool) -> &mut Self {
+        self.defer_results = yes;
+        self
+    }
+
+    pub fn hotspots(&mut self, n: usize) -> &mut Self {
+        self.hotspots = Some(n);
+        self
+    }
+
+    pub fn suggest_sites<S: AsRef<str>>(&mut self, file: S) -> &mut Self {
+        self.suggest_sites = Some(file.as_ref().to_owned());
+        self
+    }
+
+    pub fn suggest_min_reads(&mut self, n: usize) -> &mut Self {
+        self.suggest_min_reads = n;
+        self
+    }
+
+    pub fn end_profile(&mut self, bin_size: usize) -> &mut Self {
+        self.end_profile = Some(bin_size);
+        self
+    }
+
     pub fn mapq_thresh(&mut self, x: usize) -> &mut Self {
         self.mapq_thresh = x;
         self
@@ -115,58 +562,550 @@ impl ParamBuilder {
         self.margin = x;
         self
     }
+
+    pub fn end_padding(&mut self, x: usize) -> &mut Self {
+        self.end_padding = x;
+        self
+    }
+
+    pub fn auto_offset(&mut self, yes: bool) -> &mut Self {
+        self.auto_offset = yes;
+        self
+    }
+
+    pub fn refine_ends(&mut self, min_anchor: usize) -> &mut Self {
+        self.refine_ends = Some(min_anchor);
+        self
+    }
+
+    pub fn verify_deterministic(&mut self, yes: bool) -> &mut Self {
+        self.verify_deterministic = yes;
+        self
+    }
+
+    pub fn metrics_port(&mut self, port: u16) -> &mut Self {
+        self.metrics_port = Some(port);
+        self
+    }
+
+    pub fn metrics_bind(&mut self, addr: IpAddr) -> &mut Self {
+        self.metrics_bind = Some(addr);
+        self
+    }
+
+    pub fn on_complete_cmd<S: AsRef<str>>(&mut self, cmd: S) -> &mut Self {
+        self.on_complete_cmd = Some(cmd.as_ref().to_owned());
+        self
+    }
+
+    pub fn on_complete_url<S: AsRef<str>>(&mut self, url: S) -> &mut Self {
+        self.on_complete_url = Some(url.as_ref().to_owned());
+        self
+    }
+
+    pub fn tmp_dir<S: AsRef<str>>(&mut self, dir: S) -> &mut Self {
+        self.tmp_dir = Some(dir.as_ref().to_owned());
+        self
+    }
+
+    pub fn cache_dir<S: AsRef<str>>(&mut self, dir: S) -> &mut Self {
+        self.cache_dir = Some(dir.as_ref().to_owned());
+        self
+    }
+
+    pub fn synced(&mut self, yes: bool) -> &mut Self {
+        self.synced = yes;
+        self
+    }
+
+    pub fn unsorted_paf(&mut self, yes: bool) -> &mut Self {
+        self.unsorted_paf = yes;
+        self
+    }
+
+    pub fn lenient(&mut self, yes: bool) -> &mut Self {
+        self.lenient = yes;
+        self
+    }
+
+    pub fn memory_budget(&mut self, x: usize) -> &mut Self {
+        self.memory_budget = Some(x);
+        self
+    }
+
+    pub fn skip_if_current(&mut self, yes: bool) -> &mut Self {
+        self.skip_if_current = yes;
+        self
+    }
+
+    pub fn post_classify_cmd<S: AsRef<str>>(&mut self, cmd: S) -> &mut Self {
+        self.post_classify_cmd = Some(cmd.as_ref().to_owned());
+        self
+    }
+
+    pub fn preview(&mut self, n: usize) -> &mut Self {
+        self.preview = Some(n);
+        self
+    }
+
+    pub fn skip_reads(&mut self, n: usize) -> &mut Self {
+        self.skip_reads = n;
+        self
+    }
+
+    pub fn limit_reads(&mut self, n: usize) -> &mut Self {
+        self.limit_reads = Some(n);
+        self
+    }
+
+    pub fn max_reads(&mut self, n: usize) -> &mut Self {
+        self.max_reads = Some(n);
+        self
+    }
+
+    pub fn max_runtime(&mut self, secs: u64) -> &mut Self {
+        self.max_runtime = Some(secs);
+        self
+    }
+
+    pub fn threads(&mut self, x: usize) -> &mut Self {
+        self.threads = x;
+        self
+    }
+
+    pub fn write_buffer_size(&mut self, x: usize) -> &mut Self {
+        self.write_buffer_size = x;
+        self
+    }
+
+    pub fn float_precision(&mut self, x: usize) -> &mut Self {
+        self.float_precision = Some(x);
+        self
+    }
+
+    pub fn decompress_threads(&mut self, n: usize) -> &mut Self {
+        self.decompress_threads = n;
+        self
+    }
+
+    pub fn fastq_threads(&mut self, n: usize) -> &mut Self {
+        self.fastq_threads = n;
+        self
+    }
+
+    pub fn flush_interval(&mut self, secs: u64) -> &mut Self {
+        self.flush_interval = Some(secs);
+        self
+    }
+
+    pub fn max_paf_records(&mut self, n: usize) -> &mut Self {
+        self.max_paf_records = n;
+        self
+    }
+
+    // Record an input file named by option (used for the run stamp and run information)
+    pub fn input_file(&mut self, option: &str, file: &str) -> &mut Self {
+        self.input_files.push((option.to_owned(), file.to_owned()));
+        self
+    }
+
+    pub fn max_line_length(&mut self, x: usize) -> &mut Self {
+        self.max_line_length = x;
+        self
+    }
 }
 
 // Parameters for run
 #[derive(Debug, Default)]
 pub struct Param {
     paf_file: Option<String>,         // Input PAF file (if None, use stdin)
+    input_format: InputFormat,        // Format of input file (PAF, SAM or BAM)
+    align_reference: Option<String>,  // Reference for aligning the FASTQ input with minimap2 (instead of reading a PAF file)
+    minimap2: String,                 // minimap2 executable
+    minimap2_args: String,            // Additional arguments for minimap2
+    classification_file: Option<String>, // Read classifications from previous run (if set, PAF file is not read)
+    classification_out: Option<String>, // Write classifications to file (for later use as classification_file)
     fastq_file: Option<String>,       // Input FASTQ file (if None, just produce report)
-    cut_sites: Option<CutSites>, // Contigs with cut site definitions (if None, only split based on uniquely mapped/not uniquely mapped)
+    classifier: Classifier,      // Read classifier (including cut sites)
+    auto_offset: bool,           // Estimate and correct systematic read start offsets
+    refine_ends: Option<usize>,  // Minimum matching bases anchoring record ends (if set, ends are refined using cs/cg tags)
+    verify_deterministic: bool,  // Check that classification gives the same results with 1 and multiple threads
+    site_collisions: Vec<Collision>, // Pairs of colliding cut sites found when loading the cut file
+    contam: Option<ContamScreen>, // Contaminant screen (if None, no screening)
+    read_filter: Option<ReadFilter>, // Selection of reads by ID (if None, all reads are selected)
+    id_match: Option<IdMatch>,       // Matching of FASTQ IDs to classified reads (if set, an ID match report is written)
+    qlen_from: QlenFrom,             // Source of the query length for unused fraction calculations
+    fastq_lengths: Option<FastqLengths>, // Read lengths from the FASTQ (set at run time if qlen_from is Fastq)
+    seq_summary: Option<SeqSummary>, // Read channels from sequencing summary file
+    read_groups: Option<ReadGroups>, // Assignment of reads to sequencing runs
+    length_bins: Option<Vec<usize>>, // Boundaries between read length bins for statistics
+    mapq_bins: Option<Vec<usize>>,   // Boundaries between MAPQ bins for statistics
+    snps: Option<SnpSet>,            // SNPs for splitting matched reads by allele
     prefix: String,              // Output prefix (if None, use)
-    compress: bool,              // Compress output
+    compress_results: bool,      // Compress results file and reports
+    compress_fastq: bool,        // Compress FASTQ output files
+    compressor: Compressor,      // Compression backend
     matched_only: bool,          // Only output matched fastq records when demultiplexing
-    select: Select,              // Selection strategy
+    group_fastq: bool,           // Output fastq records for each group of cut sites
+    orient_reads: bool,          // Reverse complement minus strand matched reads on output
+    flag_only: bool,             // Route reads failing secondary filters to their site (flagged) rather than to unmatched
+    verify_outputs: bool,        // Re-read and check fastq output files after closing
+    ordered: bool,               // Keep the input order in the fastq outputs with --fastq-threads
+    channel_report: bool,        // Write per channel barcode assignment counts
+    efficiency_report: bool,     // Write cut efficiency (matched reads / reads mapped near site) per site
+    chain_out: bool,             // Write PAF line for the chain of records used to classify each matched read
+    margin_report: bool,         // Write distribution of overruns for matches relying on the margin
+    second_site: bool,           // Write second closest cut site for matched reads
+    bed: bool,                   // Write BED files of matched reads and cut sites
+    bedgraph: bool,              // Write bedgraph of matched read start positions
+    targets_window: Option<usize>, // Write adaptive sampling targets (cut sites +/- window) in BED format
+    run_fastq: bool,             // Output fastq records for each run and cut site
+    no_fastq_output: bool,       // Process FASTQ input for statistics only (no FASTQ output files)
+    tag_only: bool,              // Write all reads to a single FASTQ file with the classification in the header
+    no_sites_output: bool,       // Separate output and report for reads mapping to contigs with no cut sites
+    also_combined: bool,         // Output fastq records for each barcode (as well as for each cut site)
+    split_by: SplitBy,           // Primary FASTQ outputs for matched reads by cut site or by barcode
+    split_results: bool,         // Write separate results file for each match status
+    combined_results: bool,      // Write combined results file (res.txt)
+    defer_results: bool,         // Write results files once after the routing stage
+    hotspots: Option<usize>,     // Number of unmatched read hotspots to report (if None, no report)
+    suggest_sites: Option<String>, // Output file for candidate cut sites from unmatched read hotspots
+    suggest_min_reads: usize,    // Minimum number of reads in a hotspot for a candidate cut site
+    end_profile: Option<usize>,  // Bin size for the end position profile of matched reads (if None, no profile)
 //    compress_suffix: Option<String>, // Suffix for compressed files (implies --compress)
 //    compress_command: Option<String>, // Command (with arguments) for compression (implies --compress)
-    mapq_thresh: usize,               // Minimum threshold for MAPQ
-    max_distance: usize,              // Maximum distance allowed from nearest cut site
-    max_unmatched: usize, // Maximum proportion number of unmatched bases allowed per read
-    margin: usize,        // Extra margin allowed when matching on 'wrong side' of cut site
+    metrics_port: Option<u16>, // Port for metrics server (if None, no server)
+    metrics_bind: Option<IpAddr>, // Address for metrics server (if None, 127.0.0.1)
+    on_complete_cmd: Option<String>, // Command to run with JSON summary at end of run
+    on_complete_url: Option<String>, // URL to POST JSON summary to at end of run
+    tmp_dir: Option<String>,         // Base directory for temporary files (if None, use $TMPDIR)
+    cache_dir: Option<String>,       // Directory for classification cache (if None, no caching)
+    synced: bool,                    // PAF and FASTQ reads are in the same order, so route reads as they are classified
+    unsorted_paf: bool,              // Records for a read may not be adjacent in the PAF input, so regroup them in memory
+    lenient: bool,                   // Skip malformed PAF lines rather than failing
+    memory_budget: Option<usize>,    // Memory for classifications before spilling to disk (if None, no limit)
+    skip_if_current: bool,           // Skip run if outputs are up to date with the inputs and options
+    post_classify_cmd: Option<String>, // Command to review (and possibly override) read classifications
+    preview: Option<usize>,          // Only classify this number of reads and print a summary (no output files)
+    skip_reads: usize,               // Number of reads to skip at the start of the PAF and FASTQ inputs
+    limit_reads: Option<usize>,      // Maximum number of reads to process from the PAF and FASTQ inputs
+    max_reads: Option<usize>,        // Stop the run (marking it as partial) after this number of reads from the PAF or FASTQ inputs
+    deadline: Option<Instant>,       // Stop the run (marking it as partial) if still running at this time
+    threads: Option<usize>,          // Number of threads for classification (if None, set automatically)
+    decompress_threads: Option<usize>, // Number of threads for decompression of the FASTQ input (if None, set automatically)
+    fastq_threads: Option<usize>,    // Number of lookup threads for routing the FASTQ input (if None, set automatically)
+    write_buffer_size: usize,        // Buffer size for output files
+    float_precision: usize,          // Decimal places for the proportion of unused bases in the results file
+    flush_interval: Option<u64>,     // Interval (seconds) between flushes of output files (if None, no periodic flushing)
+    max_paf_records: usize,          // Maximum number of PAF records used for a read (further records are ignored)
+    max_line_length: usize,          // Maximum length of a PAF line (longer lines are truncated)
+    input_files: Vec<(String, String)>, // Option and name of each input file given on the command line
 }
 
 impl Param {
     pub fn paf_file(&self) -> Option<&str> {
         self.paf_file.as_deref()
     }
+    pub fn input_format(&self) -> InputFormat {
+        self.input_format
+    }
+    pub fn align_reference(&self) -> Option<&str> {
+        self.align_reference.as_deref()
+    }
+    pub fn minimap2(&self) -> &str {
+        &self.minimap2
+    }
+    pub fn minimap2_args(&self) -> &str {
+        &self.minimap2_args
+    }
+    pub fn classification_file(&self) -> Option<&str> {
+        self.classification_file.as_deref()
+    }
+    pub fn classification_out(&self) -> Option<&str> {
+        self.classification_out.as_deref()
+    }
     pub fn fastq_file(&self) -> Option<&str> {
         self.fastq_file.as_deref()
     }
+    pub fn classifier(&self) -> &Classifier {
+        &self.classifier
+    }
+    pub fn classify_param(&self) -> &ClassifyParam {
+        self.classifier.param()
+    }
     pub fn select(&self) -> Select {
-        self.select
+        self.classify_param().select()
+    }
+    pub fn primary_select(&self) -> PrimarySelect {
+        self.classify_param().primary_select()
+    }
+    pub fn aln_policy(&self) -> AlnPolicy {
+        self.classify_param().aln_policy()
     }
     pub fn cut_sites(&self) -> Option<&CutSites> {
-        self.cut_sites.as_ref()
+        self.classify_param().cut_sites()
+    }
+    pub fn site_collisions(&self) -> &[Collision] {
+        &self.site_collisions
+    }
+    pub fn read_filter(&self) -> Option<&ReadFilter> {
+        self.read_filter.as_ref()
+    }
+    pub fn id_match(&self) -> Option<&IdMatch> {
+        self.id_match.as_ref()
+    }
+    pub fn qlen_from(&self) -> QlenFrom {
+        self.qlen_from
+    }
+    pub fn fastq_lengths(&self) -> Option<&FastqLengths> {
+        self.fastq_lengths.as_ref()
+    }
+    // Set read lengths collected from the FASTQ input (see qlen.rs)
+    pub fn set_fastq_lengths(&mut self, x: FastqLengths) {
+        self.fastq_lengths = Some(x)
+    }
+    pub fn seq_summary(&self) -> Option<&SeqSummary> {
+        self.seq_summary.as_ref()
+    }
+    pub fn length_bins(&self) -> Option<&[usize]> {
+        self.length_bins.as_deref()
+    }
+    pub fn mapq_bins(&self) -> Option<&[usize]> {
+        self.mapq_bins.as_deref()
+    }
+    pub fn read_groups(&self) -> Option<&ReadGroups> {
+        self.read_groups.as_ref()
+    }
+    pub fn snps(&self) -> Option<&SnpSet> {
+        self.snps.as_ref()
+    }
+    pub fn contam(&self) -> Option<&ContamScreen> {
+        self.contam.as_ref()
     }
     pub fn prefix(&self) -> &str {
         &self.prefix
     }
-    pub fn compress(&self) -> bool {
-        self.compress
+    pub fn compress_results(&self) -> bool {
+        self.compress_results
+    }
+    pub fn compress_fastq(&self) -> bool {
+        self.compress_fastq
+    }
+    pub fn compressor(&self) -> &Compressor {
+        &self.compressor
     }
     pub fn matched_only(&self) -> bool {
         self.matched_only
     }
+    pub fn group_fastq(&self) -> bool {
+        self.group_fastq
+    }
+    pub fn orient_reads(&self) -> bool {
+        self.orient_reads
+    }
+    pub fn flag_only(&self) -> bool {
+        self.flag_only
+    }
+    pub fn verify_outputs(&self) -> bool {
+        self.verify_outputs
+    }
+    pub fn ordered(&self) -> bool {
+        self.ordered
+    }
+    pub fn channel_report(&self) -> bool {
+        self.channel_report
+    }
+    pub fn efficiency_report(&self) -> bool {
+        self.efficiency_report
+    }
+    pub fn chain_out(&self) -> bool {
+        self.chain_out
+    }
+    pub fn margin_report(&self) -> bool {
+        self.margin_report
+    }
+    pub fn second_site(&self) -> bool {
+        self.second_site
+    }
+    pub fn bed(&self) -> bool {
+        self.bed
+    }
+    pub fn bedgraph(&self) -> bool {
+        self.bedgraph
+    }
+    pub fn targets_window(&self) -> Option<usize> {
+        self.targets_window
+    }
+    pub fn run_fastq(&self) -> bool {
+        self.run_fastq
+    }
+    pub fn no_fastq_output(&self) -> bool {
+        self.no_fastq_output
+    }
+    pub fn tag_only(&self) -> bool {
+        self.tag_only
+    }
+    pub fn no_sites_output(&self) -> bool {
+        self.no_sites_output
+    }
+    pub fn also_combined(&self) -> bool {
+        self.also_combined
+    }
+    pub fn split_by(&self) -> SplitBy {
+        self.split_by
+    }
+    pub fn split_results(&self) -> bool {
+        self.split_results
+    }
+    pub fn combined_results(&self) -> bool {
+        self.combined_results
+    }
+    pub fn defer_results(&self) -> bool {
+        self.defer_results
+    }
+    pub fn hotspots(&self) -> Option<usize> {
+        self.hotspots
+    }
+    pub fn suggest_sites(&self) -> Option<&str> {
+        self.suggest_sites.as_deref()
+    }
+    pub fn suggest_min_reads(&self) -> usize {
+        self.suggest_min_reads
+    }
+    pub fn end_profile(&self) -> Option<usize> {
+        self.end_profile
+    }
     pub fn mapq_thresh(&self) -> usize {
-        self.mapq_thresh
+        self.classify_param().mapq_thresh()
     }
     pub fn max_distance(&self) -> usize {
-        self.max_distance
+        self.classify_param().max_distance()
     }
     pub fn margin(&self) -> usize {
-        self.margin
+        self.classify_param().margin()
+    }
+    pub fn site_margin(&self) -> usize {
+        self.classify_param().site_margin()
+    }
+    pub fn end_padding(&self) -> usize {
+        self.classify_param().end_padding()
+    }
+    pub fn auto_offset(&self) -> bool {
+        self.auto_offset
+    }
+    pub fn refine_ends(&self) -> Option<usize> {
+        self.refine_ends
+    }
+    pub fn verify_deterministic(&self) -> bool {
+        self.verify_deterministic
+    }
+    pub fn start_offset(&self) -> [i64; 2] {
+        self.classify_param().start_offset()
+    }
+
+    // Set correction for systematic read start offsets (see offset.rs)
+    pub fn set_start_offset(&mut self, x: [i64; 2]) {
+        self.classifier.set_start_offset(x)
     }
     pub fn max_unmatched(&self) -> usize {
-        self.max_unmatched
+        self.classify_param().max_unmatched()
+    }
+    pub fn metrics_port(&self) -> Option<u16> {
+        self.metrics_port
+    }
+    pub fn metrics_bind(&self) -> IpAddr {
+        self.metrics_bind.unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST))
+    }
+    pub fn on_complete_cmd(&self) -> Option<&str> {
+        self.on_complete_cmd.as_deref()
+    }
+    pub fn on_complete_url(&self) -> Option<&str> {
+        self.on_complete_url.as_deref()
+    }
+    pub fn tmp_dir(&self) -> Option<&str> {
+        self.tmp_dir.as_deref()
+    }
+    pub fn cache_dir(&self) -> Option<&str> {
+        self.cache_dir.as_deref()
+    }
+    pub fn synced(&self) -> bool {
+        self.synced
+    }
+    pub fn unsorted_paf(&self) -> bool {
+        self.unsorted_paf
+    }
+    pub fn lenient(&self) -> bool {
+        self.lenient
+    }
+    pub fn memory_budget(&self) -> Option<usize> {
+        self.memory_budget
+    }
+    pub fn skip_if_current(&self) -> bool {
+        self.skip_if_current
+    }
+    pub fn post_classify_cmd(&self) -> Option<&str> {
+        self.post_classify_cmd.as_deref()
+    }
+    pub fn preview(&self) -> Option<usize> {
+        self.preview
+    }
+    pub fn skip_reads(&self) -> usize {
+        self.skip_reads
+    }
+    pub fn limit_reads(&self) -> Option<usize> {
+        self.limit_reads
+    }
+    // Maximum number of reads to process from an input (from --limit-reads or --preview)
+    pub fn read_limit(&self) -> Option<usize> {
+        [self.preview, self.limit_reads].into_iter().flatten().min()
+    }
+    pub fn max_reads(&self) -> Option<usize> {
+        self.max_reads
+    }
+    // Safety limit (--max-reads or --max-runtime) reached after n reads from an input, if any
+    pub fn run_limit(&self, n: usize) -> Option<&'static str> {
+        if self.max_reads.map(|m| n >= m).unwrap_or(false) {
+            Some("max_reads")
+        } else if self.deadline.map(|t| Instant::now() >= t).unwrap_or(false) {
+            Some("max_runtime")
+        } else {
+            None
+        }
+    }
+    pub fn threads(&self) -> Option<usize> {
+        self.threads
+    }
+    pub fn decompress_threads(&self) -> Option<usize> {
+        self.decompress_threads
+    }
+    pub fn fastq_threads(&self) -> Option<usize> {
+        self.fastq_threads
+    }
+    pub fn write_buffer_size(&self) -> usize {
+        self.write_buffer_size
+    }
+    pub fn float_precision(&self) -> usize {
+        self.float_precision
+    }
+    pub fn flush_interval(&self) -> Option<u64> {
+        self.flush_interval
+    }
+    pub fn max_paf_records(&self) -> usize {
+        self.max_paf_records
+    }
+    pub fn max_line_length(&self) -> usize {
+        self.max_line_length
+    }
+    // Input files given on the command line, with the option naming each file
+    pub fn input_files(&self) -> &[(String, String)] {
+        &self.input_files
+    }
+    // Input file given with option (the first if the option is repeated)
+    pub fn input_file(&self, option: &str) -> Option<&str> {
+        self.input_files.iter().find(|(o, _)| o == option).map(|(_, f)| f.as_str())
+    }
+    // Maximum size of a FASTQ record (4 lines)
+    pub fn max_fastq_record(&self) -> usize {
+        self.max_line_length.saturating_mul(4)
     }
 }
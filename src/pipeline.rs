@@ -0,0 +1,142 @@
+// Multithreaded routing of the FASTQ input (--fastq-threads)
+//
+// The FASTQ input is read by a reader thread, which copies the records passing the read filter
+// into batches (see FastqBatch).  The batches are passed to a set of worker threads that look up
+// the classification of each read (and screen the read for contamination with --contam), and the
+// batches are then passed with the lookup results to the main thread, which routes the records
+// and keeps the counts.  While the pipeline is running the FASTQ outputs are written by a
+// writer thread per output (see FastqOut::start_thread()).  Batches reach the main thread in the
+// order in which they are completed, so the order of the records in the outputs can differ from
+// the input order unless --ordered is given, in which case the writer threads put the records
+// back in input order using the batch numbers.
+
+use std::{
+    sync::{mpsc, Arc, Mutex},
+    thread,
+};
+
+use anyhow::Context;
+
+use crate::class_store::ReadClass;
+use crate::fastq::{FastqBatch, FastqFile};
+use crate::id_match::{IdLookup, Lookup};
+use crate::params::Param;
+
+// Number of records in a batch
+const BATCH_SIZE: usize = 4096;
+
+// FASTQ input with the read filter and the read limits applied
+pub struct FastqInput {
+    pub fq_file: FastqFile,
+    pub n_input: u64,              // Number of input reads
+    pub prefiltered: bool,         // Input is a partition of the FASTQ input (already filtered and counted)
+    limit: u64,                    // Maximum number of input reads
+    stopped: Option<&'static str>, // Limit (max_reads or max_runtime) that stopped the input
+}
+
+impl FastqInput {
+    pub fn new(fq_file: FastqFile, param: &Param) -> Self {
+        Self {
+            fq_file,
+            n_input: 0,
+            prefiltered: false,
+            limit: param.read_limit().map(|x| x as u64).unwrap_or(u64::MAX),
+            stopped: None,
+        }
+    }
+
+    // Read the next FASTQ record passing the read filter.  Returns false at the end of the
+    // input or if a limit has been reached
+    pub fn next_read(&mut self, param: &Param) -> anyhow::Result<bool> {
+        if self.prefiltered {
+            return self.fq_file.next_read().with_context(|| "Error reading from fastq partition");
+        }
+        while self.n_input < self.limit
+            && self
+                .fq_file
+                .next_read()
+                .with_context(|| "Error reading from fastq file")?
+        {
+            if !param.read_filter().map(|f| f.pass(self.fq_file.read_id())).unwrap_or(true) {
+                continue;
+            }
+            if let Some(limit) = param.run_limit(self.n_input as usize) {
+                self.stopped = Some(limit);
+                return Ok(false);
+            }
+            self.n_input += 1;
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    // Limit that stopped the input (if any).  This is cleared once taken
+    pub fn take_stopped(&mut self) -> Option<&'static str> {
+        self.stopped.take()
+    }
+}
+
+// Lookup result for a read: the classification and, with --contam, whether the read is a
+// contaminant
+pub type Found<'h, 'a> = (Lookup<'h, ReadClass<'a>>, Option<bool>);
+
+// Look up the classification of a FASTQ record and screen it for contamination
+pub fn find<'h, 'a>(id: &str, seq: &[u8], lookup: &IdLookup<'h, ReadClass<'a>>, param: &Param) -> Found<'h, 'a> {
+    (lookup.get(id), param.contam().map(|c| c.is_contaminant(id, seq)))
+}
+
+// Run the pipeline with the given number of worker threads until the input is exhausted or
+// a limit is reached.  route is called by the calling thread for each completed batch with
+// the batch number (counting from 0 in input order) and the lookup results for the records of
+// the batch
+pub fn run<'h, 'a, F>(
+    input: &mut FastqInput,
+    param: &Param,
+    lookup: &IdLookup<'h, ReadClass<'a>>,
+    workers: usize,
+    mut route: F,
+) -> anyhow::Result<()>
+where
+    F: FnMut(u64, &FastqBatch, Vec<Found<'h, 'a>>) -> anyhow::Result<()>,
+{
+    thread::scope(|s| {
+        let (batch_tx, batch_rx) = mpsc::sync_channel(2 * workers);
+        let (found_tx, found_rx) = mpsc::sync_channel(2 * workers);
+        let reader = s.spawn(move || -> anyhow::Result<()> {
+            for seq in 0.. {
+                let mut batch = FastqBatch::with_capacity(BATCH_SIZE);
+                while batch.len() < BATCH_SIZE && input.next_read(param)? {
+                    batch.push(&input.fq_file)
+                }
+                // Stop at the end of the input or if the workers have exited
+                if batch.is_empty() || batch_tx.send((seq, batch)).is_err() {
+                    break;
+                }
+            }
+            Ok(())
+        });
+        // The workers share the batch receiver, which is dropped (stopping the reader) when
+        // the last worker exits
+        let batch_rx = Arc::new(Mutex::new(batch_rx));
+        for _ in 0..workers {
+            let (batch_rx, found_tx) = (Arc::clone(&batch_rx), found_tx.clone());
+            s.spawn(move || loop {
+                let (seq, batch): (u64, FastqBatch) = match batch_rx.lock().unwrap().recv() {
+                    Ok(b) => b,
+                    Err(_) => break,
+                };
+                let found: Vec<_> = batch.iter().map(|r| find(r.read_id(), r.seq(), lookup, param)).collect();
+                if found_tx.send((seq, batch, found)).is_err() {
+                    break;
+                }
+            });
+        }
+        drop(batch_rx);
+        drop(found_tx);
+        // On an error the receiver is dropped, so the workers and then the reader exit
+        found_rx.into_iter().try_for_each(|(seq, batch, found)| route(seq, &batch, found))?;
+        reader
+            .join()
+            .map_err(|_| anyhow!("FASTQ reader thread panicked"))?
+    })
+}
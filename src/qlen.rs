@@ -0,0 +1,122 @@
+// Source of the query length used for the unused fraction calculations (--qlen-from)
+//
+// Some PAF post-processing tools (i.e., after adapter trimming) rewrite column 2 incorrectly,
+// so the query length of a read can optionally be taken from the FASTQ input instead.  The
+// FASTQ file is read in a first pass to collect the read lengths, which then replace the
+// PAF query lengths after each read is parsed.  Reads not found in the FASTQ, or where the
+// FASTQ length is shorter than the query end of a mapping record, keep the PAF query length.
+
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use anyhow::Context;
+
+use crate::{
+    fastq::FastqFile,
+    paf::PafRead,
+    params::Param,
+    summary::RunSummary,
+};
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum QlenFrom {
+    #[default]
+    Column,
+    Fastq,
+}
+
+impl std::str::FromStr for QlenFrom {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "column" => Ok(Self::Column),
+            "fastq" => Ok(Self::Fastq),
+            _ => Err(anyhow!("Invalid QlenFrom option {}", s)),
+        }
+    }
+}
+
+// Read lengths from the FASTQ input, with counts of how they were applied
+#[derive(Debug, Default)]
+pub struct FastqLengths {
+    lengths: HashMap<String, usize>,
+    checksum: u64,             // Order independent checksum of the read IDs and lengths
+    replaced: AtomicUsize,     // Reads where the FASTQ length differed from the PAF column
+    missing: AtomicUsize,      // Reads not found in the FASTQ
+    inconsistent: AtomicUsize, // Reads where the FASTQ length was shorter than a query end
+}
+
+impl FastqLengths {
+    // Collect read lengths from the FASTQ input file
+    pub fn read(param: &Param) -> anyhow::Result<Self> {
+        let fq = param
+            .fastq_file()
+            .ok_or_else(|| anyhow!("Option --qlen-from fastq requires a FASTQ input file"))?;
+        let mut fq_file = FastqFile::open(fq, param.compressor(), param.decompress_threads().unwrap_or(1))
+            .with_context(|| "Error opening fastq file")?;
+        fq_file.set_max_record(param.max_fastq_record());
+        let mut fl = Self::default();
+        while fq_file
+            .next_read()
+            .with_context(|| "Error reading from fastq file")?
+        {
+            let id = fq_file.read_id();
+            let len = fq_file.read_len();
+            let mut h = DefaultHasher::new();
+            (id, len).hash(&mut h);
+            fl.checksum = fl.checksum.wrapping_add(h.finish());
+            fl.lengths.insert(id.to_owned(), len);
+        }
+        info!("Read lengths collected for {} FASTQ reads", fl.lengths.len());
+        Ok(fl)
+    }
+
+    pub fn len(&self) -> usize {
+        self.lengths.len()
+    }
+
+    pub fn checksum(&self) -> u64 {
+        self.checksum
+    }
+
+    // Replace the query length of read with the FASTQ read length if available
+    pub fn apply(&self, read: &mut PafRead) {
+        match self.lengths.get(read.qname()) {
+            None => {
+                self.missing.fetch_add(1, Ordering::Relaxed);
+            }
+            Some(&len) if len != read.qlen => {
+                if read.set_qlen(len) {
+                    self.replaced.fetch_add(1, Ordering::Relaxed);
+                } else {
+                    self.inconsistent.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    // Log counts and record inconsistencies in the run summary
+    pub fn report(&self, summary: &mut RunSummary) {
+        info!(
+            "Query length taken from FASTQ for {} reads with a different PAF query length",
+            self.replaced.load(Ordering::Relaxed)
+        );
+        let missing = self.missing.load(Ordering::Relaxed);
+        if missing > 0 {
+            warn!("{} PAF reads not found in FASTQ (PAF query length used)", missing);
+            summary.add_anomalies("qlen_not_in_fastq", missing);
+        }
+        let inconsistent = self.inconsistent.load(Ordering::Relaxed);
+        if inconsistent > 0 {
+            warn!(
+                "{} reads had a FASTQ length shorter than a PAF query end (PAF query length used)",
+                inconsistent
+            );
+            summary.add_anomalies("qlen_inconsistent", inconsistent);
+        }
+    }
+}
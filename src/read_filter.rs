@@ -0,0 +1,81 @@
+// Selection of reads by read ID (--include-ids, --exclude-ids and --id-regex)
+
+use std::{
+    collections::HashSet,
+    io::{self, BufRead},
+    path::Path,
+};
+
+use regex::Regex;
+
+use crate::compressor::Compressor;
+
+// Read IDs from file.  The ID is taken as the first whitespace separated field on each line
+// (with any leading '@' or '>' removed), so FASTQ/FASTA headers or tab separated files with
+// the ID in the first column can be used directly.  Blank lines and lines starting with '#'
+// are ignored
+fn read_id_file<P: AsRef<Path>>(name: P, compressor: &Compressor) -> io::Result<HashSet<String>> {
+    let mut rdr = compressor.reader(Some(name))?;
+    let mut ids = HashSet::new();
+    let mut buf = String::new();
+    loop {
+        buf.clear();
+        if rdr.read_line(&mut buf)? == 0 {
+            break;
+        }
+        if buf.starts_with('#') {
+            continue;
+        }
+        if let Some(id) = buf.split_whitespace().next() {
+            ids.insert(id.trim_start_matches(['@', '>']).to_owned());
+        }
+    }
+    Ok(ids)
+}
+
+#[derive(Debug, Default)]
+pub struct ReadFilter {
+    include: Option<HashSet<String>>, // If set, only reads in this set are selected
+    exclude: Option<HashSet<String>>, // If set, reads in this set are not selected
+    regex: Option<Regex>,             // If set, only reads with IDs matching this are selected
+}
+
+impl ReadFilter {
+    pub fn add_include_file<P: AsRef<Path>>(&mut self, name: P, compressor: &Compressor) -> io::Result<()> {
+        let ids = read_id_file(name, compressor)?;
+        info!("Read {} IDs of reads to include", ids.len());
+        self.include = Some(ids);
+        Ok(())
+    }
+
+    pub fn add_exclude_file<P: AsRef<Path>>(&mut self, name: P, compressor: &Compressor) -> io::Result<()> {
+        let ids = read_id_file(name, compressor)?;
+        info!("Read {} IDs of reads to exclude", ids.len());
+        self.exclude = Some(ids);
+        Ok(())
+    }
+
+    pub fn set_regex(&mut self, re: &str) -> Result<(), regex::Error> {
+        self.regex = Some(Regex::new(re)?);
+        Ok(())
+    }
+
+    pub fn include(&self) -> Option<&HashSet<String>> {
+        self.include.as_ref()
+    }
+
+    pub fn exclude(&self) -> Option<&HashSet<String>> {
+        self.exclude.as_ref()
+    }
+
+    pub fn regex(&self) -> Option<&Regex> {
+        self.regex.as_ref()
+    }
+
+    // Check whether read with ID id is selected
+    pub fn pass(&self, id: &str) -> bool {
+        self.include.as_ref().map(|h| h.contains(id)).unwrap_or(true)
+            && !self.exclude.as_ref().map(|h| h.contains(id)).unwrap_or(false)
+            && self.regex.as_ref().map(|r| r.is_match(id)).unwrap_or(true)
+    }
+}
@@ -0,0 +1,93 @@
+// Assignment of reads to sequencing runs (--read-groups)
+//
+// When a PAF file comes from the concatenated FASTQs of several runs, the run of each read is
+// found from its read ID so that statistics (and optionally FASTQ outputs) can be split by run.
+// Each rule is either a read ID prefix or, if it starts with '^', a regular expression matched
+// against the read ID.  Rules are tried in the order given and the first match is used.
+
+use std::{
+    io::BufRead,
+    path::Path,
+};
+
+use regex::Regex;
+
+use crate::compressor::Compressor;
+use crate::error::{self, Error};
+
+#[derive(Debug)]
+enum Rule {
+    Prefix(String),
+    Regex(Regex),
+}
+
+impl Rule {
+    fn matches(&self, id: &str) -> bool {
+        match self {
+            Self::Prefix(p) => id.starts_with(p.as_str()),
+            Self::Regex(r) => r.is_match(id),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ReadGroups {
+    rules: Vec<(Rule, String)>, // Rules and corresponding run IDs
+    runs: Vec<String>,          // Distinct run IDs (sorted)
+}
+
+impl ReadGroups {
+    // Read tab separated file with the read ID prefix (or regular expression) in the first
+    // column and the run ID in the second column.  Blank lines and lines starting with '#' are
+    // ignored
+    pub fn read<P: AsRef<Path>>(name: P, compressor: &Compressor) -> error::Result<Self> {
+        let name = name.as_ref();
+        let mut rdr = compressor.reader(Some(name))?;
+        let mut rules = Vec::new();
+        let mut buf = String::new();
+        let mut line = 0;
+        loop {
+            buf.clear();
+            if rdr.read_line(&mut buf)? == 0 {
+                break;
+            }
+            line += 1;
+            if buf.starts_with('#') || buf.trim().is_empty() {
+                continue;
+            }
+            let fd: Vec<_> = buf.trim_end_matches(['\r', '\n']).split('\t').collect();
+            if fd.len() < 2 || fd[0].is_empty() || fd[1].is_empty() {
+                return Err(Error::parse("Short line (< 2 columns)").at_line(line).in_file(name));
+            }
+            let rule = if fd[0].starts_with('^') {
+                Rule::Regex(
+                    Regex::new(fd[0])
+                        .map_err(|e| Error::parse(format!("Invalid regular expression: {}", e)).at_line(line).in_file(name))?,
+                )
+            } else {
+                Rule::Prefix(fd[0].to_owned())
+            };
+            rules.push((rule, fd[1].to_owned()))
+        }
+        if rules.is_empty() {
+            return Err(Error::parse("No rules found in read group file").in_file(name));
+        }
+        let mut runs: Vec<_> = rules.iter().map(|(_, r)| r.clone()).collect();
+        runs.sort_unstable();
+        runs.dedup();
+        info!("Read {} read group rules for {} runs", rules.len(), runs.len());
+        Ok(Self { rules, runs })
+    }
+
+    // Run ID for read (None if no rule matches)
+    pub fn run(&self, id: &str) -> Option<&str> {
+        self.rules
+            .iter()
+            .find(|(rule, _)| rule.matches(id))
+            .map(|(_, r)| r.as_str())
+    }
+
+    pub fn runs(&self) -> &[String] {
+        &self.runs
+    }
+}
@@ -0,0 +1,62 @@
+// Length and quality statistics of FASTQ reads for each output (cut site, unmapped etc.)
+
+use std::{
+    collections::BTreeMap,
+    io::{self, Write},
+};
+
+#[derive(Debug, Default)]
+struct Stats {
+    lengths: Vec<u32>,
+    bases: u64,
+    qual_sum: u64, // Sum of base qualities (Phred)
+}
+
+#[derive(Debug, Default)]
+pub struct ReadStats {
+    stats: BTreeMap<String, Stats>,
+}
+
+impl ReadStats {
+    // Add read with quality string qual (Phred + 33) for output name
+    pub fn add(&mut self, name: &str, qual: &[u8]) {
+        let st = match self.stats.get_mut(name) {
+            Some(st) => st,
+            None => self.stats.entry(name.to_owned()).or_default(),
+        };
+        st.lengths.push(qual.len() as u32);
+        st.bases += qual.len() as u64;
+        st.qual_sum += qual.iter().map(|q| q.saturating_sub(33) as u64).sum::<u64>();
+    }
+
+    // Write number of reads and bases, mean and N50 read length and mean base quality for
+    // each output
+    pub fn write_report<W: Write>(&mut self, wrt: &mut W) -> io::Result<()> {
+        writeln!(wrt, "output\treads\tbases\tmean length\tN50\tmean quality")?;
+        for (name, st) in self.stats.iter_mut() {
+            let n = st.lengths.len();
+            st.lengths.sort_unstable_by(|a, b| b.cmp(a));
+            let mut cum = 0;
+            let n50 = st
+                .lengths
+                .iter()
+                .find(|l| {
+                    cum += **l as u64;
+                    2 * cum >= st.bases
+                })
+                .copied()
+                .unwrap_or(0);
+            writeln!(
+                wrt,
+                "{}\t{}\t{}\t{:.1}\t{}\t{:.2}",
+                name,
+                n,
+                st.bases,
+                st.bases as f64 / n.max(1) as f64,
+                n50,
+                st.qual_sum as f64 / st.bases.max(1) as f64
+            )?;
+        }
+        Ok(())
+    }
+}
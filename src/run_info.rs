@@ -0,0 +1,232 @@
+// Run provenance file (<prefix>_run_info.json)
+//
+// Written at the end of every run (including failed runs), recording the full command line,
+// the effective value of each parameter after option processing (i.e., the number of threads
+// when set automatically, or the start offsets estimated with --auto-offset), the version of
+// ont_demult, the start and end times of the run and the size and MD5 checksum of each input
+// file named on the command line (see stamp::input_files()).  Inputs read from stdin can not
+// be checksummed, and are recorded as null in the parameters.  An input that can not be read
+// at the end of the run is recorded with a null checksum and the error.
+
+use std::{
+    fs,
+    io::{self, Write},
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use serde_json::{json, Value};
+
+use crate::cache::{file_digest, param_digest};
+use crate::output::{close_file, open_file};
+use crate::params::Param;
+use crate::stamp::input_files;
+use crate::summary::RunSummary;
+
+pub struct RunInfo {
+    args: Vec<String>,
+    start: SystemTime,
+}
+
+// Time as an ISO 8601 UTC timestamp (i.e., 2024-03-01T12:34:56Z)
+fn utc_time(t: SystemTime) -> String {
+    let secs = t.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let (days, s) = (secs / 86400, secs % 86400);
+    // Civil date from days since 1970-01-01
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = doy - (153 * mp + 2) / 5 + 1;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+    let y = yoe + era * 400 + i64::from(m <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        y,
+        m,
+        d,
+        s / 3600,
+        (s / 60) % 60,
+        s % 60
+    )
+}
+
+// Effective parameter values
+fn param_json(param: &Param) -> Value {
+    let opt_vec = |v: Option<&[usize]>| v.map(|x| x.to_vec());
+    let files = |opt: &str| {
+        param
+            .input_files()
+            .iter()
+            .filter(|(o, _)| o == opt)
+            .map(|(_, f)| f.as_str())
+            .collect::<Vec<_>>()
+    };
+    json!({
+        "input": {
+            "paf_file": param.paf_file(),
+            "input_format": format!("{:?}", param.input_format()),
+            "align_reference": param.align_reference(),
+            "minimap2": param.align_reference().map(|_| param.minimap2()),
+            "minimap2_args": param.align_reference().map(|_| param.minimap2_args()),
+            "classification_file": param.classification_file(),
+            "fastq_file": param.fastq_file(),
+            "qlen_from": format!("{:?}", param.qlen_from()),
+            "skip_reads": param.skip_reads(),
+            "limit_reads": param.limit_reads(),
+            "max_reads": param.max_reads(),
+            "preview": param.preview(),
+            "max_paf_records": param.max_paf_records(),
+            "max_line_length": param.max_line_length(),
+            "synced": param.synced(),
+            "unsorted_paf": param.unsorted_paf(),
+            "lenient": param.lenient(),
+        },
+        "classification": {
+            "cut_files": files("cut_file"),
+            "site_groups": param.input_file("site_groups"),
+            "site_pairs": param.input_file("site_pairs"),
+            "sample_sheet": param.input_file("sample_sheet"),
+            "cut_sites": param.cut_sites().map(|cs| cs.chash.values().map(|c| c.cut_sites.len()).sum::<usize>()),
+            "site_collisions": param.site_collisions().len(),
+            "select": format!("{:?}", param.select()),
+            "primary_select": format!("{:?}", param.primary_select()),
+            "aln_policy": param.aln_policy().to_string(),
+            "mapq_threshold": param.mapq_thresh(),
+            "max_distance": param.max_distance(),
+            "max_unmatched": param.max_unmatched(),
+            "margin": param.margin(),
+            "site_margin": param.site_margin(),
+            "end_padding": param.end_padding(),
+            "start_offset": param.start_offset(),
+            "auto_offset": param.auto_offset(),
+            "refine_ends": param.refine_ends(),
+            "read_filter": param.read_filter().map(|f| json!({
+                "include_ids": files("include_ids"),
+                "exclude_ids": files("exclude_ids"),
+                "id_regex": f.regex().map(|r| r.as_str()),
+            })),
+            "contam_screen": param.contam().map(|c| json!({
+                "contaminants": param.input_file("contaminants"),
+                "contaminant_paf": param.input_file("contaminant_paf"),
+                "threshold": c.threshold(),
+            })),
+            "post_classify_cmd": param.post_classify_cmd(),
+            "digest": format!("{:x}", param_digest(param)),
+        },
+        "output": {
+            "prefix": param.prefix(),
+            "compressor": format!("{:?}", param.compressor()),
+            "compress_results": param.compress_results(),
+            "compress_fastq": param.compress_fastq(),
+            "matched_only": param.matched_only(),
+            "group_fastq": param.group_fastq(),
+            "run_fastq": param.run_fastq(),
+            "also_combined": param.also_combined(),
+            "split_by": format!("{:?}", param.split_by()),
+            "orient_reads": param.orient_reads(),
+            "flag_only": param.flag_only(),
+            "tag_only": param.tag_only(),
+            "no_fastq_output": param.no_fastq_output(),
+            "no_sites_output": param.no_sites_output(),
+            "split_results": param.split_results(),
+            "combined_results": param.combined_results(),
+            "defer_results": param.defer_results(),
+            "float_precision": param.float_precision(),
+            "classification_out": param.classification_out(),
+            "verify_outputs": param.verify_outputs(),
+            "ordered": param.ordered(),
+        },
+        "reports": {
+            "channel_report": param.channel_report(),
+            "efficiency_report": param.efficiency_report(),
+            "margin_report": param.margin_report(),
+            "chain_out": param.chain_out(),
+            "second_site": param.second_site(),
+            "bed": param.bed(),
+            "bedgraph": param.bedgraph(),
+            "targets_window": param.targets_window(),
+            "hotspots": param.hotspots(),
+            "suggest_sites": param.suggest_sites(),
+            "suggest_min_reads": param.suggest_min_reads(),
+            "end_profile": param.end_profile(),
+            "length_bins": opt_vec(param.length_bins()),
+            "mapq_bins": opt_vec(param.mapq_bins()),
+            "id_match": param.id_match().map(|m| m.to_string()),
+            "snps": param.snps().and(param.input_file("snps")),
+            "seq_summary": param.seq_summary().and(param.input_file("seq_summary")),
+            "read_groups": param.read_groups().and(param.input_file("read_groups")),
+        },
+        "resources": {
+            "threads": param.threads(),
+            "decompress_threads": param.decompress_threads(),
+            "fastq_threads": param.fastq_threads(),
+            "write_buffer_size": param.write_buffer_size(),
+            "memory_budget": param.memory_budget(),
+            "flush_interval": param.flush_interval(),
+            "tmp_dir": param.tmp_dir(),
+            "cache_dir": param.cache_dir(),
+            "skip_if_current": param.skip_if_current(),
+            "metrics_port": param.metrics_port(),
+            "metrics_bind": param.metrics_port().map(|_| param.metrics_bind().to_string()),
+            "on_complete_cmd": param.on_complete_cmd(),
+            "on_complete_url": param.on_complete_url(),
+        },
+    })
+}
+
+impl RunInfo {
+    // Record the command line and start time.  This should be called as early as possible
+    pub fn new() -> Self {
+        Self {
+            args: std::env::args().collect(),
+            start: SystemTime::now(),
+        }
+    }
+
+    fn to_json(&self, param: &Param, summary: &RunSummary, err: Option<&str>) -> Value {
+        // The end of the run is taken before the input checksums are calculated
+        let end = SystemTime::now();
+        let t = Instant::now();
+        let inputs: Vec<_> = input_files(param)
+            .into_iter()
+            .map(|file| {
+                info!("Computing checksum of {}", file);
+                match fs::metadata(file).and_then(|md| file_digest(file).map(|d| (md.len(), d))) {
+                    Ok((size, d)) => json!({"file": file, "size": size, "md5": format!("{:x}", d)}),
+                    Err(e) => {
+                        warn!("Error computing checksum of {}: {}", file, e);
+                        json!({"file": file, "size": null, "md5": null, "error": e.to_string()})
+                    }
+                }
+            })
+            .collect();
+        json!({
+            "program": "ont_demult",
+            "version": env!("CARGO_PKG_VERSION"),
+            "command_line": self.args,
+            "status": match (err, summary.stopped()) {
+                (Some(_), _) => "failed",
+                (None, Some(_)) => "partial",
+                _ => "completed",
+            },
+            "error": err,
+            "start_time": utc_time(self.start),
+            "end_time": utc_time(end),
+            "elapsed_seconds": end.duration_since(self.start).map(|d| d.as_secs_f64()).unwrap_or(0.0),
+            "params": param_json(param),
+            "inputs": inputs,
+            "checksum_seconds": t.elapsed().as_secs_f64(),
+        })
+    }
+
+    // Write <prefix>_run_info.json (never compressed)
+    pub fn write(&self, param: &Param, summary: &RunSummary, err: Option<&str>) -> io::Result<()> {
+        let v = self.to_json(param, summary, err);
+        let mut wrt = open_file("run_info.json", param, false)?;
+        serde_json::to_writer_pretty(&mut wrt, &v)?;
+        writeln!(wrt)?;
+        close_file(wrt)
+    }
+}
@@ -0,0 +1,133 @@
+// Recovery of FASTQ records from truncated compressed output files (salvage subcommand)
+//
+// If a run is interrupted, the gzip compressed FASTQ outputs are left without the end of the
+// compressed stream, and the last record may be incomplete.  The file is decompressed until
+// the end of the valid data, and all complete FASTQ records are written to a new compressed
+// file, so that the reads written before the interruption can be used without re-running.
+
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader, Read, Write},
+    path::Path,
+};
+
+use anyhow::Context;
+use flate2::read::MultiGzDecoder;
+
+use crate::compressor::Compressor;
+
+// Reader that stops at the first error, recording it, so the data decompressed before the
+// truncation or corruption can be recovered
+struct TolerantReader<R: Read> {
+    inner: R,
+    error: Option<io::Error>,
+}
+
+impl<R: Read> Read for TolerantReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.error.is_some() {
+            return Ok(0);
+        }
+        match self.inner.read(buf) {
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => Err(e),
+            Err(e) => {
+                self.error = Some(e);
+                Ok(0)
+            }
+            x => x,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct SalvageCounts {
+    reads: u64,
+    bases: u64,
+    discarded_lines: usize, // Lines of incomplete or invalid record at the end of the data
+}
+
+// Read a line including the trailing newline into buf.  Returns false at the end of the data
+// or if the line is not terminated (i.e., truncated)
+fn read_line<R: BufRead>(rdr: &mut R, buf: &mut Vec<u8>) -> io::Result<bool> {
+    buf.clear();
+    rdr.read_until(b'\n', buf)?;
+    Ok(buf.last() == Some(&b'\n'))
+}
+
+// Copy complete FASTQ records from rdr to wrt, stopping at the first incomplete or invalid
+// record
+fn copy_records<R: BufRead, W: Write>(rdr: &mut R, wrt: &mut W) -> io::Result<SalvageCounts> {
+    let mut counts = SalvageCounts::default();
+    let mut lines: [Vec<u8>; 4] = Default::default();
+    'outer: loop {
+        for (i, l) in lines.iter_mut().enumerate() {
+            if !read_line(rdr, l)? {
+                counts.discarded_lines = i + usize::from(!l.is_empty());
+                break 'outer;
+            }
+        }
+        let trim = |l: &[u8]| l.len() - 1 - usize::from(l.ends_with(b"\r\n"));
+        let ok = lines[0].starts_with(b"@")
+            && lines[2].starts_with(b"+")
+            && trim(&lines[1]) == trim(&lines[3]);
+        if !ok {
+            counts.discarded_lines = 4;
+            break;
+        }
+        for l in lines.iter() {
+            wrt.write_all(l)?
+        }
+        counts.reads += 1;
+        counts.bases += trim(&lines[1]) as u64;
+    }
+    Ok(counts)
+}
+
+// Default output name: the input name with _salvaged before the .fastq (or .fq) suffix
+fn default_output(input: &str) -> String {
+    let s = input.strip_suffix(".gz").unwrap_or(input);
+    for ext in [".fastq", ".fq"] {
+        if let Some(stem) = s.strip_suffix(ext) {
+            return format!("{}_salvaged{}", stem, ext);
+        }
+    }
+    format!("{}_salvaged", s)
+}
+
+// Recover the complete FASTQ records from input, writing them to output (compressed, with
+// the .gz suffix added if not present)
+pub fn salvage(input: &str, output: Option<&str>, compressor: &Compressor) -> anyhow::Result<()> {
+    let output = match output {
+        Some(s) => s.strip_suffix(".gz").unwrap_or(s).to_owned(),
+        None => default_output(input),
+    };
+    let out_path = format!("{}.gz", output);
+    if Path::new(&out_path) == Path::new(input) {
+        return Err(anyhow!("Output file {} is the same as the input file", out_path));
+    }
+    let mut file = BufReader::new(File::open(input).with_context(|| format!("Error opening input file {}", input))?);
+    let gzip = file.fill_buf()?.starts_with(&[0x1f, 0x8b]);
+    if !gzip {
+        warn!("Input file {} is not gzip compressed", input)
+    }
+    let inner: Box<dyn Read> = if gzip { Box::new(MultiGzDecoder::new(file)) } else { Box::new(file) };
+    let mut rdr = BufReader::new(TolerantReader { inner, error: None });
+    let mut wrt = compressor
+        .writer(&output, true)
+        .with_context(|| format!("Error creating output file {}", out_path))?;
+    let counts = copy_records(&mut rdr, &mut wrt)
+        .and_then(|c| wrt.close().map(|_| c))
+        .with_context(|| "Error writing salvaged records")?;
+    match &rdr.get_ref().error {
+        Some(e) => info!("Input {} ends early: {}", input, e),
+        None => info!("Input {} is complete", input),
+    }
+    if counts.discarded_lines > 0 {
+        info!("Discarded incomplete record ({} lines) at end of input", counts.discarded_lines)
+    }
+    info!(
+        "Recovered {} reads ({} bases) from {} to {}",
+        counts.reads, counts.bases, input, out_path
+    );
+    Ok(())
+}
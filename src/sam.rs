@@ -0,0 +1,739 @@
+// SAM/BAM input (--sam, --bam)
+//
+// Alignments in SAM or BAM format (i.e., from minimap2 -a) are converted on the fly to PAF
+// records so that they are read by PafFile and classified in exactly the same way as PAF input.
+// The PAF fields are derived from the CIGAR, with the number of matching bases taken from the
+// =/X operations or, if these are not used, from the NM tag.  Secondary alignments are given
+// the tag tp:A:S, and the AS and cs tags are passed through.  The alignments of a read must be
+// together for PafFile, so for coordinate sorted input (from the @HD header line) all records
+// are read first and grouped by read in memory.  BAM files are gzip compatible, so they have
+// already been decompressed (by the Compressor) when they reach here.
+//
+// Unaligned BAM files (i.e., from dorado) can also be used as the FASTQ input.  These are
+// converted on the fly to FASTQ records (see BamToFastq), with the auxiliary tags (i.e., ch,
+// MM and ML) written to the header line as SAM text tags, as with samtools fastq -T '*'.
+
+use std::{
+    collections::HashMap,
+    io::{self, BufRead, Read, Write},
+};
+
+use crate::error::Error;
+
+// Format of the alignment input
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum InputFormat {
+    #[default]
+    Paf,
+    Sam,
+    Bam,
+}
+
+// BAM flags
+const FLAG_UNMAPPED: u16 = 0x4;
+const FLAG_REVERSE: u16 = 0x10;
+const FLAG_SECONDARY: u16 = 0x100;
+const FLAG_SUPPLEMENTARY: u16 = 0x800;
+
+// Magic number at the start of (decompressed) BAM files
+pub const BAM_MAGIC: &[u8; 4] = b"BAM\x01";
+
+// Bases for 4 bit BAM sequence codes
+const BAM_BASES: &[u8; 16] = b"=ACMGRSVTWYHKDBN";
+
+const BAM_CIGAR_OPS: &[u8; 9] = b"MIDNSHP=X";
+
+fn le_u16(b: &[u8]) -> u16 {
+    u16::from_le_bytes([b[0], b[1]])
+}
+
+fn le_u32(b: &[u8]) -> u32 {
+    u32::from_le_bytes([b[0], b[1], b[2], b[3]])
+}
+
+fn le_i32(b: &[u8]) -> i32 {
+    le_u32(b) as i32
+}
+
+// Parse error (see error.rs) as an io::Error, as the converted records are read through the
+// io::Read implementations below
+fn parse_error<S: Into<String>>(msg: S) -> io::Error {
+    Error::parse(msg).into()
+}
+
+fn short_record() -> io::Error {
+    parse_error("Truncated BAM record")
+}
+
+// Read BAM header, returning the header text and the target names and lengths
+fn read_bam_header<R: BufRead + ?Sized>(rdr: &mut R) -> io::Result<(String, Vec<(String, usize)>)> {
+    let mut b = [0; 8];
+    rdr.read_exact(&mut b)?;
+    if &b[..4] != BAM_MAGIC {
+        return Err(parse_error("Input is not a BAM file"));
+    }
+    let mut text = vec![0; le_u32(&b[4..]) as usize];
+    rdr.read_exact(&mut text)?;
+    let mut b = [0; 4];
+    rdr.read_exact(&mut b)?;
+    let mut targets = Vec::new();
+    for _ in 0..le_u32(&b) {
+        rdr.read_exact(&mut b)?;
+        let mut name = vec![0; le_u32(&b) as usize];
+        rdr.read_exact(&mut name)?;
+        rdr.read_exact(&mut b)?;
+        name.pop(); // Remove NUL terminator
+        targets.push((String::from_utf8_lossy(&name).into_owned(), le_u32(&b) as usize))
+    }
+    Ok((String::from_utf8_lossy(&text).trim_end_matches('\0').to_owned(), targets))
+}
+
+// Read next BAM record into rec (without the length prefix), returning false at the end of input
+fn read_bam_record<R: BufRead + ?Sized>(rdr: &mut R, rec: &mut Vec<u8>) -> io::Result<bool> {
+    // Check for end of input at a record boundary
+    if rdr.fill_buf()?.is_empty() {
+        return Ok(false);
+    }
+    let mut b = [0; 4];
+    rdr.read_exact(&mut b)?;
+    rec.resize(le_u32(&b) as usize, 0);
+    rdr.read_exact(rec)?;
+    if rec.len() < 32 {
+        return Err(short_record());
+    }
+    Ok(true)
+}
+
+// Parse CIGAR string from SAM record ('*' gives an empty CIGAR)
+fn parse_cigar(s: &str) -> io::Result<Vec<(usize, u8)>> {
+    let mut v = Vec::new();
+    if s == "*" {
+        return Ok(v);
+    }
+    let mut n = 0;
+    for c in s.bytes() {
+        if c.is_ascii_digit() {
+            n = n * 10 + (c - b'0') as usize
+        } else if BAM_CIGAR_OPS.contains(&c) {
+            v.push((n, c));
+            n = 0
+        } else {
+            return Err(parse_error(format!("Invalid CIGAR operation '{}'", c as char)));
+        }
+    }
+    Ok(v)
+}
+
+// Alignment record (the fields needed to make a PAF record)
+#[derive(Default)]
+struct Aln {
+    qname: String,
+    flag: u16,
+    tid: Option<usize>, // Index of target (None if unmapped)
+    pos: usize,         // Target position (0 offset)
+    mapq: u8,
+    cigar: Vec<(usize, u8)>,
+    seq_len: usize,
+    nm: Option<i64>,
+    score: Option<i64>,
+    cs: Option<String>,
+}
+
+pub struct SamToPaf {
+    rdr: Box<dyn BufRead>,
+    bam: bool,
+    targets: Vec<(String, usize)>, // Target names and lengths from the header
+    tid: HashMap<String, usize>,   // Index of target names (SAM input)
+    out: Vec<u8>,                  // Converted PAF line(s)
+    pos: usize,                    // Position in out
+    pending: Option<String>,       // First alignment line (SAM input, read when parsing the header)
+    line: usize,
+    sorted: bool,                            // Input is coordinate sorted
+    grouped: Option<std::vec::IntoIter<Vec<u8>>>, // PAF lines grouped by read (coordinate sorted input)
+}
+
+impl SamToPaf {
+    // Set up converter for SAM (or BAM if bam is set) input, reading the header
+    pub fn new(rdr: Box<dyn BufRead>, bam: bool) -> io::Result<Self> {
+        let mut s = Self {
+            rdr,
+            bam,
+            targets: Vec::new(),
+            tid: HashMap::new(),
+            out: Vec::new(),
+            pos: 0,
+            pending: None,
+            line: 0,
+            sorted: false,
+            grouped: None,
+        };
+        let text = if bam { s.read_bam_header()? } else { s.read_sam_header()? };
+        s.sorted = text
+            .lines()
+            .any(|l| l.starts_with("@HD") && l.split('\t').any(|f| f == "SO:coordinate"));
+        if s.sorted {
+            info!("Alignments are coordinate sorted and will be grouped by read in memory (use samtools collate to avoid this)")
+        }
+        debug!("Read alignment header with {} targets", s.targets.len());
+        Ok(s)
+    }
+
+    // Read SAM header lines, returning the header text.  The first alignment line is kept
+    fn read_sam_header(&mut self) -> io::Result<String> {
+        let mut text = String::new();
+        let mut buf = String::new();
+        loop {
+            buf.clear();
+            if self.rdr.read_line(&mut buf)? == 0 {
+                break;
+            }
+            self.line += 1;
+            if !buf.starts_with('@') {
+                self.pending = Some(std::mem::take(&mut buf));
+                break;
+            }
+            if buf.starts_with("@SQ") {
+                let mut name = None;
+                let mut len = None;
+                for f in buf.trim_end().split('\t') {
+                    if let Some(s) = f.strip_prefix("SN:") {
+                        name = Some(s.to_owned())
+                    } else if let Some(s) = f.strip_prefix("LN:") {
+                        len = s.parse::<usize>().ok()
+                    }
+                }
+                match (name, len) {
+                    (Some(n), Some(l)) => {
+                        self.tid.insert(n.clone(), self.targets.len());
+                        self.targets.push((n, l))
+                    }
+                    _ => return Err(Error::parse("Invalid @SQ header line").at_line(self.line).into()),
+                }
+            }
+            text.push_str(&buf);
+        }
+        Ok(text)
+    }
+
+    // Read BAM header, returning the header text
+    fn read_bam_header(&mut self) -> io::Result<String> {
+        let (text, targets) = read_bam_header(&mut self.rdr)?;
+        self.targets = targets;
+        Ok(text)
+    }
+
+    fn next_sam(&mut self) -> io::Result<Option<Aln>> {
+        let buf = match self.pending.take() {
+            Some(s) => s,
+            None => {
+                let mut s = String::new();
+                if self.rdr.read_line(&mut s)? == 0 {
+                    return Ok(None);
+                }
+                self.line += 1;
+                s
+            }
+        };
+        let fd: Vec<_> = buf.trim_end_matches(['\r', '\n']).split('\t').collect();
+        if fd.len() < 11 {
+            return Err(Error::parse("Short SAM line (< 11 columns)").at_line(self.line).into());
+        }
+        let err = |s: &str| io::Error::from(Error::parse(format!("Parse error for {}", s)).at_line(self.line));
+        let tid = match fd[2] {
+            "*" => None,
+            s => Some(
+                *self
+                    .tid
+                    .get(s)
+                    .ok_or_else(|| Error::parse(format!("Reference {} not found in SAM header", s)).at_line(self.line))?,
+            ),
+        };
+        let mut aln = Aln {
+            qname: fd[0].to_owned(),
+            flag: fd[1].parse().map_err(|_| err("flag"))?,
+            tid,
+            pos: fd[3].parse::<usize>().map_err(|_| err("position"))?.saturating_sub(1),
+            mapq: fd[4].parse().map_err(|_| err("mapq"))?,
+            cigar: parse_cigar(fd[5]).map_err(|e| err(&e.to_string()))?,
+            seq_len: if fd[9] == "*" { 0 } else { fd[9].len() },
+            ..Default::default()
+        };
+        for t in &fd[11..] {
+            if let Some(s) = t.strip_prefix("NM:i:") {
+                aln.nm = s.parse().ok()
+            } else if let Some(s) = t.strip_prefix("AS:i:") {
+                aln.score = s.parse().ok()
+            } else if let Some(s) = t.strip_prefix("cs:Z:") {
+                aln.cs = Some(s.to_owned())
+            }
+        }
+        Ok(Some(aln))
+    }
+
+    fn next_bam(&mut self) -> io::Result<Option<Aln>> {
+        let mut rec = Vec::new();
+        if !read_bam_record(&mut self.rdr, &mut rec)? {
+            return Ok(None);
+        }
+        let refid = le_i32(&rec[0..]);
+        let l_name = rec[8] as usize;
+        let n_cigar = le_u16(&rec[12..]) as usize;
+        let l_seq = le_u32(&rec[16..]) as usize;
+        let mut off = 32;
+        let get = |off: usize, n: usize| rec.get(off..off + n).ok_or_else(short_record);
+        let name = get(off, l_name)?;
+        let mut aln = Aln {
+            qname: String::from_utf8_lossy(&name[..l_name.saturating_sub(1)]).into_owned(),
+            flag: le_u16(&rec[14..]),
+            tid: usize::try_from(refid).ok(),
+            pos: le_i32(&rec[4..]).max(0) as usize,
+            mapq: rec[9],
+            seq_len: l_seq,
+            ..Default::default()
+        };
+        off += l_name;
+        aln.cigar = get(off, 4 * n_cigar)?
+            .chunks(4)
+            .map(|c| {
+                let x = le_u32(c);
+                ((x >> 4) as usize, BAM_CIGAR_OPS.get((x & 0xf) as usize).copied().unwrap_or(b'?'))
+            })
+            .collect();
+        off += 4 * n_cigar + l_seq.div_ceil(2) + l_seq;
+        // Auxiliary tags
+        let mut cg = None;
+        while off + 3 <= rec.len() {
+            let tag = [rec[off], rec[off + 1]];
+            let typ = rec[off + 2];
+            off += 3;
+            let int = |off: usize, t: u8| -> io::Result<(i64, usize)> {
+                Ok(match t {
+                    b'c' => (get(off, 1)?[0] as i8 as i64, 1),
+                    b'C' => (get(off, 1)?[0] as i64, 1),
+                    b's' => (le_u16(get(off, 2)?) as i16 as i64, 2),
+                    b'S' => (le_u16(get(off, 2)?) as i64, 2),
+                    b'i' => (le_i32(get(off, 4)?) as i64, 4),
+                    b'I' => (le_u32(get(off, 4)?) as i64, 4),
+                    b'f' => (0, 4),
+                    b'A' => (0, 1),
+                    _ => return Err(parse_error(format!("Invalid BAM tag type '{}'", t as char))),
+                })
+            };
+            match typ {
+                b'Z' | b'H' => {
+                    let end = rec[off..].iter().position(|c| *c == 0).ok_or_else(short_record)?;
+                    if &tag == b"cs" {
+                        aln.cs = Some(String::from_utf8_lossy(&rec[off..off + end]).into_owned())
+                    }
+                    off += end + 1
+                }
+                b'B' => {
+                    let sub = get(off, 1)?[0];
+                    let n = le_u32(get(off + 1, 4)?) as usize;
+                    let size = int(off, sub).map(|(_, k)| k)?;
+                    off += 5;
+                    if &tag == b"CG" && sub == b'I' {
+                        cg = Some(
+                            get(off, 4 * n)?
+                                .chunks(4)
+                                .map(|c| {
+                                    let x = le_u32(c);
+                                    ((x >> 4) as usize, BAM_CIGAR_OPS.get((x & 0xf) as usize).copied().unwrap_or(b'?'))
+                                })
+                                .collect::<Vec<_>>(),
+                        )
+                    }
+                    off += n * size
+                }
+                t => {
+                    let (x, k) = int(off, t)?;
+                    match &tag {
+                        b"NM" => aln.nm = Some(x),
+                        b"AS" => aln.score = Some(x),
+                        _ => (),
+                    }
+                    off += k
+                }
+            }
+        }
+        // CIGARs with more than 65535 operations are stored in the CG tag, with a placeholder
+        // CIGAR of <l_seq>S<ref_len>N in the record
+        if let Some(v) = cg {
+            if aln.cigar.len() == 2 && aln.cigar[0] == (l_seq, b'S') && aln.cigar[1].1 == b'N' {
+                aln.cigar = v
+            }
+        }
+        Ok(Some(aln))
+    }
+
+    fn next_aln(&mut self) -> io::Result<Option<Aln>> {
+        if self.bam {
+            self.next_bam()
+        } else {
+            self.next_sam()
+        }
+    }
+
+    // Read all alignments, grouping the PAF lines by read (in order of first appearance)
+    fn group_records(&mut self) -> io::Result<()> {
+        let mut ix: HashMap<String, usize> = HashMap::new();
+        let mut groups: Vec<Vec<u8>> = Vec::new();
+        while let Some(a) = self.next_aln()? {
+            self.out.clear();
+            self.write_paf(&a)?;
+            let i = *ix.entry(a.qname).or_insert_with(|| {
+                groups.push(Vec::new());
+                groups.len() - 1
+            });
+            groups[i].extend_from_slice(&self.out)
+        }
+        self.out.clear();
+        debug!("Grouped alignments for {} reads", groups.len());
+        self.grouped = Some(groups.into_iter());
+        Ok(())
+    }
+
+    // Write PAF line for alignment to out
+    fn write_paf(&mut self, a: &Aln) -> io::Result<()> {
+        let target = a.tid.and_then(|i| self.targets.get(i));
+        let (tname, tlen) = match target {
+            Some(t) if a.flag & FLAG_UNMAPPED == 0 && !a.cigar.is_empty() => (t.0.as_str(), t.1),
+            _ => {
+                // Unmapped read (see PafRecord::unmapped())
+                let qlen = if a.seq_len > 0 { a.seq_len.to_string() } else { "*".to_owned() };
+                return writeln!(self.out, "{}\t{}\t0\t0\t*\t*\t0\t0\t0\t0\t0\t0", a.qname, qlen);
+            }
+        };
+        let (mut lead, mut trail, mut qaln, mut taln) = (0, 0, 0, 0);
+        let (mut m, mut eq, mut x, mut ins, mut del) = (0, 0, 0, 0, 0);
+        for (l, op) in a.cigar.iter().copied() {
+            match op {
+                b'S' | b'H' if qaln == 0 && taln == 0 => lead += l,
+                b'S' | b'H' => trail += l,
+                b'M' => m += l,
+                b'=' => eq += l,
+                b'X' => x += l,
+                b'I' => ins += l,
+                b'D' => del += l,
+                b'N' => taln += l,
+                b'P' => (),
+                _ => return Err(parse_error(format!("Invalid CIGAR operation for read {}", a.qname))),
+            }
+            if matches!(op, b'M' | b'=' | b'X' | b'I') {
+                qaln += l
+            }
+            if matches!(op, b'M' | b'=' | b'X' | b'D') {
+                taln += l
+            }
+        }
+        // Mismatches in M operations from the NM tag (which also counts X, I and D bases)
+        let m_diff = a
+            .nm
+            .map(|nm| (nm - (x + ins + del) as i64).max(0) as usize)
+            .unwrap_or(0);
+        let matching = eq + m.saturating_sub(m_diff);
+        let qlen = lead + qaln + trail;
+        let (strand, qstart) = if a.flag & FLAG_REVERSE == 0 { ('+', lead) } else { ('-', trail) };
+        write!(
+            self.out,
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\ttp:A:{}",
+            a.qname,
+            qlen,
+            qstart,
+            qstart + qaln,
+            strand,
+            tname,
+            tlen,
+            a.pos,
+            a.pos + taln,
+            matching,
+            m + eq + x + ins + del,
+            a.mapq,
+            if a.flag & FLAG_SECONDARY == 0 { 'P' } else { 'S' }
+        )?;
+        if let Some(s) = a.score {
+            write!(self.out, "\tAS:i:{}", s)?
+        }
+        if let Some(cs) = a.cs.as_ref() {
+            write!(self.out, "\tcs:Z:{}", cs)?
+        }
+        writeln!(self.out)
+    }
+}
+
+impl Read for SamToPaf {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let src = self.fill_buf()?;
+        let n = src.len().min(buf.len());
+        buf[..n].copy_from_slice(&src[..n]);
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl BufRead for SamToPaf {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        while self.pos == self.out.len() {
+            self.out.clear();
+            self.pos = 0;
+            if self.sorted && self.grouped.is_none() {
+                self.group_records()?
+            }
+            if let Some(it) = self.grouped.as_mut() {
+                match it.next() {
+                    Some(v) => self.out = v,
+                    None => break,
+                }
+            } else {
+                match self.next_aln()? {
+                    Some(a) => self.write_paf(&a)?,
+                    None => break,
+                }
+            }
+        }
+        Ok(&self.out[self.pos..])
+    }
+
+    fn consume(&mut self, n: usize) {
+        self.pos = (self.pos + n).min(self.out.len())
+    }
+}
+
+// Write auxiliary tags from BAM record as SAM text tags (each preceded by a tab)
+fn write_sam_tags(aux: &[u8], out: &mut Vec<u8>) -> io::Result<()> {
+    let get = |off: usize, n: usize| aux.get(off..off + n).ok_or_else(short_record);
+    // Value of numeric type t at off as text, and its size
+    let num = |off: usize, t: u8| -> io::Result<(String, usize)> {
+        Ok(match t {
+            b'c' => ((get(off, 1)?[0] as i8).to_string(), 1),
+            b'C' => (get(off, 1)?[0].to_string(), 1),
+            b's' => ((le_u16(get(off, 2)?) as i16).to_string(), 2),
+            b'S' => (le_u16(get(off, 2)?).to_string(), 2),
+            b'i' => (le_i32(get(off, 4)?).to_string(), 4),
+            b'I' => (le_u32(get(off, 4)?).to_string(), 4),
+            b'f' => (f32::from_bits(le_u32(get(off, 4)?)).to_string(), 4),
+            _ => return Err(parse_error(format!("Invalid BAM tag type '{}'", t as char))),
+        })
+    };
+    let mut off = 0;
+    while off + 3 <= aux.len() {
+        let (tag, typ) = (&aux[off..off + 2], aux[off + 2]);
+        out.push(b'\t');
+        out.extend_from_slice(tag);
+        off += 3;
+        match typ {
+            b'A' => {
+                write!(out, ":A:{}", get(off, 1)?[0] as char)?;
+                off += 1
+            }
+            b'Z' | b'H' => {
+                let end = aux[off..].iter().position(|c| *c == 0).ok_or_else(short_record)?;
+                write!(out, ":{}:", typ as char)?;
+                out.extend_from_slice(&aux[off..off + end]);
+                off += end + 1
+            }
+            b'B' => {
+                let sub = get(off, 1)?[0];
+                let n = le_u32(get(off + 1, 4)?) as usize;
+                write!(out, ":B:{}", sub as char)?;
+                off += 5;
+                for _ in 0..n {
+                    let (x, k) = num(off, sub)?;
+                    write!(out, ",{}", x)?;
+                    off += k
+                }
+            }
+            b'f' => {
+                let (x, k) = num(off, typ)?;
+                write!(out, ":f:{}", x)?;
+                off += k
+            }
+            t => {
+                let (x, k) = num(off, t)?;
+                write!(out, ":i:{}", x)?;
+                off += k
+            }
+        }
+    }
+    Ok(())
+}
+
+// Convert unaligned BAM input to FASTQ records.  Secondary and supplementary records are
+// skipped, and reverse strand records (from aligned input) are reverse complemented to give
+// the original read.  Missing base qualities are written as '!'
+pub struct BamToFastq {
+    rdr: Box<dyn BufRead + Send>,
+    rec: Vec<u8>, // Current BAM record
+    out: Vec<u8>, // Converted FASTQ record
+    pos: usize,   // Position in out
+}
+
+impl BamToFastq {
+    pub fn new(mut rdr: Box<dyn BufRead + Send>) -> io::Result<Self> {
+        read_bam_header(&mut rdr)?;
+        Ok(Self {
+            rdr,
+            rec: Vec::new(),
+            out: Vec::new(),
+            pos: 0,
+        })
+    }
+
+    // Write FASTQ record for the current BAM record to out
+    fn write_fastq(&mut self) -> io::Result<()> {
+        let rec = &self.rec;
+        let get = |off: usize, n: usize| rec.get(off..off + n).ok_or_else(short_record);
+        let l_name = rec[8] as usize;
+        let n_cigar = le_u16(&rec[12..]) as usize;
+        let flag = le_u16(&rec[14..]);
+        let l_seq = le_u32(&rec[16..]) as usize;
+        let name = get(32, l_name)?;
+        let mut off = 32 + l_name + 4 * n_cigar;
+        let mut seq: Vec<u8> = get(off, l_seq.div_ceil(2))?
+            .iter()
+            .flat_map(|c| [BAM_BASES[(c >> 4) as usize], BAM_BASES[(c & 0xf) as usize]])
+            .take(l_seq)
+            .collect();
+        off += l_seq.div_ceil(2);
+        let q = get(off, l_seq)?;
+        let mut qual: Vec<u8> = if q.first() == Some(&0xff) {
+            vec![b'!'; l_seq]
+        } else {
+            q.iter().map(|c| c.saturating_add(33)).collect()
+        };
+        off += l_seq;
+        if flag & FLAG_REVERSE != 0 {
+            seq.reverse();
+            for c in seq.iter_mut() {
+                *c = match *c {
+                    b'A' => b'T',
+                    b'C' => b'G',
+                    b'G' => b'C',
+                    b'T' => b'A',
+                    x => x,
+                }
+            }
+            qual.reverse()
+        }
+        self.out.push(b'@');
+        self.out.extend_from_slice(&name[..l_name.saturating_sub(1)]);
+        write_sam_tags(&rec[off..], &mut self.out)?;
+        self.out.push(b'\n');
+        self.out.extend_from_slice(&seq);
+        self.out.extend_from_slice(b"\n+\n");
+        self.out.extend_from_slice(&qual);
+        self.out.push(b'\n');
+        Ok(())
+    }
+}
+
+impl Read for BamToFastq {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let src = self.fill_buf()?;
+        let n = src.len().min(buf.len());
+        buf[..n].copy_from_slice(&src[..n]);
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl BufRead for BamToFastq {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        while self.pos == self.out.len() {
+            self.out.clear();
+            self.pos = 0;
+            if !read_bam_record(&mut self.rdr, &mut self.rec)? {
+                break;
+            }
+            if le_u16(&self.rec[14..]) & (FLAG_SECONDARY | FLAG_SUPPLEMENTARY) == 0 {
+                self.write_fastq()?
+            }
+        }
+        Ok(&self.out[self.pos..])
+    }
+
+    fn consume(&mut self, n: usize) {
+        self.pos = (self.pos + n).min(self.out.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEADER: &str = "@HD\tVN:1.6\n@SQ\tSN:chr1\tLN:10000\n@SQ\tSN:chr2\tLN:5000\n";
+
+    // Convert SAM text to PAF
+    fn to_paf(sam: &str) -> io::Result<String> {
+        let rdr: Box<dyn BufRead> = Box::new(io::Cursor::new(sam.as_bytes().to_vec()));
+        let mut conv = SamToPaf::new(rdr, false)?;
+        let mut s = String::new();
+        conv.read_to_string(&mut s)?;
+        Ok(s)
+    }
+
+    // Expected PAF lines are as written by minimap2 -c for the same alignments
+
+    #[test]
+    fn soft_clips_and_tags() {
+        // 2 mismatches in the M operations (NM counts the 2 inserted and 3 deleted bases as well)
+        let sam = format!(
+            "{}r1\t0\tchr1\t101\t60\t5S10M2I8M3D5M4S\t*\t0\t0\t{}\t*\tNM:i:7\tAS:i:30\tcs:Z::10+ac:3*ag:2*ct:1-tca:5\n",
+            HEADER,
+            "A".repeat(34)
+        );
+        assert_eq!(
+            to_paf(&sam).unwrap(),
+            "r1\t34\t5\t30\t+\tchr1\t10000\t100\t126\t21\t28\t60\ttp:A:P\tAS:i:30\tcs:Z::10+ac:3*ag:2*ct:1-tca:5\n"
+        );
+    }
+
+    #[test]
+    fn reverse_strand_hard_clips() {
+        // Hard clipped bases are not in SEQ but count towards the query length, and on the
+        // reverse strand the query start is given by the trailing clip
+        let sam = format!("{}r2\t16\tchr1\t1\t30\t3H20M7H\t*\t0\t0\t{}\t*\tNM:i:0\n", HEADER, "C".repeat(20));
+        assert_eq!(to_paf(&sam).unwrap(), "r2\t30\t7\t27\t-\tchr1\t10000\t0\t20\t20\t20\t30\ttp:A:P\n");
+    }
+
+    #[test]
+    fn secondary_and_supplementary() {
+        // Matching bases from =/X operations (SEQ is omitted for secondary alignments)
+        let sam = format!(
+            "{}r3\t256\tchr2\t11\t0\t10=1X9=\t*\t0\t0\t*\t*\nr3\t2064\tchr1\t51\t40\t15S10M\t*\t0\t0\t{}\t*\tNM:i:1\n",
+            HEADER,
+            "G".repeat(25)
+        );
+        assert_eq!(
+            to_paf(&sam).unwrap(),
+            "r3\t20\t0\t20\t+\tchr2\t5000\t10\t30\t19\t20\t0\ttp:A:S\n\
+             r3\t25\t0\t10\t-\tchr1\t10000\t50\t60\t9\t10\t40\ttp:A:P\n"
+        );
+    }
+
+    #[test]
+    fn unmapped() {
+        let sam = format!("{}r4\t4\t*\t0\t0\t*\t*\t0\t0\tACGTACGTACGT\t*\n", HEADER);
+        assert_eq!(to_paf(&sam).unwrap(), "r4\t12\t0\t0\t*\t*\t0\t0\t0\t0\t0\t0\n");
+    }
+
+    #[test]
+    fn sorted_input_grouped_by_read() {
+        let sam = "@HD\tVN:1.6\tSO:coordinate\n@SQ\tSN:chr1\tLN:10000\n\
+                   r1\t0\tchr1\t1\t60\t10M\t*\t0\t0\t*\t*\n\
+                   r2\t0\tchr1\t5\t60\t10M\t*\t0\t0\t*\t*\n\
+                   r1\t2048\tchr1\t101\t60\t10M\t*\t0\t0\t*\t*\n";
+        let names: Vec<_> = to_paf(sam)
+            .unwrap()
+            .lines()
+            .map(|l| l.split('\t').next().unwrap().to_owned())
+            .collect();
+        assert_eq!(names, ["r1", "r1", "r2"]);
+    }
+
+    #[test]
+    fn unknown_reference() {
+        let sam = format!("{}r5\t0\tchr3\t1\t60\t10M\t*\t0\t0\t*\t*\n", HEADER);
+        assert!(to_paf(&sam).is_err());
+    }
+}
@@ -0,0 +1,57 @@
+// Second best cut site for matched reads
+//
+// For each matched read, the cut site closest to the read start other than the matched site
+// is found (from all sites on the same contig) and written with its distance from the read
+// start.  The difference between the distances of the two sites (the margin of victory)
+// allows reads to be filtered downstream where sites lie close together on a contig.
+// Distances are measured along the contig, so are not wrapped for circular contigs.
+
+use std::{
+    collections::HashMap,
+    io::{self, Write},
+};
+
+use crate::cut_site::{CutSites, Site};
+use crate::output::{close_file, OutWriter};
+use crate::paf::Match;
+
+pub struct SecondSite<'a> {
+    sites: HashMap<&'a str, &'a [Site]>, // Sites on the same contig for each site
+    wrt: OutWriter,
+}
+
+impl<'a> SecondSite<'a> {
+    pub fn new(cut_sites: &'a CutSites, mut wrt: OutWriter) -> io::Result<Self> {
+        let mut sites = HashMap::new();
+        for ctg in cut_sites.chash.values() {
+            for site in ctg.cut_sites.iter() {
+                sites.insert(site.name.as_str(), ctg.cut_sites.as_slice());
+            }
+        }
+        writeln!(
+            wrt,
+            "read_name\tcut_site\tbarcode\tdistance\tsecond_site\tsecond_barcode\tsecond_distance\tmargin"
+        )?;
+        Ok(Self { sites, wrt })
+    }
+
+    pub fn add(&mut self, name: &str, m: &Match<'a>) -> io::Result<()> {
+        let pos = m.start();
+        let d = pos.abs_diff(m.site.pos);
+        write!(self.wrt, "{}\t{}\t{}\t{}\t", name, m.site.name, m.site.barcode, d)?;
+        let second = self.sites.get(m.site.name.as_str()).and_then(|v| {
+            v.iter()
+                .filter(|s| !std::ptr::eq(*s, m.site))
+                .map(|s| (s, pos.abs_diff(s.pos)))
+                .min_by_key(|(_, d)| *d)
+        });
+        match second {
+            Some((s, d1)) => writeln!(self.wrt, "{}\t{}\t{}\t{}", s.name, s.barcode, d1, d1 as i64 - d as i64),
+            None => writeln!(self.wrt, "*\t*\t*\t*"),
+        }
+    }
+
+    pub fn finish(self) -> io::Result<()> {
+        close_file(self.wrt)
+    }
+}
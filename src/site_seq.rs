@@ -0,0 +1,130 @@
+// Extraction of the reference sequence around each cut site (site-seq subcommand)
+//
+// For each cut site the reference sequence from window bp before the cut to window bp after
+// the cut is written to FASTA, i.e., for designing verification primers.  The cut sites are
+// loaded with the same options as for the main command, so the sequences correspond to the
+// sites used for classification.  On circular contigs the sequence wraps around the contig
+// end; on linear contigs it is truncated at the contig ends.
+
+use std::{
+    fs::File,
+    io::{self, BufRead, BufWriter, Write},
+};
+
+use anyhow::Context;
+
+use crate::compressor::Compressor;
+use crate::cut_site::{Contig, CutSites};
+use crate::error::Error;
+
+// Bases per line of FASTA output
+const LINE_LENGTH: usize = 60;
+
+fn write_fasta<W: Write>(wrt: &mut W, header: &str, seq: &[u8]) -> io::Result<()> {
+    writeln!(wrt, ">{}", header)?;
+    for line in seq.chunks(LINE_LENGTH) {
+        wrt.write_all(line)?;
+        writeln!(wrt)?;
+    }
+    Ok(())
+}
+
+// Write the sequences around the sites on contig ctg with sequence seq.  Returns the number of
+// sites written
+fn write_contig_sites<W: Write>(ctg: &Contig, seq: &[u8], window: usize, wrt: &mut W) -> io::Result<usize> {
+    let l = seq.len();
+    let circular = ctg.circular.unwrap_or(false);
+    let mut n = 0;
+    for site in ctg.cut_sites.iter() {
+        if site.pos == 0 || site.pos > l + 1 {
+            warn!("Cut site {} lies outside contig {} ({} bp)", site.name, ctg.name, l);
+            continue;
+        }
+        // Site position is the first base after the cut (1 offset), so the cut lies before
+        // base p (0 offset)
+        let p = site.pos - 1;
+        let (s, e, v) = if circular && l > 0 {
+            // The sequence is limited to the contig length so that it does not repeat
+            let a = window.min(l / 2);
+            let b = window.min(l - a);
+            let s = (p + l - a) % l;
+            let v: Vec<u8> = (0..a + b).map(|i| seq[(s + i) % l]).collect();
+            (s, (s + a + b + l - 1) % l, v)
+        } else {
+            let (s, e) = (p.saturating_sub(window), (p + window).min(l));
+            (s, e.saturating_sub(1), seq[s..e].to_vec())
+        };
+        if v.is_empty() {
+            warn!("No sequence for cut site {} on contig {}", site.name, ctg.name);
+            continue;
+        }
+        let header = format!("{} {}:{}-{} barcode={}", site.name, ctg.name, s + 1, e + 1, site.barcode);
+        write_fasta(wrt, &header, &v)?;
+        n += 1
+    }
+    Ok(n)
+}
+
+// Read reference sequences from rdr and write the sequence around each cut site to wrt.
+// Sites are written in reference order and then by position.  Returns the number of sites
+// written
+fn site_seqs<R: BufRead, W: Write>(mut rdr: R, cut_sites: &CutSites, window: usize, wrt: &mut W) -> io::Result<usize> {
+    let mut buf = String::new();
+    let mut name: Option<String> = None;
+    let mut seq = Vec::new();
+    let mut n = 0;
+    let mut found = 0;
+    loop {
+        buf.clear();
+        let l = rdr.read_line(&mut buf)?;
+        if l == 0 || buf.starts_with('>') {
+            if let Some(ctg) = name.take().and_then(|s| cut_sites.chash.get(s.as_str())) {
+                n += write_contig_sites(ctg, &seq, window, wrt)?;
+                found += 1;
+            }
+            seq.clear();
+            if l == 0 {
+                break;
+            }
+            name = buf[1..].split_whitespace().next().map(|s| s.to_owned());
+            if name.is_none() {
+                return Err(Error::parse("Missing sequence name in reference").into());
+            }
+        } else if name.as_ref().map(|s| cut_sites.chash.contains_key(s.as_str())).unwrap_or(false) {
+            seq.extend(buf.trim_end().bytes())
+        }
+    }
+    let ctgs = cut_sites.chash.values().filter(|c| !c.cut_sites.is_empty()).count();
+    if found < ctgs {
+        warn!("{} contigs with cut sites not found in reference", ctgs - found)
+    }
+    Ok(n)
+}
+
+// Write the sequences around the cut sites from the FASTA file reference to output (or
+// stdout if None)
+pub fn write_site_seqs(
+    reference: &str,
+    cut_sites: &CutSites,
+    window: usize,
+    output: Option<&str>,
+    compressor: &Compressor,
+) -> anyhow::Result<()> {
+    let rdr = compressor
+        .reader(Some(reference))
+        .with_context(|| format!("Error opening reference file {}", reference))?;
+    let n = match output {
+        Some(file) => {
+            let mut wrt = BufWriter::new(File::create(file).with_context(|| format!("Error creating output file {}", file))?);
+            site_seqs(rdr, cut_sites, window, &mut wrt).and_then(|n| wrt.flush().map(|_| n))
+        }
+        None => {
+            let stdout = io::stdout();
+            let mut wrt = BufWriter::new(stdout.lock());
+            site_seqs(rdr, cut_sites, window, &mut wrt).and_then(|n| wrt.flush().map(|_| n))
+        }
+    }
+    .with_context(|| "Error writing cut site sequences")?;
+    info!("Wrote sequences for {} cut sites", n);
+    Ok(())
+}
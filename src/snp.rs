@@ -0,0 +1,114 @@
+// Allele assignment of matched reads at SNPs
+//
+// SNPs are read from a VCF file (only biallelic single base substitutions are used).  The
+// base of a read at each SNP that it covers is found from the cs tag of the PAF records
+// (minimap2 --cs), and the read is assigned to the reference or alternate allele by majority
+// vote over the SNPs.  Reads covering no SNPs, or with equal numbers of reference and alternate
+// bases, are not assigned.
+
+use std::{
+    collections::HashMap,
+    io::BufRead,
+    path::Path,
+};
+
+use crate::compressor::Compressor;
+use crate::error::{self, Error};
+use crate::paf::PafRead;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Allele {
+    Ref,
+    Alt,
+}
+
+impl Allele {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Ref => "ref",
+            Self::Alt => "alt",
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Snp {
+    pos: usize, // 0 offset
+    ref_base: u8,
+    alt_base: u8,
+}
+
+#[derive(Debug, Default)]
+pub struct SnpSet {
+    snps: HashMap<String, Vec<Snp>>, // SNPs for each contig, sorted by position
+}
+
+impl SnpSet {
+    pub fn read_vcf<P: AsRef<Path>>(name: P, compressor: &Compressor) -> error::Result<Self> {
+        let name = name.as_ref();
+        let mut rdr = compressor.reader(Some(name))?;
+        let mut snps: HashMap<String, Vec<Snp>> = HashMap::new();
+        let mut buf = String::new();
+        let (mut line, mut n, mut skipped) = (0, 0, 0);
+        loop {
+            buf.clear();
+            if rdr.read_line(&mut buf)? == 0 {
+                break;
+            }
+            line += 1;
+            if buf.starts_with('#') || buf.trim().is_empty() {
+                continue;
+            }
+            let fd: Vec<_> = buf.trim_end_matches(['\r', '\n']).split('\t').collect();
+            if fd.len() < 5 {
+                return Err(Error::parse("Short line (< 5 columns)").at_line(line).in_file(name));
+            }
+            let pos = fd[1]
+                .parse::<usize>()
+                .ok()
+                .filter(|x| *x > 0)
+                .ok_or_else(|| Error::parse("Invalid position").at_line(line).in_file(name))?;
+            let base = |s: &str| match s.as_bytes() {
+                [c] if b"ACGTacgt".contains(c) => Some(c.to_ascii_uppercase()),
+                _ => None,
+            };
+            match (base(fd[3]), base(fd[4])) {
+                (Some(ref_base), Some(alt_base)) => {
+                    snps.entry(fd[0].to_owned()).or_default().push(Snp {
+                        pos: pos - 1,
+                        ref_base,
+                        alt_base,
+                    });
+                    n += 1
+                }
+                _ => skipped += 1,
+            }
+        }
+        for v in snps.values_mut() {
+            v.sort_unstable_by_key(|s| s.pos)
+        }
+        info!("Read {} SNPs ({} other variants skipped)", n, skipped);
+        Ok(Self { snps })
+    }
+
+    // Assign read to an allele (see above)
+    pub fn allele(&self, read: &PafRead, mapq_thresh: usize) -> Option<Allele> {
+        let (mut n_ref, mut n_alt) = (0, 0);
+        for (ctg, start, end) in read.mapped_ranges(mapq_thresh) {
+            let Some(v) = self.snps.get(ctg) else { continue };
+            let i = v.partition_point(|s| s.pos < start);
+            for snp in v[i..].iter().take_while(|s| s.pos < end) {
+                match read.base_at(ctg, snp.pos, snp.ref_base) {
+                    Some(b) if b == snp.ref_base => n_ref += 1,
+                    Some(b) if b == snp.alt_base => n_alt += 1,
+                    _ => (),
+                }
+            }
+        }
+        match n_ref.cmp(&n_alt) {
+            std::cmp::Ordering::Greater => Some(Allele::Ref),
+            std::cmp::Ordering::Less => Some(Allele::Alt),
+            std::cmp::Ordering::Equal => None,
+        }
+    }
+}
@@ -0,0 +1,91 @@
+// Skipping runs whose outputs are up to date (--skip-if-current)
+//
+// After a run completes, a stamp file (<prefix>_stamp.txt) is written recording the version,
+// a digest of the command line and of the parameters affecting classification (including the
+// cut sites), and the size and modification time of every input file named on the command
+// line (see Param::input_files()).  Before a later run with the same prefix, the stamp for the current inputs and options
+// is compared with the existing stamp, and if they are identical and the JSON summary of the
+// previous run is present the run is skipped.  The stamp is removed before a run starts, so an
+// interrupted or failed run is never considered current.
+
+use std::{
+    fs,
+    io::{self, Write},
+    path::Path,
+    time::UNIX_EPOCH,
+};
+
+use crate::cache::param_digest;
+use crate::output::output_path;
+use crate::params::Param;
+
+const STAMP_MAGIC: &str = "#ont_demult_stamp";
+
+pub struct Stamp {
+    text: String,
+}
+
+// Input files named on the command line (see Param::input_files()), without duplicates
+pub fn input_files(param: &Param) -> Vec<&str> {
+    let mut v: Vec<&str> = Vec::new();
+    for (_, f) in param.input_files() {
+        if !v.contains(&f.as_str()) {
+            v.push(f)
+        }
+    }
+    v
+}
+
+impl Stamp {
+    pub fn new(param: &Param) -> io::Result<Self> {
+        let args: Vec<_> = std::env::args().skip(1).collect();
+        let mut ctx = md5::Context::new();
+        for a in args.iter() {
+            ctx.consume(a);
+            ctx.consume("\0");
+        }
+        let mut text = format!(
+            "{}\t{}\nargs\t{:x}\nparams\t{:x}\n",
+            STAMP_MAGIC,
+            env!("CARGO_PKG_VERSION"),
+            ctx.finalize(),
+            param_digest(param)
+        );
+        for a in input_files(param) {
+            let md = fs::metadata(a)?;
+            let mtime = md
+                .modified()?
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_nanos())
+                .unwrap_or(0);
+            text.push_str(&format!("input\t{}\t{}\t{}\n", a, md.len(), mtime))
+        }
+        Ok(Self { text })
+    }
+
+    fn path(param: &Param) -> String {
+        format!("{}_stamp.txt", param.prefix())
+    }
+
+    // Returns true if the outputs of a previous run with the same inputs and options are present
+    pub fn is_current(&self, param: &Param) -> bool {
+        let summary = output_path("summary.json", param, param.compress_results());
+        match fs::read_to_string(Self::path(param)) {
+            Ok(s) => s == self.text && Path::new(&summary).is_file(),
+            Err(_) => false,
+        }
+    }
+
+    // Remove the stamp of a previous run (if present)
+    pub fn remove(param: &Param) -> io::Result<()> {
+        match fs::remove_file(Self::path(param)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn write(&self, param: &Param) -> io::Result<()> {
+        let mut f = fs::File::create(Self::path(param))?;
+        f.write_all(self.text.as_bytes())
+    }
+}
@@ -0,0 +1,203 @@
+// Statistics from the results files of previous runs (stats subcommand)
+//
+// One or more results files (<prefix>_res.txt, optionally compressed) are read and the counts
+// of reads and bases by match status, cut site and barcode are written, with the read length
+// distribution and N50 of the matched reads for each barcode, without reprocessing the PAF
+// input.  The reads from all the files are combined.  The output is a long format table with
+// one row per group (status, site or barcode), name and metric, with run wide metrics given
+// with group and name '*'.
+
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{self, BufRead, BufWriter, Write},
+};
+
+use anyhow::Context;
+use serde_json::{json, Value};
+
+use crate::aggregate::AggregateFormat;
+use crate::compressor::Compressor;
+use crate::error::{self, Error};
+use crate::summary::bin_label;
+
+#[derive(Default)]
+struct SiteCounts {
+    barcode: String,
+    matched: u64,
+    excess_unmatched: u64,
+    bases: u64,
+}
+
+#[derive(Default)]
+pub struct ResStats {
+    n_files: usize,
+    status: BTreeMap<String, (u64, u64)>, // Reads and bases for each match status
+    sites: BTreeMap<String, SiteCounts>,
+    barcodes: BTreeMap<String, Vec<usize>>, // Lengths of matched reads for each barcode
+}
+
+// Length of the shortest read such that reads at least this long contain half the bases
+fn n50(lengths: &mut [usize]) -> usize {
+    lengths.sort_unstable_by(|a, b| b.cmp(a));
+    let total: usize = lengths.iter().sum();
+    let mut s = 0;
+    for x in lengths.iter() {
+        s += x;
+        if 2 * s >= total {
+            return *x;
+        }
+    }
+    0
+}
+
+impl ResStats {
+    // Add the reads from a results file
+    pub fn read<R: BufRead>(&mut self, mut rdr: R) -> error::Result<()> {
+        let mut buf = String::new();
+        let mut line = 0;
+        loop {
+            buf.clear();
+            if rdr.read_line(&mut buf)? == 0 {
+                break;
+            }
+            line += 1;
+            if buf.starts_with('#') || buf.starts_with("read_name\t") {
+                continue;
+            }
+            let fd: Vec<_> = buf.trim_end_matches(['\r', '\n']).split('\t').collect();
+            if fd.len() < 8 {
+                return Err(Error::parse("Wrong number of fields in results line").at_line(line));
+            }
+            let len = fd[7]
+                .parse::<usize>()
+                .map_err(|e| Error::parse(format!("Parse error for length: {}", e)).at_line(line))?;
+            let e = self.status.entry(fd[1].to_owned()).or_default();
+            e.0 += 1;
+            e.1 += len as u64;
+            if matches!(fd[1], "Matched" | "ExcessUnmatched") {
+                let sc = self.sites.entry(fd[2].to_owned()).or_default();
+                if sc.barcode.is_empty() {
+                    sc.barcode = fd[3].to_owned()
+                }
+                if fd[1] == "Matched" {
+                    sc.matched += 1;
+                    sc.bases += len as u64;
+                    match self.barcodes.get_mut(fd[3]) {
+                        Some(v) => v.push(len),
+                        None => {
+                            self.barcodes.insert(fd[3].to_owned(), vec![len]);
+                        }
+                    }
+                } else {
+                    sc.excess_unmatched += 1
+                }
+            }
+        }
+        self.n_files += 1;
+        Ok(())
+    }
+
+    // Rows of (group, name, metric, value), with read lengths of matched reads counted in
+    // bins starting at the values in bins
+    fn rows(&mut self, bins: &[usize]) -> Vec<(&'static str, String, String, Value)> {
+        let mut rows = Vec::new();
+        let (reads, bases) = self
+            .status
+            .values()
+            .fold((0, 0), |(r, b), (r1, b1)| (r + r1, b + b1));
+        let frac = |n: u64, total: u64| if total > 0 { json!(n as f64 / total as f64) } else { json!(0.0) };
+        rows.push(("*", "*".to_owned(), "files".to_owned(), json!(self.n_files)));
+        rows.push(("*", "*".to_owned(), "reads".to_owned(), json!(reads)));
+        rows.push(("*", "*".to_owned(), "bases".to_owned(), json!(bases)));
+        for (k, (r, b)) in self.status.iter() {
+            rows.push(("status", k.clone(), "reads".to_owned(), json!(r)));
+            rows.push(("status", k.clone(), "bases".to_owned(), json!(b)));
+            rows.push(("status", k.clone(), "fraction".to_owned(), frac(*r, reads)));
+        }
+        for (k, sc) in self.sites.iter() {
+            rows.push(("site", k.clone(), "barcode".to_owned(), json!(sc.barcode)));
+            rows.push(("site", k.clone(), "matched_reads".to_owned(), json!(sc.matched)));
+            rows.push(("site", k.clone(), "matched_bases".to_owned(), json!(sc.bases)));
+            rows.push(("site", k.clone(), "excess_unmatched_reads".to_owned(), json!(sc.excess_unmatched)));
+        }
+        let matched = self.status.get("Matched").map(|x| x.0).unwrap_or(0);
+        for (k, v) in self.barcodes.iter_mut() {
+            let n = v.len() as u64;
+            let b: usize = v.iter().sum();
+            rows.push(("barcode", k.clone(), "matched_reads".to_owned(), json!(n)));
+            rows.push(("barcode", k.clone(), "matched_bases".to_owned(), json!(b)));
+            rows.push(("barcode", k.clone(), "barcode_fraction".to_owned(), frac(n, matched)));
+            rows.push(("barcode", k.clone(), "mean_length".to_owned(), json!(b as f64 / n as f64)));
+            rows.push(("barcode", k.clone(), "n50".to_owned(), json!(n50(v))));
+            let mut counts = vec![0u64; bins.len() + 1];
+            for x in v.iter() {
+                counts[bins.partition_point(|b| b <= x)] += 1
+            }
+            for (i, c) in counts.iter().enumerate() {
+                rows.push(("barcode", k.clone(), format!("length_{}", bin_label(bins, i)), json!(c)));
+            }
+        }
+        rows
+    }
+}
+
+fn write_rows<W: Write>(rows: &[(&str, String, String, Value)], format: AggregateFormat, wrt: &mut W) -> io::Result<()> {
+    match format {
+        AggregateFormat::Tsv => {
+            writeln!(wrt, "group\tname\tmetric\tvalue")?;
+            for (g, n, m, v) in rows.iter() {
+                match v.as_str() {
+                    Some(s) => writeln!(wrt, "{}\t{}\t{}\t{}", g, n, m, s)?,
+                    None => writeln!(wrt, "{}\t{}\t{}\t{}", g, n, m, v)?,
+                }
+            }
+        }
+        AggregateFormat::Json => {
+            let v: Vec<_> = rows
+                .iter()
+                .map(|(g, n, m, v)| json!({"group": g, "name": n, "metric": m, "value": v}))
+                .collect();
+            serde_json::to_writer_pretty(&mut *wrt, &v)?;
+            writeln!(wrt)?;
+        }
+    }
+    Ok(())
+}
+
+// Read the results files and write the statistics to output (or stdout if None)
+pub fn stats(
+    files: &[&str],
+    bins: &[usize],
+    format: AggregateFormat,
+    output: Option<&str>,
+    compressor: &Compressor,
+) -> anyhow::Result<()> {
+    let mut st = ResStats::default();
+    for file in files {
+        let rdr = compressor
+            .reader(Some(file))
+            .with_context(|| format!("Error opening results file {}", file))?;
+        st.read(rdr)
+            .map_err(|e| e.in_file(file))
+            .with_context(|| format!("Error reading results file {}", file))?;
+    }
+    let rows = st.rows(bins);
+    info!(
+        "Statistics for {} reads from {} files",
+        st.status.values().map(|x| x.0).sum::<u64>(),
+        files.len()
+    );
+    match output {
+        Some(file) => {
+            let mut wrt = BufWriter::new(File::create(file).with_context(|| format!("Error creating output file {}", file))?);
+            write_rows(&rows, format, &mut wrt).and_then(|_| wrt.flush())
+        }
+        None => {
+            let stdout = io::stdout();
+            let mut wrt = BufWriter::new(stdout.lock());
+            write_rows(&rows, format, &mut wrt).and_then(|_| wrt.flush())
+        }
+    }
+    .with_context(|| "Error writing statistics")
+}
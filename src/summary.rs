@@ -0,0 +1,510 @@
+// Summary of a run, used for the site and sample reports and for reporting to external processes
+// at the end of a run
+
+use std::{
+    collections::BTreeMap,
+    io::{self, Write},
+};
+
+use serde_json::{json, Value};
+
+use crate::classify::Category;
+use crate::cut_site::Site;
+use crate::offset::OffsetCounts;
+use crate::paf::Match;
+use crate::params::Param;
+use crate::warnings;
+
+// Drop the number of sites from rollup counts
+fn matched_counts(counts: BTreeMap<&str, (usize, u64)>) -> BTreeMap<&str, u64> {
+    counts.into_iter().map(|(k, (_, n))| (k, n)).collect()
+}
+
+// Rollup of matched reads per site in sites (see RunSummary::rollup())
+fn rollup_sites<'a, F: Fn(&'a Site) -> Option<&'a str>>(
+    sites: &BTreeMap<String, u64>,
+    param: &'a Param,
+    f: F,
+) -> BTreeMap<&'a str, (usize, u64)> {
+    let mut h = BTreeMap::new();
+    if let Some(cs) = param.cut_sites() {
+        for site in cs.chash.values().flat_map(|c| c.cut_sites.iter()) {
+            if let Some(k) = f(site) {
+                let e: &mut (usize, u64) = h.entry(k).or_default();
+                e.0 += 1;
+                e.1 += sites.get(&site.name).copied().unwrap_or(0);
+            }
+        }
+    }
+    h
+}
+
+// Label for bin i, where bins has the lower bounds of all bins apart from the first (i.e.,
+// 0-999, 1000-4999, 5000+)
+pub(crate) fn bin_label(bins: &[usize], i: usize) -> String {
+    let lo = if i == 0 { 0 } else { bins[i - 1] };
+    match bins.get(i) {
+        Some(hi) if *hi == lo + 1 => format!("{}", lo),
+        Some(hi) => format!("{}-{}", lo, hi - 1),
+        None => format!("{}+", lo),
+    }
+}
+
+// Add read with value x (i.e., read length), status and matched site (if any) to its bin in v.
+// bins has the sorted lower bounds of all bins apart from the first
+fn add_binned(v: &mut Vec<(String, RunCounts)>, bins: &[usize], x: usize, status: &'static str, site: Option<&str>) {
+    if v.is_empty() {
+        *v = (0..=bins.len()).map(|i| (bin_label(bins, i), RunCounts::default())).collect()
+    }
+    let rc = &mut v[bins.partition_point(|b| *b <= x)].1;
+    *rc.counts.entry(status).or_insert(0) += 1;
+    if let Some(site) = site {
+        match rc.sites.get_mut(site) {
+            Some(x) => *x += 1,
+            None => {
+                rc.sites.insert(site.to_owned(), 1);
+            }
+        }
+    }
+}
+
+// Write number and proportion (of the reads in the bin) of reads for each match status for
+// each bin in v.  col is the name of the bin column
+fn write_bin_report<W: Write>(v: &[(String, RunCounts)], col: &str, wrt: &mut W) -> io::Result<()> {
+    writeln!(wrt, "{}\tmatch_status\treads\tprop. reads", col)?;
+    for (label, rc) in v.iter() {
+        let total = rc.total().max(1) as f64;
+        for (k, n) in rc.counts.iter() {
+            writeln!(wrt, "{}\t{}\t{}\t{:.4}", label, k, n, *n as f64 / total)?;
+        }
+    }
+    Ok(())
+}
+
+// Read counts for a sequencing run (with --read-groups) or read length or MAPQ bin (with
+// --length-bins or --mapq-bins)
+#[derive(Debug, Default)]
+struct RunCounts {
+    counts: BTreeMap<&'static str, u64>, // Reads per match status
+    sites: BTreeMap<String, u64>,         // Matched reads per cut site
+}
+
+impl RunCounts {
+    fn total(&self) -> u64 {
+        self.counts.values().sum()
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct RunSummary {
+    counts: BTreeMap<&'static str, u64>, // Reads per match status
+    bases: BTreeMap<&'static str, u64>,  // Read bases per match status
+    sites: BTreeMap<String, u64>,         // Matched reads per cut site
+    coverage: BTreeMap<String, u64>,      // Reads mapped near each cut site
+    anomalies: BTreeMap<&'static str, u64>, // Counts of input records with parse anomalies
+    runs: BTreeMap<String, RunCounts>,     // Counts per sequencing run (reads with no run are 'unassigned')
+    lengths: Vec<(String, RunCounts)>,     // Counts per read length bin (with label)
+    mapqs: Vec<(String, RunCounts)>,       // Counts per MAPQ bin of the primary record (with label)
+    overrun: BTreeMap<usize, u64>,         // Matched reads by overrun of the cut site (see Match::overrun())
+    start_offsets: OffsetCounts,           // Matched reads by read start offset from the site
+    empty_inputs: Vec<&'static str>,       // Input files with no records
+    stopped: Option<&'static str>,         // Safety limit that stopped the run early (if any)
+}
+
+impl RunSummary {
+    pub fn add_read(&mut self, status: &'static str, len: usize) {
+        *self.counts.entry(status).or_insert(0) += 1;
+        *self.bases.entry(status).or_insert(0) += len as u64
+    }
+
+    pub fn add_match(&mut self, site: &str) {
+        match self.sites.get_mut(site) {
+            Some(x) => *x += 1,
+            None => {
+                self.sites.insert(site.to_owned(), 1);
+            }
+        }
+    }
+
+    // Record input with no records (i.e., an empty PAF or FASTQ file)
+    pub fn add_empty_input(&mut self, input: &'static str) {
+        warn!("{} input contains no records", input);
+        self.empty_inputs.push(input)
+    }
+
+    pub fn empty_inputs(&self) -> &[&'static str] {
+        &self.empty_inputs
+    }
+
+    // Record that processing of input was stopped early by a safety limit (i.e., max_runtime)
+    pub fn set_stopped(&mut self, limit: &'static str) {
+        if self.stopped.is_none() {
+            warn!("Stopping early as {} limit reached: the outputs only cover part of the input", limit);
+            self.stopped = Some(limit)
+        }
+    }
+
+    pub fn stopped(&self) -> Option<&'static str> {
+        self.stopped
+    }
+
+    pub fn add_start_offset(&mut self, m: &Match) {
+        self.start_offsets.add(m)
+    }
+
+    pub fn log_start_offsets(&self, param: &Param) {
+        self.start_offsets.log_report(param.start_offset())
+    }
+
+    // Add matched read with overrun x (ignored if 0)
+    pub fn add_overrun(&mut self, x: usize) {
+        if x > 0 {
+            *self.overrun.entry(x).or_insert(0) += 1
+        }
+    }
+
+    // Number of matched reads that relied on the margin allowance.  Overruns larger than the
+    // margin (or the largest margin set for a site) can only come from reads wrapping around
+    // circular contigs, so are not counted
+    fn margin_reads(&self, param: &Param) -> u64 {
+        self.overrun.range(..=param.site_margin()).map(|(_, n)| n).sum()
+    }
+
+    // Log the number of matched reads that relied on the margin allowance
+    pub fn log_margin_usage(&self, param: &Param) {
+        let matched = self.sites.values().sum::<u64>();
+        if matched > 0 && param.site_margin() > 0 {
+            let n = self.margin_reads(param);
+            info!(
+                "{} of {} matched reads ({:.2}%) relied on the margin allowance of {} bp",
+                n,
+                matched,
+                100.0 * n as f64 / matched as f64,
+                param.margin()
+            )
+        }
+    }
+
+    // Add read mapping within max_distance of site
+    pub fn add_coverage(&mut self, site: &str) {
+        match self.coverage.get_mut(site) {
+            Some(x) => *x += 1,
+            None => {
+                self.coverage.insert(site.to_owned(), 1);
+            }
+        }
+    }
+
+    // Add read from run (None if the run is not known) with status and matched site (if any)
+    pub fn add_run_read(&mut self, run: Option<&str>, status: &'static str, site: Option<&str>) {
+        let run = run.unwrap_or("unassigned");
+        let rc = match self.runs.get_mut(run) {
+            Some(rc) => rc,
+            None => self.runs.entry(run.to_owned()).or_default(),
+        };
+        *rc.counts.entry(status).or_insert(0) += 1;
+        if let Some(site) = site {
+            match rc.sites.get_mut(site) {
+                Some(x) => *x += 1,
+                None => {
+                    rc.sites.insert(site.to_owned(), 1);
+                }
+            }
+        }
+    }
+
+    // Add read of length len with status and matched site (if any) to its length bin.  bins
+    // has the sorted lower bounds of all bins apart from the first
+    pub fn add_length_read(&mut self, bins: &[usize], len: usize, status: &'static str, site: Option<&str>) {
+        add_binned(&mut self.lengths, bins, len, status, site)
+    }
+
+    // Add read with status to the bin for mapq, the MAPQ of its primary record
+    pub fn add_mapq_read(&mut self, bins: &[usize], mapq: usize, status: &'static str) {
+        add_binned(&mut self.mapqs, bins, mapq, status, None)
+    }
+
+    // Record n input records with a parse anomaly of type kind (i.e., invalid UTF-8)
+    pub fn add_anomalies(&mut self, kind: &'static str, n: usize) {
+        if n > 0 {
+            *self.anomalies.entry(kind).or_insert(0) += n as u64
+        }
+    }
+
+    fn total(&self) -> u64 {
+        self.counts.values().sum()
+    }
+
+    // Number of sites and matched reads for each distinct value of f (i.e., sample or group)
+    // aggregated over the sites with that value
+    fn rollup<'a, F: Fn(&'a Site) -> Option<&'a str>>(
+        &self,
+        param: &'a Param,
+        f: F,
+    ) -> BTreeMap<&'a str, (usize, u64)> {
+        rollup_sites(&self.sites, param, f)
+    }
+
+    fn sample_counts<'a>(&self, param: &'a Param) -> BTreeMap<&'a str, (usize, u64)> {
+        self.rollup(param, |s| s.sample.as_deref())
+    }
+
+    fn group_counts<'a>(&self, param: &'a Param) -> BTreeMap<&'a str, (usize, u64)> {
+        self.rollup(param, |s| s.group.as_deref())
+    }
+
+    fn barcode_counts<'a>(&self, param: &'a Param) -> BTreeMap<&'a str, (usize, u64)> {
+        self.rollup(param, |s| Some(s.barcode.as_str()))
+    }
+
+    // Write JSON summary (<prefix>_summary.json)
+    pub fn write_json<W: Write>(&self, param: &Param, wrt: &mut W) -> io::Result<()> {
+        serde_json::to_writer_pretty(&mut *wrt, &self.to_json(param, None))?;
+        writeln!(wrt)
+    }
+
+    // Warn about cut sites with no matched reads or with much fewer matched reads than
+    // the average over all sites
+    pub fn check_balance(&self, param: &Param) {
+        // No reads were classified (empty input), so there is nothing to check
+        if self.counts.is_empty() {
+            return;
+        }
+        if let Some(cs) = param.cut_sites() {
+            let mut v: Vec<_> = cs.chash.values().flat_map(|c| c.cut_sites.iter()).collect();
+            v.sort_unstable_by(|a, b| a.name.cmp(&b.name));
+            let mean = self.sites.values().sum::<u64>() as f64 / v.len().max(1) as f64;
+            for site in v {
+                let n = self.sites.get(&site.name).copied().unwrap_or(0);
+                if n == 0 {
+                    warn!("No reads matched to cut site {}", site.name)
+                } else if (n as f64) < 0.1 * mean {
+                    warn!(
+                        "Low number of reads matched to cut site {} ({} vs. mean of {:.1})",
+                        site.name, n, mean
+                    )
+                }
+            }
+        }
+    }
+
+    // Write number and proportion of reads for each match status
+    pub fn write_status_report<W: Write>(&self, wrt: &mut W) -> io::Result<()> {
+        writeln!(wrt, "match_status\treads\tprop. reads")?;
+        let total = self.total().max(1) as f64;
+        for (k, n) in self.counts.iter() {
+            writeln!(wrt, "{}\t{}\t{:.4}", k, n, *n as f64 / total)?;
+        }
+        Ok(())
+    }
+
+    // Write number and proportion of matched reads for each cut site
+    pub fn write_site_report<W: Write>(&self, param: &Param, wrt: &mut W) -> io::Result<()> {
+        writeln!(wrt, "site\tbarcode\tsample\tmatched\tprop. reads")?;
+        let total = self.total().max(1) as f64;
+        if let Some(cs) = param.cut_sites() {
+            let mut v: Vec<_> = cs.chash.values().flat_map(|c| c.cut_sites.iter()).collect();
+            v.sort_unstable_by(|a, b| a.name.cmp(&b.name));
+            for site in v {
+                let n = self.sites.get(&site.name).copied().unwrap_or(0);
+                writeln!(
+                    wrt,
+                    "{}\t{}\t{}\t{}\t{:.4}",
+                    site.name,
+                    site.barcode,
+                    site.sample.as_deref().unwrap_or("*"),
+                    n,
+                    n as f64 / total
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    // Write number of matched reads, number of reads mapped near the site and the percentage
+    // of those reads matched (cut efficiency) for each cut site
+    pub fn write_efficiency_report<W: Write>(&self, param: &Param, wrt: &mut W) -> io::Result<()> {
+        writeln!(wrt, "site\tbarcode\tmatched\tcovering\tefficiency (%)")?;
+        if let Some(cs) = param.cut_sites() {
+            let mut v: Vec<_> = cs.chash.values().flat_map(|c| c.cut_sites.iter()).collect();
+            v.sort_unstable_by(|a, b| a.name.cmp(&b.name));
+            for site in v {
+                let n = self.sites.get(&site.name).copied().unwrap_or(0);
+                let cov = self.coverage.get(&site.name).copied().unwrap_or(0);
+                write!(wrt, "{}\t{}\t{}\t{}\t", site.name, site.barcode, n, cov)?;
+                if cov > 0 {
+                    writeln!(wrt, "{:.2}", 100.0 * n as f64 / cov as f64)?
+                } else {
+                    writeln!(wrt, "*")?
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Write number and proportion of reads for each match status for each run
+    // Distribution of overruns for matched reads that relied on the margin allowance
+    pub fn write_margin_report<W: Write>(&self, param: &Param, wrt: &mut W) -> io::Result<()> {
+        let matched = self.sites.values().sum::<u64>();
+        writeln!(wrt, "overrun\treads\tprop. matched")?;
+        for (x, n) in self.overrun.range(..=param.site_margin()) {
+            writeln!(wrt, "{}\t{}\t{:.4}", x, n, *n as f64 / matched.max(1) as f64)?
+        }
+        Ok(())
+    }
+
+    pub fn write_run_report<W: Write>(&self, wrt: &mut W) -> io::Result<()> {
+        writeln!(wrt, "run\tmatch_status\treads\tprop. reads")?;
+        for (run, rc) in self.runs.iter() {
+            let total = rc.total().max(1) as f64;
+            for (k, n) in rc.counts.iter() {
+                writeln!(wrt, "{}\t{}\t{}\t{:.4}", run, k, n, *n as f64 / total)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Write number and proportion (of the reads from the run) of matched reads for each cut
+    // site for each run
+    pub fn write_run_site_report<W: Write>(&self, param: &Param, wrt: &mut W) -> io::Result<()> {
+        writeln!(wrt, "run\tsite\tbarcode\tmatched\tprop. reads")?;
+        if let Some(cs) = param.cut_sites() {
+            let mut v: Vec<_> = cs.chash.values().flat_map(|c| c.cut_sites.iter()).collect();
+            v.sort_unstable_by(|a, b| a.name.cmp(&b.name));
+            for (run, rc) in self.runs.iter() {
+                let total = rc.total().max(1) as f64;
+                for site in v.iter() {
+                    let n = rc.sites.get(&site.name).copied().unwrap_or(0);
+                    writeln!(
+                        wrt,
+                        "{}\t{}\t{}\t{}\t{:.4}",
+                        run,
+                        site.name,
+                        site.barcode,
+                        n,
+                        n as f64 / total
+                    )?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Write number and proportion (of the reads in the bin) of reads for each match status for
+    // each read length bin
+    pub fn write_length_report<W: Write>(&self, wrt: &mut W) -> io::Result<()> {
+        write_bin_report(&self.lengths, "length_bin", wrt)
+    }
+
+    // As write_length_report() for each MAPQ bin
+    pub fn write_mapq_report<W: Write>(&self, wrt: &mut W) -> io::Result<()> {
+        write_bin_report(&self.mapqs, "mapq_bin", wrt)
+    }
+
+    // Write number and proportion (of the reads in the bin) of matched reads for each barcode
+    // for each read length bin
+    pub fn write_length_barcode_report<W: Write>(&self, param: &Param, wrt: &mut W) -> io::Result<()> {
+        writeln!(wrt, "length_bin\tbarcode\tmatched\tprop. reads")?;
+        for (label, rc) in self.lengths.iter() {
+            let total = rc.total().max(1) as f64;
+            for (k, (_, n)) in rollup_sites(&rc.sites, param, |s| Some(s.barcode.as_str())) {
+                writeln!(wrt, "{}\t{}\t{}\t{:.4}", label, k, n, n as f64 / total)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn write_rollup<W: Write>(
+        &self,
+        hdr: &str,
+        counts: BTreeMap<&str, (usize, u64)>,
+        wrt: &mut W,
+    ) -> io::Result<()> {
+        writeln!(wrt, "{}\tsites\tmatched\tprop. reads", hdr)?;
+        let total = self.total().max(1) as f64;
+        for (k, (nsites, n)) in counts {
+            writeln!(wrt, "{}\t{}\t{}\t{:.4}", k, nsites, n, n as f64 / total)?;
+        }
+        Ok(())
+    }
+
+    // Write number of sites and number and proportion of matched reads for each sample
+    pub fn write_sample_report<W: Write>(&self, param: &Param, wrt: &mut W) -> io::Result<()> {
+        self.write_rollup("sample", self.sample_counts(param), wrt)
+    }
+
+    // Write number of sites and number and proportion of matched reads for each group
+    pub fn write_group_report<W: Write>(&self, param: &Param, wrt: &mut W) -> io::Result<()> {
+        self.write_rollup("group", self.group_counts(param), wrt)
+    }
+
+    // Generate JSON summary.  If the run failed, err should have the error message
+    pub fn to_json(&self, param: &Param, err: Option<&str>) -> Value {
+        json!({
+            "program": "ont_demult",
+            "version": env!("CARGO_PKG_VERSION"),
+            "status": match (err, self.stopped) {
+                (Some(_), _) => "failed",
+                (None, Some(_)) => "partial",
+                _ => "completed",
+            },
+            "error": err,
+            "stop_reason": self.stopped,
+            "prefix": param.prefix(),
+            "paf_file": param.paf_file(),
+            "fastq_file": param.fastq_file(),
+            "counts": self.counts,
+            "bases": self.bases,
+            "total_reads": self.counts.values().sum::<u64>(),
+            "total_bases": self.bases.values().sum::<u64>(),
+            "category_codes": Category::ALL
+                .iter()
+                .map(|c| (c.name(), c.code()))
+                .collect::<BTreeMap<_, _>>(),
+            "sites": self.sites,
+            "samples": matched_counts(self.sample_counts(param)),
+            "groups": matched_counts(self.group_counts(param)),
+            "barcodes": matched_counts(self.barcode_counts(param)),
+            "parse_anomalies": self.anomalies,
+            "margin_matches": self.margin_reads(param),
+            "start_offsets": self.start_offsets.to_json(param.start_offset()),
+            "runs": self
+                .runs
+                .iter()
+                .map(|(run, rc)| (run.as_str(), json!({"counts": rc.counts, "sites": rc.sites})))
+                .collect::<BTreeMap<_, _>>(),
+            "length_bins": self
+                .lengths
+                .iter()
+                .map(|(label, rc)| {
+                    json!({
+                        "bin": label,
+                        "counts": rc.counts,
+                        "barcodes": matched_counts(rollup_sites(&rc.sites, param, |s| Some(s.barcode.as_str()))),
+                    })
+                })
+                .collect::<Vec<_>>(),
+            "mapq_bins": self
+                .mapqs
+                .iter()
+                .map(|(label, rc)| json!({"bin": label, "counts": rc.counts}))
+                .collect::<Vec<_>>(),
+            "empty_inputs": self.empty_inputs,
+            "parameters": {
+                "select": param.select().to_string(),
+                "primary_select": param.primary_select().to_string(),
+                "mapq_threshold": param.mapq_thresh(),
+                "max_distance": param.max_distance(),
+                "max_unmatched": param.max_unmatched(),
+                "margin": param.margin(),
+                "end_padding": param.end_padding(),
+                "start_offset": param.start_offset(),
+                "skip_reads": param.skip_reads(),
+                "limit_reads": param.limit_reads(),
+                "max_reads": param.max_reads(),
+                "cut_sites": param.cut_sites().map(|cs| cs.chash.values().map(|c| c.cut_sites.len()).sum::<usize>()),
+            },
+            "warnings": warnings::warnings(),
+        })
+    }
+}
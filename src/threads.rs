@@ -0,0 +1,70 @@
+// Automatic allocation of threads between the stages of a run
+//
+// With --threads auto (or auto for the option of an individual stage) the number of threads
+// for each stage is set here from the available cores.  Classification of the PAF input and
+// routing of the FASTQ input do not overlap (except with --synced, where the FASTQ reads are
+// routed on the main thread), so the cores are shared out separately for the two phases:
+//
+//   - classification threads are chosen by timing the first chunk of the PAF input
+//     (see classify_threads());
+//   - compression threads for output files compressed by the internal implementation take
+//     half of the available cores, as they run for the whole of the run;
+//   - for the FASTQ phase, one core is left for the main (routing) thread and up to half of
+//     the rest for the compression of the output files (by the compression threads or by
+//     external processes, one per output file).  The remainder is shared between decompression
+//     of the input and the lookup threads (--fastq-threads), with the lookup threads taking
+//     what is left after decompression (or half of the remainder if both are set
+//     automatically), up to a quarter of the available cores as looking up a read is much
+//     faster than routing it.
+
+use std::time::Duration;
+
+use crate::compressor;
+use crate::params::{available_cores, Param};
+
+// Number of classification threads given the time taken to read and to classify (with a
+// single thread) a chunk of reads.  As reading is done in the main thread, the aim is to bring
+// the classification time down to a quarter of the reading time
+pub fn classify_threads(t_read: Duration, t_class: Duration) -> usize {
+    let r = t_class.as_secs_f64() / t_read.as_secs_f64().max(1.0e-6);
+    ((4.0 * r).ceil() as usize).clamp(1, available_cores())
+}
+
+// Number of threads for internal gzip compression of the output files
+pub fn compress_threads() -> usize {
+    (available_cores() / 2).max(1)
+}
+
+// Threads for the FASTQ phase of a run, with the values given in param used where set
+#[derive(Debug, Clone, Copy)]
+pub struct FastqThreads {
+    pub decompress: usize, // Decompression of the FASTQ input
+    pub lookup: usize,     // Lookup of the classifications of the FASTQ reads
+}
+
+impl FastqThreads {
+    // Allocate the threads for routing the FASTQ input to n_outputs output files
+    pub fn new(param: &Param, n_outputs: usize) -> Self {
+        let n = available_cores().saturating_sub(1);
+        let comp = if !param.compress_fastq() {
+            0
+        } else if param.compressor().internal_gzip() {
+            compressor::compress_threads().min(n / 2)
+        } else {
+            n_outputs.min(n / 2)
+        };
+        let avail = (n - comp).max(1);
+        let lookup = param.fastq_threads().unwrap_or_else(|| {
+            let free = match param.decompress_threads() {
+                Some(d) => avail.saturating_sub(d),
+                None => avail / 2,
+            };
+            free.min(available_cores() / 4).max(1)
+        });
+        let decompress = param.decompress_threads().unwrap_or_else(|| match param.fastq_threads() {
+            Some(_) => avail,
+            None => avail.saturating_sub(lookup).max(1),
+        });
+        Self { decompress, lookup }
+    }
+}
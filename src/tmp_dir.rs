@@ -0,0 +1,109 @@
+// Temporary (scratch) directory handling
+//
+// A run specific temporary directory is created under the directory given by --tmp-dir
+// (or $TMPDIR or /tmp if not set).  The directory and its contents are removed when
+// the TmpDir is dropped, or if the process is interrupted by a signal (SIGINT, SIGTERM
+// or SIGHUP).  Temporary files written outside of the temporary directory (i.e., incomplete
+// cache entries) can also be registered for removal on interruption.
+
+use std::{
+    env, fs,
+    io,
+    path::{Path, PathBuf},
+    process,
+    sync::{Mutex, Once},
+};
+
+// Directories and files to be cleaned up if the process is interrupted
+static LIVE_DIRS: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+static LIVE_FILES: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+static HANDLER: Once = Once::new();
+
+fn install_handler() {
+    HANDLER.call_once(|| {
+        if let Err(e) = ctrlc::set_handler(|| {
+            if let Ok(v) = LIVE_DIRS.lock() {
+                for p in v.iter() {
+                    let _ = fs::remove_dir_all(p);
+                }
+            }
+            if let Ok(v) = LIVE_FILES.lock() {
+                for p in v.iter() {
+                    let _ = fs::remove_file(p);
+                }
+            }
+            error!("Interrupted");
+            process::exit(130)
+        }) {
+            warn!("Could not install signal handler for temporary file cleanup: {}", e)
+        }
+    })
+}
+
+// Register a temporary file to be removed if the process is interrupted
+pub fn register_file<P: AsRef<Path>>(path: P) {
+    install_handler();
+    LIVE_FILES.lock().unwrap().push(path.as_ref().to_owned())
+}
+
+// Remove a file from the cleanup list (once it has been renamed or removed)
+pub fn unregister_file<P: AsRef<Path>>(path: P) {
+    if let Ok(mut v) = LIVE_FILES.lock() {
+        v.retain(|p| p != path.as_ref())
+    }
+}
+
+#[derive(Debug)]
+pub struct TmpDir {
+    path: PathBuf,
+}
+
+impl TmpDir {
+    // Create a new temporary directory under base.  If base is None then $TMPDIR is used
+    // if set, otherwise the system default
+    pub fn new<P: AsRef<Path>>(base: Option<P>) -> io::Result<Self> {
+        let base = base
+            .map(|p| p.as_ref().to_owned())
+            .unwrap_or_else(env::temp_dir);
+        install_handler();
+        let pid = process::id();
+        let mut ix = 0;
+        let path = loop {
+            let p = base.join(format!("ont_demult.{}.{}", pid, ix));
+            match fs::create_dir(&p) {
+                Ok(_) => break p,
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => ix += 1,
+                Err(e) => {
+                    return Err(io::Error::other(format!(
+                        "Could not create temporary directory in {}: {}",
+                        base.display(),
+                        e
+                    )))
+                }
+            }
+        };
+        LIVE_DIRS.lock().unwrap().push(path.clone());
+        info!("Using temporary directory {}", path.display());
+        Ok(Self { path })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TmpDir {
+    fn drop(&mut self) {
+        if let Ok(mut v) = LIVE_DIRS.lock() {
+            v.retain(|p| p != &self.path)
+        }
+        match fs::remove_dir_all(&self.path) {
+            Ok(_) => debug!("Removed temporary directory {}", self.path.display()),
+            Err(e) => warn!(
+                "Could not remove temporary directory {}: {}",
+                self.path.display(),
+                e
+            ),
+        }
+    }
+}
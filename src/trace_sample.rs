@@ -0,0 +1,61 @@
+// Sampled trace logging (--trace-sample)
+//
+// Logging at the trace level gives a detailed account of the classification of every read,
+// which is impractical for large runs.  With --trace-sample, trace messages are only output
+// while a sampled read is being parsed and classified (whatever the log level).  Reads are
+// sampled by a hash of the read name and the seed (--trace-seed), so the same reads are
+// sampled whatever the number of threads or the order of the input.
+
+use std::{cell::Cell, sync::OnceLock};
+
+use log::LevelFilter;
+
+// Sampling threshold (reads with hash below this are sampled) and seed
+static SAMPLE: OnceLock<(u64, u64)> = OnceLock::new();
+
+thread_local! {
+    // Set while a sampled read is being processed on this thread
+    static TRACING: Cell<bool> = const { Cell::new(false) };
+}
+
+// Sample a proportion frac of reads for trace logging
+pub fn init(frac: f64, seed: u64) {
+    let thresh = if frac >= 1.0 {
+        u64::MAX
+    } else {
+        (frac * u64::MAX as f64) as u64
+    };
+    if SAMPLE.set((thresh, seed)).is_ok() {
+        log::set_max_level(LevelFilter::Trace)
+    }
+}
+
+// Whether trace messages should be output from the current thread
+pub fn tracing() -> bool {
+    TRACING.with(|t| t.get())
+}
+
+fn sampled(name: &str) -> bool {
+    match SAMPLE.get() {
+        Some((thresh, seed)) => {
+            let mut ctx = md5::Context::new();
+            ctx.consume(seed.to_le_bytes());
+            ctx.consume(name);
+            let d = ctx.finalize();
+            u64::from_le_bytes(d.0[..8].try_into().unwrap()) <= *thresh
+        }
+        None => false,
+    }
+}
+
+// Run f for read name, enabling trace messages if the read is sampled
+pub fn with_read<T, F: FnOnce() -> T>(name: &str, f: F) -> T {
+    if !sampled(name) {
+        return f();
+    }
+    TRACING.with(|t| t.set(true));
+    trace!("Sampled read {}", name);
+    let res = f();
+    TRACING.with(|t| t.set(false));
+    res
+}
@@ -0,0 +1,235 @@
+// io_uring backed reader and writer for uncompressed files (Linux only, enabled with
+// the io-uring feature)
+//
+// Both the reader and the writer use two large buffers so that one IO operation can be in
+// flight while the other buffer is being consumed (reader) or filled (writer).
+
+use std::{
+    fs::File,
+    io::{self, Error, Read, Write},
+    os::unix::{fs::FileExt, io::AsRawFd},
+    path::Path,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use io_uring::{opcode, types, IoUring};
+
+const URING_BLOCK_SIZE: usize = 1 << 20;
+const URING_ENTRIES: u32 = 4;
+
+// Set from the command line to enable the io_uring backend
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed)
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+// Wait for the single outstanding operation and return its result
+fn wait_one(ring: &mut IoUring) -> io::Result<usize> {
+    ring.submit_and_wait(1)?;
+    let cqe = ring
+        .completion()
+        .next()
+        .ok_or_else(|| Error::other("io_uring completion queue empty"))?;
+    let res = cqe.result();
+    if res < 0 {
+        Err(Error::from_raw_os_error(-res))
+    } else {
+        Ok(res as usize)
+    }
+}
+
+pub struct UringReader {
+    file: File,
+    ring: IoUring,
+    bufs: [Vec<u8>; 2],
+    cur: usize,       // Index of buffer being consumed
+    pos: usize,       // Position in current buffer
+    len: usize,       // Valid bytes in current buffer
+    offset: u64,      // File offset for next read submission
+    in_flight: bool,  // Read in flight to the other buffer
+    eof: bool,
+}
+
+impl UringReader {
+    pub fn open<P: AsRef<Path>>(name: P) -> io::Result<Self> {
+        let file = File::open(name)?;
+        let ring = IoUring::new(URING_ENTRIES)?;
+        let mut rdr = Self {
+            file,
+            ring,
+            bufs: [vec![0; URING_BLOCK_SIZE], vec![0; URING_BLOCK_SIZE]],
+            cur: 0,
+            pos: 0,
+            len: 0,
+            offset: 0,
+            in_flight: false,
+            eof: false,
+        };
+        rdr.submit_read()?;
+        Ok(rdr)
+    }
+
+    // Submit read into the buffer that is not currently being consumed
+    fn submit_read(&mut self) -> io::Result<()> {
+        let buf = &mut self.bufs[1 - self.cur];
+        let entry = opcode::Read::new(
+            types::Fd(self.file.as_raw_fd()),
+            buf.as_mut_ptr(),
+            buf.len() as u32,
+        )
+        .offset(self.offset)
+        .build();
+        // Safety: the buffer is not accessed or resized until the read has completed
+        // (guaranteed by in_flight, which is checked before the buffer is used and on drop)
+        unsafe {
+            self.ring
+                .submission()
+                .push(&entry)
+                .map_err(|_| Error::other("io_uring submission queue full"))?;
+        }
+        self.ring.submit()?;
+        self.in_flight = true;
+        Ok(())
+    }
+
+    // Wait for in flight read, swap buffers and start the next read
+    fn next_block(&mut self) -> io::Result<()> {
+        if !self.in_flight {
+            self.submit_read()?;
+        }
+        let n = wait_one(&mut self.ring);
+        self.in_flight = false;
+        let n = n?;
+        self.cur = 1 - self.cur;
+        self.pos = 0;
+        self.len = n;
+        if n == 0 {
+            self.eof = true
+        } else {
+            self.offset += n as u64;
+            self.submit_read()?;
+        }
+        Ok(())
+    }
+}
+
+impl Read for UringReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.len {
+            if self.eof {
+                return Ok(0);
+            }
+            self.next_block()?;
+        }
+        let n = buf.len().min(self.len - self.pos);
+        buf[..n].copy_from_slice(&self.bufs[self.cur][self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+impl Drop for UringReader {
+    fn drop(&mut self) {
+        if self.in_flight {
+            let _ = wait_one(&mut self.ring);
+        }
+    }
+}
+
+pub struct UringWriter {
+    file: File,
+    ring: IoUring,
+    bufs: [Vec<u8>; 2],
+    cur: usize,               // Index of buffer being filled
+    offset: u64,              // File offset for next write submission
+    in_flight: Option<(u64, usize)>, // Offset and length of write in flight from the other buffer
+}
+
+impl UringWriter {
+    pub fn create<P: AsRef<Path>>(name: P) -> io::Result<Self> {
+        let file = File::create(name)?;
+        let ring = IoUring::new(URING_ENTRIES)?;
+        Ok(Self {
+            file,
+            ring,
+            bufs: [
+                Vec::with_capacity(URING_BLOCK_SIZE),
+                Vec::with_capacity(URING_BLOCK_SIZE),
+            ],
+            cur: 0,
+            offset: 0,
+            in_flight: None,
+        })
+    }
+
+    // Wait for write in flight, completing any short write synchronously
+    fn wait_write(&mut self) -> io::Result<()> {
+        if let Some((off, len)) = self.in_flight.take() {
+            let n = wait_one(&mut self.ring)?;
+            let buf = &self.bufs[1 - self.cur];
+            if n < len {
+                self.file.write_all_at(&buf[n..len], off + n as u64)?;
+            }
+            self.bufs[1 - self.cur].clear();
+        }
+        Ok(())
+    }
+
+    // Submit current buffer for writing and switch to the other buffer
+    fn submit_write(&mut self) -> io::Result<()> {
+        self.wait_write()?;
+        let len = self.bufs[self.cur].len();
+        if len == 0 {
+            return Ok(());
+        }
+        let entry = opcode::Write::new(
+            types::Fd(self.file.as_raw_fd()),
+            self.bufs[self.cur].as_ptr(),
+            len as u32,
+        )
+        .offset(self.offset)
+        .build();
+        // Safety: the buffer is not modified until the write has completed (see wait_write)
+        unsafe {
+            self.ring
+                .submission()
+                .push(&entry)
+                .map_err(|_| Error::other("io_uring submission queue full"))?;
+        }
+        self.ring.submit()?;
+        self.in_flight = Some((self.offset, len));
+        self.offset += len as u64;
+        self.cur = 1 - self.cur;
+        Ok(())
+    }
+}
+
+impl Write for UringWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let space = URING_BLOCK_SIZE - self.bufs[self.cur].len();
+        let n = buf.len().min(space);
+        self.bufs[self.cur].extend_from_slice(&buf[..n]);
+        if self.bufs[self.cur].len() == URING_BLOCK_SIZE {
+            self.submit_write()?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.submit_write()?;
+        self.wait_write()
+    }
+}
+
+impl Drop for UringWriter {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            error!("Error writing output file: {}", e)
+        }
+    }
+}
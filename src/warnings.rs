@@ -0,0 +1,60 @@
+// Collection of warnings emitted during a run
+//
+// The stderr logger is wrapped so that every message logged at the warn level is also
+// stored, allowing the warnings to be included in the JSON report at the end of the run.
+// Warnings are collected even if the log level is set so that they are not displayed.  The
+// wrapper also passes trace messages for sampled reads (see trace_sample.rs) whatever the
+// log level.
+
+use std::sync::Mutex;
+
+use log::{Level, LevelFilter, Log, Metadata, Record, SetLoggerError};
+use stderrlog::StdErrLog;
+
+use crate::trace_sample;
+
+static WARNINGS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+struct CollectingLogger {
+    inner: StdErrLog,   // Logger accepting all levels
+    level: LevelFilter, // Selected log level
+}
+
+impl CollectingLogger {
+    fn pass(&self, level: Level) -> bool {
+        level <= self.level || (level == Level::Trace && trace_sample::tracing())
+    }
+}
+
+impl Log for CollectingLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= Level::Warn || self.pass(metadata.level())
+    }
+
+    fn log(&self, record: &Record) {
+        if record.level() == Level::Warn {
+            if let Ok(mut v) = WARNINGS.lock() {
+                v.push(record.args().to_string())
+            }
+        }
+        if self.pass(record.level()) {
+            self.inner.log(record)
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush()
+    }
+}
+
+// Install logger, wrapping inner (which should accept all levels) and only passing messages
+// up to level
+pub fn init(inner: StdErrLog, level: LevelFilter) -> Result<(), SetLoggerError> {
+    log::set_max_level(level.max(LevelFilter::Warn));
+    log::set_boxed_logger(Box::new(CollectingLogger { inner, level }))
+}
+
+// Warnings emitted so far
+pub fn warnings() -> Vec<String> {
+    WARNINGS.lock().map(|v| v.clone()).unwrap_or_default()
+}